@@ -0,0 +1,120 @@
+use std::{
+    env, fs, io,
+    process::exit,
+    time::{Duration, Instant},
+};
+use tinyvm::{
+    context::{ExecutionError, Memory, Program},
+    decoded::DecodedProgram,
+};
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use tinyvm::jit::JitProgram;
+
+const DEFAULT_RUNS: usize = 10;
+
+fn usage() -> ! {
+    println!("Usage: `bench [--runs n] file...`");
+    exit(1);
+}
+
+fn main() {
+    let mut runs = DEFAULT_RUNS;
+    let mut filenames = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--runs" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => runs = n,
+                _ => usage(),
+            },
+            _ => filenames.push(arg),
+        }
+    }
+
+    if filenames.is_empty() {
+        usage();
+    }
+
+    print!(
+        "{:<40} {:>12} {:>12} {:>8} {:>12} {:>8}",
+        "program", "step", "unfused", "speedup", "decoded", "speedup"
+    );
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    print!(" {:>12} {:>8}", "jit", "speedup");
+    println!();
+
+    for filename in filenames {
+        let program = match fs::read_to_string(&filename)
+            .map_err(|e| e.to_string())
+            .and_then(|s| {
+                Program::load_with_file_name(s, Some(&filename)).map_err(|e| format!("{:?}", e))
+            }) {
+            Ok(program) => program,
+            Err(e) => {
+                println!("Error loading {}: {}", filename, e);
+                exit(1);
+            }
+        };
+
+        let step = best_of(runs, &program, |memory| {
+            while program.step(memory)? {}
+            Ok(())
+        });
+        let unfused = best_of(runs, &program, |memory| {
+            DecodedProgram::unfused(&program).run(memory)
+        });
+        let decoded = best_of(runs, &program, |memory| {
+            DecodedProgram::new(&program).run(memory)
+        });
+        print!(
+            "{:<40} {:>10.3}ms {:>10.3}ms {:>7.2}x {:>10.3}ms {:>7.2}x",
+            filename,
+            step.as_secs_f64() * 1000.0,
+            unfused.as_secs_f64() * 1000.0,
+            step.as_secs_f64() / unfused.as_secs_f64(),
+            decoded.as_secs_f64() * 1000.0,
+            step.as_secs_f64() / decoded.as_secs_f64()
+        );
+
+        // Includes compiling the program
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        {
+            let jit = best_of(runs, &program, |memory| {
+                JitProgram::new(&program)
+                    .expect("failed compiling program")
+                    .run(memory)
+            });
+            print!(
+                " {:>10.3}ms {:>7.2}x",
+                jit.as_secs_f64() * 1000.0,
+                step.as_secs_f64() / jit.as_secs_f64()
+            );
+        }
+        println!();
+    }
+}
+
+/// The shortest of `runs` runs, each with freshly initialized memory that is
+/// not included in the time.
+fn best_of<F: FnMut(&mut Memory) -> Result<(), ExecutionError>>(
+    runs: usize,
+    program: &Program,
+    mut run: F,
+) -> Duration {
+    (0..runs)
+        .map(|_| {
+            let mut memory = program.initialize();
+            memory.output = Box::new(io::sink());
+
+            let start = Instant::now();
+            if let Err(e) = run(&mut memory) {
+                println!("{}", e);
+                exit(1);
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,44 @@
+use std::{env, fs, process::exit};
+use tinyvm::context::Program;
+
+fn usage() -> ! {
+    println!("Usage: `tvm-cfg file [function]`");
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let (filename, function) = match args.as_slice() {
+        [filename] => (filename, None),
+        [filename, function] => (filename, Some(function)),
+        _ => usage(),
+    };
+
+    let source = match fs::read_to_string(filename) {
+        Ok(s) => s,
+        Err(_) => {
+            println!("Error reading file {}", filename);
+            exit(1);
+        }
+    };
+
+    let program = match Program::load_with_file_name(source, Some(filename)) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Error {:?}", e);
+            exit(1);
+        }
+    };
+
+    let graph = program.control_flow_graph();
+    match function {
+        Some(name) => match graph.function(name) {
+            Some(function) => print!("{}", graph.function_to_dot(&program, function)),
+            None => {
+                println!("No function {}", name);
+                exit(1);
+            }
+        },
+        None => print!("{}", graph.to_dot(&program)),
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    env,
+    fs::File,
+    io::{stdin, stdout, BufRead, BufReader, Write},
+    process::exit,
+};
+use tinyvm::{
+    context::WordSize,
+    core_dump::CoreDump,
+    debugger::{gdb, Debugger},
+    instruction::{Register, VectorRegister},
+};
+
+const HELP: &str = "\
+info                     the error and where it occurred
+registers                the registers, flags, remainder and vector registers
+x <address> [count]      hexdump of count words (default 16)
+stack [count]            hexdump of the stack, from esp (default 16 words)
+backtrace                the active calls
+disassemble [start] [n]  n instructions (default 10) from start
+quit";
+
+fn usage() -> ! {
+    println!("Usage: `tvm-core [--gdb address:port] core [command]`");
+    exit(1);
+}
+
+fn main() {
+    let mut gdb_address = None;
+    let mut filename = None;
+    let mut command = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gdb" if filename.is_none() => match args.next() {
+                Some(address) => gdb_address = Some(address),
+                None => usage(),
+            },
+            _ if filename.is_none() => filename = Some(arg),
+            _ => command.push(arg),
+        }
+    }
+
+    let filename = match filename {
+        Some(f) => f,
+        None => usage(),
+    };
+
+    let core = match File::open(&filename)
+        .map_err(|e| e.into())
+        .and_then(|f| CoreDump::read(&mut BufReader::new(f)))
+    {
+        Ok(core) => core,
+        Err(e) => {
+            println!("Error reading core dump {}: {:?}", filename, e);
+            exit(1);
+        }
+    };
+
+    if let Some(address) = gdb_address {
+        let CoreDump {
+            program, memory, ..
+        } = core;
+        let mut debugger = Debugger::new(&program);
+        *debugger.memory_mut() = memory;
+        if let Err(e) = gdb::listen(&mut debugger, address.as_str()) {
+            println!("Debugger connection failed: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    if !command.is_empty() {
+        if !execute(&core, &command.join(" ")) {
+            exit(1);
+        }
+        return;
+    }
+
+    println!("Core dump of a program that failed with: {}", core.error);
+    loop {
+        print!("(tvm-core) ");
+        stdout().flush().expect("failed writing prompt");
+
+        let mut line = String::new();
+        match stdin().lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("Error reading command: {}", e);
+                exit(1);
+            }
+        }
+
+        match line.trim() {
+            "" => {}
+            "quit" | "q" => break,
+            command => {
+                execute(&core, command);
+            }
+        }
+    }
+}
+
+/// Runs a single command, returning `false` if it was invalid.
+fn execute(core: &CoreDump, command: &str) -> bool {
+    let words: Vec<_> = command.split_whitespace().collect();
+    let numbers: Option<Vec<i64>> = words.iter().skip(1).map(|w| parse_number(w)).collect();
+    let numbers = match numbers {
+        Some(n) => n,
+        None => {
+            println!("Invalid number in: {}", command);
+            return false;
+        }
+    };
+
+    match (words.first().copied().unwrap_or(""), numbers.as_slice()) {
+        ("info", []) => info(core),
+        ("registers", []) | ("regs", []) => registers(core),
+        ("x", [address]) => hexdump(core, *address, 16),
+        ("x", [address, count]) => hexdump(core, *address, *count),
+        ("stack", []) => stack(core, 16),
+        ("stack", [count]) => stack(core, *count),
+        ("backtrace", []) | ("bt", []) => print!("{}", core.backtrace()),
+        ("disassemble", []) => disassemble(core, core.instruction_index - 5, 10),
+        ("disassemble", [start]) => disassemble(core, *start, 10),
+        ("disassemble", [start, count]) => disassemble(core, *start, *count),
+        ("help", []) => println!("{}", HELP),
+        _ => {
+            println!("Unknown command: {}\n{}", command, HELP);
+            return false;
+        }
+    }
+
+    true
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn info(core: &CoreDump) {
+    println!("Error: {}", core.error);
+    print!("At instruction {}", core.instruction_index);
+    if let Some(instruction) = core
+        .program
+        .instructions
+        .get(core.instruction_index as usize)
+    {
+        print!(": {}", instruction);
+    }
+    if let Some(location) = core.program.locations.get(core.instruction_index as usize) {
+        print!(" ({})", location);
+    }
+    println!();
+}
+
+fn registers(core: &CoreDump) {
+    for register in Register::ALL.iter() {
+        let value = core.memory.registers[*register as usize];
+        println!("{:<9} 0x{} {}", register.name(), hex(core, value), value);
+    }
+    println!("{:<9} {:#010x}", "flags", core.memory.flags);
+    println!(
+        "{:<9} 0x{} {}",
+        "remainder",
+        hex(core, core.memory.remainder),
+        core.memory.remainder
+    );
+    for register in VectorRegister::ALL.iter() {
+        let lanes = core.memory.vector_registers[*register as usize];
+        println!("{:<9} {:?}", register.name(), lanes);
+    }
+}
+
+/// A word in hexadecimal, padded to the word size of the program.
+fn hex(core: &CoreDump, value: i64) -> String {
+    match core.program.word_size {
+        WordSize::Bits32 => format!("{:08x}", value as u32),
+        WordSize::Bits64 => format!("{:016x}", value),
+    }
+}
+
+fn hexdump(core: &CoreDump, address: i64, count: i64) {
+    let memory = &core.memory.mem_space;
+    let start = address.max(0) as usize;
+    let end = memory.len().min(start + count.max(0) as usize);
+    if start >= end {
+        println!("No memory at {:#x}", address);
+        return;
+    }
+
+    for line_start in (start..end).step_by(4) {
+        let words: Vec<_> = memory[line_start..end.min(line_start + 4)]
+            .iter()
+            .map(|w| hex(core, *w))
+            .collect();
+        println!("{:#010x}: {}", line_start, words.join(" "));
+    }
+}
+
+fn stack(core: &CoreDump, count: i64) {
+    let esp = core.memory.registers[Register::Esp as usize];
+    let stack_top = i64::from(core.stack_top);
+    if esp >= stack_top {
+        println!("The stack is empty");
+    } else {
+        hexdump(core, esp, count.min(stack_top - esp));
+    }
+}
+
+fn disassemble(core: &CoreDump, start: i64, count: i64) {
+    let start = start.max(0);
+    for index in start..start.saturating_add(count.max(0)) {
+        let instruction = match core.program.instructions.get(index as usize) {
+            Some(i) => i,
+            None => break,
+        };
+
+        if let Some(label) = core.program.label_at(index as i32) {
+            println!("{}:", label);
+        }
+        let marker = if index == core.instruction_index {
+            "=>"
+        } else {
+            "  "
+        };
+        println!("{} {:>6}  {}", marker, index, instruction);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{env, fs, process::exit};
+use tinyvm::context::{Program, STACK_SIZE};
+
+fn usage() -> ! {
+    println!("Usage: `tvm-stack file [stack size]`");
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let (filename, stack_size) = match args.as_slice() {
+        [filename] => (filename, STACK_SIZE),
+        [filename, size] => match size.parse() {
+            Ok(size) => (filename, size),
+            Err(_) => usage(),
+        },
+        _ => usage(),
+    };
+
+    let source = match fs::read_to_string(filename) {
+        Ok(s) => s,
+        Err(_) => {
+            println!("Error reading file {}", filename);
+            exit(1);
+        }
+    };
+
+    let program = match Program::load_with_file_name(source, Some(filename)) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Error {:?}", e);
+            exit(1);
+        }
+    };
+
+    let analysis = program.analyze_stack(stack_size);
+    for function in &analysis.functions {
+        let name = match &function.name {
+            Some(name) => name.clone(),
+            None => format!("function at {}", function.entry),
+        };
+        match function.max_depth {
+            Some(depth) => println!("{}: {} words", name, depth),
+            None => println!("{}: unknown", name),
+        }
+    }
+    for diagnostic in &analysis.diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    if analysis.diagnostics.iter().any(|d| d.is_error()) {
+        exit(1);
+    }
+}
@@ -0,0 +1,49 @@
+use std::{env, fs, process::exit};
+use tinyvm::context::Program;
+
+fn usage() -> ! {
+    println!("Usage: `tvmc file [output.c]`");
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let (filename, output) = match args.as_slice() {
+        [filename] => (filename, None),
+        [filename, output] => (filename, Some(output)),
+        _ => usage(),
+    };
+
+    let source = match fs::read_to_string(filename) {
+        Ok(s) => s,
+        Err(_) => {
+            println!("Error reading file {}", filename);
+            exit(1);
+        }
+    };
+
+    let program = match Program::load_with_file_name(source, Some(filename)) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Error {:?}", e);
+            exit(1);
+        }
+    };
+
+    let c = match program.to_c() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error translating {}: {}", filename, e);
+            exit(1);
+        }
+    };
+    match output {
+        Some(output) => {
+            if let Err(e) = fs::write(output, c) {
+                println!("Error writing {}: {}", output, e);
+                exit(1);
+            }
+        }
+        None => print!("{}", c),
+    }
+}
@@ -1,16 +1,230 @@
-use std::{env, fs, process::exit};
-use tinyvm::context::Program;
+use std::{
+    env, fs,
+    fs::File,
+    io::{BufReader, BufWriter},
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tinyvm::{
+    channels::{Network, NetworkError, DEFAULT_CAPACITY},
+    context::{ExecutionError, Memory, Program},
+    core_dump::CoreDump,
+    debugger::{gdb, Debugger, DEFAULT_CHECKPOINT_INTERVAL, DEFAULT_HISTORY_CAPACITY},
+    multicore::{Machine, MachineError, DEFAULT_QUANTUM},
+};
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+use tinyvm::decoded::DecodedProgram;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use tinyvm::jit::JitProgram;
+
+/// How often a running program checks whether it was interrupted.
+const STEPS_BETWEEN_INTERRUPT_CHECKS: u64 = 1 << 16;
+
+enum Mode {
+    Run,
+    GdbListen(String),
+    GdbPipe,
+}
+
+fn usage() -> ! {
+    println!(
+        "Usage: `tvmi [--gdb address:port | --gdb-pipe] [--resume snapshot] \\
+         [--save-snapshot-on-exit snapshot] [--core-dump core] [--strict-returns] \\
+         [--optimize] [--verify] [--threads | --cooperative | --quantum instructions] file` \\
+         or `tvmi [--channels count] [--capacity words] [--quantum instructions] \\
+         [--core-dump core] [--strict-returns] [--optimize] [--verify] file file...`\n\n\
+         `--threads` runs each core on a thread of its own, one instruction at a time:\n\
+         the threads never run in parallel, and only their interleaving is left to the\n\
+         operating system."
+    );
+    exit(1);
+}
 
 fn main() {
-    let mut args = env::args();
-    if args.len() != 2 {
-        println!("Usage: `tvmi file`");
-        exit(1);
+    let mut mode = Mode::Run;
+    let mut filenames = vec![];
+    let mut resume = None;
+    let mut save_snapshot = None;
+    let mut strict_returns = false;
+    let mut core_dump = None;
+    let mut optimize = false;
+    let mut verify = false;
+    let mut threads = false;
+    let mut cooperative = false;
+    let mut quantum = DEFAULT_QUANTUM;
+    let mut channels = None;
+    let mut capacity = DEFAULT_CAPACITY;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gdb" => match args.next() {
+                Some(address) => mode = Mode::GdbListen(address),
+                None => usage(),
+            },
+            "--gdb-pipe" => mode = Mode::GdbPipe,
+            "--resume" => match args.next() {
+                Some(snapshot) => resume = Some(snapshot),
+                None => usage(),
+            },
+            "--strict-returns" => strict_returns = true,
+            "--optimize" => optimize = true,
+            "--verify" => verify = true,
+            "--threads" => threads = true,
+            "--cooperative" => cooperative = true,
+            "--quantum" => match args.next().and_then(|q| q.parse().ok()) {
+                Some(q) if q > 0 => quantum = q,
+                _ => usage(),
+            },
+            "--channels" => match args.next().and_then(|c| c.parse().ok()) {
+                Some(c) => channels = Some(c),
+                None => usage(),
+            },
+            "--capacity" => match args.next().and_then(|c| c.parse().ok()) {
+                Some(c) if c > 0 => capacity = c,
+                _ => usage(),
+            },
+            "--core-dump" => match args.next() {
+                Some(core) => core_dump = Some(core),
+                None => usage(),
+            },
+            "--save-snapshot-on-exit" => match args.next() {
+                Some(snapshot) => save_snapshot = Some(snapshot),
+                None => usage(),
+            },
+            _ => filenames.push(arg),
+        }
+    }
+
+    if threads && cooperative {
+        usage();
     }
 
-    let filename = args.nth(1).unwrap();
+    let program = match filenames.as_slice() {
+        [] => usage(),
+        [filename] => load(filename, verify, optimize),
+        _ => {
+            // Several programs run connected by channels, by default in a
+            // pipeline with a channel from each one to the next
+            let single = !matches!(mode, Mode::Run) || resume.is_some() || save_snapshot.is_some();
+            if single || threads || cooperative {
+                usage();
+            }
+            let programs: Vec<_> = filenames
+                .iter()
+                .map(|filename| load(filename, verify, optimize))
+                .collect();
+            let channels = channels.unwrap_or(programs.len() - 1);
+            run_network(
+                &programs,
+                channels,
+                capacity,
+                quantum,
+                strict_returns,
+                &core_dump,
+            );
+            return;
+        }
+    };
+
+    let mut memory = match &resume {
+        Some(snapshot) => match File::open(snapshot)
+            .map_err(|e| e.into())
+            .and_then(|f| program.restore_snapshot(&mut BufReader::new(f)))
+        {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Error resuming from snapshot {}: {:?}", snapshot, e);
+                exit(1);
+            }
+        },
+        None => program.initialize(),
+    };
+    memory.strict_returns = strict_returns;
+
+    match mode {
+        Mode::Run if program.spawns_cores() => {
+            if save_snapshot.is_some() {
+                println!("Snapshots of programs that spawn cores are not supported");
+                exit(1);
+            }
+
+            let mut machine = Machine::new(&program, memory);
+            let result = if threads {
+                machine.run_threaded()
+            } else if cooperative {
+                machine.run_cooperative()
+            } else {
+                machine.run_round_robin(quantum)
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(MachineError::Execution(id, e)) => {
+                    println!("Error executing program on core {}: {}", id, e);
+                    if let Some(core) = &core_dump {
+                        let memory = machine.into_memory(id);
+                        write_core_dump(&program, &memory, &e, core);
+                    }
+                    exit(1);
+                }
+                Err(e) => {
+                    println!("Error executing program: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        Mode::Run => {
+            let result = run(&program, &mut memory, save_snapshot.is_some());
+
+            if let Some(snapshot) = &save_snapshot {
+                save(&program, &memory, snapshot);
+            }
 
-    let source = match read_to_string_with_possible_extension(&filename, ".vm") {
+            if let Err(e) = result {
+                println!("Error executing program: {}", e);
+                if let Some(core) = &core_dump {
+                    write_core_dump(&program, &memory, &e, core);
+                }
+                exit(1);
+            }
+        }
+        Mode::GdbListen(address) => {
+            let mut debugger = Debugger::new(&program);
+            *debugger.memory_mut() = memory;
+            debugger.enable_history(DEFAULT_HISTORY_CAPACITY, DEFAULT_CHECKPOINT_INTERVAL);
+            if let Err(e) = gdb::listen(&mut debugger, address.as_str()) {
+                println!("Debugger connection failed: {}", e);
+                exit(1);
+            }
+            if let Some(snapshot) = &save_snapshot {
+                save(&program, debugger.memory(), snapshot);
+            }
+        }
+        Mode::GdbPipe => {
+            // stdout carries the protocol, so the program prints to stderr
+            let mut debugger = Debugger::new(&program);
+            *debugger.memory_mut() = memory;
+            debugger.memory_mut().output = Box::new(std::io::stderr());
+            debugger.enable_history(DEFAULT_HISTORY_CAPACITY, DEFAULT_CHECKPOINT_INTERVAL);
+            if let Err(e) = gdb::serve(&mut debugger, gdb::StdioConnection::new()) {
+                eprintln!("Debugger connection failed: {}", e);
+                exit(1);
+            }
+            if let Some(snapshot) = &save_snapshot {
+                save(&program, debugger.memory(), snapshot);
+            }
+        }
+    }
+}
+
+/// Reads, verifies and optimizes a program as told, exiting if that fails.
+fn load(filename: &str, verify: bool, optimize: bool) -> Program {
+    let (source, filename) = match read_to_string_with_possible_extension(filename, ".vm") {
         Ok(s) => s,
         Err(_) => {
             println!("Error reading file {}", filename);
@@ -18,34 +232,133 @@ fn main() {
         }
     };
 
-    let program = match Program::load(source) {
+    let program = match Program::load_with_file_name(source, Some(&filename)) {
         Ok(p) => p,
         Err(e) => {
             println!("Error {:?}", e);
             exit(1);
         }
     };
+    if verify {
+        let diagnostics = program.verify();
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+        if diagnostics.iter().any(|d| d.is_error()) {
+            println!("Verification failed");
+            exit(1);
+        }
+    }
+
+    if optimize {
+        program.optimize()
+    } else {
+        program
+    }
+}
 
-    match program.run() {
-        Ok(_) => {},
+fn run_network(
+    programs: &[Program],
+    channels: usize,
+    capacity: usize,
+    quantum: u64,
+    strict_returns: bool,
+    core_dump: &Option<String>,
+) {
+    let mut network = Network::new();
+    for program in programs {
+        let mut memory = program.initialize();
+        memory.strict_returns = strict_returns;
+        network.add_program(program, memory);
+    }
+    for _ in 0..channels {
+        network.add_channel(capacity);
+    }
+
+    match network.run(quantum) {
+        Ok(()) => {}
+        Err(NetworkError::Execution(index, e)) => {
+            println!("Error executing program {}: {}", index, e);
+            if let Some(core) = core_dump {
+                let memory = network.into_memories().swap_remove(index);
+                write_core_dump(&programs[index], &memory, &e, core);
+            }
+            exit(1);
+        }
         Err(e) => {
-            println!("Error executing program: {:?}", e);
+            println!("Error executing programs: {}", e);
             exit(1);
         }
     }
 }
 
+/// Runs the program to completion. If `interruptible` is set, Ctrl-C stops
+/// the program instead of killing the process, so its state can be saved.
+fn run(program: &Program, memory: &mut Memory, interruptible: bool) -> Result<(), ExecutionError> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    if interruptible {
+        let flag = interrupted.clone();
+        if let Err(e) = ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed)) {
+            println!("Error installing interrupt handler: {}", e);
+            exit(1);
+        }
+    }
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    let engine = JitProgram::new(program).unwrap_or_else(|e| {
+        println!("Error compiling program: {}", e);
+        exit(1);
+    });
+    #[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+    let engine = DecodedProgram::new(program);
+
+    while !interrupted.load(Ordering::Relaxed) {
+        if !engine.run_steps(memory, STEPS_BETWEEN_INTERRUPT_CHECKS)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn save(program: &Program, memory: &Memory, filename: &str) {
+    let result = File::create(filename).and_then(|f| {
+        let mut writer = BufWriter::new(f);
+        program.save_snapshot(memory, &mut writer)?;
+        writer.into_inner().map(|_| ()).map_err(|e| e.into_error())
+    });
+
+    if let Err(e) = result {
+        eprintln!("Error saving snapshot {}: {}", filename, e);
+        exit(1);
+    }
+}
+
+fn write_core_dump(program: &Program, memory: &Memory, error: &ExecutionError, filename: &str) {
+    let result = File::create(filename).and_then(|f| {
+        let mut writer = BufWriter::new(f);
+        CoreDump::write(program, memory, error, &mut writer)?;
+        writer.into_inner().map(|_| ()).map_err(|e| e.into_error())
+    });
+
+    match result {
+        Ok(()) => println!("Core dumped to {}", filename),
+        Err(e) => eprintln!("Error writing core dump {}: {}", filename, e),
+    }
+}
+
 fn read_to_string_with_possible_extension(
     filename: &str,
     extension: &str,
-) -> Result<String, std::io::Error> {
+) -> Result<(String, String), std::io::Error> {
     match fs::read_to_string(filename) {
-        Ok(s) => return Ok(s),
+        Ok(s) => return Ok((s, filename.to_owned())),
         Err(error) => match error.kind() {
             std::io::ErrorKind::NotFound => (),
             _ => return Err(error),
         },
     };
 
-    fs::read_to_string(filename.to_owned() + extension)
+    let filename = filename.to_owned() + extension;
+    fs::read_to_string(&filename).map(|s| (s, filename))
 }
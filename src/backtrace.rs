@@ -0,0 +1,178 @@
+//! Symbolic backtraces, built from the shadow call stack kept in `Memory`.
+
+use crate::{
+    context::{Memory, Program},
+    instruction::Register,
+    preprocessor::SourceLocation,
+};
+use std::{convert::TryFrom, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    /// The instruction being executed, or the `call` for outer frames.
+    pub instruction_index: i64,
+    /// The label of the called function, or of the start of the program for
+    /// the outermost frame.
+    pub function: Option<String>,
+    pub location: Option<SourceLocation>,
+}
+
+/// The active calls, innermost first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "#{} instruction {}", i, frame.instruction_index)?;
+            if let Some(function) = &frame.function {
+                write!(f, " in {}", function)?;
+            }
+            if let Some(location) = &frame.location {
+                write!(f, " ({})", location)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Program {
+    /// The first label referring to `instruction_index`, if any.
+    pub fn label_at(self: &Program, instruction_index: i32) -> Option<&str> {
+        let start = self.labels.partition_point(|(_, i)| *i < instruction_index);
+        match self.labels.get(start) {
+            Some((label, i)) if *i == instruction_index => Some(label),
+            _ => None,
+        }
+    }
+
+    pub fn backtrace(self: &Program, memory: &Memory) -> Backtrace {
+        let mut instruction_index = memory.registers[Register::Eip as usize];
+        let mut frames = vec![];
+
+        for frame in memory.call_stack.iter().rev() {
+            frames.push(self.backtrace_frame(instruction_index, frame.callee));
+            instruction_index = frame.call_site.into();
+        }
+        frames.push(self.backtrace_frame(instruction_index, self.start_instruction_index));
+
+        Backtrace { frames }
+    }
+
+    fn backtrace_frame(self: &Program, instruction_index: i64, function: i32) -> BacktraceFrame {
+        BacktraceFrame {
+            instruction_index,
+            function: self.label_at(function).map(str::to_owned),
+            location: usize::try_from(instruction_index)
+                .ok()
+                .and_then(|i| self.locations.get(i))
+                .cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{ExecutionErrorKind, INT_BACKTRACE};
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    const SOURCE: &str =
+        "start: call outer\njmp end\nouter: call inner\nret\ninner: nop\nret\nend:";
+
+    fn load(source: &str) -> Program {
+        Program::load_with_file_name(source.to_owned(), Some("test.vm")).unwrap()
+    }
+
+    fn run_to(program: &Program, memory: &mut Memory, instruction_index: i64) {
+        while memory.registers[Register::Eip as usize] != instruction_index {
+            program.step(memory).unwrap();
+        }
+    }
+
+    #[test]
+    fn backtrace_lists_active_calls() {
+        let program = load(SOURCE);
+        let mut memory = program.initialize();
+        run_to(&program, &mut memory, 4);
+
+        assert_eq!(
+            program.backtrace(&memory).to_string(),
+            "#0 instruction 4 in inner (test.vm:5)\n\
+             #1 instruction 2 in outer (test.vm:3)\n\
+             #2 instruction 0 in start (test.vm:1)\n"
+        );
+
+        let frame = memory.call_stack.last().unwrap();
+        assert_eq!(
+            i64::from(frame.stack_pointer),
+            memory.registers[Register::Esp as usize]
+        );
+    }
+
+    #[test]
+    fn ret_pops_frames() {
+        let program = load(SOURCE);
+        let mut memory = program.initialize();
+        run_to(&program, &mut memory, 1);
+
+        assert!(memory.call_stack.is_empty());
+        assert_eq!(program.backtrace(&memory).frames.len(), 1);
+    }
+
+    #[test]
+    fn mismatched_return_is_only_an_error_when_strict() {
+        let program = load("call f\nnop\nf: push 5\nret");
+
+        let mut memory = program.initialize();
+        while memory.registers[Register::Eip as usize] != 5 {
+            program.step(&mut memory).unwrap();
+        }
+        assert!(memory.call_stack.is_empty());
+
+        let mut memory = program.initialize();
+        memory.strict_returns = true;
+        let error = loop {
+            if let Err(e) = program.step(&mut memory) {
+                break e;
+            }
+        };
+        assert_eq!(error.kind, ExecutionErrorKind::MismatchedReturn(5));
+        assert_eq!(error.instruction_index, 3);
+        assert_eq!(error.backtrace.frames[0].function.as_deref(), Some("f"));
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn int_service_prints_backtrace() {
+        let program = load(&format!("call f\nf: mov eax, {}\nint", INT_BACKTRACE));
+        let mut memory = program.initialize();
+        let output = SharedOutput::default();
+        memory.output = Box::new(output.clone());
+        while program.step(&mut memory).unwrap() {}
+
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "#0 instruction 2 in f (test.vm:3)\n#1 instruction 0 (test.vm:1)\n"
+        );
+    }
+}
@@ -0,0 +1,1457 @@
+//! Ahead-of-time translation of programs to C.
+//!
+//! `Program::to_c` produces a self-contained C99 file that a C compiler
+//! builds into an executable behaving like `tvmi` running the program: it
+//! prints the same output and, on a runtime error, the same error report
+//! before exiting with status 1.
+//!
+//! The registers are locals of `main` and the memory is a static array.
+//! Instructions are labels in `main`, so jumps to constant targets are
+//! `goto`s, while jumps to computed targets, including `ret`, go through a
+//! `switch` on the target. Addresses in operands are constants and are
+//! checked during translation, so only stack accesses are checked when the
+//! program runs.
+//!
+//! The executable runs the program on a single core and without channels,
+//! like `Program::run`, so `join`, `send` and `recv` fail when they run.
+//!
+//! Only 32-bit programs that neither spawn cores nor call native functions
+//! are translated, and `to_c` fails for others.
+
+use crate::{
+    context::{Program, WordSize, INT_BACKTRACE, MEMORY_SIZE, STACK_SIZE},
+    instruction::{
+        Condition, EffectiveAddress, FloatRegister, FloatSource, Instruction, Register, Source,
+        Target, VectorOp, VectorRegister, Width, NUM_LANES,
+    },
+};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Write},
+};
+
+/// The parts of the runtime that do not depend on the program.
+const RUNTIME: &str = r##"struct frame {
+    int32_t callee;
+    int32_t call_site;
+};
+
+static int32_t memory[MEMORY_SIZE];
+static struct frame *frames;
+static size_t frame_count, frame_capacity;
+
+static inline int32_t add(int32_t a, int32_t b) { return (int32_t)((uint32_t)a + (uint32_t)b); }
+static inline int32_t sub(int32_t a, int32_t b) { return (int32_t)((uint32_t)a - (uint32_t)b); }
+static inline int32_t mul(int32_t a, int32_t b) { return (int32_t)((uint32_t)a * (uint32_t)b); }
+static inline int32_t shl(int32_t a, int32_t b) { return (int32_t)((uint32_t)a << (b & 31)); }
+static inline int32_t shr(int32_t a, int32_t b) { return (int32_t)((uint32_t)a >> (b & 31)); }
+static inline int32_t sar(int32_t a, int32_t b) { return a >> (b & 31); }
+static inline int32_t rol(int32_t a, int32_t b)
+{
+    int s = b & 31;
+    return s ? (int32_t)((uint32_t)a << s | (uint32_t)a >> (32 - s)) : a;
+}
+static inline int32_t ror(int32_t a, int32_t b) { return rol(a, 32 - (b & 31)); }
+static inline int32_t divide(int32_t a, int32_t b) { return b == -1 ? sub(0, a) : a / b; }
+static inline int32_t modulo(int32_t a, int32_t b) { return b == -1 ? 0 : a % b; }
+
+/* The operations that set the flags, as `Memory::flags` describes them. */
+static inline int32_t set_flags(int32_t *flags, int32_t result, int carry, int overflow)
+{
+    *flags = (result == 0) | (result != 0 && (result < 0) == overflow) << 1 | !!carry << 2 |
+             (result < 0) << 3 | !!overflow << 4;
+    return result;
+}
+
+static inline int32_t add_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int32_t r = add(a, b);
+    return set_flags(flags, r, (uint32_t)r < (uint32_t)a, ((a ^ r) & (b ^ r)) < 0);
+}
+
+static inline int32_t sub_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int32_t r = sub(a, b);
+    return set_flags(flags, r, (uint32_t)a < (uint32_t)b, ((a ^ b) & (a ^ r)) < 0);
+}
+
+static inline int32_t mul_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int32_t r = mul(a, b);
+    int overflow = (int64_t)a * b != r;
+    return set_flags(flags, r, overflow, overflow);
+}
+
+/* `inc` and `dec` leave the carry flag as it was. */
+static inline int32_t inc_flags(int32_t a, int32_t *flags)
+{
+    int32_t carry = *flags & 0x4;
+    int32_t r = add_flags(a, 1, flags);
+    *flags = (*flags & ~0x4) | carry;
+    return r;
+}
+
+static inline int32_t dec_flags(int32_t a, int32_t *flags)
+{
+    int32_t carry = *flags & 0x4;
+    int32_t r = sub_flags(a, 1, flags);
+    *flags = (*flags & ~0x4) | carry;
+    return r;
+}
+
+static inline int32_t logic_flags(int32_t r, int32_t *flags) { return set_flags(flags, r, 0, 0); }
+
+static inline int32_t shl_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int s = b & 31;
+    return set_flags(flags, shl(a, b), s && ((uint32_t)a >> (32 - s)) & 1, 0);
+}
+
+static inline int32_t shr_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int s = b & 31;
+    return set_flags(flags, shr(a, b), s && (a >> (s - 1)) & 1, 0);
+}
+
+static inline int32_t sar_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int s = b & 31;
+    return set_flags(flags, sar(a, b), s && (a >> (s - 1)) & 1, 0);
+}
+
+static inline int32_t rol_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int32_t r = rol(a, b);
+    return set_flags(flags, r, (b & 31) && (r & 1), 0);
+}
+
+static inline int32_t ror_flags(int32_t a, int32_t b, int32_t *flags)
+{
+    int32_t r = ror(a, b);
+    return set_flags(flags, r, (b & 31) && r < 0, 0);
+}
+
+static inline int32_t neg_flags(int32_t a, int32_t *flags) { return sub_flags(0, a, flags); }
+
+/* The bit counting instructions, which leave the flags alone. */
+static inline int32_t popcnt(int32_t a)
+{
+    uint32_t u = (uint32_t)a;
+    int32_t n = 0;
+    for (; u; u &= u - 1)
+        n++;
+    return n;
+}
+
+static inline int32_t clz(int32_t a)
+{
+    uint32_t u = (uint32_t)a;
+    int32_t n = 0;
+    for (; n < 32 && !(u & 0x80000000u); u <<= 1)
+        n++;
+    return n;
+}
+
+static inline int32_t ctz(int32_t a)
+{
+    uint32_t u = (uint32_t)a;
+    int32_t n = 0;
+    for (; n < 32 && !(u & 1); u >>= 1)
+        n++;
+    return n;
+}
+
+static inline int32_t bswap(int32_t a)
+{
+    uint32_t u = (uint32_t)a;
+    return (int32_t)(u >> 24 | (u >> 8 & 0xff00) | (u << 8 & 0xff0000) | u << 24);
+}
+
+/* Memory addressed in bytes, four to a word, the least significant first. */
+static inline uint32_t load_bytes(int32_t address, int count)
+{
+    uint32_t value = 0;
+    int i;
+    for (i = count - 1; i >= 0; i--) {
+        uint32_t a = (uint32_t)address + i;
+        value = value << 8 | ((uint32_t)memory[a / 4] >> (a % 4 * 8) & 0xff);
+    }
+    return value;
+}
+
+static inline void store_bytes(int32_t address, int count, int32_t value)
+{
+    int i;
+    for (i = 0; i < count; i++) {
+        uint32_t a = (uint32_t)address + i, shift = a % 4 * 8;
+        uint32_t byte = (uint32_t)value >> (8 * i) & 0xff;
+        memory[a / 4] = (int32_t)(((uint32_t)memory[a / 4] & ~(0xffu << shift)) | byte << shift);
+    }
+}
+
+static inline int32_t sign_extend(uint32_t value, int bits)
+{
+    int32_t sign = (int32_t)(1u << (bits - 1));
+    return (int32_t)(value ^ (uint32_t)sign) - sign;
+}
+
+/* The lane-wise operations of the vector instructions other than the
+   arithmetic ones above. */
+static inline int32_t lane_min(int32_t a, int32_t b) { return a < b ? a : b; }
+static inline int32_t lane_max(int32_t a, int32_t b) { return a > b ? a : b; }
+static inline int32_t lane_eq(int32_t a, int32_t b) { return -(a == b); }
+static inline int32_t lane_gt(int32_t a, int32_t b) { return -(a > b); }
+
+/* Floats in memory are two words, the low half of the bits first. */
+static inline double load_double(int32_t address)
+{
+    uint64_t bits = (uint64_t)(uint32_t)memory[address] | (uint64_t)(uint32_t)memory[address + 1] << 32;
+    double d;
+    memcpy(&d, &bits, sizeof d);
+    return d;
+}
+
+static inline void store_double(int32_t address, double d)
+{
+    uint64_t bits;
+    memcpy(&bits, &d, sizeof bits);
+    memory[address] = (int32_t)(uint32_t)bits;
+    memory[address + 1] = (int32_t)(uint32_t)(bits >> 32);
+}
+
+static inline int is_negative(double d)
+{
+    uint64_t bits;
+    memcpy(&bits, &d, sizeof bits);
+    return bits >> 63;
+}
+
+static inline double absolute(double d)
+{
+    uint64_t bits;
+    memcpy(&bits, &d, sizeof bits);
+    bits &= ~(UINT64_C(1) << 63);
+    memcpy(&d, &bits, sizeof d);
+    return d;
+}
+
+/* A correctly rounded square root, computed a bit at a time so that the
+   program does not need libm. */
+static inline double square_root(double d)
+{
+    uint64_t bits, m, root = 0, rest = 0;
+    int e, i;
+
+    if (d != d || d == 0 || d == INFINITY)
+        return d + d;
+    if (d < 0)
+        return (d - d) / (d - d);
+
+    /* d is m * 2^e, with m at least 2^52 and e even */
+    memcpy(&bits, &d, sizeof bits);
+    m = bits & ((UINT64_C(1) << 52) - 1);
+    e = (int)(bits >> 52);
+    if (e == 0) {
+        for (e = -1074; m < UINT64_C(1) << 52; e--)
+            m <<= 1;
+    } else {
+        m |= UINT64_C(1) << 52;
+        e -= 1075;
+    }
+    if (e & 1) {
+        m <<= 1;
+        e--;
+    }
+
+    /* The 54 bit integer square root of m * 2^54, and what is left over */
+    for (i = 0; i < 54; i++) {
+        uint64_t trial = root << 2 | 1;
+        rest = rest << 2 | (i < 27 ? m >> (52 - 2 * i) & 3 : 0);
+        root <<= 1;
+        if (rest >= trial) {
+            rest -= trial;
+            root |= 1;
+        }
+    }
+
+    m = root >> 1;
+    e = e / 2 - 26;
+    if ((root & 1) && (rest != 0 || (m & 1)))
+        m++;
+    if (m == UINT64_C(1) << 53) {
+        m >>= 1;
+        e++;
+    }
+    bits = (uint64_t)(e + 1075) << 52 | (m & ((UINT64_C(1) << 52) - 1));
+    memcpy(&d, &bits, sizeof d);
+    return d;
+}
+
+/* `fcmp`, as `compare_floats` describes it. */
+static inline int32_t fcmp_flags(double a, double b)
+{
+    return a == b ? 0x1 : a > b ? 0x2 : a < b ? 0x4 : 0x15;
+}
+
+/* Rounds toward zero, saturating, with NaN becoming zero. */
+static inline int32_t to_int(double d)
+{
+    if (d != d)
+        return 0;
+    if (d >= 2147483647.0)
+        return INT32_MAX;
+    if (d <= -2147483648.0)
+        return INT32_MIN;
+    return (int32_t)d;
+}
+
+/* Prints a float like Rust does: the fewest digits that read back as the
+   same value, without an exponent. */
+static inline void print_double(double d)
+{
+    char text[32], digits[20], *c;
+    int precision, exponent, n = 0, i;
+
+    if (d != d) {
+        printf("NaN\n");
+        return;
+    }
+    if (is_negative(d))
+        putchar('-');
+    if (d == INFINITY || d == -INFINITY) {
+        printf("inf\n");
+        return;
+    }
+    if (d == 0) {
+        printf("0\n");
+        return;
+    }
+
+    for (precision = 0; precision < 16; precision++) {
+        snprintf(text, sizeof text, "%.*e", precision, d);
+        if (strtod(text, NULL) == d)
+            break;
+    }
+    snprintf(text, sizeof text, "%.*e", precision, absolute(d));
+    c = strchr(text, 'e');
+    exponent = atoi(c + 1);
+    *c = '\0';
+    for (c = text; *c; c++) {
+        if (*c != '.')
+            digits[n++] = *c;
+    }
+    while (n > 1 && digits[n - 1] == '0')
+        n--;
+
+    if (exponent < 0) {
+        printf("0.");
+        for (i = -1; i > exponent; i--)
+            putchar('0');
+        printf("%.*s", n, digits);
+    } else if (exponent + 1 >= n) {
+        printf("%.*s", n, digits);
+        for (i = n; i < exponent + 1; i++)
+            putchar('0');
+    } else {
+        printf("%.*s.%.*s", exponent + 1, digits, n - exponent - 1, digits + exponent + 1);
+    }
+    putchar('\n');
+}
+
+static inline void push_frame(int32_t callee, int32_t call_site)
+{
+    if (frame_count == frame_capacity) {
+        frame_capacity = frame_capacity ? 2 * frame_capacity : 64;
+        frames = realloc(frames, frame_capacity * sizeof *frames);
+        if (!frames) {
+            fputs("Out of memory\n", stderr);
+            exit(2);
+        }
+    }
+    frames[frame_count].callee = callee;
+    frames[frame_count].call_site = call_site;
+    frame_count++;
+}
+
+static inline void pop_frame(void)
+{
+    if (frame_count > 0)
+        frame_count--;
+}
+
+static void print_frame(size_t i, int32_t instruction, int32_t function)
+{
+    printf("#%lu instruction %" PRId32, (unsigned long)i, instruction);
+    if (function >= 0 && function <= INSTRUCTION_COUNT && labels[function])
+        printf(" in %s", labels[function]);
+    if (instruction >= 0 && instruction < INSTRUCTION_COUNT && locations[instruction])
+        printf(" (%s)", locations[instruction]);
+    printf("\n");
+}
+
+static inline void print_backtrace(int32_t instruction)
+{
+    size_t i;
+    for (i = 0; i < frame_count; i++) {
+        struct frame frame = frames[frame_count - 1 - i];
+        print_frame(i, instruction, frame.callee);
+        instruction = frame.call_site;
+    }
+    print_frame(frame_count, instruction, START);
+}
+
+static inline void fail(int32_t instruction, const int32_t *registers, int32_t flags,
+                 int32_t remainder, const char *format, ...)
+{
+    va_list arguments;
+    int i;
+
+    printf("Error executing program: ");
+    va_start(arguments, format);
+    vprintf(format, arguments);
+    va_end(arguments);
+
+    printf("\n  at instruction %" PRId32, instruction);
+    if (instruction >= 0 && instruction < INSTRUCTION_COUNT) {
+        printf(": %s", texts[instruction]);
+        if (locations[instruction])
+            printf(" (%s)", locations[instruction]);
+    }
+    printf("\n");
+
+    for (i = 0; i < REGISTER_COUNT; i++) {
+        const char *separator = i == 0 ? "  " : i % 6 == 0 ? "\n  " : " ";
+        printf("%s%s=%" PRId32, separator, register_names[i], registers[i]);
+    }
+    printf("\n  flags=%" PRId32 " remainder=%" PRId32 "\n", flags, remainder);
+
+    printf("backtrace:\n");
+    print_backtrace(instruction);
+    printf("\n");
+    exit(1);
+}
+
+#define FAIL(instruction, ...) \
+    fail((instruction), REGISTERS(instruction), flags, remainder, __VA_ARGS__)
+
+#define CHECK_ADDRESS(instruction, address)                                       \
+    do {                                                                          \
+        if ((uint32_t)(address) >= MEMORY_SIZE)                                   \
+            FAIL((instruction), "data address %" PRId32 " is out of range", (address)); \
+    } while (0)
+
+/* Fails unless the `count` bytes from byte address `address` are in the
+   memory, reporting the first that is not. */
+#define CHECK_BYTES(instruction, address, count)                                  \
+    do {                                                                          \
+        if ((uint32_t)(address) >= MEMORY_SIZE * 4u)                              \
+            FAIL((instruction), "data address %" PRId32 " is out of range", (address)); \
+        if ((uint32_t)(address) + (count) > MEMORY_SIZE * 4u)                     \
+            FAIL((instruction), "data address %" PRId32 " is out of range",       \
+                 (int32_t)(MEMORY_SIZE * 4u));                                    \
+    } while (0)
+
+/* Fails unless the words of a vector from `address` are in the memory,
+   reporting the first that is not. */
+#define CHECK_VECTOR(instruction, address)                                        \
+    do {                                                                          \
+        CHECK_ADDRESS((instruction), (address));                                  \
+        if ((uint32_t)(address) + LANE_COUNT > MEMORY_SIZE)                       \
+            FAIL((instruction), "data address %" PRId32 " is out of range",       \
+                 (int32_t)MEMORY_SIZE);                                           \
+    } while (0)
+"##;
+
+/// Why a program cannot be translated to C.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranslationError {
+    /// The program is 64-bit.
+    Bits64,
+    /// The program calls native functions, which the executable cannot.
+    Natives,
+    /// The program spawns cores, which needs a `Machine` to run.
+    Cores,
+}
+
+impl Program {
+    /// Translates the program to a C source file.
+    pub fn to_c(self: &Program) -> Result<String, TranslationError> {
+        if self.word_size != WordSize::Bits32 {
+            return Err(TranslationError::Bits64);
+        }
+        if self.calls_natives() {
+            return Err(TranslationError::Natives);
+        }
+        if self.spawns_cores() {
+            return Err(TranslationError::Cores);
+        }
+
+        let mut translator = Translator::new(self);
+        translator.translate();
+        Ok(translator.c)
+    }
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranslationError::Bits64 => write!(f, "64-bit programs cannot be translated to C"),
+            TranslationError::Natives => write!(
+                f,
+                "programs that call native functions cannot be translated to C"
+            ),
+            TranslationError::Cores => {
+                write!(f, "programs that spawn cores cannot be translated to C")
+            }
+        }
+    }
+}
+
+struct Translator<'a> {
+    program: &'a Program,
+    c: String,
+    /// Whether the instruction at each index, or the end of the program,
+    /// needs a label.
+    targets: Vec<bool>,
+    /// Whether any jump has a target that is only known at run time.
+    dispatch: bool,
+    indent: usize,
+}
+
+impl<'a> Translator<'a> {
+    fn new(program: &'a Program) -> Translator<'a> {
+        let len = program.instructions.len();
+        let mut targets = vec![false; len + 1];
+        let mut dispatch = false;
+
+        if let Some(start) = index(program.start_instruction_index, len) {
+            targets[start] = true;
+        }
+        for instruction in &program.instructions {
+            match jump_source(instruction) {
+                Some(Source::Value(target)) => {
+                    if let Some(target) = index(target, len) {
+                        targets[target] = true;
+                    }
+                }
+                Some(_) => dispatch = true,
+                None => dispatch |= *instruction == Instruction::Ret,
+            }
+        }
+        if dispatch {
+            targets = vec![true; len + 1];
+        }
+
+        Translator {
+            program,
+            c: String::new(),
+            targets,
+            dispatch,
+            indent: 0,
+        }
+    }
+
+    fn line<S: AsRef<str>>(self: &mut Translator<'a>, line: S) {
+        for _ in 0..self.indent {
+            self.c.push_str("    ");
+        }
+        self.c.push_str(line.as_ref());
+        self.c.push('\n');
+    }
+
+    fn translate(self: &mut Translator<'a>) {
+        let program = self.program;
+        let len = program.instructions.len();
+
+        self.line("/* Translated from a tinyvm program. */");
+        for header in &[
+            "inttypes.h",
+            "math.h",
+            "stdarg.h",
+            "stdint.h",
+            "stdio.h",
+            "stdlib.h",
+            "string.h",
+        ] {
+            self.line(format!("#include <{}>", header));
+        }
+        self.line("");
+        self.line(format!("#define MEMORY_SIZE {}", MEMORY_SIZE));
+        self.line(format!("#define INSTRUCTION_COUNT {}", len));
+        self.line(format!("#define REGISTER_COUNT {}", Register::ALL.len()));
+        self.line(format!("#define LANE_COUNT {}", NUM_LANES));
+        self.line(format!("#define START {}", program.start_instruction_index));
+        self.line("");
+
+        let texts: Vec<_> = program
+            .instructions
+            .iter()
+            .map(|i| Some(i.to_string()))
+            .collect();
+        self.table("texts", &texts);
+        let locations: Vec<_> = (0..len)
+            .map(|i| program.locations.get(i).map(|l| l.to_string()))
+            .collect();
+        self.table("locations", &locations);
+        let labels: Vec<_> = (0..=len as i32)
+            .map(|i| program.label_at(i).map(str::to_owned))
+            .collect();
+        self.table("labels", &labels);
+        let names: Vec<_> = Register::ALL
+            .iter()
+            .map(|r| Some(r.name().to_owned()))
+            .collect();
+        self.table("register_names", &names);
+
+        let registers: Vec<_> = Register::ALL
+            .iter()
+            .map(|r| match r {
+                Register::Eip => "(instruction)",
+                _ => r.name(),
+            })
+            .collect();
+        self.line(format!(
+            "#define REGISTERS(instruction) ((const int32_t[]){{{}}})",
+            registers.join(", ")
+        ));
+        self.line("");
+        self.c.push_str(RUNTIME);
+        self.line("");
+
+        self.line("int main(void)");
+        self.line("{");
+        self.indent += 1;
+        for register in Register::ALL.iter() {
+            let value = match register {
+                Register::Eip => continue,
+                Register::Esp | Register::Ebp => STACK_SIZE,
+                _ => 0,
+            };
+            self.line(format!("int32_t {} = {};", register.name(), value));
+        }
+        self.line("int32_t flags = 0, remainder = 0;");
+        let floats: Vec<_> = FloatRegister::ALL
+            .iter()
+            .map(|register| format!("{} = 0", register.name()))
+            .collect();
+        self.line(format!("double {};", floats.join(", ")));
+        let vectors: Vec<_> = VectorRegister::ALL
+            .iter()
+            .map(|register| format!("{}[LANE_COUNT] = {{0}}", register.name()))
+            .collect();
+        self.line(format!("int32_t {};", vectors.join(", ")));
+        // Not every program touches these
+        let mut unused: Vec<_> = Register::ALL
+            .iter()
+            .filter(|register| **register != Register::Eip)
+            .map(|register| format!("(void){}", register.name()))
+            .collect();
+        unused.extend(
+            FloatRegister::ALL
+                .iter()
+                .map(|register| format!("(void){}", register.name())),
+        );
+        unused.extend(
+            VectorRegister::ALL
+                .iter()
+                .map(|register| format!("(void){}", register.name())),
+        );
+        unused.extend(["(void)flags", "(void)remainder", "(void)memory"].map(String::from));
+        self.line(format!("{};", unused.join(", ")));
+        if self.dispatch {
+            self.line("int32_t target;");
+        }
+        self.line("");
+        self.jump(program.start_instruction_index.into());
+        self.line("");
+
+        for (pc, instruction) in program.instructions.iter().enumerate() {
+            self.label(pc);
+            self.line(format!("/* {} */", instruction));
+            self.instruction(pc as i32, *instruction);
+        }
+        self.label(len);
+        self.line("return 0;");
+
+        if self.dispatch {
+            self.line("");
+            self.indent -= 1;
+            self.line("dispatch:");
+            self.indent += 1;
+            self.line("switch (target) {");
+            for target in 0..=len {
+                self.line(format!("case {}: goto i{};", target, target));
+            }
+            self.line("}");
+            self.line(format!(
+                "FAIL(target, \"{}\", target);",
+                INSTRUCTION_OUT_OF_RANGE
+            ));
+            self.line("return 1;");
+        }
+
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn table(self: &mut Translator<'a>, name: &str, entries: &[Option<String>]) {
+        self.line(format!("static const char *const {}[] = {{", name));
+        self.indent += 1;
+        for entry in entries {
+            match entry {
+                Some(s) => self.line(format!("{},", c_string(s))),
+                None => self.line("0,"),
+            }
+        }
+        // Keeps the array from being empty
+        self.line("0");
+        self.indent -= 1;
+        self.line("};");
+        self.line("");
+    }
+
+    fn label(self: &mut Translator<'a>, index: usize) {
+        if self.targets[index] {
+            self.indent -= 1;
+            self.line(format!("i{}:", index));
+            self.indent += 1;
+        }
+    }
+
+    /// Jumps to a constant target, or fails if it is not an instruction.
+    fn jump(self: &mut Translator<'a>, target: i64) {
+        match index(target, self.program.instructions.len()) {
+            Some(target) => self.line(format!("goto i{};", target)),
+            None => self.line(format!(
+                "FAIL({0}, \"{1}\", {0});",
+                literal(target),
+                INSTRUCTION_OUT_OF_RANGE
+            )),
+        }
+    }
+
+    /// Reads `source` and jumps to it, or fails.
+    fn jump_to_source(self: &mut Translator<'a>, pc: i32, source: Source) {
+        if !self.check_source(pc, source) {
+            return;
+        }
+        match source {
+            Source::Value(target) => self.jump(target),
+            _ => {
+                let value = self.source(pc, source);
+                self.line(format!("target = {};", value));
+                self.line("goto dispatch;");
+            }
+        }
+    }
+
+    fn conditional_jump(self: &mut Translator<'a>, pc: i32, condition: Condition, source: Source) {
+        self.line(format!("if ({}) {{", condition_expression(condition)));
+        self.indent += 1;
+        self.jump_to_source(pc, source);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// Fails if `address` is constant and outside the memory, returning
+    /// whether the instruction can go on.
+    fn check_address(self: &mut Translator<'a>, pc: i32, address: i32) -> bool {
+        if index(address, MEMORY_SIZE - 1).is_some() {
+            return true;
+        }
+        self.line(format!(
+            "FAIL({}, \"{}\", {});",
+            pc,
+            DATA_ADDRESS_OUT_OF_RANGE,
+            literal(address)
+        ));
+        false
+    }
+
+    /// Like `check_address`, for both words of a float.
+    fn check_float_address(self: &mut Translator<'a>, pc: i32, address: i32) -> bool {
+        self.check_address(pc, address) && self.check_address(pc, address.wrapping_add(1))
+    }
+
+    fn check_float_source(self: &mut Translator<'a>, pc: i32, source: FloatSource) -> bool {
+        match source {
+            FloatSource::Address(address) => self.check_float_address(pc, address),
+            _ => true,
+        }
+    }
+
+    fn check_source(self: &mut Translator<'a>, pc: i32, source: Source) -> bool {
+        match source {
+            Source::Address(address) => self.check_address(pc, address),
+            _ => true,
+        }
+    }
+
+    fn check_target(self: &mut Translator<'a>, pc: i32, target: Target) -> bool {
+        match target {
+            Target::Address(address) => self.check_address(pc, address),
+            Target::Register(_) => true,
+        }
+    }
+
+    fn source(self: &Translator<'a>, pc: i32, source: Source) -> String {
+        match source {
+            Source::Register(Register::Eip) => pc.to_string(),
+            Source::Register(register) => register.name().to_owned(),
+            Source::Value(value) => literal(value),
+            Source::Address(address) => format!("memory[{}]", address),
+        }
+    }
+
+    fn float_source(self: &Translator<'a>, source: FloatSource) -> String {
+        match source {
+            FloatSource::Register(register) => register.name().to_owned(),
+            FloatSource::Value(value) => float_literal(value),
+            FloatSource::Address(address) => format!("load_double({})", address),
+        }
+    }
+
+    /// Sets the floating-point `register` to `expression`, with `{r}` and
+    /// `{s}` in it replaced by the register and the source.
+    fn float(
+        self: &mut Translator<'a>,
+        pc: i32,
+        register: FloatRegister,
+        source: FloatSource,
+        expression: &str,
+    ) {
+        if self.check_float_source(pc, source) {
+            let value = expression
+                .replace("{r}", register.name())
+                .replace("{s}", &self.float_source(source));
+            self.line(format!("{} = {};", register.name(), value));
+        }
+    }
+
+    fn target(self: &Translator<'a>, pc: i32, target: Target) -> String {
+        match target {
+            Target::Register(register) => self.source(pc, Source::Register(register)),
+            Target::Address(address) => self.source(pc, Source::Address(address)),
+        }
+    }
+
+    /// The expression for the address `lea` computes.
+    fn address(self: &Translator<'a>, pc: i32, address: EffectiveAddress) -> String {
+        let mut value = literal(address.displacement);
+        if let Some(base) = address.base {
+            value = format!(
+                "add({}, {})",
+                self.source(pc, Source::Register(base)),
+                value
+            );
+        }
+        if let Some((index, scale)) = address.index {
+            let index = self.source(pc, Source::Register(index));
+            value = format!("add(mul({}, {}), {})", index, scale, value);
+        }
+        value
+    }
+
+    /// Writes `value` to `target`. Writes to `eip` have no effect, since
+    /// the interpreter moves on to the next instruction anyway.
+    fn assign(self: &mut Translator<'a>, target: Target, value: &str) {
+        match target {
+            Target::Register(Register::Eip) => self.line(format!("(void)({});", value)),
+            Target::Register(register) => self.line(format!("{} = {};", register.name(), value)),
+            Target::Address(address) => self.line(format!("memory[{}] = {};", address, value)),
+        }
+    }
+
+    /// Assigns `expression` to the target, with `{t}` and `{s}` in it
+    /// replaced by the target and the source.
+    fn binary(
+        self: &mut Translator<'a>,
+        pc: i32,
+        target: Target,
+        source: Source,
+        expression: &str,
+    ) {
+        if self.check_target(pc, target) && self.check_source(pc, source) {
+            let value = expression
+                .replace("{t}", &self.target(pc, target))
+                .replace("{s}", &self.source(pc, source));
+            self.assign(target, &value);
+        }
+    }
+
+    fn unary(self: &mut Translator<'a>, pc: i32, target: Target, expression: &str) {
+        if self.check_target(pc, target) {
+            let value = expression.replace("{t}", &self.target(pc, target));
+            self.assign(target, &value);
+        }
+    }
+
+    /// Checks the stack and pushes the value of `expression`, which may read
+    /// `esp` before it changes.
+    fn push<F: FnOnce(&mut Translator<'a>) -> Option<String>>(
+        self: &mut Translator<'a>,
+        pc: i32,
+        value: F,
+    ) {
+        self.line("{");
+        self.indent += 1;
+        self.line("int32_t address = sub(esp, 1);");
+        self.line(format!("CHECK_ADDRESS({}, address);", pc));
+        if let Some(value) = value(self) {
+            self.line(format!("memory[address] = {};", value));
+            self.line("esp = address;");
+        }
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// Checks the stack and pops into `value`, declared by the caller.
+    fn pop(self: &mut Translator<'a>, pc: i32, value: &str) {
+        self.line(format!("CHECK_ADDRESS({}, esp);", pc));
+        self.line(format!("{} = memory[esp];", value));
+        self.line("esp = add(esp, 1);");
+    }
+
+    fn instruction(self: &mut Translator<'a>, pc: i32, instruction: Instruction) {
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::Int => self.line(format!(
+                "if (eax == {}) print_backtrace({});",
+                INT_BACKTRACE, pc
+            )),
+            Instruction::Mov(t, s) => {
+                if self.check_target(pc, t) && self.check_source(pc, s) {
+                    let value = self.source(pc, s);
+                    self.assign(t, &value);
+                }
+            }
+            Instruction::Push(s) => self.push(pc, |this| {
+                if this.check_source(pc, s) {
+                    Some(this.source(pc, s))
+                } else {
+                    None
+                }
+            }),
+            Instruction::Pop(t) => {
+                if self.check_target(pc, t) {
+                    self.line("{");
+                    self.indent += 1;
+                    self.line("int32_t value;");
+                    self.pop(pc, "value");
+                    self.assign(t, "value");
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Pushf => self.push(pc, |_| Some("flags".to_owned())),
+            Instruction::Popf => self.pop(pc, "flags"),
+            Instruction::Inc(t) => self.unary(pc, t, "inc_flags({t}, &flags)"),
+            Instruction::Dec(t) => self.unary(pc, t, "dec_flags({t}, &flags)"),
+            Instruction::Not(t) => self.unary(pc, t, "~{t}"),
+            Instruction::Add(t, s) => self.binary(pc, t, s, "add_flags({t}, {s}, &flags)"),
+            Instruction::Sub(t, s) => self.binary(pc, t, s, "sub_flags({t}, {s}, &flags)"),
+            Instruction::Mul(t, s) => self.binary(pc, t, s, "mul_flags({t}, {s}, &flags)"),
+            Instruction::Xor(t, s) => self.binary(pc, t, s, "logic_flags({t} ^ {s}, &flags)"),
+            Instruction::Or(t, s) => self.binary(pc, t, s, "logic_flags({t} | {s}, &flags)"),
+            Instruction::And(t, s) => self.binary(pc, t, s, "logic_flags({t} & {s}, &flags)"),
+            Instruction::Shl(t, s) => self.binary(pc, t, s, "shl_flags({t}, {s}, &flags)"),
+            Instruction::Shr(t, s) => self.binary(pc, t, s, "shr_flags({t}, {s}, &flags)"),
+            Instruction::Sar(t, s) => self.binary(pc, t, s, "sar_flags({t}, {s}, &flags)"),
+            Instruction::Rol(t, s) => self.binary(pc, t, s, "rol_flags({t}, {s}, &flags)"),
+            Instruction::Ror(t, s) => self.binary(pc, t, s, "ror_flags({t}, {s}, &flags)"),
+            Instruction::Neg(t) => self.unary(pc, t, "neg_flags({t}, &flags)"),
+            Instruction::Xchg(t1, t2) => {
+                if self.check_target(pc, t1) && self.check_target(pc, t2) {
+                    self.line("{");
+                    self.indent += 1;
+                    let (a, b) = (self.target(pc, t1), self.target(pc, t2));
+                    self.line(format!("int32_t a = {}, b = {};", a, b));
+                    self.assign(t1, "b");
+                    self.assign(t2, "a");
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Lea(t, address) => {
+                if self.check_target(pc, t) {
+                    let value = self.address(pc, address);
+                    self.assign(t, &value);
+                }
+            }
+            Instruction::Test(s1, s2) => {
+                if self.check_source(pc, s1) && self.check_source(pc, s2) {
+                    let (a, b) = (self.source(pc, s1), self.source(pc, s2));
+                    self.line(format!("(void)logic_flags({} & {}, &flags);", a, b));
+                }
+            }
+            Instruction::Cmov(condition, t, s) => {
+                if self.check_source(pc, s) && self.check_target(pc, t) {
+                    self.line(format!("if ({}) {{", condition_expression(condition)));
+                    self.indent += 1;
+                    let value = self.source(pc, s);
+                    self.assign(t, &value);
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Popcnt(t, s) => self.binary(pc, t, s, "popcnt({s})"),
+            Instruction::Clz(t, s) => self.binary(pc, t, s, "clz({s})"),
+            Instruction::Ctz(t, s) => self.binary(pc, t, s, "ctz({s})"),
+            Instruction::Bswap(t) => self.unary(pc, t, "bswap({t})"),
+            Instruction::Ld(width, t, s) => self.load(pc, width, t, s, false),
+            Instruction::Lds(width, t, s) => self.load(pc, width, t, s, true),
+            Instruction::St(width, address, s) => {
+                if self.check_source(pc, address) && self.check_source(pc, s) {
+                    self.line("{");
+                    self.indent += 1;
+                    let (a, b) = (self.source(pc, address), self.source(pc, s));
+                    self.line(format!("int32_t address = {}, value = {};", a, b));
+                    self.line(format!("CHECK_BYTES({}, address, {});", pc, width.bytes()));
+                    self.line(format!("store_bytes(address, {}, value);", width.bytes()));
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Div(t, s) => {
+                if self.check_target(pc, t) && self.check_source(pc, s) {
+                    self.line("{");
+                    self.indent += 1;
+                    self.divisor(pc, s);
+                    let value = format!("divide({}, divisor)", self.target(pc, t));
+                    self.assign(t, &value);
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Mod(s1, s2) => {
+                if self.check_source(pc, s1) && self.check_source(pc, s2) {
+                    self.line("{");
+                    self.indent += 1;
+                    self.divisor(pc, s2);
+                    let value = self.source(pc, s1);
+                    self.line(format!("remainder = modulo({}, divisor);", value));
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Rem(t) => {
+                if self.check_target(pc, t) {
+                    self.assign(t, "remainder");
+                }
+            }
+            Instruction::Cmp(s1, s2) => {
+                if self.check_source(pc, s1) && self.check_source(pc, s2) {
+                    self.line("{");
+                    self.indent += 1;
+                    let (a, b) = (self.source(pc, s1), self.source(pc, s2));
+                    self.line(format!("int32_t a = {}, b = {};", a, b));
+                    self.line("(void)sub_flags(a, b, &flags);");
+                    self.indent -= 1;
+                    self.line("}");
+                }
+            }
+            Instruction::Jmp(s) => self.jump_to_source(pc, s),
+            Instruction::Je(s) => self.conditional_jump(pc, Condition::Equal, s),
+            Instruction::Jne(s) => self.conditional_jump(pc, Condition::NotEqual, s),
+            Instruction::Jg(s) => self.conditional_jump(pc, Condition::Greater, s),
+            Instruction::Jge(s) => self.conditional_jump(pc, Condition::GreaterOrEqual, s),
+            Instruction::Jl(s) => self.conditional_jump(pc, Condition::Less, s),
+            Instruction::Jle(s) => self.conditional_jump(pc, Condition::LessOrEqual, s),
+            Instruction::Ja(s) => self.conditional_jump(pc, Condition::Above, s),
+            Instruction::Jae(s) => self.conditional_jump(pc, Condition::AboveOrEqual, s),
+            Instruction::Jb(s) => self.conditional_jump(pc, Condition::Below, s),
+            Instruction::Jbe(s) => self.conditional_jump(pc, Condition::BelowOrEqual, s),
+            Instruction::Js(s) => self.conditional_jump(pc, Condition::Sign, s),
+            Instruction::Jns(s) => self.conditional_jump(pc, Condition::NotSign, s),
+            Instruction::Jo(s) => self.conditional_jump(pc, Condition::Overflow, s),
+            Instruction::Jno(s) => self.conditional_jump(pc, Condition::NotOverflow, s),
+            Instruction::Loop(s) => {
+                self.line("ecx = sub(ecx, 1);");
+                self.line("if (ecx != 0) {");
+                self.indent += 1;
+                self.jump_to_source(pc, s);
+                self.indent -= 1;
+                self.line("}");
+            }
+            Instruction::Call(s) => {
+                self.push(pc, |_| Some((pc + 1).to_string()));
+                if self.check_source(pc, s) {
+                    match s {
+                        Source::Value(callee) => {
+                            self.line(format!("push_frame({}, {});", literal(callee), pc));
+                            self.jump(callee);
+                        }
+                        _ => {
+                            let value = self.source(pc, s);
+                            self.line(format!("target = {};", value));
+                            self.line(format!("push_frame(target, {});", pc));
+                            self.line("goto dispatch;");
+                        }
+                    }
+                }
+            }
+            Instruction::Ret => {
+                self.pop(pc, "target");
+                self.line("pop_frame();");
+                self.line("goto dispatch;");
+            }
+            Instruction::Prn(s) => {
+                if self.check_source(pc, s) {
+                    let value = self.source(pc, s);
+                    self.line(format!("printf(\"%\" PRId32 \"\\n\", {});", value));
+                }
+            }
+            Instruction::Fld(r, s) => self.float(pc, r, s, "{s}"),
+            Instruction::Fst(address, r) => {
+                if self.check_float_address(pc, address) {
+                    self.line(format!("store_double({}, {});", address, r.name()));
+                }
+            }
+            Instruction::Fadd(r, s) => self.float(pc, r, s, "{r} + {s}"),
+            Instruction::Fsub(r, s) => self.float(pc, r, s, "{r} - {s}"),
+            Instruction::Fmul(r, s) => self.float(pc, r, s, "{r} * {s}"),
+            Instruction::Fdiv(r, s) => self.float(pc, r, s, "{r} / {s}"),
+            Instruction::Fneg(r) => self.line(format!("{0} = -{0};", r.name())),
+            Instruction::Fabs(r) => self.line(format!("{0} = absolute({0});", r.name())),
+            Instruction::Fsqrt(r) => self.line(format!("{0} = square_root({0});", r.name())),
+            Instruction::Fcmp(r, s) => {
+                if self.check_float_source(pc, s) {
+                    let value = self.float_source(s);
+                    self.line(format!("flags = fcmp_flags({}, {});", r.name(), value));
+                }
+            }
+            Instruction::Itof(r, s) => {
+                if self.check_source(pc, s) {
+                    let value = self.source(pc, s);
+                    self.line(format!("{} = (double){};", r.name(), value));
+                }
+            }
+            Instruction::Ftoi(t, r) => {
+                if self.check_target(pc, t) {
+                    self.assign(t, &format!("to_int({})", r.name()));
+                }
+            }
+            Instruction::Fprn(s) => {
+                if self.check_float_source(pc, s) {
+                    let value = self.float_source(s);
+                    self.line(format!("print_double({});", value));
+                }
+            }
+            Instruction::Vld(r, address) => self.vector_memory(pc, address, |address| {
+                format!("memcpy({}, &memory[{}], sizeof {0});", r.name(), address)
+            }),
+            Instruction::Vst(address, r) => self.vector_memory(pc, address, |address| {
+                format!("memcpy(&memory[{}], {}, sizeof {1});", address, r.name())
+            }),
+            Instruction::Vop(op, r1, r2) => {
+                let function = match op {
+                    VectorOp::Add => "add",
+                    VectorOp::Sub => "sub",
+                    VectorOp::Mul => "mul",
+                    VectorOp::Min => "lane_min",
+                    VectorOp::Max => "lane_max",
+                    VectorOp::CmpEq => "lane_eq",
+                    VectorOp::CmpGt => "lane_gt",
+                };
+                self.line("{");
+                self.indent += 1;
+                self.line("int i;");
+                self.line("for (i = 0; i < LANE_COUNT; i++)");
+                self.line(format!(
+                    "    {0}[i] = {1}({0}[i], {2}[i]);",
+                    r1.name(),
+                    function,
+                    r2.name()
+                ));
+                self.indent -= 1;
+                self.line("}");
+            }
+            Instruction::Vins(r, lane, s) => {
+                if self.check_source(pc, s) {
+                    let value = self.source(pc, s);
+                    self.line(format!("{}[{}] = {};", r.name(), lane, value));
+                }
+            }
+            Instruction::Vext(t, r, lane) => {
+                if self.check_target(pc, t) {
+                    self.assign(t, &format!("{}[{}]", r.name(), lane));
+                }
+            }
+            Instruction::Vsum(t, r) => {
+                if self.check_target(pc, t) {
+                    let value = format!("add(add(add({0}[0], {0}[1]), {0}[2]), {0}[3])", r.name());
+                    self.assign(t, &value);
+                }
+            }
+            Instruction::Spawn(..) => unreachable!("programs spawning cores are not translated"),
+            Instruction::Join(s) => {
+                if self.check_source(pc, s) {
+                    self.line(format!("FAIL({}, \"{}\");", pc, SINGLE_CORE));
+                }
+            }
+            Instruction::Cas(t, address, s) => {
+                self.atomic(pc, address, |translator| {
+                    if translator.check_source(pc, s) && translator.check_target(pc, t) {
+                        let (value, expected) =
+                            (translator.source(pc, s), translator.target(pc, t));
+                        translator.line(format!(
+                            "int32_t value = {}, expected = {}, old = memory[address];",
+                            value, expected
+                        ));
+                        translator.line("if (old == expected) memory[address] = value;");
+                        translator.line("(void)sub_flags(old, expected, &flags);");
+                        translator.assign(t, "old");
+                    }
+                });
+            }
+            Instruction::Xadd(t, address) => {
+                self.atomic(pc, address, |translator| {
+                    if translator.check_target(pc, t) {
+                        let value = translator.target(pc, t);
+                        translator
+                            .line(format!("int32_t value = {}, old = memory[address];", value));
+                        translator.line("memory[address] = add_flags(old, value, &flags);");
+                        translator.assign(t, "old");
+                    }
+                });
+            }
+            Instruction::Fence | Instruction::Yield => {}
+            Instruction::Send(s1, s2) => {
+                if self.check_source(pc, s1) && self.check_source(pc, s2) {
+                    self.line(format!("FAIL({}, \"{}\");", pc, NO_CHANNELS));
+                }
+            }
+            Instruction::Recv(t, s) => {
+                if self.check_source(pc, s) && self.check_target(pc, t) {
+                    self.line(format!("FAIL({}, \"{}\");", pc, NO_CHANNELS));
+                }
+            }
+            Instruction::Native(..) => unreachable!("programs calling natives are not translated"),
+        }
+    }
+
+    /// Checks the words of the vector from the address in `source`, and
+    /// emits the statement `access` returns for the address.
+    fn vector_memory<F: FnOnce(&str) -> String>(
+        self: &mut Translator<'a>,
+        pc: i32,
+        source: Source,
+        access: F,
+    ) {
+        if self.check_source(pc, source) {
+            self.line("{");
+            self.indent += 1;
+            let address = self.source(pc, source);
+            self.line(format!("int32_t address = {};", address));
+            self.line(format!("CHECK_VECTOR({}, address);", pc));
+            self.line(access("address"));
+            self.indent -= 1;
+            self.line("}");
+        }
+    }
+
+    /// Checks the word at the address in `source` for `cas` or `xadd`, and
+    /// emits the rest of the instruction with `emit`, which can use the
+    /// address as `address`.
+    fn atomic<F: FnOnce(&mut Translator<'a>)>(
+        self: &mut Translator<'a>,
+        pc: i32,
+        source: Source,
+        emit: F,
+    ) {
+        if self.check_source(pc, source) {
+            self.line("{");
+            self.indent += 1;
+            let address = self.source(pc, source);
+            self.line(format!("int32_t address = {};", address));
+            self.line(format!("CHECK_ADDRESS({}, address);", pc));
+            emit(self);
+            self.indent -= 1;
+            self.line("}");
+        }
+    }
+
+    /// Loads the unit of `width` at the byte address in `source` into
+    /// `target`, zero or sign extended. The target is checked last, as the
+    /// interpreter writes it after loading.
+    fn load(
+        self: &mut Translator<'a>,
+        pc: i32,
+        width: Width,
+        target: Target,
+        source: Source,
+        signed: bool,
+    ) {
+        if self.check_source(pc, source) {
+            self.line("{");
+            self.indent += 1;
+            let address = self.source(pc, source);
+            self.line(format!("int32_t address = {};", address));
+            self.line(format!("CHECK_BYTES({}, address, {});", pc, width.bytes()));
+            if self.check_target(pc, target) {
+                let value = format!("load_bytes(address, {})", width.bytes());
+                let value = if signed {
+                    format!("sign_extend({}, {})", value, 8 * width.bytes())
+                } else {
+                    format!("(int32_t){}", value)
+                };
+                self.assign(target, &value);
+            }
+            self.indent -= 1;
+            self.line("}");
+        }
+    }
+
+    /// Declares `divisor` as the value of `source`, and fails if it is zero.
+    fn divisor(self: &mut Translator<'a>, pc: i32, source: Source) {
+        let value = self.source(pc, source);
+        self.line(format!("int32_t divisor = {};", value));
+        self.line(format!(
+            "if (divisor == 0) FAIL({}, \"{}\");",
+            pc, DIVISION_BY_ZERO
+        ));
+    }
+}
+
+// The messages of `ExecutionErrorKind`, as printf formats
+const INSTRUCTION_OUT_OF_RANGE: &str = "instruction index %\" PRId32 \" is out of range";
+const DATA_ADDRESS_OUT_OF_RANGE: &str = "data address %\" PRId32 \" is out of range";
+const DIVISION_BY_ZERO: &str = "division by zero";
+const SINGLE_CORE: &str = "spawn and join need more than one core";
+const NO_CHANNELS: &str = "send and recv need channels";
+
+/// The source of a jump or call, which `ret` does not have.
+fn jump_source(instruction: &Instruction) -> Option<Source> {
+    match *instruction {
+        Instruction::Jmp(s)
+        | Instruction::Call(s)
+        | Instruction::Je(s)
+        | Instruction::Jne(s)
+        | Instruction::Jg(s)
+        | Instruction::Jge(s)
+        | Instruction::Jl(s)
+        | Instruction::Jle(s)
+        | Instruction::Ja(s)
+        | Instruction::Jae(s)
+        | Instruction::Jb(s)
+        | Instruction::Jbe(s)
+        | Instruction::Js(s)
+        | Instruction::Jns(s)
+        | Instruction::Jo(s)
+        | Instruction::Jno(s)
+        | Instruction::Loop(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// The C expression for whether `condition` holds for `flags`.
+fn condition_expression(condition: Condition) -> &'static str {
+    match condition {
+        Condition::Equal => "flags & 0x1",
+        Condition::NotEqual => "!(flags & 0x1)",
+        Condition::Greater => "flags & 0x2",
+        Condition::GreaterOrEqual => "flags & 0x3",
+        Condition::Less => "!(flags & 0x3)",
+        Condition::LessOrEqual => "!(flags & 0x2)",
+        Condition::Above => "!(flags & 0x5)",
+        Condition::AboveOrEqual => "!(flags & 0x4)",
+        Condition::Below => "flags & 0x4",
+        Condition::BelowOrEqual => "flags & 0x5",
+        Condition::Sign => "flags & 0x8",
+        Condition::NotSign => "!(flags & 0x8)",
+        Condition::Overflow => "flags & 0x10",
+        Condition::NotOverflow => "!(flags & 0x10)",
+    }
+}
+
+/// `value` as an index up to and including `max`.
+fn index(value: impl Into<i64>, max: usize) -> Option<usize> {
+    usize::try_from(value.into()).ok().filter(|i| *i <= max)
+}
+
+fn literal(value: impl Into<i64>) -> String {
+    match value.into() {
+        value if value == i32::MIN.into() => "INT32_MIN".to_owned(),
+        value => value.to_string(),
+    }
+}
+
+fn float_literal(value: f64) -> String {
+    let literal = if value.is_nan() {
+        "NAN".to_owned()
+    } else if value.is_infinite() {
+        "INFINITY".to_owned()
+    } else {
+        format!("{:?}", value.abs())
+    };
+    if value.is_sign_negative() {
+        format!("(-{})", literal)
+    } else {
+        literal
+    }
+}
+
+fn c_string(s: &str) -> String {
+    let mut c = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                c.push('\\');
+                c.push(byte as char);
+            }
+            b' '..=b'~' if byte != b'?' => c.push(byte as char),
+            _ => write!(c, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    c.push('"');
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(c_string("a \"b\" \\ c"), r#""a \"b\" \\ c""#);
+        assert_eq!(c_string("é\n??"), r#""\303\251\012\077\077""#);
+    }
+
+    #[test]
+    fn constant_jumps_only_label_their_targets() {
+        let program = Program::load("start: jmp end\nnop\nend: prn 1".to_owned()).unwrap();
+        let c = program.to_c().unwrap();
+
+        assert!(c.contains("goto i2;"));
+        assert!(c.contains("i0:"));
+        assert!(!c.contains("i1:"));
+        assert!(!c.contains("dispatch"));
+    }
+
+    #[test]
+    fn invalid_constant_addresses_fail_during_translation() {
+        let program = Program::load("mov eax, [100000000]".to_owned()).unwrap();
+        let c = program.to_c().unwrap();
+
+        assert!(c.contains("FAIL(0, \"data address %\" PRId32 \" is out of range\", 100000000);"));
+        assert!(!c.contains("memory[100000000]"));
+    }
+
+    #[test]
+    fn only_32_bit_programs_are_translated() {
+        let program = Program::load("%bits 64\nprn 1".to_owned()).unwrap();
+        assert_eq!(program.to_c(), Err(TranslationError::Bits64));
+    }
+
+    #[test]
+    fn programs_spawning_cores_are_not_translated() {
+        let program = Program::load("spawn eax, end, 1000\njoin eax\nend:".to_owned()).unwrap();
+        assert!(program.spawns_cores());
+        assert_eq!(program.to_c(), Err(TranslationError::Cores));
+
+        // A `join` on its own fails when it runs, as in `Program::run`
+        let program = Program::load("join 1".to_owned()).unwrap();
+        let c = program.to_c().unwrap();
+        assert!(c.contains("spawn and join need more than one core"));
+    }
+}
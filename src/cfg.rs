@@ -0,0 +1,478 @@
+//! Control-flow graphs: the instructions of a program split into basic
+//! blocks, with the ways execution can get from one block to another, and
+//! grouped into the functions they belong to.
+
+use crate::{
+    context::Program,
+    instruction::{jump_target, Instruction, Source},
+};
+use std::{collections::BTreeSet, convert::TryFrom, fmt::Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    /// To the next instruction, without jumping.
+    Fallthrough,
+    /// A `jmp`.
+    Jump,
+    /// A conditional jump that is taken.
+    Branch,
+    /// From a `call` to the function.
+    Call,
+    /// From a `call` to the instruction after it, where the function
+    /// returns to.
+    CallReturn,
+    /// From a `ret` to the instruction after a `call` of its function.
+    Return,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    /// The index of a block.
+    Block(usize),
+    /// The end of the program, where it halts.
+    End,
+    /// An instruction index outside the program, which is an error.
+    OutOfRange(i64),
+    /// An address only known when running, like that of `jmp eax`, or of a
+    /// `ret` outside any function.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub destination: Destination,
+}
+
+/// Instructions that are always executed in sequence: only the first one can
+/// be jumped to, and only the last one can jump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    /// The index of the first instruction.
+    pub start: usize,
+    /// The index after the last instruction.
+    pub end: usize,
+    pub edges: Vec<Edge>,
+}
+
+/// The start of the program or the target of a `call`, and the blocks that
+/// can be reached from it without calling or returning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// The instruction index of the entry.
+    pub entry: usize,
+    pub name: Option<String>,
+    /// The indices of the blocks, in order.
+    pub blocks: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+    /// The blocks, in instruction order.
+    pub blocks: Vec<BasicBlock>,
+    /// The start of the program first, then the targets of calls in
+    /// instruction order.
+    pub functions: Vec<Function>,
+}
+
+impl Program {
+    pub fn control_flow_graph(self: &Program) -> ControlFlowGraph {
+        let instructions = &self.instructions;
+        let len = instructions.len();
+        let in_program = |target: i64| usize::try_from(target).ok().filter(|t| *t < len);
+
+        // The first instruction of every block
+        let mut leaders = BTreeSet::new();
+        leaders.insert(0);
+        leaders.extend(in_program(self.start_instruction_index.into()));
+        leaders.extend(
+            self.addresses_taken
+                .iter()
+                .filter_map(|&a| in_program(a.into())),
+        );
+        for (pc, instruction) in instructions.iter().enumerate() {
+            if let Some(target) = jump_target(*instruction) {
+                if let Source::Value(target) = target {
+                    leaders.extend(in_program(target));
+                }
+                leaders.insert(pc + 1);
+            } else if *instruction == Instruction::Ret {
+                leaders.insert(pc + 1);
+            }
+        }
+        leaders.retain(|leader| *leader < len);
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_of = |target: i64| match usize::try_from(target) {
+            Ok(t) if t == len => Destination::End,
+            Ok(t) if t < len => Destination::Block(starts.partition_point(|s| *s <= t) - 1),
+            _ => Destination::OutOfRange(target),
+        };
+
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(len);
+                let last = instructions[end - 1];
+                let to = |source: Source| match source {
+                    Source::Value(target) => block_of(target),
+                    _ => Destination::Unknown,
+                };
+                let edge = |kind, destination| Edge { kind, destination };
+
+                let edges = match last {
+                    Instruction::Jmp(s) => vec![edge(EdgeKind::Jump, to(s))],
+                    Instruction::Call(s) => vec![
+                        edge(EdgeKind::Call, to(s)),
+                        edge(EdgeKind::CallReturn, block_of(end as i64)),
+                    ],
+                    // Added once the functions are known
+                    Instruction::Ret => vec![],
+                    _ => match jump_target(last) {
+                        Some(s) => vec![
+                            edge(EdgeKind::Branch, to(s)),
+                            edge(EdgeKind::Fallthrough, block_of(end as i64)),
+                        ],
+                        None => vec![edge(EdgeKind::Fallthrough, block_of(end as i64))],
+                    },
+                };
+
+                BasicBlock { start, end, edges }
+            })
+            .collect();
+
+        let mut entries: Vec<usize> = vec![];
+        entries.extend(in_program(self.start_instruction_index.into()));
+        let mut call_targets: Vec<usize> = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Call(Source::Value(target)) => in_program(*target),
+                _ => None,
+            })
+            .filter(|target| !entries.contains(target))
+            .collect();
+        call_targets.sort_unstable();
+        call_targets.dedup();
+        entries.extend(call_targets);
+
+        let functions: Vec<Function> = entries
+            .into_iter()
+            .map(|entry| Function {
+                entry,
+                name: self.label_at(entry as i32).map(str::to_owned),
+                blocks: function_blocks(&blocks, starts.binary_search(&entry).unwrap()),
+            })
+            .collect();
+
+        // A `ret` returns to wherever its functions were called from
+        for block in 0..blocks.len() {
+            if instructions[blocks[block].end - 1] != Instruction::Ret {
+                continue;
+            }
+
+            let mut return_sites = vec![];
+            for function in functions.iter().filter(|f| f.blocks.contains(&block)) {
+                let call = Instruction::Call(Source::Value(function.entry as i64));
+                for caller in blocks.iter().filter(|c| instructions[c.end - 1] == call) {
+                    let site = block_of(caller.end as i64);
+                    if !return_sites.contains(&site) {
+                        return_sites.push(site);
+                    }
+                }
+            }
+            if return_sites.is_empty() {
+                return_sites.push(Destination::Unknown);
+            }
+
+            blocks[block].edges = return_sites
+                .into_iter()
+                .map(|destination| Edge {
+                    kind: EdgeKind::Return,
+                    destination,
+                })
+                .collect();
+        }
+
+        ControlFlowGraph { blocks, functions }
+    }
+}
+
+/// The blocks reachable from `entry` without calling or returning.
+fn function_blocks(blocks: &[BasicBlock], entry: usize) -> Vec<usize> {
+    let mut reached = vec![false; blocks.len()];
+    let mut pending = vec![entry];
+    while let Some(block) = pending.pop() {
+        if reached[block] {
+            continue;
+        }
+        reached[block] = true;
+
+        for edge in &blocks[block].edges {
+            match (edge.kind, edge.destination) {
+                (EdgeKind::Call, _) | (EdgeKind::Return, _) => {}
+                (_, Destination::Block(next)) => pending.push(next),
+                _ => {}
+            }
+        }
+    }
+
+    (0..blocks.len()).filter(|b| reached[*b]).collect()
+}
+
+impl ControlFlowGraph {
+    /// The index of the block containing an instruction.
+    pub fn block_of(self: &ControlFlowGraph, instruction_index: usize) -> Option<usize> {
+        let block = self
+            .blocks
+            .partition_point(|b| b.start <= instruction_index)
+            .checked_sub(1)?;
+        Some(block).filter(|b| instruction_index < self.blocks[*b].end)
+    }
+
+    /// The function with the given label.
+    pub fn function(self: &ControlFlowGraph, name: &str) -> Option<&Function> {
+        self.functions
+            .iter()
+            .find(|f| f.name.as_deref() == Some(name))
+    }
+
+    /// The whole program in the Graphviz DOT format.
+    pub fn to_dot(self: &ControlFlowGraph, program: &Program) -> String {
+        let blocks: Vec<usize> = (0..self.blocks.len()).collect();
+        self.dot(program, "program", &blocks)
+    }
+
+    /// One function in the Graphviz DOT format. Calls and returns lead to
+    /// nodes of their own rather than to the other functions.
+    pub fn function_to_dot(
+        self: &ControlFlowGraph,
+        program: &Program,
+        function: &Function,
+    ) -> String {
+        let name = match &function.name {
+            Some(name) => name.clone(),
+            None => format!("function at {}", function.entry),
+        };
+        self.dot(program, &name, &function.blocks)
+    }
+
+    fn dot(self: &ControlFlowGraph, program: &Program, name: &str, blocks: &[usize]) -> String {
+        let mut dot = String::new();
+        let mut extra_nodes = BTreeSet::new();
+        let whole_program = blocks.len() == self.blocks.len();
+
+        writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for &b in blocks {
+            let block = &self.blocks[b];
+            let mut label = String::new();
+            for pc in block.start..block.end {
+                if let Some(l) = program.label_at(pc as i32) {
+                    write!(label, "{}:\\l", escape(l)).unwrap();
+                }
+                write!(
+                    label,
+                    "{}: {}\\l",
+                    pc,
+                    escape(&program.instructions[pc].to_string())
+                )
+                .unwrap();
+            }
+            writeln!(dot, "    b{} [label=\"{}\"];", block.start, label).unwrap();
+        }
+
+        for &b in blocks {
+            let block = &self.blocks[b];
+            for edge in &block.edges {
+                let to = match edge.destination {
+                    Destination::Block(to) if whole_program || blocks.contains(&to) => {
+                        format!("b{}", self.blocks[to].start)
+                    }
+                    Destination::Block(to) => {
+                        let start = self.blocks[to].start;
+                        let (node, label) = match edge.kind {
+                            EdgeKind::Return => ("return".to_owned(), "return".to_owned()),
+                            _ => (
+                                format!("f{}", start),
+                                program
+                                    .label_at(start as i32)
+                                    .map(str::to_owned)
+                                    .unwrap_or_else(|| start.to_string()),
+                            ),
+                        };
+                        extra_nodes.insert((node.clone(), label));
+                        node
+                    }
+                    Destination::End => {
+                        extra_nodes.insert(("end".to_owned(), "end".to_owned()));
+                        "end".to_owned()
+                    }
+                    Destination::OutOfRange(target) => {
+                        let node = format!("out_of_range_{}", target.wrapping_sub(i64::MIN) as u64);
+                        extra_nodes.insert((node.clone(), format!("out of range: {}", target)));
+                        node
+                    }
+                    Destination::Unknown => {
+                        extra_nodes.insert(("unknown".to_owned(), "?".to_owned()));
+                        "unknown".to_owned()
+                    }
+                };
+
+                let attributes = match edge.kind {
+                    EdgeKind::Fallthrough | EdgeKind::Jump => String::new(),
+                    EdgeKind::Branch => {
+                        let jump = program.instructions[block.end - 1];
+                        format!(" [label=\"{}\"]", jump.mnemonic())
+                    }
+                    EdgeKind::Call => " [label=\"call\", style=bold]".to_owned(),
+                    EdgeKind::CallReturn => " [style=dotted]".to_owned(),
+                    EdgeKind::Return => " [label=\"ret\", style=dashed]".to_owned(),
+                };
+                writeln!(dot, "    b{} -> {}{};", block.start, to, attributes).unwrap();
+            }
+        }
+
+        for (node, label) in extra_nodes {
+            writeln!(
+                dot,
+                "    {} [shape=oval, label=\"{}\"];",
+                node,
+                escape(&label)
+            )
+            .unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(source: &str) -> (Program, ControlFlowGraph) {
+        let program = Program::load(source.to_owned()).unwrap();
+        let graph = program.control_flow_graph();
+        (program, graph)
+    }
+
+    fn edge(kind: EdgeKind, destination: Destination) -> Edge {
+        Edge { kind, destination }
+    }
+
+    #[test]
+    fn jumps_split_blocks() {
+        let (_, graph) =
+            graph("mov eax, 0\nloop: inc eax\ncmp eax, 3\njl loop\nprn eax\njmp end\nend:");
+
+        let bounds: Vec<_> = graph.blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(bounds, &[(0, 1), (1, 4), (4, 6)]);
+        assert_eq!(
+            graph.blocks[0].edges,
+            &[edge(EdgeKind::Fallthrough, Destination::Block(1))]
+        );
+        assert_eq!(
+            graph.blocks[1].edges,
+            &[
+                edge(EdgeKind::Branch, Destination::Block(1)),
+                edge(EdgeKind::Fallthrough, Destination::Block(2))
+            ]
+        );
+        assert_eq!(
+            graph.blocks[2].edges,
+            &[edge(EdgeKind::Jump, Destination::End)]
+        );
+        assert_eq!(graph.block_of(2), Some(1));
+        assert_eq!(graph.block_of(6), None);
+    }
+
+    #[test]
+    fn unknown_and_out_of_range_destinations() {
+        let (_, graph) = graph("jmp eax\njg 100\nret");
+
+        assert_eq!(
+            graph.blocks[0].edges,
+            &[edge(EdgeKind::Jump, Destination::Unknown)]
+        );
+        assert_eq!(
+            graph.blocks[1].edges,
+            &[
+                edge(EdgeKind::Branch, Destination::OutOfRange(100)),
+                edge(EdgeKind::Fallthrough, Destination::Block(2))
+            ]
+        );
+        assert_eq!(
+            graph.blocks[2].edges,
+            &[edge(EdgeKind::Return, Destination::Unknown)]
+        );
+    }
+
+    #[test]
+    fn functions_return_to_their_call_sites() {
+        let (_, graph) = graph(
+            "f: cmp eax, 0\nje done\ncall g\ndone: ret\n\
+             g: ret\nstart: call f\nprn eax\ncall f",
+        );
+
+        let functions: Vec<_> = graph
+            .functions
+            .iter()
+            .map(|f| (f.entry, f.name.as_deref(), f.blocks.clone()))
+            .collect();
+        assert_eq!(
+            functions,
+            &[
+                (5, Some("start"), vec![4, 5]),
+                (0, Some("f"), vec![0, 1, 2]),
+                (4, Some("g"), vec![3]),
+            ]
+        );
+        assert_eq!(
+            graph.blocks[1].edges,
+            &[
+                edge(EdgeKind::Call, Destination::Block(3)),
+                edge(EdgeKind::CallReturn, Destination::Block(2))
+            ]
+        );
+        assert_eq!(
+            graph.blocks[2].edges,
+            &[
+                edge(EdgeKind::Return, Destination::Block(5)),
+                edge(EdgeKind::Return, Destination::End)
+            ]
+        );
+        assert_eq!(
+            graph.blocks[3].edges,
+            &[edge(EdgeKind::Return, Destination::Block(2))]
+        );
+        assert_eq!(graph.function("g").map(|f| f.entry), Some(4));
+        assert_eq!(graph.function("h"), None);
+    }
+
+    #[test]
+    fn dot_output() {
+        let (program, graph) = graph("f: ret\nstart: call f\ncmp eax, 1\nje start\njmp eax");
+
+        let dot = graph.to_dot(&program);
+        assert!(dot.starts_with("digraph \"program\" {\n"));
+        assert!(dot.contains("    b0 [label=\"f:\\l0: ret\\l\"];\n"));
+        assert!(dot.contains("    b1 -> b0 [label=\"call\", style=bold];\n"));
+        assert!(dot.contains("    b1 -> b2 [style=dotted];\n"));
+        assert!(dot.contains("    b0 -> b2 [label=\"ret\", style=dashed];\n"));
+        assert!(dot.contains("    b2 -> b1 [label=\"je\"];\n"));
+        assert!(dot.contains("    b4 -> unknown;\n"));
+        assert!(dot.contains("    unknown [shape=oval, label=\"?\"];\n"));
+        assert!(dot.ends_with("}\n"));
+
+        let dot = graph.function_to_dot(&program, graph.function("start").unwrap());
+        assert!(dot.starts_with("digraph \"start\" {\n"));
+        assert!(!dot.contains("    b0 ["));
+        assert!(dot.contains("    b1 -> f0 [label=\"call\", style=bold];\n"));
+        assert!(dot.contains("    f0 [shape=oval, label=\"f\"];\n"));
+    }
+}
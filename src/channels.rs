@@ -0,0 +1,372 @@
+//! Running several programs that pass messages to each other over channels.
+//!
+//! A `Network` runs programs that each have a `Memory` of their own, such as
+//! the stages of a pipeline, and the channels the host creates for them.
+//! Every program can use every channel, by its id. A channel is a queue of
+//! 32-bit words with a fixed capacity: `send` puts a word at its back,
+//! waiting while it is full, and `recv` takes the word at its front,
+//! waiting while it is empty. 64-bit programs send the low 32 bits of a
+//! word and receive words sign extended.
+//!
+//! The programs run in turn, in the order they were added, each for a fixed
+//! number of instructions, so a network runs the same way every time. A
+//! program that waits in a `send` or `recv` gives up the rest of its turn,
+//! and stays at that instruction until it can go on. When a whole round
+//! passes without any program getting further, those that have not
+//! finished wait for each other forever, which is reported as a deadlock.
+
+use crate::{
+    context::{ExecutionError, ExecutionErrorKind, Memory, Program},
+    instruction::{Instruction, Register},
+    preprocessor::SourceLocation,
+};
+use std::{collections::VecDeque, convert::TryFrom, fmt, mem};
+
+/// The number of words a channel holds, unless told otherwise.
+pub const DEFAULT_CAPACITY: usize = 1;
+
+/// The channels of a network, as a running program sees them through
+/// `Memory::channels`.
+pub struct Channels {
+    channels: Vec<Channel>,
+}
+
+struct Channel {
+    capacity: usize,
+    words: VecDeque<i32>,
+}
+
+/// Programs connected by channels.
+pub struct Network<'a> {
+    programs: Vec<Node<'a>>,
+    /// Moved into a program while it runs.
+    channels: Option<Box<Channels>>,
+}
+
+struct Node<'a> {
+    program: &'a Program,
+    memory: Memory,
+    finished: bool,
+}
+
+/// A program of a network waiting in a `send` or `recv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waiting {
+    /// The index of the program in the network.
+    pub program: usize,
+    pub instruction_index: i64,
+    pub instruction: Instruction,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    /// A runtime error in the program with the given index.
+    Execution(usize, ExecutionError),
+    /// The programs that have not finished, all waiting on channels that
+    /// none of the others will use.
+    Deadlock(Vec<Waiting>),
+}
+
+impl Channels {
+    /// Sends `word` on the channel, returning whether there was room for it.
+    pub(crate) fn send(
+        self: &mut Channels,
+        id: i64,
+        word: i32,
+    ) -> Result<bool, ExecutionErrorKind> {
+        let channel = self.channel(id)?;
+        if channel.words.len() == channel.capacity {
+            return Ok(false);
+        }
+        channel.words.push_back(word);
+        Ok(true)
+    }
+
+    /// Receives the next word on the channel, or `None` if it is empty.
+    pub(crate) fn receive(self: &mut Channels, id: i64) -> Result<Option<i32>, ExecutionErrorKind> {
+        Ok(self.channel(id)?.words.pop_front())
+    }
+
+    fn channel(self: &mut Channels, id: i64) -> Result<&mut Channel, ExecutionErrorKind> {
+        let channels = &mut self.channels;
+        usize::try_from(id)
+            .ok()
+            .and_then(move |index| channels.get_mut(index))
+            .ok_or(ExecutionErrorKind::InvalidChannel(id))
+    }
+}
+
+impl<'a> Network<'a> {
+    pub fn new() -> Network<'a> {
+        Network {
+            programs: vec![],
+            channels: Some(Box::new(Channels { channels: vec![] })),
+        }
+    }
+
+    /// Adds a program with `memory` as its state, returning its index.
+    pub fn add_program(self: &mut Network<'a>, program: &'a Program, memory: Memory) -> usize {
+        self.programs.push(Node {
+            program,
+            memory,
+            finished: false,
+        });
+        self.programs.len() - 1
+    }
+
+    /// Adds a channel that holds up to `capacity` words, which must be at
+    /// least 1, returning its id.
+    pub fn add_channel(self: &mut Network<'a>, capacity: usize) -> i64 {
+        assert!(capacity > 0, "channels must hold at least one word");
+        let channels = &mut self.channels.as_mut().unwrap().channels;
+        channels.push(Channel {
+            capacity,
+            words: VecDeque::new(),
+        });
+        channels.len() as i64 - 1
+    }
+
+    pub fn memory(self: &Network<'a>, program: usize) -> &Memory {
+        &self.programs[program].memory
+    }
+
+    pub fn into_memories(self: Network<'a>) -> Vec<Memory> {
+        self.programs.into_iter().map(|node| node.memory).collect()
+    }
+
+    /// Runs the programs in turn, `quantum` instructions at a time, until
+    /// they have all finished, one of them fails, or they deadlock.
+    pub fn run(self: &mut Network<'a>, quantum: u64) -> Result<(), NetworkError> {
+        loop {
+            let mut running = false;
+            let mut progressed = false;
+            for index in 0..self.programs.len() {
+                if !self.programs[index].finished {
+                    running = true;
+                    progressed |= self.run_turn(index, quantum)?;
+                }
+            }
+
+            if !running {
+                return Ok(());
+            } else if !progressed {
+                return Err(NetworkError::Deadlock(self.waiting()));
+            }
+        }
+    }
+
+    /// Runs a program for a turn, returning whether it got any further.
+    fn run_turn(self: &mut Network<'a>, index: usize, quantum: u64) -> Result<bool, NetworkError> {
+        let node = &mut self.programs[index];
+        let mut progressed = false;
+
+        mem::swap(&mut node.memory.channels, &mut self.channels);
+        for _ in 0..quantum.max(1) {
+            let eip = node.memory.registers[Register::Eip as usize];
+            match node.program.step(&mut node.memory) {
+                Ok(true) if is_waiting(node, eip) => break,
+                Ok(true) => progressed = true,
+                Ok(false) => {
+                    node.finished = true;
+                    progressed = true;
+                    break;
+                }
+                Err(error) => {
+                    mem::swap(&mut node.memory.channels, &mut self.channels);
+                    return Err(NetworkError::Execution(index, error));
+                }
+            }
+        }
+        mem::swap(&mut node.memory.channels, &mut self.channels);
+
+        Ok(progressed)
+    }
+
+    fn waiting(self: &Network<'a>) -> Vec<Waiting> {
+        self.programs
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !node.finished)
+            .map(|(program, node)| {
+                let instruction_index = node.memory.registers[Register::Eip as usize];
+                let index = instruction_index as usize;
+                Waiting {
+                    program,
+                    instruction_index,
+                    instruction: node.program.instructions[index],
+                    location: node.program.locations.get(index).cloned(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl<'a> Default for Network<'a> {
+    fn default() -> Network<'a> {
+        Network::new()
+    }
+}
+
+/// Whether the program stayed at a `send` or `recv` at `eip`, waiting on its
+/// channel.
+fn is_waiting(node: &Node, eip: i64) -> bool {
+    node.memory.registers[Register::Eip as usize] == eip
+        && matches!(
+            node.program.instructions.get(eip as usize),
+            Some(Instruction::Send(..)) | Some(Instruction::Recv(..))
+        )
+}
+
+impl fmt::Display for Waiting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "program {} at instruction {}: {}",
+            self.program, self.instruction_index, self.instruction
+        )?;
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Execution(program, error) => {
+                write!(f, "in program {}: {}", program, error)
+            }
+            NetworkError::Deadlock(waiting) => {
+                write!(f, "deadlock, every program is waiting on a channel:")?;
+                for waiting in waiting {
+                    write!(f, "\n  {}", waiting)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn load(sources: &[&str]) -> Vec<Program> {
+        sources
+            .iter()
+            .map(|source| Program::load(source.to_string()).unwrap())
+            .collect()
+    }
+
+    /// Runs the programs connected by channels of `capacities`, returning
+    /// the result and what they printed, in order.
+    fn run(
+        programs: &[Program],
+        capacities: &[usize],
+        quantum: u64,
+    ) -> (Result<(), NetworkError>, String) {
+        let output = SharedOutput::default();
+        let mut network = Network::new();
+        for program in programs {
+            let mut memory = program.initialize();
+            memory.output = Box::new(output.clone());
+            network.add_program(program, memory);
+        }
+        for capacity in capacities {
+            network.add_channel(*capacity);
+        }
+
+        let result = network.run(quantum);
+        let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        (result, printed)
+    }
+
+    #[test]
+    fn senders_wait_while_the_channel_is_full() {
+        let programs = load(&[
+            "send 0, 1\nprn 1\nsend 0, 2\nprn 2\nsend 0, 3\nprn 3",
+            "mov ecx, 3\nnext: recv eax, 0\nadd eax, 10\nprn eax\nloop next",
+        ]);
+
+        // The sender gives up its turn when the channel is full
+        assert_eq!(
+            run(&programs, &[1], 10),
+            (Ok(()), "1\n11\n2\n12\n3\n13\n".to_owned())
+        );
+        assert_eq!(
+            run(&programs, &[3], 10),
+            (Ok(()), "1\n2\n3\n11\n12\n13\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn programs_waiting_for_each_other_deadlock() {
+        let programs = load(&["recv eax, 0\nsend 1, eax", "nop\nrecv eax, 1\nsend 0, eax"]);
+
+        let (result, _) = run(&programs, &[1, 1], 1);
+        let waiting = match result {
+            Err(NetworkError::Deadlock(waiting)) => waiting,
+            result => panic!("expected a deadlock, got {:?}", result),
+        };
+        let waiting: Vec<_> = waiting
+            .iter()
+            .map(|w| (w.program, w.instruction_index))
+            .collect();
+        assert_eq!(waiting, [(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn finished_programs_do_not_deadlock_the_others() {
+        let programs = load(&["send 0, 5", "recv eax, 0\nrecv eax, 0"]);
+
+        match run(&programs, &[1], 1).0 {
+            Err(NetworkError::Deadlock(waiting)) => assert_eq!(waiting.len(), 1),
+            result => panic!("expected a deadlock, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn words_are_sent_as_32_bits() {
+        let programs = load(&[
+            "%bits 64\nmov eax, 0x1ffffffff\nsend 0, eax",
+            "%bits 64\nrecv eax, 0\nprn eax",
+        ]);
+
+        assert_eq!(run(&programs, &[1], 1), (Ok(()), "-1\n".to_owned()));
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_program() {
+        let programs = load(&["send 0, 1", "recv eax, 1"]);
+
+        match run(&programs, &[1], 1).0 {
+            Err(NetworkError::Execution(1, error)) => {
+                assert_eq!(error.kind, ExecutionErrorKind::InvalidChannel(1));
+            }
+            result => panic!("expected an error in program 1, got {:?}", result),
+        }
+
+        let error = programs[0].run().unwrap_err();
+        assert_eq!(error.kind, ExecutionErrorKind::NoChannels);
+    }
+}
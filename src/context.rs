@@ -1,24 +1,130 @@
 use crate::{
-    instruction::{Instruction, Register, Source, Target, NUM_REGISTERS},
+    backtrace::Backtrace,
+    channels::Channels,
+    decoded::DecodedProgram,
+    instruction::{
+        FloatRegister, FloatSource, Instruction, Register, Source, Target, VectorRegister,
+        NUM_FLOAT_REGISTERS, NUM_LANES, NUM_REGISTERS, NUM_VECTOR_REGISTERS,
+    },
     lexer::LexerContext,
+    multicore::{self, Cores},
+    native::Natives,
     parser::{parse, ParseError},
-    preprocessor::{preprocess, PreprocessingError},
+    preprocessor::{preprocess_with_locations, PreprocessingError, SourceLocation},
 };
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    io::Write,
+    ops::{BitAnd, BitOr, BitXor, Not, RangeInclusive},
+};
+
+pub(crate) const MEMORY_SIZE: usize = 16 * 1024 * 1024; // 128 MB (16M words)
+pub const STACK_SIZE: usize = 512 * 1024; // 4 MB (512k words)
+
+/// The `int` service, selected by `eax`, that prints a backtrace to the
+/// output. `int` does nothing for values of `eax` that are not services.
+pub const INT_BACKTRACE: i32 = 1;
+
+// The bits of `Memory::flags`, which `pushf` and `popf` move as a word.
+// Arithmetic and logic instructions set them from their result, like `cmp`
+// does from the difference of its operands. Bits 0 and 1 are what `cmp` set
+// before the others existed, and are all `je`, `jne`, `jg`, `jge`, `jl` and
+// `jle` look at, so a word popped into the flags works with them as it
+// always did. The other bits are always clear in such a word.
+
+/// Set if the result is zero, or the operands of `cmp` are equal.
+pub const FLAG_ZERO: i32 = 0x1;
+/// Set if the result is greater than zero, or the first operand of `cmp`
+/// is greater than the second, as signed numbers. It is the `ZF == 0 &&
+/// SF == OF` condition of x86.
+pub const FLAG_GREATER: i32 = 0x2;
+/// Set if the result as an unsigned number is wrong: an addition carried
+/// out, a subtraction borrowed, or a multiplication overflowed. Shifts and
+/// rotates set it to the last bit shifted out or rotated around.
+pub const FLAG_CARRY: i32 = 0x4;
+/// Set if the result is negative.
+pub const FLAG_SIGN: i32 = 0x8;
+/// Set if the result as a signed number is wrong.
+pub const FLAG_OVERFLOW: i32 = 0x10;
+
+/// The size of the registers and memory words of a program, selected by a
+/// `%bits 32` or `%bits 64` line in its source. Programs are 32-bit unless
+/// they say otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordSize {
+    #[default]
+    Bits32,
+    Bits64,
+}
+
+impl WordSize {
+    pub fn bits(self) -> u32 {
+        match self {
+            WordSize::Bits32 => 32,
+            WordSize::Bits64 => 64,
+        }
+    }
 
-const MEMORY_SIZE: usize = 16 * 1024 * 1024; // 64 MB (8M i32)
-const STACK_SIZE: usize = 512 * 1024; // 2 MB (512k i32)
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+}
 
 pub struct Program {
     pub instructions: Vec<Instruction>,
     pub start_instruction_index: i32,
+    /// The source location of each instruction.
+    pub locations: Vec<SourceLocation>,
+    /// The labels and the instruction indices they refer to, ordered by
+    /// instruction index.
+    pub labels: Vec<(String, i32)>,
+    /// The instruction indices of the labels used as values other than jump
+    /// targets, sorted.
+    pub addresses_taken: Vec<i32>,
+    pub word_size: WordSize,
+    /// The native functions the program was loaded with, which
+    /// `Instruction::Native` calls by index.
+    pub natives: Natives,
 }
 
+/// An entry in the shadow call stack, pushed by `call` and popped by `ret`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// The instruction index that was called.
+    pub callee: i32,
+    /// The instruction index of the `call`.
+    pub call_site: i32,
+    /// The stack pointer after the return address was pushed.
+    pub stack_pointer: i32,
+}
+
+/// The state of a running program. Registers and memory words are stored as
+/// `i64` whatever the word size of the program, with the words of 32-bit
+/// programs sign extended.
 pub struct Memory {
+    /// A combination of the `FLAG_` bits.
     pub flags: i32,
-    pub remainder: i32,
-    pub mem_space: Vec<i32>,
-    pub registers: [i32; NUM_REGISTERS],
+    pub remainder: i64,
+    pub mem_space: Vec<i64>,
+    pub registers: [i64; NUM_REGISTERS],
+    pub float_registers: [f64; NUM_FLOAT_REGISTERS],
+    pub vector_registers: [[i32; NUM_LANES]; NUM_VECTOR_REGISTERS],
+    /// Where `prn` writes its output. Defaults to stdout.
+    pub output: Box<dyn Write + Send>,
+    /// The calls that have not returned yet, innermost last.
+    pub call_stack: Vec<Frame>,
+    /// Whether `ret` to anything but the instruction after the innermost
+    /// `call` is an error. Without it, `ret` drops the innermost frame
+    /// regardless.
+    pub strict_returns: bool,
+    /// The cores of the `multicore::Machine` running the program, which
+    /// `spawn` and `join` act on. `None` when it runs on a single core.
+    pub cores: Option<Box<Cores>>,
+    /// The channels of the `channels::Network` running the program, which
+    /// `send` and `recv` use. `None` when it runs on its own.
+    pub channels: Option<Box<Channels>>,
 }
 
 #[derive(Debug)]
@@ -27,10 +133,227 @@ pub enum LoadError {
     ParseError(ParseError),
 }
 
-#[derive(Debug, PartialEq)]
-pub enum ExecutionError {
-    InstructionOutOfRange(i32),
-    DataAddressOutOfRange(i32),
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecutionErrorKind {
+    InstructionOutOfRange(i64),
+    DataAddressOutOfRange(i64),
+    /// A `ret` to the given address, which is not where the innermost `call`
+    /// returns to. Only reported when `Memory::strict_returns` is set.
+    MismatchedReturn(i64),
+    /// A `div` or `mod` by zero.
+    DivisionByZero,
+    /// A `spawn` or `join` on a single core.
+    SingleCore,
+    /// A `join` of the given id, which no core has.
+    InvalidCore(i64),
+    /// A `send` or `recv` in a program that runs on its own.
+    NoChannels,
+    /// A `send` or `recv` on the given id, which no channel has.
+    InvalidChannel(i64),
+    /// An error of a native function, with its message. Boxed to keep
+    /// `ExecutionError` small.
+    Native(Box<String>),
+}
+
+/// A runtime error, along with the state of the VM when it occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionError {
+    pub kind: ExecutionErrorKind,
+    pub instruction_index: i64,
+    /// The faulting instruction, unless `instruction_index` is out of range.
+    /// Boxed like `registers`.
+    pub instruction: Option<Box<Instruction>>,
+    pub location: Option<SourceLocation>,
+    /// Boxed to keep the `Result`s returned when stepping small.
+    pub registers: Box<[i64; NUM_REGISTERS]>,
+    pub flags: i32,
+    pub remainder: i64,
+    pub backtrace: Backtrace,
+}
+
+/// Receives notifications about the state accesses made by
+/// `Program::step_observed`. All methods default to doing nothing, and `()`
+/// can be used where no observer is needed, in which case the notifications
+/// compile away entirely.
+///
+/// Moving `eip` to the next instruction or a jump target is not reported as a
+/// register write.
+pub trait Observer {
+    /// Called after the word at `address` has been read.
+    fn read_memory(&mut self, _address: i32) {}
+
+    /// Called before the word at `address`, which currently holds
+    /// `old_value`, is overwritten.
+    fn write_memory(&mut self, _address: i32, _old_value: i64) {}
+
+    /// Called before `register`, which currently holds `old_value`, is
+    /// overwritten.
+    fn write_register(&mut self, _register: Register, _old_value: i64) {}
+
+    /// Called before the floating-point `register`, which currently holds
+    /// `old_value`, is overwritten.
+    fn write_float_register(&mut self, _register: FloatRegister, _old_value: f64) {}
+
+    /// Called before the vector `register`, which currently holds
+    /// `old_value`, is overwritten.
+    fn write_vector_register(&mut self, _register: VectorRegister, _old_value: [i32; NUM_LANES]) {}
+
+    /// Called before the flags, which currently hold `old_value`, are
+    /// overwritten.
+    fn write_flags(&mut self, _old_value: i32) {}
+
+    /// Called before the remainder, which currently holds `old_value`, is
+    /// overwritten.
+    fn write_remainder(&mut self, _old_value: i64) {}
+
+    /// Called before a frame is pushed onto the call stack.
+    fn push_frame(&mut self) {}
+
+    /// Called after `frame` has been popped from the call stack.
+    fn pop_frame(&mut self, _frame: Frame) {}
+}
+
+impl Observer for () {}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+    fn read_memory(&mut self, address: i32) {
+        (**self).read_memory(address);
+    }
+
+    fn write_memory(&mut self, address: i32, old_value: i64) {
+        (**self).write_memory(address, old_value);
+    }
+
+    fn write_register(&mut self, register: Register, old_value: i64) {
+        (**self).write_register(register, old_value);
+    }
+
+    fn write_float_register(&mut self, register: FloatRegister, old_value: f64) {
+        (**self).write_float_register(register, old_value);
+    }
+
+    fn write_vector_register(&mut self, register: VectorRegister, old_value: [i32; NUM_LANES]) {
+        (**self).write_vector_register(register, old_value);
+    }
+
+    fn write_flags(&mut self, old_value: i32) {
+        (**self).write_flags(old_value);
+    }
+
+    fn write_remainder(&mut self, old_value: i64) {
+        (**self).write_remainder(old_value);
+    }
+
+    fn push_frame(&mut self) {
+        (**self).push_frame();
+    }
+
+    fn pop_frame(&mut self, frame: Frame) {
+        (**self).pop_frame(frame);
+    }
+}
+
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn read_memory(&mut self, address: i32) {
+        self.0.read_memory(address);
+        self.1.read_memory(address);
+    }
+
+    fn write_memory(&mut self, address: i32, old_value: i64) {
+        self.0.write_memory(address, old_value);
+        self.1.write_memory(address, old_value);
+    }
+
+    fn write_register(&mut self, register: Register, old_value: i64) {
+        self.0.write_register(register, old_value);
+        self.1.write_register(register, old_value);
+    }
+
+    fn write_float_register(&mut self, register: FloatRegister, old_value: f64) {
+        self.0.write_float_register(register, old_value);
+        self.1.write_float_register(register, old_value);
+    }
+
+    fn write_vector_register(&mut self, register: VectorRegister, old_value: [i32; NUM_LANES]) {
+        self.0.write_vector_register(register, old_value);
+        self.1.write_vector_register(register, old_value);
+    }
+
+    fn write_flags(&mut self, old_value: i32) {
+        self.0.write_flags(old_value);
+        self.1.write_flags(old_value);
+    }
+
+    fn write_remainder(&mut self, old_value: i64) {
+        self.0.write_remainder(old_value);
+        self.1.write_remainder(old_value);
+    }
+
+    fn push_frame(&mut self) {
+        self.0.push_frame();
+        self.1.push_frame();
+    }
+
+    fn pop_frame(&mut self, frame: Frame) {
+        self.0.pop_frame(frame);
+        self.1.pop_frame(frame);
+    }
+}
+
+impl<O: Observer> Observer for Option<O> {
+    fn read_memory(&mut self, address: i32) {
+        if let Some(observer) = self {
+            observer.read_memory(address);
+        }
+    }
+
+    fn write_memory(&mut self, address: i32, old_value: i64) {
+        if let Some(observer) = self {
+            observer.write_memory(address, old_value);
+        }
+    }
+
+    fn write_register(&mut self, register: Register, old_value: i64) {
+        if let Some(observer) = self {
+            observer.write_register(register, old_value);
+        }
+    }
+
+    fn write_float_register(&mut self, register: FloatRegister, old_value: f64) {
+        if let Some(observer) = self {
+            observer.write_float_register(register, old_value);
+        }
+    }
+
+    fn write_vector_register(&mut self, register: VectorRegister, old_value: [i32; NUM_LANES]) {
+        if let Some(observer) = self {
+            observer.write_vector_register(register, old_value);
+        }
+    }
+
+    fn write_flags(&mut self, old_value: i32) {
+        if let Some(observer) = self {
+            observer.write_flags(old_value);
+        }
+    }
+
+    fn write_remainder(&mut self, old_value: i64) {
+        if let Some(observer) = self {
+            observer.write_remainder(old_value);
+        }
+    }
+
+    fn push_frame(&mut self) {
+        if let Some(observer) = self {
+            observer.push_frame();
+        }
+    }
+
+    fn pop_frame(&mut self, frame: Frame) {
+        if let Some(observer) = self {
+            observer.pop_frame(frame);
+        }
+    }
 }
 
 impl From<PreprocessingError> for LoadError {
@@ -45,69 +368,188 @@ impl From<ParseError> for LoadError {
     }
 }
 
+impl fmt::Display for ExecutionErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecutionErrorKind::InstructionOutOfRange(index) => {
+                write!(f, "instruction index {} is out of range", index)
+            }
+            ExecutionErrorKind::DataAddressOutOfRange(address) => {
+                write!(f, "data address {} is out of range", address)
+            }
+            ExecutionErrorKind::MismatchedReturn(address) => {
+                write!(f, "return to {}, which is not a call site", address)
+            }
+            ExecutionErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ExecutionErrorKind::SingleCore => {
+                write!(f, "spawn and join need more than one core")
+            }
+            ExecutionErrorKind::InvalidCore(id) => write!(f, "core {} does not exist", id),
+            ExecutionErrorKind::NoChannels => write!(f, "send and recv need channels"),
+            ExecutionErrorKind::InvalidChannel(id) => write!(f, "channel {} does not exist", id),
+            ExecutionErrorKind::Native(message) => f.write_str(message),
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.kind)?;
+
+        write!(f, "  at instruction {}", self.instruction_index)?;
+        if let Some(instruction) = &self.instruction {
+            write!(f, ": {}", instruction)?;
+        }
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        writeln!(f)?;
+
+        for (i, register) in Register::ALL.iter().enumerate() {
+            let separator = match i {
+                0 => "  ",
+                _ if i % 6 == 0 => "\n  ",
+                _ => " ",
+            };
+            write!(
+                f,
+                "{}{}={}",
+                separator,
+                register.name(),
+                self.registers[*register as usize]
+            )?;
+        }
+
+        writeln!(f, "\n  flags={} remainder={}", self.flags, self.remainder)?;
+
+        write!(f, "backtrace:\n{}", self.backtrace)
+    }
+}
+
 impl Program {
     pub fn load(source: String) -> Result<Program, LoadError> {
+        Program::load_with_file_name(source, None)
+    }
+
+    /// Like `load`, but reports source locations relative to `file`, which
+    /// `source` was read from.
+    pub fn load_with_file_name(source: String, file: Option<&str>) -> Result<Program, LoadError> {
+        Program::load_with_natives(source, file, &Natives::default())
+    }
+
+    /// Like `load_with_file_name`, but a `call` of a name that is not a
+    /// label calls the native function of that name, if there is one.
+    pub fn load_with_natives(
+        source: String,
+        file: Option<&str>,
+        natives: &Natives,
+    ) -> Result<Program, LoadError> {
         let mut defines = HashMap::<String, String>::default();
-        let source = preprocess(source, &mut defines)?;
+        let (source, line_locations) = preprocess_with_locations(source, file, &mut defines)?;
 
         let lexer = LexerContext::lex(&source, &defines);
 
-        let program = parse(&lexer.tokens())?;
+        let mut program = parse(lexer.tokens(), natives)?;
+
+        // The parser only knows the lines of the preprocessed source
+        for location in program.locations.iter_mut() {
+            *location = line_locations[location.line - 1].clone();
+        }
 
         Ok(program)
     }
 
     pub fn run(self: &Program) -> Result<(), ExecutionError> {
         let mut memory = self.initialize();
-
-        loop {
-            if !self.step(&mut memory)? {
-                break;
-            }
-        }
-
-        Ok(())
+        DecodedProgram::new(self).run(&mut memory)
     }
 
     pub fn initialize(self: &Program) -> Memory {
         let mut memory = Memory::new(MEMORY_SIZE, STACK_SIZE);
-        memory.registers[Register::Eip as usize] = self.start_instruction_index;
+        memory.registers[Register::Eip as usize] = self.start_instruction_index.into();
         memory
     }
 
     pub fn step(self: &Program, memory: &mut Memory) -> Result<bool, ExecutionError> {
+        self.step_observed(memory, &mut ())
+    }
+
+    /// Like `step`, but reports every memory access to `observer`.
+    pub fn step_observed<O: Observer>(
+        self: &Program,
+        memory: &mut Memory,
+        observer: &mut O,
+    ) -> Result<bool, ExecutionError> {
+        let result = match self.word_size {
+            WordSize::Bits32 => self.execute::<i32, O>(memory, observer),
+            WordSize::Bits64 => self.execute::<i64, O>(memory, observer),
+        };
+        result.map_err(|kind| self.execution_error(kind, memory))
+    }
+
+    pub(crate) fn execution_error(
+        self: &Program,
+        kind: ExecutionErrorKind,
+        memory: &Memory,
+    ) -> ExecutionError {
         let instruction_index = memory.registers[Register::Eip as usize];
+        let index = usize::try_from(instruction_index).ok();
 
-        if instruction_index < 0 || instruction_index > self.instructions.len() as i32 {
-            return Err(ExecutionError::InstructionOutOfRange(instruction_index));
-        } else if instruction_index == self.instructions.len() as i32 {
+        ExecutionError {
+            kind,
+            instruction_index,
+            instruction: index
+                .and_then(|i| self.instructions.get(i))
+                .map(|i| Box::new(*i)),
+            location: index.and_then(|i| self.locations.get(i)).cloned(),
+            registers: Box::new(memory.registers),
+            flags: memory.flags,
+            remainder: memory.remainder,
+            backtrace: self.backtrace(memory),
+        }
+    }
+
+    /// Executes an instruction with the registers and memory words as `W`.
+    fn execute<W: Word, O: Observer>(
+        self: &Program,
+        memory: &mut Memory,
+        observer: &mut O,
+    ) -> Result<bool, ExecutionErrorKind> {
+        let instruction_index = memory.registers[Register::Eip as usize];
+
+        if instruction_index < 0 || instruction_index > self.instructions.len() as i64 {
+            return Err(ExecutionErrorKind::InstructionOutOfRange(instruction_index));
+        } else if instruction_index == self.instructions.len() as i64 {
             return Ok(false);
         }
+        let instruction_index = instruction_index as i32;
 
         macro_rules! read {
             ($source:ident) => {
                 match $source {
-                    Source::Register(reg) => memory.registers[reg as usize],
-                    Source::Value(value) => value,
+                    Source::Register(reg) => W::from_i64(memory.registers[reg as usize]),
+                    Source::Value(value) => W::from_i64(value),
                     Source::Address(addr) => {
                         if addr < 0 || addr as usize >= memory.mem_space.len() {
-                            return Err(ExecutionError::DataAddressOutOfRange(addr));
+                            return Err(ExecutionErrorKind::DataAddressOutOfRange(addr.into()));
                         }
-                        memory.mem_space[addr as usize]
+                        observer.read_memory(addr);
+                        W::from_i64(memory.mem_space[addr as usize])
                     }
-                };
+                }
             };
         }
 
         macro_rules! readt {
             ($target:ident) => {
                 match $target {
-                    Target::Register(reg) => memory.registers[reg as usize],
+                    Target::Register(reg) => W::from_i64(memory.registers[reg as usize]),
                     Target::Address(addr) => {
                         if addr < 0 || addr as usize >= memory.mem_space.len() {
-                            return Err(ExecutionError::DataAddressOutOfRange(addr));
+                            return Err(ExecutionErrorKind::DataAddressOutOfRange(addr.into()));
                         }
-                        memory.mem_space[addr as usize]
+                        observer.read_memory(addr);
+                        W::from_i64(memory.mem_space[addr as usize])
                     }
                 }
             };
@@ -116,21 +558,111 @@ impl Program {
         macro_rules! write {
             ($target:ident, $value:expr) => {
                 match $target {
-                    Target::Register(reg) => memory.registers[reg as usize] = $value,
+                    Target::Register(reg) => {
+                        let value: W = $value;
+                        observer.write_register(reg, memory.registers[reg as usize]);
+                        memory.registers[reg as usize] = value.into();
+                    }
                     Target::Address(addr) => {
                         if addr < 0 || addr as usize >= memory.mem_space.len() {
-                            return Err(ExecutionError::DataAddressOutOfRange(addr));
+                            return Err(ExecutionErrorKind::DataAddressOutOfRange(addr.into()));
                         }
-                        memory.mem_space[addr as usize] = $value;
+                        let value: W = $value;
+                        observer.write_memory(addr, memory.mem_space[addr as usize]);
+                        memory.mem_space[addr as usize] = value.into();
                     }
                 };
             };
         }
 
+        // Writes the result of an operation that also sets the flags
+        macro_rules! write_flags {
+            ($target:ident, $operation:expr) => {
+                let (value, flags) = $operation;
+                write!($target, value);
+                observer.write_flags(memory.flags);
+                memory.flags = flags;
+            };
+        }
+
+        // A float in memory is two words, which are both checked before
+        // either is accessed
+        macro_rules! check_float_address {
+            ($addr:expr) => {
+                for addr in [$addr, $addr.wrapping_add(1)] {
+                    if addr < 0 || addr as usize >= memory.mem_space.len() {
+                        return Err(ExecutionErrorKind::DataAddressOutOfRange(addr.into()));
+                    }
+                }
+            };
+        }
+
+        macro_rules! read_float {
+            ($source:ident) => {
+                match $source {
+                    FloatSource::Register(reg) => memory.float_registers[reg as usize],
+                    FloatSource::Value(value) => value,
+                    FloatSource::Address(addr) => {
+                        check_float_address!(addr);
+                        observer.read_memory(addr);
+                        observer.read_memory(addr + 1);
+                        float_from_words(
+                            memory.mem_space[addr as usize],
+                            memory.mem_space[addr as usize + 1],
+                        )
+                    }
+                }
+            };
+        }
+
+        macro_rules! write_float {
+            ($reg:ident, $value:expr) => {
+                let value = $value;
+                observer.write_float_register($reg, memory.float_registers[$reg as usize]);
+                memory.float_registers[$reg as usize] = value;
+            };
+        }
+
+        // The index of the first of the words a vector is loaded from or
+        // stored to, which are all checked before any is accessed
+        macro_rules! vector_address {
+            ($source:ident) => {{
+                let address: i64 = read!($source).into();
+                for i in 0..NUM_LANES as i64 {
+                    let addr = address.wrapping_add(i);
+                    if addr < 0 || addr as usize >= memory.mem_space.len() {
+                        return Err(ExecutionErrorKind::DataAddressOutOfRange(addr));
+                    }
+                }
+                address as usize
+            }};
+        }
+
+        macro_rules! write_vector {
+            ($reg:ident, $value:expr) => {
+                let value = $value;
+                observer.write_vector_register($reg, memory.vector_registers[$reg as usize]);
+                memory.vector_registers[$reg as usize] = value;
+            };
+        }
+
+        // The index of the word at the address in `$source`, which `cas`
+        // and `xadd` read and write as one step
+        macro_rules! word_address {
+            ($source:ident) => {{
+                let addr: i64 = read!($source).into();
+                if addr < 0 || addr as usize >= memory.mem_space.len() {
+                    return Err(ExecutionErrorKind::DataAddressOutOfRange(addr));
+                }
+                observer.read_memory(addr as i32);
+                addr as usize
+            }};
+        }
+
         let mut should_advance = true;
         macro_rules! jump {
             ($source:ident) => {
-                memory.registers[Register::Eip as usize] = read!($source);
+                memory.registers[Register::Eip as usize] = read!($source).into();
                 should_advance = false;
             };
             ($condition:expr, $source:ident) => {
@@ -142,29 +674,61 @@ impl Program {
 
         macro_rules! push {
             ($value:expr) => {
-                let addr = memory.registers[Register::Esp as usize] - 1;
+                let esp = W::from_i64(memory.registers[Register::Esp as usize]);
+                let addr: i64 = esp.wrapping_sub(W::ONE).into();
                 if addr < 0 || addr as usize >= memory.mem_space.len() {
-                    return Err(ExecutionError::DataAddressOutOfRange(addr));
+                    return Err(ExecutionErrorKind::DataAddressOutOfRange(addr));
                 }
-                memory.mem_space[addr as usize] = $value;
+                let value: W = $value;
+                observer.write_memory(addr as i32, memory.mem_space[addr as usize]);
+                memory.mem_space[addr as usize] = value.into();
+                observer.write_register(Register::Esp, memory.registers[Register::Esp as usize]);
                 memory.registers[Register::Esp as usize] = addr;
             };
         }
 
+        // Reads the unit of `$width` at the byte address in `$source`,
+        // zero extended
+        macro_rules! load {
+            ($width:ident, $source:ident) => {{
+                let address: i64 = read!($source).into();
+                let mut bytes = [0; 2];
+                let bytes = &mut bytes[..$width.bytes()];
+                for word in memory.words_of_bytes(self.word_size, address, bytes.len())? {
+                    observer.read_memory(word as i32);
+                }
+                memory.read_bytes(self.word_size, address, bytes)?;
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| value << 8 | i64::from(*byte))
+            }};
+        }
+
         macro_rules! pop {
             () => {{
                 let addr = memory.registers[Register::Esp as usize];
                 if addr < 0 || addr as usize >= memory.mem_space.len() {
-                    return Err(ExecutionError::DataAddressOutOfRange(addr));
+                    return Err(ExecutionErrorKind::DataAddressOutOfRange(addr));
                 }
+                observer.write_register(Register::Esp, addr);
                 memory.registers[Register::Esp as usize] = addr + 1;
-                memory.mem_space[addr as usize]
+                observer.read_memory(addr as i32);
+                W::from_i64(memory.mem_space[addr as usize])
             }};
         }
 
         match self.instructions[instruction_index as usize] {
             Instruction::Nop => {}
-            Instruction::Int => { /* unimplemented */ }
+            Instruction::Int => {
+                if memory.registers[Register::Eax as usize] == INT_BACKTRACE.into() {
+                    let backtrace = self.backtrace(memory).to_string();
+                    memory
+                        .output
+                        .write_all(backtrace.as_bytes())
+                        .expect("failed printing to output");
+                }
+            }
             Instruction::Mov(target, source) => {
                 write!(target, read!(source));
             }
@@ -175,70 +739,166 @@ impl Program {
                 write!(target, pop!());
             }
             Instruction::Pushf => {
-                push!(memory.flags);
+                push!(W::from_i64(memory.flags.into()));
             }
             Instruction::Popf => {
-                memory.flags = pop!();
+                let value: W = pop!();
+                observer.write_flags(memory.flags);
+                memory.flags = value.into() as i32;
             }
             Instruction::Inc(target) => {
-                write!(target, readt!(target) + 1);
+                write_flags!(target, increment(readt!(target), memory.flags));
             }
             Instruction::Dec(target) => {
-                write!(target, readt!(target) - 1);
+                write_flags!(target, decrement(readt!(target), memory.flags));
             }
             Instruction::Add(target, source) => {
-                write!(target, readt!(target) + read!(source));
+                write_flags!(target, add(readt!(target), read!(source)));
             }
             Instruction::Sub(target, source) => {
-                write!(target, readt!(target) - read!(source));
+                write_flags!(target, subtract(readt!(target), read!(source)));
             }
             Instruction::Mul(target, source) => {
-                write!(target, readt!(target) * read!(source));
+                write_flags!(target, multiply(readt!(target), read!(source)));
             }
             Instruction::Div(target, source) => {
-                write!(target, readt!(target) / read!(source));
+                write!(target, divide(readt!(target), read!(source))?);
             }
             Instruction::Mod(source1, source2) => {
-                memory.remainder = read!(source1) % read!(source2);
+                let value = remainder(read!(source1), read!(source2))?;
+                observer.write_remainder(memory.remainder);
+                memory.remainder = value.into();
             }
             Instruction::Rem(target) => {
-                write!(target, memory.remainder);
+                write!(target, W::from_i64(memory.remainder));
             }
             Instruction::Not(target) => {
                 write!(target, !readt!(target));
             }
             Instruction::Xor(target, source) => {
-                write!(target, readt!(target) ^ read!(source));
+                write_flags!(target, logic(readt!(target) ^ read!(source)));
             }
             Instruction::Or(target, source) => {
-                write!(target, readt!(target) | read!(source));
+                write_flags!(target, logic(readt!(target) | read!(source)));
             }
             Instruction::And(target, source) => {
-                write!(target, readt!(target) & read!(source));
+                write_flags!(target, logic(readt!(target) & read!(source)));
             }
             Instruction::Shl(target, source) => {
-                write!(target, readt!(target) << read!(source));
+                write_flags!(target, shift_left(readt!(target), read!(source)));
             }
             Instruction::Shr(target, source) => {
-                write!(target, readt!(target) >> read!(source));
+                write_flags!(target, shift_right(readt!(target), read!(source)));
+            }
+            Instruction::Sar(target, source) => {
+                write_flags!(target, shift_arithmetic(readt!(target), read!(source)));
+            }
+            Instruction::Rol(target, source) => {
+                write_flags!(target, rotate_left(readt!(target), read!(source)));
+            }
+            Instruction::Ror(target, source) => {
+                write_flags!(target, rotate_right(readt!(target), read!(source)));
+            }
+            Instruction::Neg(target) => {
+                write_flags!(target, negate(readt!(target)));
+            }
+            Instruction::Xchg(target1, target2) => {
+                let value1 = readt!(target1);
+                let value2 = readt!(target2);
+                write!(target1, value2);
+                write!(target2, value1);
+            }
+            Instruction::Lea(target, address) => {
+                write!(
+                    target,
+                    address.address(|reg| W::from_i64(memory.registers[reg as usize]))
+                );
+            }
+            Instruction::Test(source1, source2) => {
+                let value1 = read!(source1);
+                let value2 = read!(source2);
+
+                observer.write_flags(memory.flags);
+                memory.flags = logic(value1 & value2).1;
+            }
+            Instruction::Cmov(condition, target, source) => {
+                // Both operands are checked even if nothing is moved
+                let value = read!(source);
+                readt!(target);
+                if condition.holds(memory.flags) {
+                    write!(target, value);
+                }
+            }
+            Instruction::Popcnt(target, source) => {
+                write!(target, W::from_i64(read!(source).count_ones().into()));
+            }
+            Instruction::Clz(target, source) => {
+                write!(target, W::from_i64(read!(source).leading_zeros().into()));
+            }
+            Instruction::Ctz(target, source) => {
+                write!(target, W::from_i64(read!(source).trailing_zeros().into()));
+            }
+            Instruction::Bswap(target) => {
+                write!(target, readt!(target).swap_bytes());
+            }
+            Instruction::Ld(width, target, source) => {
+                write!(target, W::from_i64(load!(width, source)));
+            }
+            Instruction::Lds(width, target, source) => {
+                let shift = 64 - 8 * width.bytes() as u32;
+                write!(target, W::from_i64(load!(width, source) << shift >> shift));
+            }
+            Instruction::St(width, address, source) => {
+                let address: i64 = read!(address).into();
+                let value: i64 = read!(source).into();
+                let bytes = &value.to_le_bytes()[..width.bytes()];
+                for word in memory.words_of_bytes(self.word_size, address, bytes.len())? {
+                    observer.write_memory(word as i32, memory.mem_space[word]);
+                }
+                memory.write_bytes(self.word_size, address, bytes)?;
             }
             Instruction::Cmp(source1, source2) => {
                 let value1 = read!(source1);
                 let value2 = read!(source2);
 
-                memory.flags =
-                    if value1 == value2 { 1 } else { 0 } | if value1 > value2 { 2 } else { 0 }
+                observer.write_flags(memory.flags);
+                memory.flags = compare(value1, value2);
             }
             Instruction::Jmp(source) => {
                 jump!(source);
             }
             Instruction::Call(source) => {
-                push!(instruction_index + 1);
+                push!(W::from_i64(i64::from(instruction_index) + 1));
                 jump!(source);
+                observer.push_frame();
+                // A callee outside the program fails on the next step, and
+                // only needs to stay outside of it
+                let callee = memory.registers[Register::Eip as usize];
+                memory.call_stack.push(Frame {
+                    callee: callee.clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+                    call_site: instruction_index,
+                    stack_pointer: memory.registers[Register::Esp as usize] as i32,
+                });
             }
             Instruction::Ret => {
-                memory.registers[Register::Eip as usize] = pop!();
+                if memory.strict_returns {
+                    let addr = memory.registers[Register::Esp as usize];
+                    if addr < 0 || addr as usize >= memory.mem_space.len() {
+                        return Err(ExecutionErrorKind::DataAddressOutOfRange(addr));
+                    }
+                    let return_address = memory.mem_space[addr as usize];
+                    let expected = memory.call_stack.last().map(|f| f.call_site + 1);
+                    if expected.map(i64::from) != Some(return_address) {
+                        return Err(ExecutionErrorKind::MismatchedReturn(return_address));
+                    }
+                }
+
+                let return_address: W = pop!();
+                memory.registers[Register::Eip as usize] = return_address.into();
                 should_advance = false;
+                if let Some(frame) = memory.call_stack.pop() {
+                    observer.pop_frame(frame);
+                }
             }
             Instruction::Je(source) => {
                 jump!(memory.flags & 0x1 != 0, source);
@@ -258,17 +918,566 @@ impl Program {
             Instruction::Jle(source) => {
                 jump!(memory.flags & 0x2 == 0, source);
             }
-            Instruction::Prn(source) => println!("{}", read!(source)),
+            Instruction::Ja(source) => {
+                jump!(memory.flags & (FLAG_CARRY | FLAG_ZERO) == 0, source);
+            }
+            Instruction::Jae(source) => {
+                jump!(memory.flags & FLAG_CARRY == 0, source);
+            }
+            Instruction::Jb(source) => {
+                jump!(memory.flags & FLAG_CARRY != 0, source);
+            }
+            Instruction::Jbe(source) => {
+                jump!(memory.flags & (FLAG_CARRY | FLAG_ZERO) != 0, source);
+            }
+            Instruction::Js(source) => {
+                jump!(memory.flags & FLAG_SIGN != 0, source);
+            }
+            Instruction::Jns(source) => {
+                jump!(memory.flags & FLAG_SIGN == 0, source);
+            }
+            Instruction::Jo(source) => {
+                jump!(memory.flags & FLAG_OVERFLOW != 0, source);
+            }
+            Instruction::Jno(source) => {
+                jump!(memory.flags & FLAG_OVERFLOW == 0, source);
+            }
+            Instruction::Loop(source) => {
+                let ecx = Target::Register(Register::Ecx);
+                let count =
+                    W::from_i64(memory.registers[Register::Ecx as usize]).wrapping_sub(W::ONE);
+                write!(ecx, count);
+                jump!(count != W::ZERO, source);
+            }
+            Instruction::Prn(source) => {
+                let value: W = read!(source);
+                writeln!(memory.output, "{}", value).expect("failed printing to output");
+            }
+            Instruction::Fld(reg, source) => {
+                write_float!(reg, read_float!(source));
+            }
+            Instruction::Fst(addr, reg) => {
+                check_float_address!(addr);
+                let (low, high) = float_words(memory.float_registers[reg as usize]);
+                for (addr, value) in [(addr, low), (addr + 1, high)] {
+                    observer.write_memory(addr, memory.mem_space[addr as usize]);
+                    memory.mem_space[addr as usize] = value;
+                }
+            }
+            Instruction::Fadd(reg, source) => {
+                write_float!(
+                    reg,
+                    memory.float_registers[reg as usize] + read_float!(source)
+                );
+            }
+            Instruction::Fsub(reg, source) => {
+                write_float!(
+                    reg,
+                    memory.float_registers[reg as usize] - read_float!(source)
+                );
+            }
+            Instruction::Fmul(reg, source) => {
+                write_float!(
+                    reg,
+                    memory.float_registers[reg as usize] * read_float!(source)
+                );
+            }
+            Instruction::Fdiv(reg, source) => {
+                write_float!(
+                    reg,
+                    memory.float_registers[reg as usize] / read_float!(source)
+                );
+            }
+            Instruction::Fneg(reg) => {
+                write_float!(reg, -memory.float_registers[reg as usize]);
+            }
+            Instruction::Fabs(reg) => {
+                write_float!(reg, memory.float_registers[reg as usize].abs());
+            }
+            Instruction::Fsqrt(reg) => {
+                write_float!(reg, memory.float_registers[reg as usize].sqrt());
+            }
+            Instruction::Fcmp(reg, source) => {
+                let flags =
+                    compare_floats(memory.float_registers[reg as usize], read_float!(source));
+                observer.write_flags(memory.flags);
+                memory.flags = flags;
+            }
+            Instruction::Itof(reg, source) => {
+                let value: i64 = read!(source).into();
+                write_float!(reg, value as f64);
+            }
+            Instruction::Ftoi(target, reg) => {
+                write!(target, W::from_f64(memory.float_registers[reg as usize]));
+            }
+            Instruction::Fprn(source) => {
+                let value = read_float!(source);
+                writeln!(memory.output, "{}", value).expect("failed printing to output");
+            }
+            Instruction::Vld(reg, source) => {
+                let address = vector_address!(source);
+                let mut lanes = [0; NUM_LANES];
+                for (i, lane) in lanes.iter_mut().enumerate() {
+                    observer.read_memory((address + i) as i32);
+                    *lane = memory.mem_space[address + i] as i32;
+                }
+                write_vector!(reg, lanes);
+            }
+            Instruction::Vst(address, reg) => {
+                let address = vector_address!(address);
+                let lanes = memory.vector_registers[reg as usize];
+                for (i, lane) in lanes.iter().enumerate() {
+                    observer.write_memory((address + i) as i32, memory.mem_space[address + i]);
+                    memory.mem_space[address + i] = i64::from(*lane);
+                }
+            }
+            Instruction::Vop(op, reg1, reg2) => {
+                let mut lanes = memory.vector_registers[reg1 as usize];
+                for (lane, other) in lanes.iter_mut().zip(memory.vector_registers[reg2 as usize]) {
+                    *lane = op.apply(*lane, other);
+                }
+                write_vector!(reg1, lanes);
+            }
+            Instruction::Vins(reg, lane, source) => {
+                let value: i64 = read!(source).into();
+                let mut lanes = memory.vector_registers[reg as usize];
+                lanes[lane as usize] = value as i32;
+                write_vector!(reg, lanes);
+            }
+            Instruction::Vext(target, reg, lane) => {
+                let value = memory.vector_registers[reg as usize][lane as usize];
+                write!(target, W::from_i64(value.into()));
+            }
+            Instruction::Vsum(target, reg) => {
+                let lanes = memory.vector_registers[reg as usize];
+                let sum = lanes.iter().fold(0i32, |sum, lane| sum.wrapping_add(*lane));
+                write!(target, W::from_i64(sum.into()));
+            }
+            Instruction::Spawn(target, entry, stack) => {
+                let entry: i64 = read!(entry).into();
+                let stack: i64 = read!(stack).into();
+                let id = match &memory.cores {
+                    Some(cores) => cores.next_id(),
+                    None => return Err(ExecutionErrorKind::SingleCore),
+                };
+                // The core starts with the target set to its id too
+                write!(target, W::from_i64(id as i64));
+                let core = multicore::spawned_core(memory, entry, stack);
+                if let Some(cores) = &mut memory.cores {
+                    cores.spawn(core);
+                }
+            }
+            Instruction::Join(source) => {
+                let id: i64 = read!(source).into();
+                let cores = memory
+                    .cores
+                    .as_ref()
+                    .ok_or(ExecutionErrorKind::SingleCore)?;
+                match cores.is_finished(id) {
+                    Some(true) => {}
+                    // Runs the `join` again on the next step
+                    Some(false) => should_advance = false,
+                    None => return Err(ExecutionErrorKind::InvalidCore(id)),
+                }
+            }
+            Instruction::Cas(target, address, source) => {
+                let addr = word_address!(address);
+                let value = read!(source);
+                let expected = readt!(target);
+                let old = W::from_i64(memory.mem_space[addr]);
+                if old == expected {
+                    observer.write_memory(addr as i32, memory.mem_space[addr]);
+                    memory.mem_space[addr] = value.into();
+                }
+                write_flags!(target, (old, compare(old, expected)));
+            }
+            Instruction::Xadd(target, address) => {
+                let addr = word_address!(address);
+                let value = readt!(target);
+                let old = W::from_i64(memory.mem_space[addr]);
+                let (sum, flags) = add(old, value);
+                observer.write_memory(addr as i32, memory.mem_space[addr]);
+                memory.mem_space[addr] = sum.into();
+                write_flags!(target, (old, flags));
+            }
+            // Every instruction is atomic, so memory accesses are already
+            // ordered
+            Instruction::Fence => {}
+            // Only a machine switches cores
+            Instruction::Yield => {}
+            Instruction::Send(channel, source) => {
+                let id: i64 = read!(channel).into();
+                let value: i64 = read!(source).into();
+                let channels = memory
+                    .channels
+                    .as_mut()
+                    .ok_or(ExecutionErrorKind::NoChannels)?;
+                if !channels.send(id, value as i32)? {
+                    // Runs the `send` again on the next step
+                    should_advance = false;
+                }
+            }
+            Instruction::Recv(target, channel) => {
+                let id: i64 = read!(channel).into();
+                // The target is checked before a message is taken for it
+                readt!(target);
+                let channels = memory
+                    .channels
+                    .as_mut()
+                    .ok_or(ExecutionErrorKind::NoChannels)?;
+                match channels.receive(id)? {
+                    Some(value) => {
+                        write!(target, W::from_i64(value.into()));
+                    }
+                    None => should_advance = false,
+                }
+            }
+            Instruction::Native(index, _) => {
+                // The function may write any register, and the program goes
+                // on after the `call` whatever it does to `eip`
+                for register in Register::ALL {
+                    if register != Register::Eip {
+                        observer.write_register(register, memory.registers[register as usize]);
+                    }
+                }
+                for register in FloatRegister::ALL {
+                    observer
+                        .write_float_register(register, memory.float_registers[register as usize]);
+                }
+                for register in VectorRegister::ALL {
+                    observer.write_vector_register(
+                        register,
+                        memory.vector_registers[register as usize],
+                    );
+                }
+                observer.write_flags(memory.flags);
+                observer.write_remainder(memory.remainder);
+
+                (self.natives.function(index))(memory)?;
+                for value in memory.registers.iter_mut() {
+                    *value = W::from_i64(*value).into();
+                }
+            }
         };
 
         if should_advance {
-            memory.registers[Register::Eip as usize] = instruction_index + 1;
+            memory.registers[Register::Eip as usize] = i64::from(instruction_index) + 1;
         }
 
         Ok(true)
     }
 }
 
+/// The integer type of the registers and memory words of a program while it
+/// runs: `i32` or `i64`, as its `WordSize` says.
+pub(crate) trait Word:
+    Copy
+    + PartialEq
+    + PartialOrd
+    + fmt::Display
+    + Into<i64>
+    + Not<Output = Self>
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+{
+    const BITS: u32;
+    const ZERO: Self;
+    const ONE: Self;
+
+    /// Keeps the low bits of `value`.
+    fn from_i64(value: i64) -> Self;
+    /// Rounds towards zero, saturating, with NaN becoming zero.
+    fn from_f64(value: f64) -> Self;
+    fn overflowing_add(self, other: Self) -> (Self, bool);
+    fn overflowing_sub(self, other: Self) -> (Self, bool);
+    fn overflowing_mul(self, other: Self) -> (Self, bool);
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+    fn wrapping_div(self, other: Self) -> Self;
+    fn wrapping_rem(self, other: Self) -> Self;
+    /// Compares the words as unsigned numbers.
+    fn unsigned_less(self, other: Self) -> bool;
+    /// Shifts in zeros. `shift` must be less than `BITS`.
+    fn logical_shift_right(self, shift: u32) -> Self;
+    /// Shifts in copies of the sign bit. `shift` must be less than `BITS`.
+    fn arithmetic_shift_right(self, shift: u32) -> Self;
+    fn wrapping_shl(self, shift: u32) -> Self;
+    fn rotate_left(self, count: u32) -> Self;
+    fn rotate_right(self, count: u32) -> Self;
+    fn count_ones(self) -> u32;
+    fn leading_zeros(self) -> u32;
+    fn trailing_zeros(self) -> u32;
+    fn swap_bytes(self) -> Self;
+}
+
+macro_rules! words {
+    ($($signed:ty, $unsigned:ty;)*) => {
+        $(
+            impl Word for $signed {
+                const BITS: u32 = <$signed>::BITS;
+                const ZERO: $signed = 0;
+                const ONE: $signed = 1;
+
+                fn from_i64(value: i64) -> $signed {
+                    value as $signed
+                }
+
+                fn from_f64(value: f64) -> $signed {
+                    value as $signed
+                }
+
+                fn overflowing_add(self, other: $signed) -> ($signed, bool) {
+                    <$signed>::overflowing_add(self, other)
+                }
+
+                fn overflowing_sub(self, other: $signed) -> ($signed, bool) {
+                    <$signed>::overflowing_sub(self, other)
+                }
+
+                fn overflowing_mul(self, other: $signed) -> ($signed, bool) {
+                    <$signed>::overflowing_mul(self, other)
+                }
+
+                fn wrapping_add(self, other: $signed) -> $signed {
+                    <$signed>::wrapping_add(self, other)
+                }
+
+                fn wrapping_sub(self, other: $signed) -> $signed {
+                    <$signed>::wrapping_sub(self, other)
+                }
+
+                fn wrapping_mul(self, other: $signed) -> $signed {
+                    <$signed>::wrapping_mul(self, other)
+                }
+
+                fn wrapping_div(self, other: $signed) -> $signed {
+                    <$signed>::wrapping_div(self, other)
+                }
+
+                fn wrapping_rem(self, other: $signed) -> $signed {
+                    <$signed>::wrapping_rem(self, other)
+                }
+
+                fn unsigned_less(self, other: $signed) -> bool {
+                    (self as $unsigned) < (other as $unsigned)
+                }
+
+                fn logical_shift_right(self, shift: u32) -> $signed {
+                    ((self as $unsigned) >> shift) as $signed
+                }
+
+                fn arithmetic_shift_right(self, shift: u32) -> $signed {
+                    self >> shift
+                }
+
+                fn wrapping_shl(self, shift: u32) -> $signed {
+                    <$signed>::wrapping_shl(self, shift)
+                }
+
+                fn rotate_left(self, count: u32) -> $signed {
+                    <$signed>::rotate_left(self, count)
+                }
+
+                fn rotate_right(self, count: u32) -> $signed {
+                    <$signed>::rotate_right(self, count)
+                }
+
+                fn count_ones(self) -> u32 {
+                    <$signed>::count_ones(self)
+                }
+
+                fn leading_zeros(self) -> u32 {
+                    <$signed>::leading_zeros(self)
+                }
+
+                fn trailing_zeros(self) -> u32 {
+                    <$signed>::trailing_zeros(self)
+                }
+
+                fn swap_bytes(self) -> $signed {
+                    <$signed>::swap_bytes(self)
+                }
+            }
+        )*
+    };
+}
+
+words! {
+    i32, u32;
+    i64, u64;
+}
+
+// Arithmetic shared by all execution engines, for either word size.
+// Overflow wraps around, and shift amounts are taken modulo the number of
+// bits in a word.
+
+/// `div`, which truncates towards zero.
+pub(crate) fn divide<W: Word>(dividend: W, divisor: W) -> Result<W, ExecutionErrorKind> {
+    if divisor == W::ZERO {
+        return Err(ExecutionErrorKind::DivisionByZero);
+    }
+    Ok(dividend.wrapping_div(divisor))
+}
+
+/// `mod`, which has the sign of the dividend.
+pub(crate) fn remainder<W: Word>(dividend: W, divisor: W) -> Result<W, ExecutionErrorKind> {
+    if divisor == W::ZERO {
+        return Err(ExecutionErrorKind::DivisionByZero);
+    }
+    Ok(dividend.wrapping_rem(divisor))
+}
+
+/// The flags for `result`, given whether it carried and overflowed.
+pub(crate) fn flags<W: Word>(result: W, carry: bool, overflow: bool) -> i32 {
+    let mut flags = 0;
+    if result == W::ZERO {
+        flags |= FLAG_ZERO;
+    } else if (result < W::ZERO) == overflow {
+        flags |= FLAG_GREATER;
+    }
+    if carry {
+        flags |= FLAG_CARRY;
+    }
+    if result < W::ZERO {
+        flags |= FLAG_SIGN;
+    }
+    if overflow {
+        flags |= FLAG_OVERFLOW;
+    }
+    flags
+}
+
+// The operations that set the flags return the result along with them.
+
+pub(crate) fn add<W: Word>(value1: W, value2: W) -> (W, i32) {
+    let (result, overflow) = value1.overflowing_add(value2);
+    let carry = result.unsigned_less(value1);
+    (result, flags(result, carry, overflow))
+}
+
+pub(crate) fn subtract<W: Word>(value1: W, value2: W) -> (W, i32) {
+    let (result, overflow) = value1.overflowing_sub(value2);
+    let carry = value1.unsigned_less(value2);
+    (result, flags(result, carry, overflow))
+}
+
+/// Sets both the carry and overflow flags if the result overflowed, like
+/// the two operand `imul` of x86.
+pub(crate) fn multiply<W: Word>(value1: W, value2: W) -> (W, i32) {
+    let (result, overflow) = value1.overflowing_mul(value2);
+    (result, flags(result, overflow, overflow))
+}
+
+/// Leaves the carry flag of `old_flags` as it was, like `inc` on x86.
+pub(crate) fn increment<W: Word>(value: W, old_flags: i32) -> (W, i32) {
+    let (result, flags) = add(value, W::ONE);
+    (result, flags & !FLAG_CARRY | old_flags & FLAG_CARRY)
+}
+
+/// Leaves the carry flag of `old_flags` as it was, like `dec` on x86.
+pub(crate) fn decrement<W: Word>(value: W, old_flags: i32) -> (W, i32) {
+    let (result, flags) = subtract(value, W::ONE);
+    (result, flags & !FLAG_CARRY | old_flags & FLAG_CARRY)
+}
+
+/// `and`, `or` and `xor`, which clear the carry and overflow flags.
+pub(crate) fn logic<W: Word>(result: W) -> (W, i32) {
+    (result, flags(result, false, false))
+}
+
+/// A shift or rotate count, modulo the number of bits in a word.
+fn count<W: Word>(count: W) -> u32 {
+    (count.into() as u32) % W::BITS
+}
+
+/// Sets the carry flag to the last bit shifted out, and clears the
+/// overflow flag.
+pub(crate) fn shift_left<W: Word>(value: W, shift: W) -> (W, i32) {
+    let shift = count(shift);
+    let result = value.wrapping_shl(shift);
+    let carry = shift != 0 && value.logical_shift_right(W::BITS - shift) & W::ONE != W::ZERO;
+    (result, flags(result, carry, false))
+}
+
+/// `shr`, which shifts in zeros. Sets the carry flag to the last bit
+/// shifted out, and clears the overflow flag.
+pub(crate) fn shift_right<W: Word>(value: W, shift: W) -> (W, i32) {
+    let shift = count(shift);
+    let result = value.logical_shift_right(shift);
+    let carry = shift != 0 && value.arithmetic_shift_right(shift - 1) & W::ONE != W::ZERO;
+    (result, flags(result, carry, false))
+}
+
+/// `sar`, which shifts in copies of the sign bit. Sets the carry flag to
+/// the last bit shifted out, and clears the overflow flag.
+pub(crate) fn shift_arithmetic<W: Word>(value: W, shift: W) -> (W, i32) {
+    let shift = count(shift);
+    let result = value.arithmetic_shift_right(shift);
+    let carry = shift != 0 && value.arithmetic_shift_right(shift - 1) & W::ONE != W::ZERO;
+    (result, flags(result, carry, false))
+}
+
+/// Sets the carry flag to the last bit rotated around, which is the lowest
+/// bit of the result, unless the count is a multiple of the number of bits
+/// in a word. Clears the overflow flag.
+pub(crate) fn rotate_left<W: Word>(value: W, count: W) -> (W, i32) {
+    let count = self::count(count);
+    let result = value.rotate_left(count);
+    (
+        result,
+        flags(result, count != 0 && result & W::ONE != W::ZERO, false),
+    )
+}
+
+/// Sets the carry flag to the last bit rotated around, which is the sign
+/// bit of the result, unless the count is a multiple of the number of bits
+/// in a word. Clears the overflow flag.
+pub(crate) fn rotate_right<W: Word>(value: W, count: W) -> (W, i32) {
+    let count = self::count(count);
+    let result = value.rotate_right(count);
+    (result, flags(result, count != 0 && result < W::ZERO, false))
+}
+
+/// `neg`, with the flags of subtracting the value from zero.
+pub(crate) fn negate<W: Word>(value: W) -> (W, i32) {
+    subtract(W::ZERO, value)
+}
+
+/// The flags set by `fcmp`, which are those `comisd` sets on x86: the zero
+/// flag if the values are equal, the carry flag if the first is less, and
+/// all of zero, carry and overflow if either is NaN. The greater flag is set
+/// if the first is greater, so that the signed jumps work too.
+pub(crate) fn compare_floats(value1: f64, value2: f64) -> i32 {
+    if value1 == value2 {
+        FLAG_ZERO
+    } else if value1 > value2 {
+        FLAG_GREATER
+    } else if value1 < value2 {
+        FLAG_CARRY
+    } else {
+        FLAG_ZERO | FLAG_CARRY | FLAG_OVERFLOW
+    }
+}
+
+/// The words a float is stored as in memory, the low half of its bits
+/// first. Each word holds 32 bits, sign extended, whatever the word size.
+pub(crate) fn float_words(value: f64) -> (i64, i64) {
+    let bits = value.to_bits();
+    ((bits as i32).into(), ((bits >> 32) as i32).into())
+}
+
+pub(crate) fn float_from_words(low: i64, high: i64) -> f64 {
+    f64::from_bits(u64::from(low as u32) | u64::from(high as u32) << 32)
+}
+
+/// The flags set by `cmp`, which are those of subtracting the second value
+/// from the first.
+pub(crate) fn compare<W: Word>(value1: W, value2: W) -> i32 {
+    subtract(value1, value2).1
+}
+
 impl Memory {
     pub fn new(size: usize, stack_size: usize) -> Memory {
         let mut memory = Memory {
@@ -276,11 +1485,127 @@ impl Memory {
             remainder: 0,
             mem_space: vec![0; size],
             registers: [0; NUM_REGISTERS],
+            float_registers: [0.0; NUM_FLOAT_REGISTERS],
+            vector_registers: [[0; NUM_LANES]; NUM_VECTOR_REGISTERS],
+            output: Box::new(std::io::stdout()),
+            call_stack: vec![],
+            strict_returns: false,
+            cores: None,
+            channels: None,
+        };
+
+        memory.registers[Register::Esp as usize] = stack_size as i64;
+        memory.registers[Register::Ebp as usize] = stack_size as i64;
+
+        memory
+    }
+
+    /// The indices of the words holding the `len` bytes from byte address
+    /// `address`, in a program with words of `word_size`, or the first byte
+    /// address out of range.
+    pub fn words_of_bytes(
+        self: &Memory,
+        word_size: WordSize,
+        address: i64,
+        len: usize,
+    ) -> Result<RangeInclusive<usize>, ExecutionErrorKind> {
+        let word_bytes = word_size.bytes();
+        let end = self.mem_space.len() * word_bytes;
+        let start = match usize::try_from(address) {
+            Ok(start) if start < end => start,
+            _ => return Err(ExecutionErrorKind::DataAddressOutOfRange(address)),
         };
+        if len > end - start {
+            return Err(ExecutionErrorKind::DataAddressOutOfRange(end as i64));
+        }
+        Ok(start / word_bytes..=(start + len.max(1) - 1) / word_bytes)
+    }
 
-        memory.registers[Register::Esp as usize] = stack_size as i32;
-        memory.registers[Register::Ebp as usize] = stack_size as i32;
+    /// Copies the bytes from byte address `address` into `buffer`. Memory
+    /// is addressed in bytes by splitting each word of `word_size` into its
+    /// bytes, least significant first.
+    pub fn read_bytes(
+        self: &Memory,
+        word_size: WordSize,
+        address: i64,
+        buffer: &mut [u8],
+    ) -> Result<(), ExecutionErrorKind> {
+        self.words_of_bytes(word_size, address, buffer.len())?;
+        let word_bytes = word_size.bytes();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            let address = address as usize + i;
+            let shift = address % word_bytes * 8;
+            *byte = (self.mem_space[address / word_bytes] >> shift) as u8;
+        }
+        Ok(())
+    }
 
+    /// Copies `bytes` to byte address `address`, addressed as by
+    /// `read_bytes`. Nothing is written unless all of them are in range.
+    pub fn write_bytes(
+        self: &mut Memory,
+        word_size: WordSize,
+        address: i64,
+        bytes: &[u8],
+    ) -> Result<(), ExecutionErrorKind> {
+        self.words_of_bytes(word_size, address, bytes.len())?;
+        let word_bytes = word_size.bytes();
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = address as usize + i;
+            let shift = address % word_bytes * 8;
+            let word = &mut self.mem_space[address / word_bytes];
+            let value = *word & !(0xff << shift) | i64::from(*byte) << shift;
+            // Keep the words of 32-bit programs sign extended
+            *word = match word_size {
+                WordSize::Bits32 => i64::from(value as i32),
+                WordSize::Bits64 => value,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_are_least_significant_first() {
+        let mut memory = Memory::new(4, 0);
+        memory.mem_space[1] = 0x1234_5678;
+        memory.mem_space[2] = 0x0123_4567_89ab_cdef;
+
+        let mut bytes = [0; 3];
+        memory.read_bytes(WordSize::Bits32, 3, &mut bytes).unwrap();
+        assert_eq!(bytes, [0, 0x78, 0x56]);
+        memory.read_bytes(WordSize::Bits64, 21, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x45, 0x23, 0x01]);
+    }
+
+    #[test]
+    fn written_words_stay_sign_extended() {
+        let mut memory = Memory::new(4, 0);
         memory
+            .write_bytes(WordSize::Bits32, 6, &[0xff, 0x80])
+            .unwrap();
+        assert_eq!(memory.mem_space[1], i64::from(0x80ff_0000u32 as i32));
+
+        memory.write_bytes(WordSize::Bits64, 7, &[0x80]).unwrap();
+        assert_eq!(memory.mem_space[0], i64::MIN);
+    }
+
+    #[test]
+    fn out_of_range_bytes_are_not_accessed() {
+        let mut memory = Memory::new(2, 0);
+        assert_eq!(
+            memory.write_bytes(WordSize::Bits32, 7, &[1, 2]),
+            Err(ExecutionErrorKind::DataAddressOutOfRange(8))
+        );
+        assert_eq!(
+            memory.read_bytes(WordSize::Bits32, -1, &mut [0]),
+            Err(ExecutionErrorKind::DataAddressOutOfRange(-1))
+        );
+        assert_eq!(memory.mem_space, [0, 0]);
+        assert_eq!(memory.words_of_bytes(WordSize::Bits64, 6, 4), Ok(0..=1));
     }
 }
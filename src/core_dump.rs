@@ -0,0 +1,328 @@
+//! Core dumps, written when a program fails, for post-mortem inspection.
+//!
+//! A core file is a little-endian binary file laid out as follows:
+//!
+//! | Field             | Type                                  |
+//! |-------------------|---------------------------------------|
+//! | magic             | `b"TVMCORE\0"`                        |
+//! | version           | u32 (currently 1)                     |
+//! | error             | string                                |
+//! | instruction index | i64                                   |
+//! | stack top         | i32                                   |
+//! | word size         | u32 bits                              |
+//! | start index       | i32                                   |
+//! | instruction count | u32                                   |
+//! | instructions      | see below                             |
+//! | label count       | u32                                   |
+//! | labels            | string and i32 instruction index each |
+//! | native count      | u32                                   |
+//! | natives           | string each                           |
+//! | state             | as in a snapshot                      |
+//!
+//! Strings are a u32 byte length followed by UTF-8. Each instruction is its
+//! assembly text, a u8 that is 1 if a file name string follows, and a u32
+//! line number. The natives are the names of the native functions the
+//! program was loaded with, in order, which the program read back from the
+//! core file calls by name. They fail when run, as the functions themselves
+//! are not part of the core file. The state holds the registers, flags, remainder, shadow call
+//! stack, float and vector registers and the non-zero memory, which
+//! includes the used part of the stack between `esp` and the stack top.
+//!
+//! Since the core file contains the program, it can be inspected without the
+//! source it was built from.
+
+use crate::{
+    backtrace::Backtrace,
+    context::{ExecutionError, ExecutionErrorKind, Memory, Program, WordSize, STACK_SIZE},
+    native::Natives,
+    preprocessor::SourceLocation,
+    snapshot::{read_i32, read_i64, read_state, read_u32, write_state, SnapshotError},
+};
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+const MAGIC: &[u8; 8] = b"TVMCORE\0";
+const VERSION: u32 = 1;
+
+pub struct CoreDump {
+    /// A description of the error that caused the dump.
+    pub error: String,
+    pub instruction_index: i64,
+    /// The initial stack pointer, so the stack is the memory from `esp` up
+    /// to here.
+    pub stack_top: i32,
+    pub program: Program,
+    pub memory: Memory,
+}
+
+impl CoreDump {
+    /// Writes a core file for `error`, which occurred when running `program`
+    /// with `memory`.
+    pub fn write<W: Write>(
+        program: &Program,
+        memory: &Memory,
+        error: &ExecutionError,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        write_string(&error.kind.to_string(), writer)?;
+        writer.write_all(&error.instruction_index.to_le_bytes())?;
+        writer.write_all(&(STACK_SIZE as i32).to_le_bytes())?;
+        writer.write_all(&program.word_size.bits().to_le_bytes())?;
+
+        writer.write_all(&program.start_instruction_index.to_le_bytes())?;
+        writer.write_all(&(program.instructions.len() as u32).to_le_bytes())?;
+        for (i, instruction) in program.instructions.iter().enumerate() {
+            write_string(&instruction.to_string(), writer)?;
+            match program.locations.get(i) {
+                Some(location) => {
+                    match &location.file {
+                        Some(file) => {
+                            writer.write_all(&[1])?;
+                            write_string(file, writer)?;
+                        }
+                        None => writer.write_all(&[0])?,
+                    }
+                    writer.write_all(&(location.line as u32).to_le_bytes())?;
+                }
+                None => {
+                    writer.write_all(&[0])?;
+                    writer.write_all(&0u32.to_le_bytes())?;
+                }
+            }
+        }
+
+        writer.write_all(&(program.labels.len() as u32).to_le_bytes())?;
+        for (label, index) in &program.labels {
+            write_string(label, writer)?;
+            writer.write_all(&index.to_le_bytes())?;
+        }
+
+        let names: Vec<_> = program.natives.names().collect();
+        writer.write_all(&(names.len() as u32).to_le_bytes())?;
+        for name in names {
+            write_string(name, writer)?;
+        }
+
+        write_state(memory, writer)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<CoreDump, SnapshotError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::InvalidFormat);
+        }
+
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let error = read_string(reader)?;
+        let instruction_index = read_i64(reader)?;
+        let stack_top = read_i32(reader)?;
+        let word_size = match read_u32(reader)? {
+            32 => WordSize::Bits32,
+            64 => WordSize::Bits64,
+            _ => return Err(SnapshotError::InvalidFormat),
+        };
+
+        let start_instruction_index = read_i32(reader)?;
+        let mut source = match word_size {
+            WordSize::Bits32 => String::new(),
+            WordSize::Bits64 => "%bits 64\n".to_owned(),
+        };
+        let mut locations = vec![];
+        for _ in 0..read_u32(reader)? {
+            source += &read_string(reader)?;
+            source.push('\n');
+
+            let mut has_file = [0];
+            reader.read_exact(&mut has_file)?;
+            let file = match has_file[0] {
+                0 => None,
+                _ => Some(Arc::from(read_string(reader)?)),
+            };
+            let line = read_u32(reader)? as usize;
+            locations.push(SourceLocation { file, line });
+        }
+
+        let mut labels = vec![];
+        for _ in 0..read_u32(reader)? {
+            labels.push((read_string(reader)?, read_i32(reader)?));
+        }
+
+        let mut natives = Natives::new();
+        for _ in 0..read_u32(reader)? {
+            natives.register(&read_string(reader)?, unavailable);
+        }
+
+        // The instructions are stored as assembly with labels resolved, so
+        // they parse back to the same instructions
+        let mut program = Program::load_with_natives(source, None, &natives)
+            .map_err(|_| SnapshotError::InvalidFormat)?;
+        if program.instructions.len() != locations.len() {
+            return Err(SnapshotError::InvalidFormat);
+        }
+        program.start_instruction_index = start_instruction_index;
+        program.locations = locations;
+        program.labels = labels;
+
+        let memory = read_state(reader)?;
+
+        Ok(CoreDump {
+            error,
+            instruction_index,
+            stack_top,
+            program,
+            memory,
+        })
+    }
+
+    pub fn backtrace(self: &CoreDump) -> Backtrace {
+        self.program.backtrace(&self.memory)
+    }
+}
+
+/// Stands in for the native functions of a program read from a core file.
+fn unavailable(_: &mut Memory) -> Result<(), ExecutionErrorKind> {
+    Err(ExecutionErrorKind::Native(Box::new(
+        "native functions are not part of core files".to_owned(),
+    )))
+}
+
+fn write_string<W: Write>(s: &str, writer: &mut W) -> io::Result<()> {
+    writer.write_all(&(s.len() as u32).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, SnapshotError> {
+    let length = read_u32(reader)? as usize;
+    let mut bytes = vec![];
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(SnapshotError::InvalidFormat);
+    }
+
+    String::from_utf8(bytes).map_err(|_| SnapshotError::InvalidFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Register;
+
+    fn crash(source: &str) -> (Program, Memory, ExecutionError) {
+        let program = Program::load_with_file_name(source.to_owned(), Some("test.vm")).unwrap();
+        let mut memory = program.initialize();
+        loop {
+            if let Err(e) = program.step(&mut memory) {
+                return (program, memory, e);
+            }
+        }
+    }
+
+    fn dump(source: &str) -> CoreDump {
+        let (program, memory, error) = crash(source);
+        let mut data = vec![];
+        CoreDump::write(&program, &memory, &error, &mut data).unwrap();
+        CoreDump::read(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn contains_the_program_and_state() {
+        let source = "jmp start\nf: push -3\nmov [7], 0x10\nfld f3, -1e-7\nfst [8], f3\n\
+                      vins v1, 2, 9\nmov eax, [100000000]\nstart: call f";
+        let (program, memory, _) = crash(source);
+        let core = dump(source);
+
+        assert_eq!(core.error, "data address 100000000 is out of range");
+        assert_eq!(core.instruction_index, 6);
+        assert_eq!(core.stack_top, STACK_SIZE as i32);
+        assert_eq!(core.program.instructions, program.instructions);
+        assert_eq!(core.program.start_instruction_index, 7);
+        assert_eq!(core.program.locations, program.locations);
+        assert_eq!(core.program.labels, program.labels);
+        assert_eq!(core.memory.registers, memory.registers);
+        assert_eq!(core.memory.float_registers[3], -1e-7);
+        assert_eq!(core.memory.vector_registers[1], [0, 0, 9, 0]);
+        assert_eq!(core.memory.call_stack, memory.call_stack);
+        assert_eq!(core.memory.mem_space[7], 0x10);
+
+        let esp = core.memory.registers[Register::Esp as usize] as usize;
+        assert_eq!(core.memory.mem_space[esp], -3);
+    }
+
+    #[test]
+    fn keeps_the_word_size() {
+        let core = dump("%bits 64\nmov eax, 0x123456789\nmov [5], eax\nmov ebx, [100000000]");
+
+        assert_eq!(core.program.word_size, WordSize::Bits64);
+        assert_eq!(core.memory.registers[Register::Eax as usize], 0x123456789);
+        assert_eq!(core.memory.mem_space[5], 0x123456789);
+        assert_eq!(core.program.locations.len(), 3);
+    }
+
+    #[test]
+    fn backtrace_is_symbolic() {
+        let core = dump("call f\nf: call g\ng: mov eax, [100000000]");
+
+        assert_eq!(
+            core.backtrace().to_string(),
+            "#0 instruction 2 in g (test.vm:3)\n\
+             #1 instruction 1 in f (test.vm:2)\n\
+             #2 instruction 0 (test.vm:1)\n"
+        );
+    }
+
+    #[test]
+    fn keeps_calls_of_native_functions() {
+        fn answer(memory: &mut Memory) -> Result<(), ExecutionErrorKind> {
+            memory.registers[Register::Eax as usize] = 42;
+            Ok(())
+        }
+        let mut natives = Natives::new();
+        natives.register("unused", answer);
+        natives.register("answer", answer);
+
+        let source = "call answer\nmov [3], eax\nmov ebx, [100000000]";
+        let program = Program::load_with_natives(source.to_owned(), None, &natives).unwrap();
+        let mut memory = program.initialize();
+        let error = loop {
+            if let Err(e) = program.step(&mut memory) {
+                break e;
+            }
+        };
+        let mut data = vec![];
+        CoreDump::write(&program, &memory, &error, &mut data).unwrap();
+        let core = CoreDump::read(&mut &data[..]).unwrap();
+
+        assert_eq!(core.program.instructions, program.instructions);
+        assert_eq!(core.program.instructions[0].to_string(), "call answer");
+        assert_eq!(core.memory.mem_space[3], 42);
+
+        // The function itself is not in the core file
+        let mut memory = core.program.initialize();
+        let error = core.program.step(&mut memory).unwrap_err();
+        assert_eq!(error.instruction_index, 0);
+        assert!(matches!(error.kind, ExecutionErrorKind::Native(_)));
+    }
+
+    #[test]
+    fn rejects_snapshots() {
+        let program = Program::load("nop".to_owned()).unwrap();
+        let mut data = vec![];
+        program
+            .save_snapshot(&program.initialize(), &mut data)
+            .unwrap();
+
+        assert!(matches!(
+            CoreDump::read(&mut &data[..]),
+            Err(SnapshotError::InvalidFormat)
+        ));
+    }
+}
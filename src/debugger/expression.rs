@@ -0,0 +1,393 @@
+//! Expressions over registers, memory and flags, used as breakpoint
+//! conditions, e.g. `eax == 5 && [100] > 0`.
+//!
+//! The syntax and precedence of operators follow C. Values use the same
+//! syntax as in assembly source, `[expr]` reads the word at address `expr`,
+//! and `flags` and `remainder` name the corresponding parts of the VM state.
+//! All arithmetic wraps.
+
+use crate::{
+    context::Memory,
+    instruction::Register,
+    parser::{parse_register, parse_value},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+    BitwiseNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    BitwiseAnd,
+    BitwiseXor,
+    BitwiseOr,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Value(i64),
+    Register(Register),
+    Flags,
+    Remainder,
+    Memory(Box<Expression>),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    InvalidOperand(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EvaluationError {
+    DataAddressOutOfRange(i64),
+    DivisionByZero,
+}
+
+/// Binary operators by precedence level, loosest binding first.
+const BINARY_OPERATORS: &[&[(&str, BinaryOperator)]] = &[
+    &[("||", BinaryOperator::Or)],
+    &[("&&", BinaryOperator::And)],
+    &[("|", BinaryOperator::BitwiseOr)],
+    &[("^", BinaryOperator::BitwiseXor)],
+    &[("&", BinaryOperator::BitwiseAnd)],
+    &[
+        ("==", BinaryOperator::Equal),
+        ("!=", BinaryOperator::NotEqual),
+    ],
+    &[
+        ("<", BinaryOperator::Less),
+        ("<=", BinaryOperator::LessEqual),
+        (">", BinaryOperator::Greater),
+        (">=", BinaryOperator::GreaterEqual),
+    ],
+    &[("<<", BinaryOperator::Shl), (">>", BinaryOperator::Shr)],
+    &[("+", BinaryOperator::Add), ("-", BinaryOperator::Sub)],
+    &[
+        ("*", BinaryOperator::Mul),
+        ("/", BinaryOperator::Div),
+        ("%", BinaryOperator::Mod),
+    ],
+];
+
+const TWO_CHARACTER_OPERATORS: &[&str] = &["||", "&&", "==", "!=", "<=", ">=", "<<", ">>"];
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '@'
+}
+
+/// The length of the register name or value at the start of `s`. A `|` is
+/// only part of the word as a base specifier, like in `12|h`.
+fn word_length(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let is_word_byte = |i: usize| bytes.get(i).is_some_and(|b| is_word_char(*b as char));
+
+    let mut length = 0;
+    while length < bytes.len() {
+        if is_word_byte(length) {
+            length += 1;
+        } else if bytes[length] == b'|'
+            && matches!(bytes.get(length + 1), Some(b'h') | Some(b'b'))
+            && !is_word_byte(length + 2)
+        {
+            length += 2;
+        } else {
+            break;
+        }
+    }
+
+    length
+}
+
+fn tokenize(source: &str) -> Result<Vec<&str>, ExpressionError> {
+    let mut tokens = vec![];
+    let mut rest = source.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let length = if is_word_char(c) {
+            word_length(rest)
+        } else if TWO_CHARACTER_OPERATORS
+            .iter()
+            .any(|op| rest.starts_with(op))
+        {
+            2
+        } else if "+-*/%<>=!~&|^()[]".contains(c) {
+            1
+        } else {
+            return Err(ExpressionError::UnexpectedToken(c.to_string()));
+        };
+
+        tokens.push(&rest[..length]);
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct ExpressionParser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn peek(self: &ExpressionParser<'a>) -> Option<&'a str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(self: &mut ExpressionParser<'a>) -> Result<&'a str, ExpressionError> {
+        let token = self.peek().ok_or(ExpressionError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(self: &mut ExpressionParser<'a>, expected: &str) -> Result<(), ExpressionError> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(ExpressionError::UnexpectedToken(token.to_owned())),
+        }
+    }
+
+    fn binary(
+        self: &mut ExpressionParser<'a>,
+        level: usize,
+    ) -> Result<Expression, ExpressionError> {
+        if level == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(token) = self.peek() {
+            let operator = match BINARY_OPERATORS[level].iter().find(|(s, _)| *s == token) {
+                Some((_, operator)) => *operator,
+                None => break,
+            };
+
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(self: &mut ExpressionParser<'a>) -> Result<Expression, ExpressionError> {
+        let operator = match self.peek() {
+            Some("-") => UnaryOperator::Negate,
+            Some("!") => UnaryOperator::Not,
+            Some("~") => UnaryOperator::BitwiseNot,
+            _ => return self.primary(),
+        };
+
+        self.position += 1;
+        Ok(Expression::Unary(operator, Box::new(self.unary()?)))
+    }
+
+    fn primary(self: &mut ExpressionParser<'a>) -> Result<Expression, ExpressionError> {
+        let token = self.next()?;
+        match token {
+            "(" => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            "[" => {
+                let address = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expression::Memory(Box::new(address)))
+            }
+            "flags" => Ok(Expression::Flags),
+            "remainder" => Ok(Expression::Remainder),
+            _ => {
+                if let Some(reg) = parse_register(token) {
+                    Ok(Expression::Register(reg))
+                } else if let Ok(value) = parse_value(token) {
+                    Ok(Expression::Value(value))
+                } else if token.starts_with(is_word_char) {
+                    Err(ExpressionError::InvalidOperand(token.to_owned()))
+                } else {
+                    Err(ExpressionError::UnexpectedToken(token.to_owned()))
+                }
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        let mut parser = ExpressionParser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+
+        let expression = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(ExpressionError::UnexpectedToken(token.to_owned())),
+        }
+    }
+
+    pub fn evaluate(self: &Expression, memory: &Memory) -> Result<i64, EvaluationError> {
+        let result = match self {
+            Expression::Value(value) => *value,
+            Expression::Register(reg) => memory.registers[*reg as usize],
+            Expression::Flags => memory.flags.into(),
+            Expression::Remainder => memory.remainder,
+            Expression::Memory(address) => {
+                let address = address.evaluate(memory)?;
+                if address < 0 || address as usize >= memory.mem_space.len() {
+                    return Err(EvaluationError::DataAddressOutOfRange(address));
+                }
+                memory.mem_space[address as usize]
+            }
+            Expression::Unary(operator, operand) => {
+                let value = operand.evaluate(memory)?;
+                match operator {
+                    UnaryOperator::Negate => value.wrapping_neg(),
+                    UnaryOperator::Not => (value == 0) as i64,
+                    UnaryOperator::BitwiseNot => !value,
+                }
+            }
+            Expression::Binary(BinaryOperator::And, left, right) => {
+                (left.evaluate(memory)? != 0 && right.evaluate(memory)? != 0) as i64
+            }
+            Expression::Binary(BinaryOperator::Or, left, right) => {
+                (left.evaluate(memory)? != 0 || right.evaluate(memory)? != 0) as i64
+            }
+            Expression::Binary(operator, left, right) => {
+                let left = left.evaluate(memory)?;
+                let right = right.evaluate(memory)?;
+                match operator {
+                    BinaryOperator::Mul => left.wrapping_mul(right),
+                    BinaryOperator::Div | BinaryOperator::Mod if right == 0 => {
+                        return Err(EvaluationError::DivisionByZero)
+                    }
+                    BinaryOperator::Div => left.wrapping_div(right),
+                    BinaryOperator::Mod => left.wrapping_rem(right),
+                    BinaryOperator::Add => left.wrapping_add(right),
+                    BinaryOperator::Sub => left.wrapping_sub(right),
+                    BinaryOperator::Shl => left.wrapping_shl(right as u32),
+                    BinaryOperator::Shr => left.wrapping_shr(right as u32),
+                    BinaryOperator::Less => (left < right) as i64,
+                    BinaryOperator::LessEqual => (left <= right) as i64,
+                    BinaryOperator::Greater => (left > right) as i64,
+                    BinaryOperator::GreaterEqual => (left >= right) as i64,
+                    BinaryOperator::Equal => (left == right) as i64,
+                    BinaryOperator::NotEqual => (left != right) as i64,
+                    BinaryOperator::BitwiseAnd => left & right,
+                    BinaryOperator::BitwiseXor => left ^ right,
+                    BinaryOperator::BitwiseOr => left | right,
+                    BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+                }
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str, memory: &Memory) -> Result<i64, EvaluationError> {
+        Expression::parse(source).unwrap().evaluate(memory)
+    }
+
+    #[test]
+    fn operators_have_c_precedence() {
+        let memory = Memory::new(16, 16);
+
+        assert_eq!(evaluate("1 + 2 * 3", &memory), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3", &memory), Ok(9));
+        assert_eq!(evaluate("10 - 4 - 3", &memory), Ok(3));
+        assert_eq!(evaluate("1 << 2 + 1", &memory), Ok(8));
+        assert_eq!(evaluate("1 | 2 == 2", &memory), Ok(1));
+        assert_eq!(evaluate("6 & 3 ^ 1", &memory), Ok(3));
+        assert_eq!(evaluate("1 < 2 == 1", &memory), Ok(1));
+        assert_eq!(evaluate("0 || 2 && 3", &memory), Ok(1));
+        assert_eq!(evaluate("-2 * -3", &memory), Ok(6));
+        assert_eq!(evaluate("!0 + ~0", &memory), Ok(0));
+        assert_eq!(evaluate("0x10 + 10|h + 11b", &memory), Ok(35));
+        assert_eq!(evaluate("1|2", &memory), Ok(3));
+        assert_eq!(evaluate("0||1", &memory), Ok(1));
+    }
+
+    #[test]
+    fn can_read_registers_memory_and_flags() {
+        let mut memory = Memory::new(16, 16);
+        memory.registers[Register::Eax as usize] = 5;
+        memory.registers[Register::Ebx as usize] = 3;
+        memory.mem_space[3] = 100;
+        memory.flags = 2;
+        memory.remainder = 4;
+
+        assert_eq!(evaluate("eax == 5 && [3] > 0", &memory), Ok(1));
+        assert_eq!(evaluate("[ebx] + [ebx - 3]", &memory), Ok(100));
+        assert_eq!(evaluate("flags & 2", &memory), Ok(2));
+        assert_eq!(evaluate("remainder", &memory), Ok(4));
+    }
+
+    #[test]
+    fn evaluation_errors_are_reported() {
+        let memory = Memory::new(16, 16);
+
+        assert_eq!(
+            evaluate("[16]", &memory),
+            Err(EvaluationError::DataAddressOutOfRange(16))
+        );
+        assert_eq!(
+            evaluate("1 / 0", &memory),
+            Err(EvaluationError::DivisionByZero)
+        );
+        assert_eq!(evaluate("0 && 1 / 0", &memory), Ok(0));
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        assert_eq!(
+            Expression::parse("eax =="),
+            Err(ExpressionError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Expression::parse("eax ebx"),
+            Err(ExpressionError::UnexpectedToken("ebx".to_owned()))
+        );
+        assert_eq!(
+            Expression::parse("[eax"),
+            Err(ExpressionError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Expression::parse("foo + 1"),
+            Err(ExpressionError::InvalidOperand("foo".to_owned()))
+        );
+        assert_eq!(
+            Expression::parse("eax = 1"),
+            Err(ExpressionError::UnexpectedToken("=".to_owned()))
+        );
+        assert_eq!(
+            Expression::parse("eax ; 1"),
+            Err(ExpressionError::UnexpectedToken(";".to_owned()))
+        );
+    }
+}
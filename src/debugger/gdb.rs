@@ -0,0 +1,907 @@
+//! A stub for the GDB remote serial protocol, so that `gdb` or `lldb` can
+//! attach to a program running in a `Debugger`.
+//!
+//! Registers are numbered as in `Register`, followed by the flags, the
+//! remainder and the vector registers. The flags are 32 bits wide, the
+//! vector registers 128 bits of four 32-bit lanes, and the other registers
+//! as wide as a word of the program. Memory is presented to the debugger as
+//! little-endian bytes, so the word at VM address `n` is at byte address
+//! `4 * n`, or `8 * n` for 64-bit programs. The program counter (`eip`) and
+//! breakpoint addresses are instruction indices.
+
+use super::{Debugger, StopReason, WatchKind, Watchpoint};
+use crate::{
+    context::WordSize,
+    instruction::{Register, VectorRegister, NUM_LANES, NUM_REGISTERS, NUM_VECTOR_REGISTERS},
+    parser::parse_value,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, stdin, stdout, Read, Stdin, Stdout, Write},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+const FLAGS_REGISTER: usize = NUM_REGISTERS;
+const REMAINDER_REGISTER: usize = NUM_REGISTERS + 1;
+const FIRST_VECTOR_REGISTER: usize = NUM_REGISTERS + 2;
+const NUM_GDB_REGISTERS: usize = FIRST_VECTOR_REGISTER + NUM_VECTOR_REGISTERS;
+
+/// The largest packet the stub accepts, as it tells GDB in hex.
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Serves a single debugger session over `connection` until the debugger
+/// detaches, kills the program or closes the connection.
+pub fn serve<C: Read + Write>(debugger: &mut Debugger, connection: C) -> io::Result<()> {
+    GdbStub::new(debugger, connection).run()
+}
+
+/// Waits for a debugger to connect on `address` and serves it.
+pub fn listen<A: ToSocketAddrs>(debugger: &mut Debugger, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    serve(debugger, stream)
+}
+
+/// A connection over stdin and stdout, for use with `target remote | tvmi ...`.
+pub struct StdioConnection {
+    input: Stdin,
+    output: Stdout,
+}
+
+impl StdioConnection {
+    pub fn new() -> StdioConnection {
+        StdioConnection {
+            input: stdin(),
+            output: stdout(),
+        }
+    }
+}
+
+impl Default for StdioConnection {
+    fn default() -> StdioConnection {
+        StdioConnection::new()
+    }
+}
+
+impl Read for StdioConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for StdioConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+pub fn target_description(word_size: WordSize) -> String {
+    let bits = word_size.bits();
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.tinyvm.core\">\n",
+        "    <flags id=\"tinyvm_flags\" size=\"4\">\n",
+        "      <field name=\"ZF\" start=\"0\" end=\"0\"/>\n",
+        "      <field name=\"GT\" start=\"1\" end=\"1\"/>\n",
+        "      <field name=\"CF\" start=\"2\" end=\"2\"/>\n",
+        "      <field name=\"SF\" start=\"3\" end=\"3\"/>\n",
+        "      <field name=\"OF\" start=\"4\" end=\"4\"/>\n",
+        "    </flags>\n",
+    ));
+
+    for reg in Register::ALL.iter() {
+        let reg_type = match reg {
+            Register::Eip => "code_ptr",
+            Register::Esp | Register::Ebp => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            reg.name(),
+            bits,
+            reg_type,
+            *reg as usize
+        );
+    }
+
+    xml += &format!(
+        "    <reg name=\"flags\" bitsize=\"32\" type=\"tinyvm_flags\" regnum=\"{}\"/>\n",
+        FLAGS_REGISTER
+    );
+    xml += &format!(
+        "    <reg name=\"remainder\" bitsize=\"{}\" type=\"int\" regnum=\"{}\"/>\n",
+        bits, REMAINDER_REGISTER
+    );
+    xml += "  </feature>\n";
+
+    xml += &format!(
+        "  <feature name=\"org.tinyvm.vector\">\n    <vector id=\"v4i32\" type=\"int32\" count=\"{}\"/>\n",
+        NUM_LANES
+    );
+    for reg in VectorRegister::ALL.iter() {
+        xml += &format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"v4i32\" regnum=\"{}\"/>\n",
+            reg.name(),
+            32 * NUM_LANES,
+            FIRST_VECTOR_REGISTER + *reg as usize
+        );
+    }
+    xml += "  </feature>\n</target>\n";
+
+    xml
+}
+
+enum Action {
+    Continue,
+    Stop,
+}
+
+struct GdbStub<'a, 'b, C: Read + Write> {
+    debugger: &'b mut Debugger<'a>,
+    connection: C,
+    buffer: Vec<u8>,
+    position: usize,
+    no_ack: bool,
+    /// The number of bytes in a word of the program.
+    word_bytes: usize,
+}
+
+impl<'a, 'b, C: Read + Write> GdbStub<'a, 'b, C> {
+    fn new(debugger: &'b mut Debugger<'a>, connection: C) -> GdbStub<'a, 'b, C> {
+        let word_bytes = debugger.program().word_size.bits() as usize / 8;
+        GdbStub {
+            word_bytes,
+            debugger,
+            connection,
+            buffer: vec![],
+            position: 0,
+            no_ack: false,
+        }
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            if let Action::Stop = self.handle_packet(&packet)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.position == self.buffer.len() {
+            self.buffer.resize(4096, 0);
+            let count = self.connection.read(&mut self.buffer)?;
+            self.buffer.truncate(count);
+            self.position = 0;
+
+            if count == 0 {
+                return Ok(None);
+            }
+        }
+
+        self.position += 1;
+        Ok(Some(self.buffer[self.position - 1]))
+    }
+
+    /// Reads the next packet, skipping acknowledgements and interrupt
+    /// requests. Returns `None` when the connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+
+            let mut checksum = [0u8; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b) => b,
+                };
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if self.no_ack {
+                return Ok(Some(data));
+            } else if expected == Some(actual) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(data));
+            } else {
+                self.connection.write_all(b"-")?;
+                self.connection.flush()?;
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.connection, "${}#{:02x}", data, checksum)?;
+        self.connection.flush()
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> io::Result<Action> {
+        let packet = String::from_utf8_lossy(packet).into_owned();
+
+        let reply = if packet == "?" {
+            format!("S{:02x}", SIGTRAP)
+        } else if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            self.send("OK")?;
+            self.no_ack = true;
+            return Ok(Action::Continue);
+        } else if let Some(annex) = packet.strip_prefix("qXfer:features:read:") {
+            self.read_features(annex)
+        } else if packet == "qAttached" {
+            "1".to_owned()
+        } else if packet == "qC" {
+            "QC1".to_owned()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_owned()
+        } else if packet == "qsThreadInfo" {
+            "l".to_owned()
+        } else if packet.starts_with('H') {
+            "OK".to_owned()
+        } else if packet == "vCont?" {
+            "vCont;c;s".to_owned()
+        } else if packet == "g" {
+            self.read_registers()
+        } else if let Some(data) = packet.strip_prefix('G') {
+            self.write_registers(data)
+        } else if let Some(args) = packet.strip_prefix('p') {
+            self.read_register(args)
+        } else if let Some(args) = packet.strip_prefix('P') {
+            self.write_register(args)
+        } else if let Some(args) = packet.strip_prefix('m') {
+            self.read_memory(args)
+        } else if let Some(args) = packet.strip_prefix('M') {
+            self.write_memory(args)
+        } else if let Some(args) = packet.strip_prefix('Z') {
+            self.set_breakpoint(args, true)
+        } else if let Some(args) = packet.strip_prefix('z') {
+            self.set_breakpoint(args, false)
+        } else if packet == "bs" {
+            let reason = self.debugger.reverse_step();
+            stop_reply(reason, self.word_bytes)
+        } else if packet == "bc" {
+            let reason = self.debugger.reverse_cont();
+            stop_reply(reason, self.word_bytes)
+        } else if let Some(command) = packet.strip_prefix("qRcmd,") {
+            self.monitor_command(command)
+        } else if let Some(args) = packet.strip_prefix('s') {
+            self.resume(args, false)
+        } else if let Some(args) = packet.strip_prefix('c') {
+            self.resume(args, true)
+        } else if packet.starts_with("vCont;s") {
+            self.resume("", false)
+        } else if packet.starts_with("vCont;c") {
+            self.resume("", true)
+        } else if packet == "D" || packet.starts_with("D;") {
+            self.send("OK")?;
+            return Ok(Action::Stop);
+        } else if packet == "k" || packet.starts_with("vKill") {
+            return Ok(Action::Stop);
+        } else {
+            String::new()
+        };
+
+        self.send(&reply)?;
+        Ok(Action::Continue)
+    }
+
+    fn read_features(&self, annex: &str) -> String {
+        let (name, range) = match annex.find(':') {
+            Some(i) => (&annex[..i], &annex[i + 1..]),
+            None => return "E00".to_owned(),
+        };
+
+        if name != "target.xml" {
+            return "E00".to_owned();
+        }
+
+        let (offset, length) = match parse_pair(range) {
+            Some(v) => v,
+            None => return "E00".to_owned(),
+        };
+
+        let xml = target_description(self.debugger.program().word_size);
+        if offset >= xml.len() {
+            return "l".to_owned();
+        }
+
+        let end = xml.len().min(offset.saturating_add(length));
+        let marker = if end == xml.len() { 'l' } else { 'm' };
+        format!("{}{}", marker, &xml[offset..end])
+    }
+
+    /// The number of bytes in a register.
+    fn register_size(&self, number: usize) -> usize {
+        match number {
+            FLAGS_REGISTER => 4,
+            n if n >= FIRST_VECTOR_REGISTER => 4 * NUM_LANES,
+            _ => self.word_bytes,
+        }
+    }
+
+    /// The little-endian bytes of a register.
+    fn register_bytes(&self, number: usize) -> Vec<u8> {
+        let memory = self.debugger.memory();
+        let value = match number {
+            FLAGS_REGISTER => memory.flags.into(),
+            REMAINDER_REGISTER => memory.remainder,
+            n if n >= FIRST_VECTOR_REGISTER => {
+                return memory.vector_registers[n - FIRST_VECTOR_REGISTER]
+                    .iter()
+                    .flat_map(|lane| lane.to_le_bytes())
+                    .collect();
+            }
+            n => memory.registers[n],
+        };
+        value.to_le_bytes()[..self.register_size(number)].to_vec()
+    }
+
+    /// Sets a register to `bytes`, of which there are as many as it holds.
+    fn set_register_bytes(&mut self, number: usize, bytes: &[u8]) {
+        let memory = self.debugger.memory_mut();
+        if number >= FIRST_VECTOR_REGISTER {
+            let lanes = &mut memory.vector_registers[number - FIRST_VECTOR_REGISTER];
+            for (lane, bytes) in lanes.iter_mut().zip(bytes.chunks(4)) {
+                *lane = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            return;
+        }
+
+        let mut word = [0; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        let value = sign_extend(i64::from_le_bytes(word), bytes.len());
+        match number {
+            FLAGS_REGISTER => memory.flags = value as i32,
+            REMAINDER_REGISTER => memory.remainder = value,
+            n => memory.registers[n] = value,
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_GDB_REGISTERS)
+            .map(|n| encode_bytes(&self.register_bytes(n)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let sizes: Vec<usize> = (0..NUM_GDB_REGISTERS)
+            .map(|n| self.register_size(n))
+            .collect();
+        let bytes = match decode_bytes(data) {
+            Some(bytes) if bytes.len() == sizes.iter().sum::<usize>() => bytes,
+            _ => return "E00".to_owned(),
+        };
+
+        let mut offset = 0;
+        for (n, size) in sizes.iter().enumerate() {
+            self.set_register_bytes(n, &bytes[offset..offset + size]);
+            offset += size;
+        }
+
+        "OK".to_owned()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(n) if n < NUM_GDB_REGISTERS => encode_bytes(&self.register_bytes(n)),
+            _ => "E00".to_owned(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (number, value) = match args.find('=') {
+            Some(i) => (&args[..i], &args[i + 1..]),
+            None => return "E00".to_owned(),
+        };
+
+        let number = match usize::from_str_radix(number, 16) {
+            Ok(n) if n < NUM_GDB_REGISTERS => n,
+            _ => return "E00".to_owned(),
+        };
+        match decode_bytes(value) {
+            Some(bytes) if bytes.len() == self.register_size(number) => {
+                self.set_register_bytes(number, &bytes);
+                "OK".to_owned()
+            }
+            _ => "E00".to_owned(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (address, length) = match parse_pair(args) {
+            Some(v) => v,
+            None => return "E00".to_owned(),
+        };
+        // Each byte is two hex digits of the reply, which has to fit in a
+        // packet. GDB asks again for the bytes that were left out.
+        let length = length.min(PACKET_SIZE / 2);
+        let end = match address.checked_add(length) {
+            Some(end) => end,
+            None => return "E00".to_owned(),
+        };
+
+        let mem_space = &self.debugger.memory().mem_space;
+        let word_bytes = self.word_bytes;
+        let mut reply = String::with_capacity(length * 2);
+        for byte_address in address..end {
+            match mem_space.get(byte_address / word_bytes) {
+                Some(word) => {
+                    let byte = word.to_le_bytes()[byte_address % word_bytes];
+                    reply += &format!("{:02x}", byte);
+                }
+                None if byte_address == address => return "E01".to_owned(),
+                None => break,
+            }
+        }
+
+        reply
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (range, data) = match args.find(':') {
+            Some(i) => (&args[..i], &args[i + 1..]),
+            None => return "E00".to_owned(),
+        };
+
+        let (address, end) = match parse_pair(range) {
+            Some((address, length)) if length <= PACKET_SIZE => match address.checked_add(length) {
+                Some(end) => (address, end),
+                None => return "E00".to_owned(),
+            },
+            _ => return "E00".to_owned(),
+        };
+
+        let bytes = match decode_bytes(data) {
+            Some(bytes) if bytes.len() == end - address => bytes,
+            _ => return "E00".to_owned(),
+        };
+
+        let word_bytes = self.word_bytes;
+        let mem_space = &mut self.debugger.memory_mut().mem_space;
+        if end.div_ceil(word_bytes) > mem_space.len() {
+            return "E01".to_owned();
+        }
+
+        for (i, byte) in bytes.iter().enumerate() {
+            let byte_address = address + i;
+            let word = &mut mem_space[byte_address / word_bytes];
+            let mut le_bytes = word.to_le_bytes();
+            le_bytes[byte_address % word_bytes] = *byte;
+            *word = sign_extend(i64::from_le_bytes(le_bytes), word_bytes);
+        }
+
+        "OK".to_owned()
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let (kind, args) = match args.find(',') {
+            Some(i) => (&args[..i], &args[i + 1..]),
+            None => return "E00".to_owned(),
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return String::new(),
+        };
+
+        let (address, length) = match parse_pair(args) {
+            Some(v) => v,
+            None => return "E00".to_owned(),
+        };
+
+        match watch_kind {
+            None => {
+                let address = match i32::try_from(address) {
+                    Ok(address) => address,
+                    Err(_) => return "E00".to_owned(),
+                };
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+            }
+            Some(kind) => {
+                // Watch every word that overlaps the byte range
+                let start = address / self.word_bytes;
+                let end = match address.checked_add(length.max(1)) {
+                    Some(end) => end.div_ceil(self.word_bytes),
+                    None => return "E00".to_owned(),
+                };
+                let watchpoint = match (i32::try_from(start), i32::try_from(end - start)) {
+                    (Ok(start), Ok(length)) => Watchpoint {
+                        start,
+                        length,
+                        kind,
+                    },
+                    _ => return "E00".to_owned(),
+                };
+
+                if insert {
+                    self.debugger.add_watchpoint(watchpoint);
+                } else {
+                    self.debugger.remove_watchpoint(watchpoint);
+                }
+            }
+        }
+
+        "OK".to_owned()
+    }
+
+    fn resume(&mut self, args: &str, until_breakpoint: bool) -> String {
+        if !args.is_empty() {
+            match i64::from_str_radix(args, 16) {
+                Ok(address) => {
+                    self.debugger.memory_mut().registers[Register::Eip as usize] = address
+                }
+                Err(_) => return "E00".to_owned(),
+            }
+        }
+
+        let reason = if until_breakpoint {
+            self.debugger.cont()
+        } else {
+            self.debugger.step()
+        };
+
+        stop_reply(reason, self.word_bytes)
+    }
+
+    /// Handles `monitor` commands. The reply is hex-encoded console output.
+    fn monitor_command(&mut self, command: &str) -> String {
+        let command = match decode_bytes(command) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            None => return "E00".to_owned(),
+        };
+
+        let words: Vec<_> = command.split_whitespace().collect();
+        let output = match words.as_slice() {
+            ["last-write", address] => match parse_value(address).map(i32::try_from) {
+                Ok(Ok(address)) => match self.debugger.last_write(address) {
+                    Some(write) => format!(
+                        "[{}] was last written at step {} by instruction {}, previous value {}\n",
+                        address, write.position, write.instruction_index, write.old_value
+                    ),
+                    None => format!("No write to [{}] in the recorded history\n", address),
+                },
+                _ => format!("Invalid address: {}\n", address),
+            },
+            ["backtrace"] => self.debugger.backtrace().to_string(),
+            _ => "Commands: last-write <address>, backtrace\n".to_owned(),
+        };
+
+        encode_bytes(output.as_bytes())
+    }
+}
+
+fn stop_reply(reason: StopReason, word_bytes: usize) -> String {
+    match reason {
+        StopReason::Step | StopReason::Breakpoint(_) => format!("S{:02x}", SIGTRAP),
+        StopReason::Watchpoint { address, kind } => {
+            let name = match kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write => "watch",
+                WatchKind::Access => "awatch",
+            };
+            format!(
+                "T{:02x}{}:{:x};",
+                SIGTRAP,
+                name,
+                address * word_bytes as i32
+            )
+        }
+        StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        StopReason::Exited => "W00".to_owned(),
+        StopReason::Error(_) => format!("S{:02x}", SIGSEGV),
+    }
+}
+
+fn parse_pair(s: &str) -> Option<(usize, usize)> {
+    let i = s.find(',')?;
+    let first = usize::from_str_radix(&s[..i], 16).ok()?;
+    let second = usize::from_str_radix(&s[i + 1..], 16).ok()?;
+    Some((first, second))
+}
+
+fn encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Extends the sign of the low `size` bytes of `value`.
+fn sign_extend(value: i64, size: usize) -> i64 {
+    let shift = 64 - 8 * size as u32;
+    value << shift >> shift
+}
+
+fn decode_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Program;
+    use std::io::Cursor;
+
+    struct TestConnection {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("${}#{:02x}", data, checksum)
+    }
+
+    /// Sends the packets to a stub debugging `source` and returns the replies.
+    fn run(source: &str, packets: &[&str]) -> Vec<String> {
+        let program = Program::load(source.to_owned()).unwrap();
+        let mut debugger = Debugger::new(&program);
+
+        let input: String = packets.iter().map(|p| packet(p)).collect();
+        let mut connection = TestConnection {
+            input: Cursor::new(input.into_bytes()),
+            output: vec![],
+        };
+
+        serve(&mut debugger, &mut connection).unwrap();
+
+        String::from_utf8(connection.output)
+            .unwrap()
+            .split('$')
+            .skip(1)
+            .map(|reply| {
+                let end = reply.find('#').unwrap();
+                assert_eq!(reply[..end + 3], packet(&reply[..end])[1..]);
+                reply[..end].to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replies_are_acknowledged() {
+        let program = Program::load("nop".to_owned()).unwrap();
+        let mut debugger = Debugger::new(&program);
+        let mut connection = TestConnection {
+            input: Cursor::new(format!("{}$?#00", packet("?")).into_bytes()),
+            output: vec![],
+        };
+
+        serve(&mut debugger, &mut connection).unwrap();
+
+        assert_eq!(
+            String::from_utf8(connection.output).unwrap(),
+            format!("+{}-", packet("S05"))
+        );
+    }
+
+    #[test]
+    fn can_read_and_write_registers() {
+        let replies = run(
+            "mov eax, 1\nmov ebx, 2",
+            &["s", "s", "p1", "P2=78563412", "p2", "g"],
+        );
+
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[2], "02000000");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "78563412");
+        assert_eq!(
+            replies[5],
+            format!(
+                "010000000200000078563412{}{}{}{}{}",
+                "00000000".repeat(3),
+                "00000800".repeat(2),
+                "02000000",
+                "00000000".repeat(10),
+                "00000000".repeat(4 * NUM_VECTOR_REGISTERS)
+            )
+        );
+    }
+
+    #[test]
+    fn vector_registers_follow_the_remainder() {
+        let replies = run(
+            "vins v1, 2, -2",
+            &[
+                "s",
+                "p14",
+                "P13=01000000020000000300000004000000",
+                "p13",
+                "P13=0100",
+            ],
+        );
+
+        assert_eq!(replies[1], "0000000000000000feffffff00000000");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(replies[3], "01000000020000000300000004000000");
+        assert_eq!(replies[4], "E00");
+    }
+
+    #[test]
+    fn can_read_and_write_memory() {
+        let replies = run(
+            "mov [1], 0x11223344",
+            &["s", "m4,6", "M5,2:aabb", "m4,4", "m100000000,4"],
+        );
+
+        assert_eq!(replies, &["S05", "443322110000", "OK", "44aabb11", "E01"]);
+    }
+
+    #[test]
+    fn malformed_memory_packets_are_errors() {
+        let max = format!("{:x}", usize::MAX);
+        let replies = run(
+            "nop",
+            &[
+                "mzz,2",
+                &format!("m{},2", max),
+                &format!("M{},1:aa", max),
+                &format!("M0,{}:aa", max),
+                &format!("Z0,{},1", max),
+                &format!("Z2,{},4", max),
+                "Z2,fffffffff0,4",
+                "m0,100000",
+            ],
+        );
+
+        assert_eq!(replies[..7], ["E00"; 7]);
+        // Reads are cut short to fit in a packet
+        assert_eq!(replies[7], "0".repeat(PACKET_SIZE));
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        let replies = run(
+            "mov eax, 1\nloop: inc eax\ncmp eax, 3\njl loop",
+            &["Z0,1,1", "c", "p0", "c", "p0", "z0,1,1", "c"],
+        );
+
+        assert_eq!(
+            replies,
+            &["OK", "S05", "01000000", "S05", "02000000", "OK", "W00"]
+        );
+    }
+
+    #[test]
+    fn watchpoints_cover_the_words_overlapping_the_byte_range() {
+        let replies = run(
+            "mov [1], 1\nmov [2], 2\nmov eax, [3]\nmov [4], eax",
+            &["Z2,9,1", "Z3,c,4", "c", "c", "z2,9,1", "z3,c,4", "c"],
+        );
+
+        assert_eq!(
+            replies,
+            &["OK", "OK", "T05watch:8;", "T05rwatch:c;", "OK", "OK", "W00"]
+        );
+    }
+
+    #[test]
+    fn can_execute_in_reverse() {
+        let program = Program::load("mov eax, 1\nmov [2], eax\ninc eax".to_owned()).unwrap();
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(100, 100);
+
+        let input: String = ["c", "bs", "p0", "bc", "qRcmd,6c6173742d7772697465203222"]
+            .iter()
+            .map(|p| packet(p))
+            .collect();
+        let mut connection = TestConnection {
+            input: Cursor::new(input.into_bytes()),
+            output: vec![],
+        };
+        serve(&mut debugger, &mut connection).unwrap();
+        let output = String::from_utf8(connection.output).unwrap();
+
+        assert!(output.contains(&packet("W00")));
+        assert!(output.contains(&packet("S05")));
+        assert!(output.contains(&packet("01000000")));
+        assert!(output.contains(&packet("T05replaylog:begin;")));
+    }
+
+    #[test]
+    fn backtrace_monitor_command() {
+        let hex = |s: &str| s.bytes().map(|b| format!("{:02x}", b)).collect::<String>();
+        let replies = run(
+            "call f\nf: nop",
+            &["s", &format!("qRcmd,{}", hex("backtrace"))],
+        );
+
+        assert_eq!(
+            replies[1],
+            hex("#0 instruction 1 in f (line 2)\n#1 instruction 0 (line 1)\n")
+        );
+    }
+
+    #[test]
+    fn execution_errors_are_reported_as_segmentation_faults() {
+        let replies = run("mov eax, [100000000]", &["c"]);
+
+        assert_eq!(replies, &["S0b"]);
+    }
+
+    #[test]
+    fn target_description_can_be_read_in_chunks() {
+        let xml = target_description(WordSize::Bits32);
+        let replies = run(
+            "nop",
+            &[
+                "qXfer:features:read:target.xml:0,a",
+                &format!("qXfer:features:read:target.xml:a,{:x}", xml.len()),
+            ],
+        );
+
+        assert_eq!(replies[0], format!("m{}", &xml[..10]));
+        assert_eq!(replies[1], format!("l{}", &xml[10..]));
+        assert!(xml.contains("<reg name=\"eip\" bitsize=\"32\" type=\"code_ptr\" regnum=\"8\"/>"));
+        assert!(xml
+            .contains("<reg name=\"flags\" bitsize=\"32\" type=\"tinyvm_flags\" regnum=\"17\"/>"));
+        assert!(xml.contains("<reg name=\"v7\" bitsize=\"128\" type=\"v4i32\" regnum=\"26\"/>"));
+    }
+
+    #[test]
+    fn unsupported_packets_get_an_empty_reply() {
+        let replies = run("nop", &["vMustReplyEmpty", "Z5,0,4"]);
+
+        assert_eq!(replies, &["", ""]);
+    }
+}
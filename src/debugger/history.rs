@@ -0,0 +1,376 @@
+//! Execution history for reverse debugging.
+//!
+//! Every recorded step stores the previous values of the registers, float
+//! and vector registers, memory words, flags, remainder and call stack
+//! frames it overwrote, in a ring buffer holding the most recent steps. In
+//! addition, a full checkpoint of the VM state is taken every
+//! `checkpoint_interval` steps. Once the ring buffer runs out, earlier
+//! states are reached by restoring a checkpoint and executing forward again.
+//!
+//! Checkpoints store memory sparsely, as the non-zero pages, and share pages
+//! that did not change with the previous checkpoint.
+
+use crate::{
+    context::{Frame, Memory, Observer},
+    instruction::{
+        FloatRegister, Register, VectorRegister, NUM_FLOAT_REGISTERS, NUM_LANES, NUM_REGISTERS,
+        NUM_VECTOR_REGISTERS,
+    },
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    rc::Rc,
+};
+
+const PAGE_SIZE: usize = 1024;
+const MAX_CHECKPOINTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Register(Register, i64),
+    FloatRegister(FloatRegister, f64),
+    VectorRegister(VectorRegister, [i32; NUM_LANES]),
+    Memory(i32, i64),
+    Flags(i32),
+    Remainder(i64),
+    FramePushed,
+    FramePopped(Frame),
+}
+
+struct StepRecord {
+    position: u64,
+    instruction_index: i64,
+    change_count: usize,
+}
+
+struct Checkpoint {
+    position: u64,
+    registers: [i64; NUM_REGISTERS],
+    float_registers: [f64; NUM_FLOAT_REGISTERS],
+    vector_registers: [[i32; NUM_LANES]; NUM_VECTOR_REGISTERS],
+    flags: i32,
+    remainder: i64,
+    call_stack: Vec<Frame>,
+    pages: BTreeMap<usize, Rc<[i64]>>,
+}
+
+/// The most recent recorded write to a memory word.
+#[derive(Debug, PartialEq)]
+pub struct LastWrite {
+    /// The number of steps executed before the writing step.
+    pub position: u64,
+    pub instruction_index: i64,
+    pub old_value: i64,
+}
+
+pub struct History {
+    capacity: usize,
+    checkpoint_interval: u64,
+    position: u64,
+    steps: VecDeque<StepRecord>,
+    changes: VecDeque<Change>,
+    checkpoints: VecDeque<Checkpoint>,
+    dirty_pages: BTreeSet<usize>,
+    full_scan_needed: bool,
+}
+
+impl History {
+    /// Starts recording from the current state of `memory`, keeping undo
+    /// information for the last `capacity` steps.
+    pub fn new(capacity: usize, checkpoint_interval: u64, memory: &Memory) -> History {
+        let mut history = History {
+            capacity,
+            checkpoint_interval: checkpoint_interval.max(1),
+            position: 0,
+            steps: VecDeque::default(),
+            changes: VecDeque::default(),
+            checkpoints: VecDeque::default(),
+            dirty_pages: BTreeSet::default(),
+            full_scan_needed: true,
+        };
+
+        history.take_checkpoint(memory);
+        history
+    }
+
+    /// The number of steps executed since recording started.
+    pub fn position(self: &History) -> u64 {
+        self.position
+    }
+
+    /// The number of steps that can be undone without replaying from a
+    /// checkpoint.
+    pub fn recorded_steps(self: &History) -> usize {
+        self.steps.len()
+    }
+
+    /// The earliest position that can still be reached.
+    pub fn earliest_position(self: &History) -> u64 {
+        let from_steps = self.steps.front().map(|s| s.position);
+        let from_checkpoints = self.checkpoints.front().map(|c| c.position);
+
+        match (from_steps, from_checkpoints) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => self.position,
+        }
+    }
+
+    /// Finds the most recent recorded write to the word at `address`.
+    pub fn last_write(self: &History, address: i32) -> Option<LastWrite> {
+        let mut end = self.changes.len();
+        for step in self.steps.iter().rev() {
+            let start = end - step.change_count;
+            let old_value = self.changes.range(start..end).find_map(|c| match c {
+                Change::Memory(a, old_value) if *a == address => Some(*old_value),
+                _ => None,
+            });
+
+            if let Some(old_value) = old_value {
+                return Some(LastWrite {
+                    position: step.position,
+                    instruction_index: step.instruction_index,
+                    old_value,
+                });
+            }
+
+            end = start;
+        }
+
+        None
+    }
+
+    /// Marks the whole memory as possibly changed, for when it has been
+    /// modified outside of recorded steps.
+    pub(super) fn invalidate(self: &mut History) {
+        self.full_scan_needed = true;
+    }
+
+    /// Must be called before each recorded step, which must then report its
+    /// changes to the history as an `Observer`.
+    pub(super) fn begin_step(self: &mut History, memory: &Memory) {
+        if self.position.is_multiple_of(self.checkpoint_interval)
+            && self.checkpoints.back().map(|c| c.position) != Some(self.position)
+        {
+            self.take_checkpoint(memory);
+        }
+
+        self.steps.push_back(StepRecord {
+            position: self.position,
+            instruction_index: memory.registers[Register::Eip as usize],
+            change_count: 0,
+        });
+
+        if self.steps.len() > self.capacity {
+            let evicted = self.steps.pop_front().unwrap();
+            self.changes.drain(..evicted.change_count);
+        }
+    }
+
+    /// Must be called after each recorded step, with `executed` being `false`
+    /// if the program had already ended.
+    pub(super) fn end_step(self: &mut History, executed: bool) {
+        if executed {
+            self.position += 1;
+        } else if let Some(step) = self.steps.pop_back() {
+            self.changes
+                .truncate(self.changes.len() - step.change_count);
+        }
+    }
+
+    /// Undoes the most recent step in the ring buffer, reporting every word
+    /// it restores to `observer`. Returns `false` if there is no such step.
+    pub(super) fn undo<O: Observer>(
+        self: &mut History,
+        memory: &mut Memory,
+        observer: &mut O,
+    ) -> bool {
+        let step = match self.steps.pop_back() {
+            Some(step) => step,
+            None => return false,
+        };
+
+        for _ in 0..step.change_count {
+            match self.changes.pop_back().unwrap() {
+                Change::Register(reg, value) => memory.registers[reg as usize] = value,
+                Change::FloatRegister(reg, value) => memory.float_registers[reg as usize] = value,
+                Change::VectorRegister(reg, value) => memory.vector_registers[reg as usize] = value,
+                Change::Memory(address, value) => {
+                    observer.write_memory(address, memory.mem_space[address as usize]);
+                    memory.mem_space[address as usize] = value;
+                    self.dirty_pages.insert(address as usize / PAGE_SIZE);
+                }
+                Change::Flags(value) => memory.flags = value,
+                Change::Remainder(value) => memory.remainder = value,
+                Change::FramePushed => {
+                    memory.call_stack.pop();
+                }
+                Change::FramePopped(frame) => memory.call_stack.push(frame),
+            }
+        }
+
+        memory.registers[Register::Eip as usize] = step.instruction_index;
+        self.position = step.position;
+        self.drop_checkpoints_after(self.position);
+
+        true
+    }
+
+    /// Restores the most recent checkpoint at or before `position`, and
+    /// returns its position.
+    pub(super) fn restore_checkpoint(
+        self: &mut History,
+        position: u64,
+        memory: &mut Memory,
+    ) -> Option<u64> {
+        let index = self
+            .checkpoints
+            .iter()
+            .rposition(|c| c.position <= position)?;
+        self.drop_checkpoints_after(self.checkpoints[index].position);
+
+        let checkpoint = self.checkpoints.back().unwrap();
+        memory.registers = checkpoint.registers;
+        memory.float_registers = checkpoint.float_registers;
+        memory.vector_registers = checkpoint.vector_registers;
+        memory.flags = checkpoint.flags;
+        memory.remainder = checkpoint.remainder;
+        memory.call_stack = checkpoint.call_stack.clone();
+
+        // Only the pages changed since the checkpoint need to be restored
+        let pages: Vec<usize> = if self.full_scan_needed {
+            (0..memory.mem_space.len().div_ceil(PAGE_SIZE)).collect()
+        } else {
+            self.dirty_pages.iter().copied().collect()
+        };
+
+        for page in pages {
+            let start = page * PAGE_SIZE;
+            let end = memory.mem_space.len().min(start + PAGE_SIZE);
+            match checkpoint.pages.get(&page) {
+                Some(words) => memory.mem_space[start..end].copy_from_slice(words),
+                None => memory.mem_space[start..end].iter_mut().for_each(|w| *w = 0),
+            }
+        }
+
+        self.position = checkpoint.position;
+        self.steps.clear();
+        self.changes.clear();
+        self.dirty_pages.clear();
+        self.full_scan_needed = false;
+
+        Some(self.position)
+    }
+
+    fn drop_checkpoints_after(self: &mut History, position: u64) {
+        while self
+            .checkpoints
+            .back()
+            .is_some_and(|c| c.position > position)
+        {
+            let dropped = self.checkpoints.pop_back().unwrap();
+
+            // Pages are tracked as dirty relative to the latest checkpoint,
+            // which is now the previous one
+            match self.checkpoints.back() {
+                Some(previous) => {
+                    let keys: BTreeSet<_> =
+                        dropped.pages.keys().chain(previous.pages.keys()).collect();
+                    for page in keys {
+                        match (dropped.pages.get(page), previous.pages.get(page)) {
+                            (Some(a), Some(b)) if Rc::ptr_eq(a, b) => {}
+                            _ => {
+                                self.dirty_pages.insert(*page);
+                            }
+                        }
+                    }
+                }
+                None => self.full_scan_needed = true,
+            }
+        }
+    }
+
+    fn take_checkpoint(self: &mut History, memory: &Memory) {
+        let mut pages = BTreeMap::default();
+
+        let changed_pages: Vec<usize> = match self.checkpoints.back() {
+            Some(previous) if !self.full_scan_needed => {
+                pages = previous.pages.clone();
+                self.dirty_pages.iter().copied().collect()
+            }
+            _ => (0..memory.mem_space.len().div_ceil(PAGE_SIZE)).collect(),
+        };
+
+        for page in changed_pages {
+            let start = page * PAGE_SIZE;
+            let end = memory.mem_space.len().min(start + PAGE_SIZE);
+            let words = &memory.mem_space[start..end];
+
+            if words.iter().all(|w| *w == 0) {
+                pages.remove(&page);
+            } else {
+                pages.insert(page, Rc::from(words));
+            }
+        }
+
+        self.checkpoints.push_back(Checkpoint {
+            position: self.position,
+            registers: memory.registers,
+            float_registers: memory.float_registers,
+            vector_registers: memory.vector_registers,
+            flags: memory.flags,
+            remainder: memory.remainder,
+            call_stack: memory.call_stack.clone(),
+            pages,
+        });
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+
+        self.dirty_pages.clear();
+        self.full_scan_needed = false;
+    }
+
+    fn record(self: &mut History, change: Change) {
+        if let Some(step) = self.steps.back_mut() {
+            step.change_count += 1;
+            self.changes.push_back(change);
+        }
+    }
+}
+
+impl Observer for History {
+    fn write_memory(&mut self, address: i32, old_value: i64) {
+        self.dirty_pages.insert(address as usize / PAGE_SIZE);
+        self.record(Change::Memory(address, old_value));
+    }
+
+    fn write_register(&mut self, register: Register, old_value: i64) {
+        self.record(Change::Register(register, old_value));
+    }
+
+    fn write_float_register(&mut self, register: FloatRegister, old_value: f64) {
+        self.record(Change::FloatRegister(register, old_value));
+    }
+
+    fn write_vector_register(&mut self, register: VectorRegister, old_value: [i32; NUM_LANES]) {
+        self.record(Change::VectorRegister(register, old_value));
+    }
+
+    fn write_flags(&mut self, old_value: i32) {
+        self.record(Change::Flags(old_value));
+    }
+
+    fn write_remainder(&mut self, old_value: i64) {
+        self.record(Change::Remainder(old_value));
+    }
+
+    fn push_frame(&mut self) {
+        self.record(Change::FramePushed);
+    }
+
+    fn pop_frame(&mut self, frame: Frame) {
+        self.record(Change::FramePopped(frame));
+    }
+}
@@ -0,0 +1,582 @@
+pub mod expression;
+pub mod gdb;
+pub mod history;
+
+use self::{
+    expression::Expression,
+    history::{History, LastWrite},
+};
+use crate::{
+    backtrace::Backtrace,
+    context::{ExecutionError, Memory, Observer, Program},
+    instruction::Register,
+};
+use std::{collections::BTreeMap, convert::TryFrom, io::sink};
+
+/// The number of steps `Debugger::enable_history` keeps undo information for
+/// unless told otherwise.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 1_000_000;
+
+/// How many steps apart the history takes checkpoints unless told otherwise.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Stops execution after an instruction accesses any word in
+/// `start..start + length`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: i32,
+    pub length: i32,
+    pub kind: WatchKind,
+}
+
+/// Why execution stopped after a call to `Debugger::step` or `Debugger::cont`.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(i32),
+    Watchpoint {
+        address: i32,
+        kind: WatchKind,
+    },
+    /// Reverse execution reached the start of the recorded history.
+    HistoryStart,
+    Exited,
+    Error(ExecutionError),
+}
+
+/// Records the first access matching any of the watchpoints.
+struct WatchObserver<'w> {
+    watchpoints: &'w [Watchpoint],
+    hit: Option<(i32, WatchKind)>,
+}
+
+impl WatchObserver<'_> {
+    fn access(&mut self, address: i32, is_write: bool) {
+        if self.hit.is_some() {
+            return;
+        }
+
+        self.hit = self
+            .watchpoints
+            .iter()
+            .find(|w| {
+                let kind_matches = match w.kind {
+                    WatchKind::Read => !is_write,
+                    WatchKind::Write => is_write,
+                    WatchKind::Access => true,
+                };
+                kind_matches && address >= w.start && address - w.start < w.length
+            })
+            .map(|w| (address, w.kind));
+    }
+}
+
+impl Observer for WatchObserver<'_> {
+    fn read_memory(&mut self, address: i32) {
+        self.access(address, false);
+    }
+
+    fn write_memory(&mut self, address: i32, _old_value: i64) {
+        self.access(address, true);
+    }
+}
+
+/// Runs a program under control of a debugger front-end, keeping the VM state
+/// around between steps so that it can be inspected and modified.
+pub struct Debugger<'a> {
+    program: &'a Program,
+    memory: Memory,
+    breakpoints: BTreeMap<i32, Option<Expression>>,
+    watchpoints: Vec<Watchpoint>,
+    history: Option<History>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program) -> Debugger<'a> {
+        Debugger {
+            program,
+            memory: program.initialize(),
+            breakpoints: BTreeMap::default(),
+            watchpoints: vec![],
+            history: None,
+        }
+    }
+
+    pub fn program(self: &Debugger<'a>) -> &'a Program {
+        self.program
+    }
+
+    pub fn memory(self: &Debugger<'a>) -> &Memory {
+        &self.memory
+    }
+
+    /// Changes made through the returned reference are not recorded in the
+    /// history, so reverse execution will not undo them.
+    pub fn memory_mut(self: &mut Debugger<'a>) -> &mut Memory {
+        if let Some(history) = &mut self.history {
+            history.invalidate();
+        }
+        &mut self.memory
+    }
+
+    pub fn instruction_index(self: &Debugger<'a>) -> i64 {
+        self.memory.registers[Register::Eip as usize]
+    }
+
+    /// Returns `false` if there already was a breakpoint at the index.
+    pub fn add_breakpoint(self: &mut Debugger<'a>, instruction_index: i32) -> bool {
+        self.breakpoints.insert(instruction_index, None).is_none()
+    }
+
+    /// Adds a breakpoint that only stops when `condition` evaluates to a
+    /// non-zero value, replacing any existing breakpoint at the index.
+    pub fn add_conditional_breakpoint(
+        self: &mut Debugger<'a>,
+        instruction_index: i32,
+        condition: Expression,
+    ) {
+        self.breakpoints.insert(instruction_index, Some(condition));
+    }
+
+    /// Returns `false` if there was no breakpoint at the index.
+    pub fn remove_breakpoint(self: &mut Debugger<'a>, instruction_index: i32) -> bool {
+        self.breakpoints.remove(&instruction_index).is_some()
+    }
+
+    pub fn breakpoints(self: &Debugger<'a>) -> impl Iterator<Item = i32> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    pub fn add_watchpoint(self: &mut Debugger<'a>, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Returns `false` if there was no such watchpoint.
+    pub fn remove_watchpoint(self: &mut Debugger<'a>, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(self: &Debugger<'a>) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Starts recording execution, so that it can be reversed.
+    pub fn enable_history(self: &mut Debugger<'a>, capacity: usize, checkpoint_interval: u64) {
+        self.history = Some(History::new(capacity, checkpoint_interval, &self.memory));
+    }
+
+    pub fn disable_history(self: &mut Debugger<'a>) {
+        self.history = None;
+    }
+
+    pub fn history(self: &Debugger<'a>) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Finds the most recent recorded write to the word at `address`.
+    pub fn last_write(self: &Debugger<'a>, address: i32) -> Option<LastWrite> {
+        self.history.as_ref()?.last_write(address)
+    }
+
+    pub fn backtrace(self: &Debugger<'a>) -> Backtrace {
+        self.program.backtrace(&self.memory)
+    }
+
+    /// Executes a single instruction, ignoring breakpoints.
+    pub fn step(self: &mut Debugger<'a>) -> StopReason {
+        let mut hit = None;
+
+        let result = if self.watchpoints.is_empty() && self.history.is_none() {
+            self.program.step(&mut self.memory)
+        } else {
+            let watch_observer = if self.watchpoints.is_empty() {
+                None
+            } else {
+                Some(WatchObserver {
+                    watchpoints: &self.watchpoints,
+                    hit: None,
+                })
+            };
+
+            if let Some(history) = &mut self.history {
+                history.begin_step(&self.memory);
+            }
+
+            let mut observer = (watch_observer, self.history.as_mut());
+            let result = self.program.step_observed(&mut self.memory, &mut observer);
+            hit = observer.0.and_then(|o| o.hit);
+
+            if let Some(history) = &mut self.history {
+                history.end_step(result != Ok(false));
+            }
+
+            result
+        };
+
+        match (result, hit) {
+            (Ok(true), Some((address, kind))) => StopReason::Watchpoint { address, kind },
+            (Ok(true), None) => StopReason::Step,
+            (Ok(false), _) => StopReason::Exited,
+            (Err(e), _) => StopReason::Error(e),
+        }
+    }
+
+    /// Runs until the program ends, fails, or reaches a breakpoint. The
+    /// instruction at the current position is always executed, so continuing
+    /// from a breakpoint does not immediately stop at it again.
+    pub fn cont(self: &mut Debugger<'a>) -> StopReason {
+        loop {
+            match self.step() {
+                StopReason::Step => {}
+                reason => return reason,
+            }
+
+            let instruction_index = self.instruction_index();
+            if self.should_stop_at(instruction_index) {
+                // Breakpoints are only at instruction indices
+                return StopReason::Breakpoint(instruction_index as i32);
+            }
+        }
+    }
+
+    /// Undoes the most recent step. Watchpoints are triggered by the words
+    /// the step wrote.
+    pub fn reverse_step(self: &mut Debugger<'a>) -> StopReason {
+        match self.undo() {
+            None => StopReason::HistoryStart,
+            Some(Some((address, kind))) => StopReason::Watchpoint { address, kind },
+            Some(None) => StopReason::Step,
+        }
+    }
+
+    /// Executes backwards until reaching a breakpoint, a step that wrote to a
+    /// watched word, or the start of the recorded history.
+    pub fn reverse_cont(self: &mut Debugger<'a>) -> StopReason {
+        loop {
+            match self.reverse_step() {
+                StopReason::Step => {}
+                reason => return reason,
+            }
+
+            let instruction_index = self.instruction_index();
+            if self.should_stop_at(instruction_index) {
+                // Breakpoints are only at instruction indices
+                return StopReason::Breakpoint(instruction_index as i32);
+            }
+        }
+    }
+
+    fn should_stop_at(self: &Debugger<'a>, instruction_index: i64) -> bool {
+        let breakpoint = i32::try_from(instruction_index)
+            .ok()
+            .and_then(|index| self.breakpoints.get(&index));
+        match breakpoint {
+            None => false,
+            Some(None) => true,
+            // A condition that cannot be evaluated stops, so that the problem
+            // can be investigated
+            Some(Some(condition)) => condition.evaluate(&self.memory) != Ok(0),
+        }
+    }
+
+    /// Undoes the most recent step, replaying from a checkpoint if it is no
+    /// longer in the ring buffer. Returns the watchpoint hit by undoing it, or
+    /// `None` if there was no step to undo.
+    fn undo(self: &mut Debugger<'a>) -> Option<Option<(i32, WatchKind)>> {
+        let history = self.history.as_mut()?;
+
+        if history.recorded_steps() == 0 {
+            let position = history.position();
+            history.restore_checkpoint(position.checked_sub(1)?, &mut self.memory)?;
+            self.replay_until(position);
+        }
+
+        let mut observer = WatchObserver {
+            watchpoints: &self.watchpoints,
+            hit: None,
+        };
+
+        if self.history.as_mut()?.undo(&mut self.memory, &mut observer) {
+            Some(observer.hit)
+        } else {
+            None
+        }
+    }
+
+    /// Executes forward while recording, without output, until the history
+    /// reaches `position`.
+    fn replay_until(self: &mut Debugger<'a>, position: u64) {
+        let output = std::mem::replace(&mut self.memory.output, Box::new(sink()));
+
+        if let Some(history) = &mut self.history {
+            while history.position() < position {
+                history.begin_step(&self.memory);
+                let result = self.program.step_observed(&mut self.memory, &mut *history);
+                history.end_step(result != Ok(false));
+
+                if result != Ok(true) {
+                    break;
+                }
+            }
+        }
+
+        self.memory.output = output;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ExecutionErrorKind;
+
+    const STACK_TOP: usize = 512 * 1024;
+
+    fn load(source: &str) -> Program {
+        Program::load(source.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        let program = load("mov eax, 1\nmov ebx, 2");
+        let mut debugger = Debugger::new(&program);
+
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.instruction_index(), 1);
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 1);
+        assert_eq!(debugger.memory().registers[Register::Ebx as usize], 0);
+    }
+
+    #[test]
+    fn cont_stops_at_breakpoints() {
+        let program = load("mov eax, 1\nloop: inc eax\ncmp eax, 3\njl loop");
+        let mut debugger = Debugger::new(&program);
+        debugger.add_breakpoint(1);
+
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(1));
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 1);
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(1));
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 2);
+
+        assert!(debugger.remove_breakpoint(1));
+        assert_eq!(debugger.cont(), StopReason::Exited);
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 3);
+    }
+
+    #[test]
+    fn conditional_breakpoints_only_stop_when_the_condition_holds() {
+        let program = load("mov eax, 1\nloop: inc eax\nmov [4], eax\ncmp eax, 5\njl loop");
+        let mut debugger = Debugger::new(&program);
+        debugger.add_conditional_breakpoint(3, Expression::parse("eax == 4 && [4] > 0").unwrap());
+
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(3));
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 4);
+        assert_eq!(debugger.cont(), StopReason::Exited);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_matching_accesses() {
+        let program = load("mov [10], 1\nmov eax, [11]\nmov eax, [12]\nmov [12], eax\ninc [13]");
+        let mut debugger = Debugger::new(&program);
+        debugger.add_watchpoint(Watchpoint {
+            start: 11,
+            length: 2,
+            kind: WatchKind::Write,
+        });
+        debugger.add_watchpoint(Watchpoint {
+            start: 11,
+            length: 1,
+            kind: WatchKind::Read,
+        });
+        debugger.add_watchpoint(Watchpoint {
+            start: 13,
+            length: 1,
+            kind: WatchKind::Access,
+        });
+
+        let read = StopReason::Watchpoint {
+            address: 11,
+            kind: WatchKind::Read,
+        };
+        let write = StopReason::Watchpoint {
+            address: 12,
+            kind: WatchKind::Write,
+        };
+        let access = StopReason::Watchpoint {
+            address: 13,
+            kind: WatchKind::Access,
+        };
+
+        assert_eq!(debugger.cont(), read);
+        assert_eq!(debugger.instruction_index(), 2);
+        assert_eq!(debugger.cont(), write);
+        assert_eq!(debugger.instruction_index(), 4);
+        assert_eq!(debugger.step(), access);
+        assert_eq!(debugger.cont(), StopReason::Exited);
+    }
+
+    #[test]
+    fn reverse_step_restores_the_previous_state() {
+        let program =
+            load("mov eax, 1\npush eax\nmov [10], 5\ncmp eax, 1\nmod 7, 4\npop [10]\nfld f1, 2.5\nfsqrt f1\n\
+                  vins v2, 1, 7\nvld v3, 8\nvadd v3, v2");
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(100, 1000);
+
+        let mut states = vec![];
+        loop {
+            let memory = debugger.memory();
+            states.push((
+                memory.registers,
+                memory.float_registers,
+                memory.vector_registers,
+                memory.flags,
+                memory.remainder,
+                memory.mem_space[10],
+                memory.mem_space[STACK_TOP - 1],
+            ));
+            if debugger.step() == StopReason::Exited {
+                break;
+            }
+        }
+
+        while let Some(state) = states.pop() {
+            let memory = debugger.memory();
+            assert_eq!(
+                (
+                    memory.registers,
+                    memory.float_registers,
+                    memory.vector_registers,
+                    memory.flags,
+                    memory.remainder,
+                    memory.mem_space[10],
+                    memory.mem_space[STACK_TOP - 1],
+                ),
+                state
+            );
+            if states.is_empty() {
+                assert_eq!(debugger.reverse_step(), StopReason::HistoryStart);
+            } else {
+                assert_eq!(debugger.reverse_step(), StopReason::Step);
+            }
+        }
+    }
+
+    #[test]
+    fn reverse_step_replays_from_checkpoints_beyond_the_ring_buffer() {
+        let program = load("mov eax, 0\nloop: inc eax\npush eax\ncmp eax, 50\njl loop");
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(3, 20);
+        debugger.memory_mut().output = Box::new(sink());
+
+        assert_eq!(debugger.cont(), StopReason::Exited);
+        let mut eax = 50;
+        let mut steps = 0;
+        while debugger.reverse_step() == StopReason::Step {
+            steps += 1;
+            let memory = debugger.memory();
+            if memory.registers[Register::Eip as usize] == 1 {
+                eax -= 1;
+                assert_eq!(memory.registers[Register::Eax as usize], eax);
+                assert_eq!(memory.mem_space[STACK_TOP - eax as usize - 1], 0);
+            }
+        }
+
+        assert_eq!(steps, 1 + 50 * 4);
+        assert_eq!(eax, 0);
+        assert_eq!(debugger.instruction_index(), 0);
+    }
+
+    #[test]
+    fn reverse_cont_stops_at_breakpoints_and_watched_writes() {
+        let program = load("mov eax, 1\nloop: inc eax\nmov [5], eax\ncmp eax, 4\njl loop\nnop");
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(1000, 1000);
+
+        assert_eq!(debugger.cont(), StopReason::Exited);
+        debugger.add_breakpoint(3);
+        assert_eq!(debugger.reverse_cont(), StopReason::Breakpoint(3));
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 4);
+
+        debugger.remove_breakpoint(3);
+        debugger.add_watchpoint(Watchpoint {
+            start: 5,
+            length: 1,
+            kind: WatchKind::Write,
+        });
+        assert_eq!(
+            debugger.reverse_cont(),
+            StopReason::Watchpoint {
+                address: 5,
+                kind: WatchKind::Write
+            }
+        );
+        assert_eq!(debugger.instruction_index(), 2);
+        assert_eq!(debugger.memory().mem_space[5], 3);
+        assert_eq!(debugger.memory().registers[Register::Eax as usize], 4);
+    }
+
+    #[test]
+    fn last_write_finds_the_most_recent_write_to_an_address() {
+        let program = load("mov [3], 1\nmov [4], 2\nmov [3], 3\nnop");
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(1000, 1000);
+
+        assert_eq!(debugger.cont(), StopReason::Exited);
+        assert_eq!(
+            debugger.last_write(3),
+            Some(LastWrite {
+                position: 2,
+                instruction_index: 2,
+                old_value: 1
+            })
+        );
+        assert_eq!(debugger.last_write(5), None);
+    }
+
+    #[test]
+    fn reverse_step_restores_the_call_stack() {
+        let program = load("call f\nnop\nf: nop\nret");
+        let mut debugger = Debugger::new(&program);
+        debugger.enable_history(1000, 1000);
+
+        debugger.step();
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.backtrace().frames.len(), 1);
+
+        debugger.reverse_step();
+        let frames = debugger.backtrace().frames;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].function.as_deref(), Some("f"));
+
+        debugger.reverse_step();
+        debugger.reverse_step();
+        assert_eq!(debugger.backtrace().frames.len(), 1);
+    }
+
+    #[test]
+    fn cont_reports_execution_errors() {
+        let program = load("mov eax, 1\njmp 100");
+        let mut debugger = Debugger::new(&program);
+
+        match debugger.cont() {
+            StopReason::Error(e) => {
+                assert_eq!(e.kind, ExecutionErrorKind::InstructionOutOfRange(100));
+                assert_eq!(e.instruction_index, 100);
+                assert_eq!(e.registers[Register::Eax as usize], 1);
+            }
+            other => panic!("Expected an error, found {:?}", other),
+        }
+    }
+}
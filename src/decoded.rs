@@ -0,0 +1,1228 @@
+//! An execution engine that decodes the program once, ahead of time.
+//!
+//! Every instruction is decoded into a handler specialized for the kinds of
+//! its operands, so running it does not need to match on the instruction or
+//! its operands again. The handlers are generic functions over the operand
+//! kinds, `Reg`, `Imm` and `Mem`, monomorphized for every combination.
+//!
+//! Jump targets are range checked when jumping rather than before every
+//! instruction, and the end of the program is a handler of its own.
+//! Instructions that use `eip` as an operand, `int`, `lea` and the byte and
+//! halfword loads and stores are rare enough that they are executed by
+//! `Program::step` instead. So are the floating-point instructions, as their
+//! literals do not fit in an `Op`, the vector instructions, those that act
+//! on other cores or on channels, and calls of native functions. The
+//! handlers work on 32-bit words, so 64-bit programs run entirely through
+//! `Program::step`.
+//!
+//! Common sequences of instructions are also fused into a single handler:
+//! `cmp` followed by a conditional jump, `push` followed by `pop`, and the
+//! `inc`, `cmp`, conditional jump tail of loops. A fused handler only runs
+//! when the sequence is entered at its first instruction and there are
+//! enough steps left for all of it, and it is only used where only the first
+//! instruction can fail, so errors are still reported at the right
+//! instruction.
+
+use crate::{
+    context::{
+        add, compare, decrement, divide, increment, logic, multiply, negate, remainder,
+        rotate_left, rotate_right, shift_arithmetic, shift_left, shift_right, subtract,
+        ExecutionError, ExecutionErrorKind, Frame, Memory, Program, WordSize, FLAG_CARRY,
+        FLAG_OVERFLOW, FLAG_SIGN, FLAG_ZERO,
+    },
+    instruction::{Instruction, Register, Source, Target},
+};
+use std::{convert::TryFrom, io::Write};
+
+const EIP: usize = Register::Eip as usize;
+const ESP: usize = Register::Esp as usize;
+
+/// Why a handler did not continue with another instruction.
+enum Stop {
+    Halt,
+    /// An error in the current instruction.
+    Error(ExecutionErrorKind),
+    /// A jump to an instruction index outside the program.
+    OutOfRange(i64),
+    /// An error from an instruction executed by `Program::step`.
+    Failed(Box<ExecutionError>),
+}
+
+impl From<ExecutionErrorKind> for Stop {
+    fn from(kind: ExecutionErrorKind) -> Stop {
+        Stop::Error(kind)
+    }
+}
+
+/// Executes the operation at `pc` and returns the index of the next one.
+type Handler = fn(&DecodedProgram, &mut Memory, &Op, usize) -> Result<usize, Stop>;
+
+#[derive(Clone, Copy)]
+struct Op {
+    handler: Handler,
+    a: i32,
+    b: i32,
+}
+
+impl Op {
+    fn new(handler: Handler, a: i32, b: i32) -> Op {
+        Op { handler, a, b }
+    }
+}
+
+/// An op that may execute several instructions.
+#[derive(Clone, Copy)]
+struct FusedOp {
+    op: Op,
+    steps: u64,
+}
+
+/// The most instructions a fused op executes.
+const MAX_FUSED_STEPS: u64 = 3;
+
+pub struct DecodedProgram<'a> {
+    program: &'a Program,
+    /// The op of every instruction, and a `halt` op after them.
+    ops: Vec<Op>,
+    /// The same, with the first instruction of fused sequences replaced by
+    /// an op that executes the whole sequence.
+    fused: Vec<FusedOp>,
+}
+
+impl<'a> DecodedProgram<'a> {
+    pub fn new(program: &'a Program) -> DecodedProgram<'a> {
+        let mut decoded = DecodedProgram::unfused(program);
+        if program.word_size != WordSize::Bits32 {
+            return decoded;
+        }
+        for pc in 0..program.instructions.len() {
+            if let Some(fused) = fuse(&program.instructions[pc..], decoded.ops[pc]) {
+                decoded.fused[pc] = fused;
+            }
+        }
+        decoded
+    }
+
+    /// Like `new`, but without fusing instructions, to measure what it gains.
+    pub fn unfused(program: &'a Program) -> DecodedProgram<'a> {
+        // The handlers work on 32-bit words, so 64-bit programs are executed
+        // by `Program::step` throughout
+        let mut ops: Vec<Op> = match program.word_size {
+            WordSize::Bits32 => program.instructions.iter().map(decode).collect(),
+            WordSize::Bits64 => vec![Op::new(fallback, 0, 0); program.instructions.len()],
+        };
+        ops.push(Op::new(halt, 0, 0));
+        let fused = ops.iter().map(|&op| FusedOp { op, steps: 1 }).collect();
+
+        DecodedProgram {
+            program,
+            ops,
+            fused,
+        }
+    }
+
+    /// Runs the program from the current `eip` until it ends, with the same
+    /// effect as calling `Program::step` until it returns `false`.
+    pub fn run(self: &DecodedProgram<'a>, memory: &mut Memory) -> Result<(), ExecutionError> {
+        while self.run_steps(memory, u64::MAX)? {}
+        Ok(())
+    }
+
+    /// Executes at most `steps` instructions, and returns whether the
+    /// program is still running.
+    pub fn run_steps(
+        self: &DecodedProgram<'a>,
+        memory: &mut Memory,
+        steps: u64,
+    ) -> Result<bool, ExecutionError> {
+        let eip = memory.registers[EIP];
+        let mut pc = match self.target(eip) {
+            Ok(pc) => pc,
+            Err(stop) => return Err(self.error(stop, memory, 0)),
+        };
+
+        macro_rules! execute {
+            ($op:expr) => {
+                match ($op.handler)(self, memory, $op, pc) {
+                    Ok(next) => pc = next,
+                    Err(Stop::Halt) => {
+                        memory.registers[EIP] = pc as i64;
+                        return Ok(false);
+                    }
+                    Err(stop) => return Err(self.error(stop, memory, pc)),
+                }
+            };
+        }
+
+        let mut remaining = steps;
+        while remaining >= MAX_FUSED_STEPS {
+            let fused = &self.fused[pc];
+            execute!(&fused.op);
+            remaining -= fused.steps;
+        }
+        for _ in 0..remaining {
+            execute!(&self.ops[pc]);
+        }
+
+        memory.registers[EIP] = pc as i64;
+        Ok(true)
+    }
+
+    fn error(
+        self: &DecodedProgram<'a>,
+        stop: Stop,
+        memory: &mut Memory,
+        pc: usize,
+    ) -> ExecutionError {
+        match stop {
+            Stop::Error(kind) => {
+                memory.registers[EIP] = pc as i64;
+                self.program.execution_error(kind, memory)
+            }
+            Stop::OutOfRange(target) => {
+                memory.registers[EIP] = target;
+                self.program
+                    .execution_error(ExecutionErrorKind::InstructionOutOfRange(target), memory)
+            }
+            Stop::Failed(error) => *error,
+            Stop::Halt => unreachable!("halting is not an error"),
+        }
+    }
+
+    /// Checks that `target` is an instruction index or the end of the
+    /// program.
+    #[inline(always)]
+    fn target(self: &DecodedProgram<'a>, target: i64) -> Result<usize, Stop> {
+        if target >= 0 && (target as usize) < self.ops.len() {
+            Ok(target as usize)
+        } else {
+            Err(Stop::OutOfRange(target))
+        }
+    }
+}
+
+trait Operand {
+    fn read(memory: &Memory, operand: i32) -> Result<i32, ExecutionErrorKind>;
+}
+
+trait Place: Operand {
+    /// Fails like `read` would, without reading.
+    fn check(memory: &Memory, operand: i32) -> Result<(), ExecutionErrorKind>;
+
+    /// Must only be called after a successful `check` or `read`.
+    fn write(memory: &mut Memory, operand: i32, value: i32);
+}
+
+/// A register, given by its number.
+struct Reg;
+
+/// An immediate value.
+struct Imm;
+
+/// A memory word, given by its address.
+struct Mem;
+
+impl Operand for Reg {
+    #[inline(always)]
+    fn read(memory: &Memory, operand: i32) -> Result<i32, ExecutionErrorKind> {
+        Ok(memory.registers[operand as usize] as i32)
+    }
+}
+
+impl Place for Reg {
+    #[inline(always)]
+    fn check(_: &Memory, _: i32) -> Result<(), ExecutionErrorKind> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn write(memory: &mut Memory, operand: i32, value: i32) {
+        memory.registers[operand as usize] = value.into();
+    }
+}
+
+impl Operand for Imm {
+    #[inline(always)]
+    fn read(_: &Memory, operand: i32) -> Result<i32, ExecutionErrorKind> {
+        Ok(operand)
+    }
+}
+
+impl Operand for Mem {
+    #[inline(always)]
+    fn read(memory: &Memory, operand: i32) -> Result<i32, ExecutionErrorKind> {
+        match usize::try_from(operand)
+            .ok()
+            .and_then(|a| memory.mem_space.get(a))
+        {
+            Some(value) => Ok(*value as i32),
+            None => Err(ExecutionErrorKind::DataAddressOutOfRange(operand.into())),
+        }
+    }
+}
+
+impl Place for Mem {
+    #[inline(always)]
+    fn check(memory: &Memory, operand: i32) -> Result<(), ExecutionErrorKind> {
+        Mem::read(memory, operand).map(|_| ())
+    }
+
+    #[inline(always)]
+    fn write(memory: &mut Memory, operand: i32, value: i32) {
+        memory.mem_space[operand as usize] = value.into();
+    }
+}
+
+trait BinaryOp {
+    /// The result and the new flags.
+    fn apply(value1: i32, value2: i32, flags: i32) -> Result<(i32, i32), ExecutionErrorKind>;
+}
+
+macro_rules! binary_ops {
+    ($($name:ident($a:ident, $b:ident, $flags:ident) => $value:expr;)*) => {
+        $(
+            struct $name;
+
+            impl BinaryOp for $name {
+                #[inline(always)]
+                fn apply(
+                    $a: i32,
+                    $b: i32,
+                    $flags: i32,
+                ) -> Result<(i32, i32), ExecutionErrorKind> {
+                    $value
+                }
+            }
+        )*
+    };
+}
+
+binary_ops! {
+    Add(a, b, _flags) => Ok(add(a, b));
+    Sub(a, b, _flags) => Ok(subtract(a, b));
+    Mul(a, b, _flags) => Ok(multiply(a, b));
+    Div(a, b, flags) => Ok((divide(a, b)?, flags));
+    Xor(a, b, _flags) => Ok(logic(a ^ b));
+    Or(a, b, _flags) => Ok(logic(a | b));
+    And(a, b, _flags) => Ok(logic(a & b));
+    Shl(a, b, _flags) => Ok(shift_left(a, b));
+    Shr(a, b, _flags) => Ok(shift_right(a, b));
+    Sar(a, b, _flags) => Ok(shift_arithmetic(a, b));
+    Rol(a, b, _flags) => Ok(rotate_left(a, b));
+    Ror(a, b, _flags) => Ok(rotate_right(a, b));
+}
+
+/// The bit counting instructions, which do not set the flags.
+trait CountOp {
+    fn count(value: i32) -> i32;
+}
+
+macro_rules! count_ops {
+    ($($name:ident($value:ident) => $count:expr;)*) => {
+        $(
+            struct $name;
+
+            impl CountOp for $name {
+                #[inline(always)]
+                fn count($value: i32) -> i32 {
+                    $count
+                }
+            }
+        )*
+    };
+}
+
+count_ops! {
+    Popcnt(value) => value.count_ones() as i32;
+    Clz(value) => value.leading_zeros() as i32;
+    Ctz(value) => value.trailing_zeros() as i32;
+}
+
+trait Condition {
+    fn holds(flags: i32) -> bool;
+}
+
+macro_rules! conditions {
+    ($($name:ident($flags:ident) => $holds:expr;)*) => {
+        $(
+            struct $name;
+
+            impl Condition for $name {
+                #[inline(always)]
+                fn holds($flags: i32) -> bool {
+                    $holds
+                }
+            }
+        )*
+    };
+}
+
+conditions! {
+    Always(_flags) => true;
+    Equal(flags) => flags & 0x1 != 0;
+    NotEqual(flags) => flags & 0x1 == 0;
+    Greater(flags) => flags & 0x2 != 0;
+    GreaterOrEqual(flags) => flags & 0x3 != 0;
+    Less(flags) => flags & 0x3 == 0;
+    LessOrEqual(flags) => flags & 0x2 == 0;
+    Above(flags) => flags & (FLAG_CARRY | FLAG_ZERO) == 0;
+    AboveOrEqual(flags) => flags & FLAG_CARRY == 0;
+    Below(flags) => flags & FLAG_CARRY != 0;
+    BelowOrEqual(flags) => flags & (FLAG_CARRY | FLAG_ZERO) != 0;
+    Sign(flags) => flags & FLAG_SIGN != 0;
+    NotSign(flags) => flags & FLAG_SIGN == 0;
+    Overflow(flags) => flags & FLAG_OVERFLOW != 0;
+    NotOverflow(flags) => flags & FLAG_OVERFLOW == 0;
+}
+
+/// The address `push` writes to.
+#[inline(always)]
+fn push_address(memory: &Memory) -> Result<usize, ExecutionErrorKind> {
+    let address = memory.registers[ESP] - 1;
+    if address < 0 || address as usize >= memory.mem_space.len() {
+        return Err(ExecutionErrorKind::DataAddressOutOfRange(address));
+    }
+    Ok(address as usize)
+}
+
+#[inline(always)]
+fn pop_value(memory: &mut Memory) -> Result<i32, ExecutionErrorKind> {
+    let address = memory.registers[ESP];
+    if address < 0 || address as usize >= memory.mem_space.len() {
+        return Err(ExecutionErrorKind::DataAddressOutOfRange(address));
+    }
+    let value = memory.mem_space[address as usize] as i32;
+    memory.registers[ESP] = address + 1;
+    Ok(value)
+}
+
+fn halt(_: &DecodedProgram, _: &mut Memory, _: &Op, _: usize) -> Result<usize, Stop> {
+    Err(Stop::Halt)
+}
+
+fn nop(_: &DecodedProgram, _: &mut Memory, _: &Op, pc: usize) -> Result<usize, Stop> {
+    Ok(pc + 1)
+}
+
+/// Executes the instruction with `Program::step`.
+fn fallback(p: &DecodedProgram, m: &mut Memory, _: &Op, pc: usize) -> Result<usize, Stop> {
+    m.registers[EIP] = pc as i64;
+    match p.program.step(m) {
+        Ok(_) => p.target(m.registers[EIP]),
+        Err(error) => Err(Stop::Failed(Box::new(error))),
+    }
+}
+
+fn mov<T: Place, S: Operand>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    T::check(m, op.a)?;
+    let value = S::read(m, op.b)?;
+    T::write(m, op.a, value);
+    Ok(pc + 1)
+}
+
+fn binary<T: Place, S: Operand, F: BinaryOp>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let (value, flags) = F::apply(T::read(m, op.a)?, S::read(m, op.b)?, m.flags)?;
+    T::write(m, op.a, value);
+    m.flags = flags;
+    Ok(pc + 1)
+}
+
+fn inc<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let (value, flags) = increment(T::read(m, op.a)?, m.flags);
+    T::write(m, op.a, value);
+    m.flags = flags;
+    Ok(pc + 1)
+}
+
+fn dec<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let (value, flags) = decrement(T::read(m, op.a)?, m.flags);
+    T::write(m, op.a, value);
+    m.flags = flags;
+    Ok(pc + 1)
+}
+
+fn not<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let value = !T::read(m, op.a)?;
+    T::write(m, op.a, value);
+    Ok(pc + 1)
+}
+
+fn neg<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let (value, flags) = negate(T::read(m, op.a)?);
+    T::write(m, op.a, value);
+    m.flags = flags;
+    Ok(pc + 1)
+}
+
+fn bswap<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let value = T::read(m, op.a)?.swap_bytes();
+    T::write(m, op.a, value);
+    Ok(pc + 1)
+}
+
+fn count<T: Place, S: Operand, F: CountOp>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    T::check(m, op.a)?;
+    let value = F::count(S::read(m, op.b)?);
+    T::write(m, op.a, value);
+    Ok(pc + 1)
+}
+
+fn xchg<T1: Place, T2: Place>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let value1 = T1::read(m, op.a)?;
+    let value2 = T2::read(m, op.b)?;
+    T1::write(m, op.a, value2);
+    T2::write(m, op.b, value1);
+    Ok(pc + 1)
+}
+
+/// Checks the target even if nothing is moved, like `Program::step`.
+fn cmov<T: Place, S: Operand, C: Condition>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let value = S::read(m, op.b)?;
+    T::check(m, op.a)?;
+    if C::holds(m.flags) {
+        T::write(m, op.a, value);
+    }
+    Ok(pc + 1)
+}
+
+fn rem<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    T::check(m, op.a)?;
+    T::write(m, op.a, m.remainder as i32);
+    Ok(pc + 1)
+}
+
+fn modulo<S1: Operand, S2: Operand>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    m.remainder = remainder(S1::read(m, op.a)?, S2::read(m, op.b)?)?.into();
+    Ok(pc + 1)
+}
+
+fn cmp<S1: Operand, S2: Operand>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    m.flags = compare(S1::read(m, op.a)?, S2::read(m, op.b)?);
+    Ok(pc + 1)
+}
+
+fn test<S1: Operand, S2: Operand>(
+    _: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    m.flags = logic(S1::read(m, op.a)? & S2::read(m, op.b)?).1;
+    Ok(pc + 1)
+}
+
+fn push<S: Operand>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let address = push_address(m)?;
+    m.mem_space[address] = S::read(m, op.a)?.into();
+    m.registers[ESP] = address as i64;
+    Ok(pc + 1)
+}
+
+fn pop<T: Place>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    T::check(m, op.a)?;
+    let value = pop_value(m)?;
+    T::write(m, op.a, value);
+    Ok(pc + 1)
+}
+
+fn pushf(_: &DecodedProgram, m: &mut Memory, _: &Op, pc: usize) -> Result<usize, Stop> {
+    let address = push_address(m)?;
+    m.mem_space[address] = m.flags.into();
+    m.registers[ESP] = address as i64;
+    Ok(pc + 1)
+}
+
+fn popf(_: &DecodedProgram, m: &mut Memory, _: &Op, pc: usize) -> Result<usize, Stop> {
+    m.flags = pop_value(m)?;
+    Ok(pc + 1)
+}
+
+fn jump<S: Operand, C: Condition>(
+    p: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    if C::holds(m.flags) {
+        p.target(S::read(m, op.a)?.into())
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn loop_jump<S: Operand>(
+    p: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let ecx = Register::Ecx as usize;
+    let count = (m.registers[ecx] as i32).wrapping_sub(1);
+    m.registers[ecx] = count.into();
+    if count != 0 {
+        p.target(S::read(m, op.a)?.into())
+    } else {
+        Ok(pc + 1)
+    }
+}
+
+fn call<S: Operand>(p: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let address = push_address(m)?;
+    m.mem_space[address] = pc as i64 + 1;
+    m.registers[ESP] = address as i64;
+
+    let callee = S::read(m, op.a)?;
+    m.call_stack.push(Frame {
+        callee,
+        call_site: pc as i32,
+        stack_pointer: address as i32,
+    });
+    p.target(callee.into())
+}
+
+fn ret(p: &DecodedProgram, m: &mut Memory, _: &Op, _: usize) -> Result<usize, Stop> {
+    if m.strict_returns {
+        let return_address = Mem::read(m, m.registers[ESP] as i32)?;
+        if m.call_stack.last().map(|f| f.call_site + 1) != Some(return_address) {
+            return Err(ExecutionErrorKind::MismatchedReturn(return_address.into()).into());
+        }
+    }
+
+    let return_address = pop_value(m)?;
+    m.call_stack.pop();
+    p.target(return_address.into())
+}
+
+fn prn<S: Operand>(_: &DecodedProgram, m: &mut Memory, op: &Op, pc: usize) -> Result<usize, Stop> {
+    let value = S::read(m, op.a)?;
+    writeln!(m.output, "{}", value).expect("failed printing to output");
+    Ok(pc + 1)
+}
+
+/// `cmp` followed by a conditional jump to an immediate.
+fn cmp_jump<S1: Operand, S2: Operand, C: Condition>(
+    p: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    m.flags = compare(S1::read(m, op.a)?, S2::read(m, op.b)?);
+    if C::holds(m.flags) {
+        p.target(p.ops[pc + 1].a.into())
+    } else {
+        Ok(pc + 2)
+    }
+}
+
+/// `push` followed by `pop` to a register, which leaves `esp` as it was.
+fn push_pop<S: Operand>(
+    p: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let address = push_address(m)?;
+    let value = S::read(m, op.a)?;
+    m.mem_space[address] = value.into();
+    m.registers[p.ops[pc + 1].a as usize] = value.into();
+    Ok(pc + 2)
+}
+
+/// `inc` of a register, `cmp` of registers or immediates, which cannot fail,
+/// and a conditional jump to an immediate.
+fn inc_cmp_jump<S1: Operand, S2: Operand, C: Condition>(
+    p: &DecodedProgram,
+    m: &mut Memory,
+    op: &Op,
+    pc: usize,
+) -> Result<usize, Stop> {
+    let reg = op.a as usize;
+    m.registers[reg] = (m.registers[reg] as i32).wrapping_add(1).into();
+
+    let cmp = &p.ops[pc + 1];
+    m.flags = compare(S1::read(m, cmp.a)?, S2::read(m, cmp.b)?);
+    if C::holds(m.flags) {
+        p.target(p.ops[pc + 2].a.into())
+    } else {
+        Ok(pc + 3)
+    }
+}
+
+/// Whether the instruction reads or writes `eip` as an operand.
+pub(crate) fn uses_eip(instruction: &Instruction) -> bool {
+    let target = |t: &Target| *t == Target::Register(Register::Eip);
+    let source = |s: &Source| *s == Source::Register(Register::Eip);
+
+    match instruction {
+        Instruction::Mov(t, s)
+        | Instruction::Add(t, s)
+        | Instruction::Sub(t, s)
+        | Instruction::Mul(t, s)
+        | Instruction::Div(t, s)
+        | Instruction::Xor(t, s)
+        | Instruction::Or(t, s)
+        | Instruction::And(t, s)
+        | Instruction::Shl(t, s)
+        | Instruction::Shr(t, s)
+        | Instruction::Sar(t, s)
+        | Instruction::Rol(t, s)
+        | Instruction::Ror(t, s)
+        | Instruction::Cmov(_, t, s)
+        | Instruction::Popcnt(t, s)
+        | Instruction::Clz(t, s)
+        | Instruction::Ctz(t, s)
+        | Instruction::Ld(_, t, s)
+        | Instruction::Lds(_, t, s) => target(t) || source(s),
+        Instruction::Pop(t)
+        | Instruction::Inc(t)
+        | Instruction::Dec(t)
+        | Instruction::Rem(t)
+        | Instruction::Not(t)
+        | Instruction::Neg(t)
+        | Instruction::Bswap(t) => target(t),
+        Instruction::Xchg(t1, t2) => target(t1) || target(t2),
+        Instruction::Itof(_, s) => source(s),
+        Instruction::Ftoi(t, _) => target(t),
+        Instruction::Vld(_, s) | Instruction::Vst(s, _) | Instruction::Vins(_, _, s) => source(s),
+        Instruction::Vext(t, ..) | Instruction::Vsum(t, _) => target(t),
+        Instruction::Vop(..) => false,
+        Instruction::Spawn(t, s1, s2) | Instruction::Cas(t, s1, s2) => {
+            target(t) || source(s1) || source(s2)
+        }
+        Instruction::Join(s) => source(s),
+        Instruction::Xadd(t, s) | Instruction::Recv(t, s) => target(t) || source(s),
+        Instruction::Send(s1, s2) => source(s1) || source(s2),
+        Instruction::Fld(..)
+        | Instruction::Fst(..)
+        | Instruction::Fadd(..)
+        | Instruction::Fsub(..)
+        | Instruction::Fmul(..)
+        | Instruction::Fdiv(..)
+        | Instruction::Fneg(_)
+        | Instruction::Fabs(_)
+        | Instruction::Fsqrt(_)
+        | Instruction::Fcmp(..)
+        | Instruction::Fprn(_) => false,
+        Instruction::Lea(t, address) => {
+            target(t)
+                || address.base == Some(Register::Eip)
+                || address
+                    .index
+                    .is_some_and(|(index, _)| index == Register::Eip)
+        }
+        Instruction::Push(s)
+        | Instruction::Jmp(s)
+        | Instruction::Call(s)
+        | Instruction::Je(s)
+        | Instruction::Jne(s)
+        | Instruction::Jg(s)
+        | Instruction::Jge(s)
+        | Instruction::Jl(s)
+        | Instruction::Jle(s)
+        | Instruction::Ja(s)
+        | Instruction::Jae(s)
+        | Instruction::Jb(s)
+        | Instruction::Jbe(s)
+        | Instruction::Js(s)
+        | Instruction::Jns(s)
+        | Instruction::Jo(s)
+        | Instruction::Jno(s)
+        | Instruction::Loop(s)
+        | Instruction::Prn(s) => source(s),
+        Instruction::Mod(s1, s2)
+        | Instruction::Cmp(s1, s2)
+        | Instruction::Test(s1, s2)
+        | Instruction::St(_, s1, s2) => source(s1) || source(s2),
+        Instruction::Nop
+        | Instruction::Int
+        | Instruction::Pushf
+        | Instruction::Popf
+        | Instruction::Ret
+        | Instruction::Fence
+        | Instruction::Yield
+        | Instruction::Native(..) => false,
+    }
+}
+
+// The decoding macros pick the handler instance for the kinds of the
+// operands. Extra type arguments are passed after the operand kinds.
+
+macro_rules! target {
+    ($handler:ident $(, $extra:ty)*; $target:expr) => {
+        match $target {
+            Target::Register(t) => Op::new($handler::<Reg $(, $extra)*>, t as i32, 0),
+            Target::Address(t) => Op::new($handler::<Mem $(, $extra)*>, t, 0),
+        }
+    };
+}
+
+macro_rules! source {
+    ($handler:ident $(, $extra:ty)*; $source:expr) => {
+        match $source {
+            Source::Register(s) => Op::new($handler::<Reg $(, $extra)*>, s as i32, 0),
+            Source::Value(s) => Op::new($handler::<Imm $(, $extra)*>, s as i32, 0),
+            Source::Address(s) => Op::new($handler::<Mem $(, $extra)*>, s, 0),
+        }
+    };
+}
+
+macro_rules! target_source {
+    ($handler:ident $(, $extra:ty)*; $target:expr, $source:expr) => {
+        match ($target, $source) {
+            (Target::Register(t), Source::Register(s)) => {
+                Op::new($handler::<Reg, Reg $(, $extra)*>, t as i32, s as i32)
+            }
+            (Target::Register(t), Source::Value(s)) => {
+                Op::new($handler::<Reg, Imm $(, $extra)*>, t as i32, s as i32)
+            }
+            (Target::Register(t), Source::Address(s)) => {
+                Op::new($handler::<Reg, Mem $(, $extra)*>, t as i32, s)
+            }
+            (Target::Address(t), Source::Register(s)) => {
+                Op::new($handler::<Mem, Reg $(, $extra)*>, t, s as i32)
+            }
+            (Target::Address(t), Source::Value(s)) => {
+                Op::new($handler::<Mem, Imm $(, $extra)*>, t, s as i32)
+            }
+            (Target::Address(t), Source::Address(s)) => Op::new($handler::<Mem, Mem $(, $extra)*>, t, s),
+        }
+    };
+}
+
+macro_rules! sources {
+    ($handler:ident $(, $extra:ty)*; $source1:expr, $source2:expr) => {{
+        let (a, b) = (operand($source1), operand($source2));
+        match ($source1, $source2) {
+            (Source::Register(_), Source::Register(_)) => {
+                Op::new($handler::<Reg, Reg $(, $extra)*>, a, b)
+            }
+            (Source::Register(_), Source::Value(_)) => {
+                Op::new($handler::<Reg, Imm $(, $extra)*>, a, b)
+            }
+            (Source::Register(_), Source::Address(_)) => {
+                Op::new($handler::<Reg, Mem $(, $extra)*>, a, b)
+            }
+            (Source::Value(_), Source::Register(_)) => {
+                Op::new($handler::<Imm, Reg $(, $extra)*>, a, b)
+            }
+            (Source::Value(_), Source::Value(_)) => Op::new($handler::<Imm, Imm $(, $extra)*>, a, b),
+            (Source::Value(_), Source::Address(_)) => {
+                Op::new($handler::<Imm, Mem $(, $extra)*>, a, b)
+            }
+            (Source::Address(_), Source::Register(_)) => {
+                Op::new($handler::<Mem, Reg $(, $extra)*>, a, b)
+            }
+            (Source::Address(_), Source::Value(_)) => {
+                Op::new($handler::<Mem, Imm $(, $extra)*>, a, b)
+            }
+            (Source::Address(_), Source::Address(_)) => {
+                Op::new($handler::<Mem, Mem $(, $extra)*>, a, b)
+            }
+        }
+    }};
+}
+
+/// Picks the handler instance for the condition of a conditional jump, or
+/// returns `None` for other instructions.
+macro_rules! condition {
+    ($decode:ident!($handler:ident; $($operand:expr),*), $jump:expr) => {
+        match $jump {
+            Instruction::Je(_) => $decode!($handler, Equal; $($operand),*),
+            Instruction::Jne(_) => $decode!($handler, NotEqual; $($operand),*),
+            Instruction::Jg(_) => $decode!($handler, Greater; $($operand),*),
+            Instruction::Jge(_) => $decode!($handler, GreaterOrEqual; $($operand),*),
+            Instruction::Jl(_) => $decode!($handler, Less; $($operand),*),
+            Instruction::Jle(_) => $decode!($handler, LessOrEqual; $($operand),*),
+            Instruction::Ja(_) => $decode!($handler, Above; $($operand),*),
+            Instruction::Jae(_) => $decode!($handler, AboveOrEqual; $($operand),*),
+            Instruction::Jb(_) => $decode!($handler, Below; $($operand),*),
+            Instruction::Jbe(_) => $decode!($handler, BelowOrEqual; $($operand),*),
+            Instruction::Js(_) => $decode!($handler, Sign; $($operand),*),
+            Instruction::Jns(_) => $decode!($handler, NotSign; $($operand),*),
+            Instruction::Jo(_) => $decode!($handler, Overflow; $($operand),*),
+            Instruction::Jno(_) => $decode!($handler, NotOverflow; $($operand),*),
+            _ => return None,
+        }
+    };
+}
+
+fn operand(source: Source) -> i32 {
+    match source {
+        Source::Register(reg) => reg as i32,
+        Source::Value(value) => value as i32,
+        Source::Address(address) => address,
+    }
+}
+
+fn decode(instruction: &Instruction) -> Op {
+    if uses_eip(instruction) {
+        return Op::new(fallback, 0, 0);
+    }
+
+    match *instruction {
+        Instruction::Nop => Op::new(nop, 0, 0),
+        Instruction::Int => Op::new(fallback, 0, 0),
+        Instruction::Mov(t, s) => target_source!(mov; t, s),
+        Instruction::Push(s) => source!(push; s),
+        Instruction::Pop(t) => target!(pop; t),
+        Instruction::Pushf => Op::new(pushf, 0, 0),
+        Instruction::Popf => Op::new(popf, 0, 0),
+        Instruction::Inc(t) => target!(inc; t),
+        Instruction::Dec(t) => target!(dec; t),
+        Instruction::Add(t, s) => target_source!(binary, Add; t, s),
+        Instruction::Sub(t, s) => target_source!(binary, Sub; t, s),
+        Instruction::Mul(t, s) => target_source!(binary, Mul; t, s),
+        Instruction::Div(t, s) => target_source!(binary, Div; t, s),
+        Instruction::Mod(s1, s2) => sources!(modulo; s1, s2),
+        Instruction::Rem(t) => target!(rem; t),
+        Instruction::Not(t) => target!(not; t),
+        Instruction::Xor(t, s) => target_source!(binary, Xor; t, s),
+        Instruction::Or(t, s) => target_source!(binary, Or; t, s),
+        Instruction::And(t, s) => target_source!(binary, And; t, s),
+        Instruction::Shl(t, s) => target_source!(binary, Shl; t, s),
+        Instruction::Shr(t, s) => target_source!(binary, Shr; t, s),
+        Instruction::Sar(t, s) => target_source!(binary, Sar; t, s),
+        Instruction::Rol(t, s) => target_source!(binary, Rol; t, s),
+        Instruction::Ror(t, s) => target_source!(binary, Ror; t, s),
+        Instruction::Neg(t) => target!(neg; t),
+        Instruction::Xchg(t1, t2) => match (t1, t2) {
+            (Target::Register(a), Target::Register(b)) => {
+                Op::new(xchg::<Reg, Reg>, a as i32, b as i32)
+            }
+            (Target::Register(a), Target::Address(b)) => Op::new(xchg::<Reg, Mem>, a as i32, b),
+            (Target::Address(a), Target::Register(b)) => Op::new(xchg::<Mem, Reg>, a, b as i32),
+            (Target::Address(a), Target::Address(b)) => Op::new(xchg::<Mem, Mem>, a, b),
+        },
+        Instruction::Lea(..) | Instruction::Ld(..) | Instruction::Lds(..) | Instruction::St(..) => {
+            Op::new(fallback, 0, 0)
+        }
+        Instruction::Fld(..)
+        | Instruction::Fst(..)
+        | Instruction::Fadd(..)
+        | Instruction::Fsub(..)
+        | Instruction::Fmul(..)
+        | Instruction::Fdiv(..)
+        | Instruction::Fneg(_)
+        | Instruction::Fabs(_)
+        | Instruction::Fsqrt(_)
+        | Instruction::Fcmp(..)
+        | Instruction::Itof(..)
+        | Instruction::Ftoi(..)
+        | Instruction::Fprn(_)
+        | Instruction::Vld(..)
+        | Instruction::Vst(..)
+        | Instruction::Vop(..)
+        | Instruction::Vins(..)
+        | Instruction::Vext(..)
+        | Instruction::Vsum(..)
+        | Instruction::Spawn(..)
+        | Instruction::Join(_)
+        | Instruction::Cas(..)
+        | Instruction::Xadd(..)
+        | Instruction::Fence
+        | Instruction::Yield
+        | Instruction::Send(..)
+        | Instruction::Recv(..)
+        | Instruction::Native(..) => Op::new(fallback, 0, 0),
+        Instruction::Test(s1, s2) => sources!(test; s1, s2),
+        Instruction::Cmov(condition, t, s) => {
+            use crate::instruction::Condition as C;
+            match condition {
+                C::Equal => target_source!(cmov, Equal; t, s),
+                C::NotEqual => target_source!(cmov, NotEqual; t, s),
+                C::Greater => target_source!(cmov, Greater; t, s),
+                C::GreaterOrEqual => target_source!(cmov, GreaterOrEqual; t, s),
+                C::Less => target_source!(cmov, Less; t, s),
+                C::LessOrEqual => target_source!(cmov, LessOrEqual; t, s),
+                C::Above => target_source!(cmov, Above; t, s),
+                C::AboveOrEqual => target_source!(cmov, AboveOrEqual; t, s),
+                C::Below => target_source!(cmov, Below; t, s),
+                C::BelowOrEqual => target_source!(cmov, BelowOrEqual; t, s),
+                C::Sign => target_source!(cmov, Sign; t, s),
+                C::NotSign => target_source!(cmov, NotSign; t, s),
+                C::Overflow => target_source!(cmov, Overflow; t, s),
+                C::NotOverflow => target_source!(cmov, NotOverflow; t, s),
+            }
+        }
+        Instruction::Popcnt(t, s) => target_source!(count, Popcnt; t, s),
+        Instruction::Clz(t, s) => target_source!(count, Clz; t, s),
+        Instruction::Ctz(t, s) => target_source!(count, Ctz; t, s),
+        Instruction::Bswap(t) => target!(bswap; t),
+        Instruction::Cmp(s1, s2) => sources!(cmp; s1, s2),
+        Instruction::Jmp(s) => source!(jump, Always; s),
+        Instruction::Call(s) => source!(call; s),
+        Instruction::Ret => Op::new(ret, 0, 0),
+        Instruction::Je(s) => source!(jump, Equal; s),
+        Instruction::Jne(s) => source!(jump, NotEqual; s),
+        Instruction::Jg(s) => source!(jump, Greater; s),
+        Instruction::Jge(s) => source!(jump, GreaterOrEqual; s),
+        Instruction::Jl(s) => source!(jump, Less; s),
+        Instruction::Jle(s) => source!(jump, LessOrEqual; s),
+        Instruction::Ja(s) => source!(jump, Above; s),
+        Instruction::Jae(s) => source!(jump, AboveOrEqual; s),
+        Instruction::Jb(s) => source!(jump, Below; s),
+        Instruction::Jbe(s) => source!(jump, BelowOrEqual; s),
+        Instruction::Js(s) => source!(jump, Sign; s),
+        Instruction::Jns(s) => source!(jump, NotSign; s),
+        Instruction::Jo(s) => source!(jump, Overflow; s),
+        Instruction::Jno(s) => source!(jump, NotOverflow; s),
+        Instruction::Loop(s) => source!(loop_jump; s),
+        Instruction::Prn(s) => source!(prn; s),
+    }
+}
+
+/// The fused op for the sequence at the start of `instructions`, if any,
+/// given the op of its first instruction.
+fn fuse(instructions: &[Instruction], op: Op) -> Option<FusedOp> {
+    if instructions
+        .iter()
+        .take(MAX_FUSED_STEPS as usize)
+        .any(uses_eip)
+    {
+        return None;
+    }
+    let conditional_jump = |i: usize| match instructions.get(i) {
+        Some(
+            jump @ (Instruction::Je(Source::Value(_))
+            | Instruction::Jne(Source::Value(_))
+            | Instruction::Jg(Source::Value(_))
+            | Instruction::Jge(Source::Value(_))
+            | Instruction::Jl(Source::Value(_))
+            | Instruction::Jle(Source::Value(_))
+            | Instruction::Ja(Source::Value(_))
+            | Instruction::Jae(Source::Value(_))
+            | Instruction::Jb(Source::Value(_))
+            | Instruction::Jbe(Source::Value(_))
+            | Instruction::Js(Source::Value(_))
+            | Instruction::Jns(Source::Value(_))
+            | Instruction::Jo(Source::Value(_))
+            | Instruction::Jno(Source::Value(_))),
+        ) => Some(*jump),
+        _ => None,
+    };
+    let cannot_fail = |source: Source| !matches!(source, Source::Address(_));
+
+    let (handler, steps) = match *instructions {
+        [Instruction::Inc(Target::Register(_)), Instruction::Cmp(s1, s2), ..]
+            if cannot_fail(s1) && cannot_fail(s2) && conditional_jump(2).is_some() =>
+        {
+            let jump = conditional_jump(2)?;
+            (condition!(sources!(inc_cmp_jump; s1, s2), jump).handler, 3)
+        }
+        [Instruction::Cmp(s1, s2), ..] => {
+            let jump = conditional_jump(1)?;
+            (condition!(sources!(cmp_jump; s1, s2), jump).handler, 2)
+        }
+        [Instruction::Push(s), Instruction::Pop(Target::Register(_)), ..] => {
+            (source!(push_pop; s).handler, 2)
+        }
+        _ => return None,
+    };
+
+    Some(FusedOp {
+        op: Op::new(handler, op.a, op.b),
+        steps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_both(
+        source: &str,
+    ) -> (
+        Memory,
+        Memory,
+        Result<(), ExecutionError>,
+        Result<(), ExecutionError>,
+    ) {
+        let program = Program::load(source.to_owned()).unwrap();
+
+        let mut stepped = program.initialize();
+        stepped.output = Box::new(std::io::sink());
+        let step_result = loop {
+            match program.step(&mut stepped) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let mut decoded = program.initialize();
+        decoded.output = Box::new(std::io::sink());
+        let decoded_result = DecodedProgram::new(&program).run(&mut decoded);
+
+        (stepped, decoded, step_result, decoded_result)
+    }
+
+    fn assert_same(source: &str) {
+        let (stepped, decoded, step_result, decoded_result) = run_both(source);
+
+        assert_eq!(decoded_result, step_result, "{}", source);
+        assert_eq!(decoded.registers, stepped.registers, "{}", source);
+        assert_eq!(decoded.flags, stepped.flags, "{}", source);
+        assert_eq!(decoded.remainder, stepped.remainder, "{}", source);
+        assert_eq!(decoded.call_stack, stepped.call_stack, "{}", source);
+        assert!(decoded.mem_space == stepped.mem_space, "{}", source);
+    }
+
+    #[test]
+    fn operand_forms_behave_like_step() {
+        for source in &[
+            "mov eax, 5\nmov [10], eax\nmov ebx, [10]\nmov [11], [10]\nmov [12], 7",
+            "mov eax, 7\nadd eax, 3\nsub [5], eax\nmul eax, [5]\ndiv eax, -3\nxor eax, 0xff",
+            "mov eax, 1\nshl eax, 33\nmov ebx, -8\nshr ebx, 1\nor [3], ebx\nand [3], 12",
+            "mov eax, 0x7fffffff\ninc eax\ndec [4]\nnot [4]\nnot eax",
+            "mod 17, 5\nrem eax\nmod [1], -3\nrem [2]",
+            "push 1\npush [0]\npushf\ncmp 2, 1\npopf\npop eax\npop [9]",
+            "cmp 1, 2\njl 3\nprn 1\ncmp eax, ebx\nje 6\nprn 2\njge 8\nprn 3\nprn 4",
+            "mov eax, 0x7fffffff\nadd eax, 1\npushf\nsub eax, 1\npushf\ninc eax\npushf\nmul eax, 2\npushf",
+            "mov ebx, -1\nadd ebx, 1\npushf\ndec ebx\npushf\nshl ebx, 31\npushf\nshr ebx, 32\npushf\nxor ebx, ebx\npushf",
+            "cmp -1, 1\nja 3\nprn 1\ncmp 1, -1\njb 6\njbe 6\nprn 2\nmov eax, -5\nor eax, eax\njs 10\nprn 3",
+            "mov eax, -2147483648\nsub eax, 1\njo 3\nprn 4\njno 5\njns 6\nprn 5\ncmp 2, 2\njae 9\nprn 6",
+            "mov eax, 5\nneg eax\npushf\nneg [3]\npushf\nmov ebx, -2147483648\nneg ebx\npushf\nbswap eax\nbswap [3]",
+            "mov eax, -8\nsar eax, 1\npushf\nrol eax, 33\npushf\nror [2], 1\nmov ebx, 3\nror ebx, 2\npushf\nshr eax, 4",
+            "mov eax, 1\nmov ebx, 2\nxchg eax, ebx\nxchg ebx, [4]\nxchg [4], [5]\nlea ecx, [eax+ebx*8-3]\nlea edx, [7]",
+            "mov eax, 6\ntest eax, 1\npushf\ntest [2], eax\ncmp 1, 2\ncmovl eax, 9\ncmovge ebx, 9\ncmova [3], eax\ncmovb [4], [3]",
+            "popcnt eax, -1\nclz ebx, 1\nctz ecx, 0\nclz [3], 0\nctz edx, [3]\npopcnt [5], 1234",
+            "mov ecx, 3\nadd eax, 2\nloop 1\nprn eax\nmov ecx, 1\nloop 0\nprn ecx",
+            "call 3\nprn eax\njmp 6\nmov eax, 5\nret\nnop\nnop",
+            "mov eax, eip\nadd eip, 2\nmov ebx, eip",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn errors_behave_like_step() {
+        for source in &[
+            "mov eax, 1\nmov [100000000], eax",
+            "mov [100000000], [200000000]",
+            "mov eax, 0\ndiv ebx, eax",
+            "mod 1, 0",
+            "jmp 100",
+            "call -1",
+            "mov ecx, 2\nloop 100",
+            "xchg eax, [100000000]",
+            "cmove [100000000], 1",
+            "mov esp, 0\npush 1",
+            "mov esp, 16777216\npop eax",
+            "ret",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn fused_sequences_behave_like_step() {
+        for source in &[
+            "loop: inc ecx\ncmp ecx, 10\njl loop\nprn ecx",
+            "mov eax, 5\nloop: inc ebx\ncmp eax, ebx\njg loop\ninc ecx\ncmp 3, ecx\njne loop",
+            "mov [5], 2\nloop: inc eax\ncmp eax, [5]\njle loop",
+            "cmp 1, 1\nje 3\nprn 1\ncmp [7], 1\njge 6\nprn 2\ncmp eax, ebx\njne 0",
+            "push 5\npop eax\npush esp\npop ebx\npush [3]\npop esp",
+            "jmp 2\ninc eax\ncmp eax, 3\njl 1\njmp 6\npush 1\npop eax",
+            "inc eip\ncmp eip, 2\nje 4\npush eip\npop eax",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn fused_sequences_fail_like_step() {
+        for source in &[
+            "cmp [100000000], 1\nje 0",
+            "inc eax\ncmp eax, 1\nje 100",
+            "cmp 1, 1\nje -1",
+            "mov esp, 0\npush 1\npop eax",
+            "push [100000000]\npop eax",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn run_steps_counts_every_fused_instruction() {
+        let program = Program::load("inc eax\ncmp eax, 3\njl 0".to_owned()).unwrap();
+        let decoded = DecodedProgram::new(&program);
+        let mut memory = program.initialize();
+
+        assert_eq!(decoded.run_steps(&mut memory, 4), Ok(true));
+        assert_eq!(memory.registers[Register::Eax as usize], 2);
+        assert_eq!(memory.registers[EIP], 1);
+
+        assert_eq!(decoded.run_steps(&mut memory, 6), Ok(false));
+        assert_eq!(memory.registers[Register::Eax as usize], 3);
+        assert_eq!(memory.registers[EIP], 3);
+    }
+
+    #[test]
+    fn strict_returns_behave_like_step() {
+        let program = Program::load("call 2\nnop\npush 1\nret".to_owned()).unwrap();
+
+        let mut stepped = program.initialize();
+        stepped.strict_returns = true;
+        let step_error = loop {
+            if let Err(e) = program.step(&mut stepped) {
+                break e;
+            }
+        };
+
+        let mut decoded = program.initialize();
+        decoded.strict_returns = true;
+        let decoded_error = DecodedProgram::new(&program).run(&mut decoded).unwrap_err();
+
+        assert_eq!(decoded_error, step_error);
+    }
+
+    #[test]
+    fn run_steps_stops_after_the_given_number_of_steps() {
+        let program = Program::load("inc eax\ninc eax\ninc eax".to_owned()).unwrap();
+        let decoded = DecodedProgram::new(&program);
+        let mut memory = program.initialize();
+
+        assert_eq!(decoded.run_steps(&mut memory, 2), Ok(true));
+        assert_eq!(memory.registers[Register::Eax as usize], 2);
+        assert_eq!(memory.registers[EIP], 2);
+
+        assert_eq!(decoded.run_steps(&mut memory, 2), Ok(false));
+        assert_eq!(memory.registers[Register::Eax as usize], 3);
+        assert_eq!(memory.registers[EIP], 3);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::context::{Word, FLAG_CARRY, FLAG_GREATER, FLAG_OVERFLOW, FLAG_SIGN, FLAG_ZERO};
+use std::fmt;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Register {
     Eax = 0,
@@ -21,10 +24,145 @@ pub enum Register {
 
 pub const NUM_REGISTERS: usize = 17;
 
+impl Register {
+    /// All registers, in numbering order.
+    pub const ALL: [Register; NUM_REGISTERS] = [
+        Register::Eax,
+        Register::Ebx,
+        Register::Ecx,
+        Register::Edx,
+        Register::Esi,
+        Register::Edi,
+        Register::Esp,
+        Register::Ebp,
+        Register::Eip,
+        Register::R08,
+        Register::R09,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+    ];
+
+    /// The name used for the register in assembly source.
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Eax => "eax",
+            Register::Ebx => "ebx",
+            Register::Ecx => "ecx",
+            Register::Edx => "edx",
+            Register::Esi => "esi",
+            Register::Edi => "edi",
+            Register::Esp => "esp",
+            Register::Ebp => "ebp",
+            Register::Eip => "eip",
+            Register::R08 => "r08",
+            Register::R09 => "r09",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+            Register::R12 => "r12",
+            Register::R13 => "r13",
+            Register::R14 => "r14",
+            Register::R15 => "r15",
+        }
+    }
+}
+
+/// The floating-point registers, which hold `f64`s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatRegister {
+    F0 = 0,
+    F1 = 1,
+    F2 = 2,
+    F3 = 3,
+    F4 = 4,
+    F5 = 5,
+    F6 = 6,
+    F7 = 7,
+}
+
+pub const NUM_FLOAT_REGISTERS: usize = 8;
+
+impl FloatRegister {
+    /// All floating-point registers, in numbering order.
+    pub const ALL: [FloatRegister; NUM_FLOAT_REGISTERS] = [
+        FloatRegister::F0,
+        FloatRegister::F1,
+        FloatRegister::F2,
+        FloatRegister::F3,
+        FloatRegister::F4,
+        FloatRegister::F5,
+        FloatRegister::F6,
+        FloatRegister::F7,
+    ];
+
+    /// The name used for the register in assembly source.
+    pub fn name(self) -> &'static str {
+        match self {
+            FloatRegister::F0 => "f0",
+            FloatRegister::F1 => "f1",
+            FloatRegister::F2 => "f2",
+            FloatRegister::F3 => "f3",
+            FloatRegister::F4 => "f4",
+            FloatRegister::F5 => "f5",
+            FloatRegister::F6 => "f6",
+            FloatRegister::F7 => "f7",
+        }
+    }
+}
+
+/// The vector registers, which hold four `i32` lanes each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorRegister {
+    V0 = 0,
+    V1 = 1,
+    V2 = 2,
+    V3 = 3,
+    V4 = 4,
+    V5 = 5,
+    V6 = 6,
+    V7 = 7,
+}
+
+pub const NUM_VECTOR_REGISTERS: usize = 8;
+
+/// The number of `i32` lanes in a vector register.
+pub const NUM_LANES: usize = 4;
+
+impl VectorRegister {
+    /// All vector registers, in numbering order.
+    pub const ALL: [VectorRegister; NUM_VECTOR_REGISTERS] = [
+        VectorRegister::V0,
+        VectorRegister::V1,
+        VectorRegister::V2,
+        VectorRegister::V3,
+        VectorRegister::V4,
+        VectorRegister::V5,
+        VectorRegister::V6,
+        VectorRegister::V7,
+    ];
+
+    /// The name used for the register in assembly source.
+    pub fn name(self) -> &'static str {
+        match self {
+            VectorRegister::V0 => "v0",
+            VectorRegister::V1 => "v1",
+            VectorRegister::V2 => "v2",
+            VectorRegister::V3 => "v3",
+            VectorRegister::V4 => "v4",
+            VectorRegister::V5 => "v5",
+            VectorRegister::V6 => "v6",
+            VectorRegister::V7 => "v7",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Source {
     Register(Register),
-    Value(i32),
+    Value(i64),
     Address(i32),
 }
 
@@ -34,6 +172,92 @@ pub enum Target {
     Address(i32),
 }
 
+/// The operand of a floating-point instruction. A float in memory takes two
+/// words, with the low half of its bits at the address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloatSource {
+    Register(FloatRegister),
+    Value(f64),
+    Address(i32),
+}
+
+/// The address `lea` computes: `base + index * scale + displacement`,
+/// wrapping around on overflow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectiveAddress {
+    pub base: Option<Register>,
+    /// The index register and its scale, 1, 2, 4 or 8.
+    pub index: Option<(Register, i32)>,
+    pub displacement: i32,
+}
+
+/// The units smaller than a word that `ldb`, `ldh` and the like access
+/// memory in. Their operands are byte addresses, which count the bytes of
+/// each word least significant first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    Byte,
+    Halfword,
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Halfword => 2,
+        }
+    }
+}
+
+/// The lane-wise operations of the packed vector instructions. The
+/// comparisons set the lanes where they hold to -1 and the others to 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorOp {
+    Add,
+    Sub,
+    Mul,
+    Min,
+    Max,
+    CmpEq,
+    CmpGt,
+}
+
+impl VectorOp {
+    /// The result of the operation on one pair of lanes, wrapping around on
+    /// overflow.
+    pub fn apply(self, a: i32, b: i32) -> i32 {
+        match self {
+            VectorOp::Add => a.wrapping_add(b),
+            VectorOp::Sub => a.wrapping_sub(b),
+            VectorOp::Mul => a.wrapping_mul(b),
+            VectorOp::Min => a.min(b),
+            VectorOp::Max => a.max(b),
+            VectorOp::CmpEq => -((a == b) as i32),
+            VectorOp::CmpGt => -((a > b) as i32),
+        }
+    }
+}
+
+/// The conditions of the conditional moves, with the flags they test as the
+/// conditional jumps of the same names do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Above,
+    AboveOrEqual,
+    Below,
+    BelowOrEqual,
+    Sign,
+    NotSign,
+    Overflow,
+    NotOverflow,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     Nop,
@@ -57,6 +281,25 @@ pub enum Instruction {
     And(Target, Source),
     Shl(Target, Source),
     Shr(Target, Source),
+    Sar(Target, Source),
+    Rol(Target, Source),
+    Ror(Target, Source),
+    Neg(Target),
+    Xchg(Target, Target),
+    Lea(Target, EffectiveAddress),
+    Test(Source, Source),
+    Cmov(Condition, Target, Source),
+    Popcnt(Target, Source),
+    Clz(Target, Source),
+    Ctz(Target, Source),
+    Bswap(Target),
+    /// Loads the unit at the byte address in the source, zero extended.
+    Ld(Width, Target, Source),
+    /// Loads the unit at the byte address in the source, sign extended.
+    Lds(Width, Target, Source),
+    /// Stores the low bits of the second source at the byte address in the
+    /// first.
+    St(Width, Source, Source),
     Cmp(Source, Source),
     Jmp(Source),
     Call(Source),
@@ -67,5 +310,538 @@ pub enum Instruction {
     Jge(Source),
     Jl(Source),
     Jle(Source),
+    Ja(Source),
+    Jae(Source),
+    Jb(Source),
+    Jbe(Source),
+    Js(Source),
+    Jns(Source),
+    Jo(Source),
+    Jno(Source),
+    Loop(Source),
     Prn(Source),
+    Fld(FloatRegister, FloatSource),
+    Fst(i32, FloatRegister),
+    Fadd(FloatRegister, FloatSource),
+    Fsub(FloatRegister, FloatSource),
+    Fmul(FloatRegister, FloatSource),
+    Fdiv(FloatRegister, FloatSource),
+    Fneg(FloatRegister),
+    Fabs(FloatRegister),
+    Fsqrt(FloatRegister),
+    Fcmp(FloatRegister, FloatSource),
+    Itof(FloatRegister, Source),
+    Ftoi(Target, FloatRegister),
+    Fprn(FloatSource),
+    /// Loads the four words from the address in the source on.
+    Vld(VectorRegister, Source),
+    /// Stores the lanes to the four words from the address in the source on.
+    Vst(Source, VectorRegister),
+    Vop(VectorOp, VectorRegister, VectorRegister),
+    /// Sets the lane to the source, truncated to 32 bits.
+    Vins(VectorRegister, u8, Source),
+    Vext(Target, VectorRegister, u8),
+    /// Sets the target to the sum of the lanes, wrapping around in 32 bits.
+    Vsum(Target, VectorRegister),
+    /// Starts a core at the instruction index in the first source, with its
+    /// stack pointer at the second, and sets the target to the core's id.
+    Spawn(Target, Source, Source),
+    /// Waits until the core with the id in the source has finished.
+    Join(Source),
+    /// Replaces the word at the address in the first source with the second
+    /// source if it equals the target. The target is set to the word as it
+    /// was, and the flags to those of comparing it to the target.
+    Cas(Target, Source, Source),
+    /// Adds the target to the word at the address in the source, and sets
+    /// the target to the word as it was. The flags are those of the addition.
+    Xadd(Target, Source),
+    Fence,
+    /// Ends the turn of the core on a machine, so the others can run. It
+    /// does nothing on a single core.
+    Yield,
+    /// Sends the second source, truncated to 32 bits, on the channel with
+    /// the id in the first, waiting while the channel is full.
+    Send(Source, Source),
+    /// Receives a word from the channel with the id in the source, waiting
+    /// while the channel is empty.
+    Recv(Target, Source),
+    /// Calls the native function with the given index in `Program::natives`
+    /// and name, written `call` and the name.
+    Native(u32, &'static str),
+}
+
+impl From<Target> for Source {
+    fn from(target: Target) -> Source {
+        match target {
+            Target::Register(reg) => Source::Register(reg),
+            Target::Address(addr) => Source::Address(addr),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for FloatRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for VectorRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Values are written so that they parse back to the same `f64`.
+impl fmt::Display for FloatSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FloatSource::Register(reg) => write!(f, "{}", reg),
+            FloatSource::Value(value) => write!(f, "{:?}", value),
+            FloatSource::Address(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Register(reg) => write!(f, "{}", reg),
+            Source::Value(value) => write!(f, "{}", value),
+            Source::Address(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Register(reg) => write!(f, "{}", reg),
+            Target::Address(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for EffectiveAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        let mut empty = true;
+        if let Some(base) = self.base {
+            write!(f, "{}", base)?;
+            empty = false;
+        }
+        if let Some((index, scale)) = self.index {
+            if !empty {
+                f.write_str("+")?;
+            }
+            write!(f, "{}*{}", index, scale)?;
+            empty = false;
+        }
+        if empty || self.displacement != 0 {
+            if !empty && self.displacement > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", self.displacement)?;
+        }
+        f.write_str("]")
+    }
+}
+
+impl EffectiveAddress {
+    /// The address, given the values of the registers.
+    pub(crate) fn address<W: Word>(self: &EffectiveAddress, register: impl Fn(Register) -> W) -> W {
+        let base = self.base.map_or(W::ZERO, &register);
+        let index = self.index.map_or(W::ZERO, |(index, scale)| {
+            register(index).wrapping_mul(W::from_i64(scale.into()))
+        });
+        base.wrapping_add(index)
+            .wrapping_add(W::from_i64(self.displacement.into()))
+    }
+}
+
+impl Condition {
+    /// Whether the condition holds for `flags`, a combination of the `FLAG_`
+    /// bits.
+    pub fn holds(self, flags: i32) -> bool {
+        match self {
+            Condition::Equal => flags & FLAG_ZERO != 0,
+            Condition::NotEqual => flags & FLAG_ZERO == 0,
+            Condition::Greater => flags & FLAG_GREATER != 0,
+            Condition::GreaterOrEqual => flags & (FLAG_ZERO | FLAG_GREATER) != 0,
+            Condition::Less => flags & (FLAG_ZERO | FLAG_GREATER) == 0,
+            Condition::LessOrEqual => flags & FLAG_GREATER == 0,
+            Condition::Above => flags & (FLAG_CARRY | FLAG_ZERO) == 0,
+            Condition::AboveOrEqual => flags & FLAG_CARRY == 0,
+            Condition::Below => flags & FLAG_CARRY != 0,
+            Condition::BelowOrEqual => flags & (FLAG_CARRY | FLAG_ZERO) != 0,
+            Condition::Sign => flags & FLAG_SIGN != 0,
+            Condition::NotSign => flags & FLAG_SIGN == 0,
+            Condition::Overflow => flags & FLAG_OVERFLOW != 0,
+            Condition::NotOverflow => flags & FLAG_OVERFLOW == 0,
+        }
+    }
+}
+
+impl Instruction {
+    /// The mnemonic used for the instruction in assembly source.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Instruction::Nop => "nop",
+            Instruction::Int => "int",
+            Instruction::Mov(..) => "mov",
+            Instruction::Push(..) => "push",
+            Instruction::Pop(..) => "pop",
+            Instruction::Pushf => "pushf",
+            Instruction::Popf => "popf",
+            Instruction::Inc(..) => "inc",
+            Instruction::Dec(..) => "dec",
+            Instruction::Add(..) => "add",
+            Instruction::Sub(..) => "sub",
+            Instruction::Mul(..) => "mul",
+            Instruction::Div(..) => "div",
+            Instruction::Mod(..) => "mod",
+            Instruction::Rem(..) => "rem",
+            Instruction::Not(..) => "not",
+            Instruction::Xor(..) => "xor",
+            Instruction::Or(..) => "or",
+            Instruction::And(..) => "and",
+            Instruction::Shl(..) => "shl",
+            Instruction::Shr(..) => "shr",
+            Instruction::Sar(..) => "sar",
+            Instruction::Rol(..) => "rol",
+            Instruction::Ror(..) => "ror",
+            Instruction::Neg(..) => "neg",
+            Instruction::Xchg(..) => "xchg",
+            Instruction::Lea(..) => "lea",
+            Instruction::Test(..) => "test",
+            Instruction::Cmov(condition, ..) => match condition {
+                Condition::Equal => "cmove",
+                Condition::NotEqual => "cmovne",
+                Condition::Greater => "cmovg",
+                Condition::GreaterOrEqual => "cmovge",
+                Condition::Less => "cmovl",
+                Condition::LessOrEqual => "cmovle",
+                Condition::Above => "cmova",
+                Condition::AboveOrEqual => "cmovae",
+                Condition::Below => "cmovb",
+                Condition::BelowOrEqual => "cmovbe",
+                Condition::Sign => "cmovs",
+                Condition::NotSign => "cmovns",
+                Condition::Overflow => "cmovo",
+                Condition::NotOverflow => "cmovno",
+            },
+            Instruction::Popcnt(..) => "popcnt",
+            Instruction::Clz(..) => "clz",
+            Instruction::Ctz(..) => "ctz",
+            Instruction::Bswap(..) => "bswap",
+            Instruction::Ld(Width::Byte, ..) => "ldb",
+            Instruction::Ld(Width::Halfword, ..) => "ldh",
+            Instruction::Lds(Width::Byte, ..) => "ldsb",
+            Instruction::Lds(Width::Halfword, ..) => "ldsh",
+            Instruction::St(Width::Byte, ..) => "stb",
+            Instruction::St(Width::Halfword, ..) => "sth",
+            Instruction::Cmp(..) => "cmp",
+            Instruction::Jmp(..) => "jmp",
+            Instruction::Call(..) => "call",
+            Instruction::Ret => "ret",
+            Instruction::Je(..) => "je",
+            Instruction::Jne(..) => "jne",
+            Instruction::Jg(..) => "jg",
+            Instruction::Jge(..) => "jge",
+            Instruction::Jl(..) => "jl",
+            Instruction::Jle(..) => "jle",
+            Instruction::Ja(..) => "ja",
+            Instruction::Jae(..) => "jae",
+            Instruction::Jb(..) => "jb",
+            Instruction::Jbe(..) => "jbe",
+            Instruction::Js(..) => "js",
+            Instruction::Jns(..) => "jns",
+            Instruction::Jo(..) => "jo",
+            Instruction::Jno(..) => "jno",
+            Instruction::Loop(..) => "loop",
+            Instruction::Prn(..) => "prn",
+            Instruction::Fld(..) => "fld",
+            Instruction::Fst(..) => "fst",
+            Instruction::Fadd(..) => "fadd",
+            Instruction::Fsub(..) => "fsub",
+            Instruction::Fmul(..) => "fmul",
+            Instruction::Fdiv(..) => "fdiv",
+            Instruction::Fneg(..) => "fneg",
+            Instruction::Fabs(..) => "fabs",
+            Instruction::Fsqrt(..) => "fsqrt",
+            Instruction::Fcmp(..) => "fcmp",
+            Instruction::Itof(..) => "itof",
+            Instruction::Ftoi(..) => "ftoi",
+            Instruction::Fprn(..) => "fprn",
+            Instruction::Vld(..) => "vld",
+            Instruction::Vst(..) => "vst",
+            Instruction::Vop(op, ..) => match op {
+                VectorOp::Add => "vadd",
+                VectorOp::Sub => "vsub",
+                VectorOp::Mul => "vmul",
+                VectorOp::Min => "vmin",
+                VectorOp::Max => "vmax",
+                VectorOp::CmpEq => "vcmpeq",
+                VectorOp::CmpGt => "vcmpgt",
+            },
+            Instruction::Vins(..) => "vins",
+            Instruction::Vext(..) => "vext",
+            Instruction::Vsum(..) => "vsum",
+            Instruction::Spawn(..) => "spawn",
+            Instruction::Join(..) => "join",
+            Instruction::Cas(..) => "cas",
+            Instruction::Xadd(..) => "xadd",
+            Instruction::Fence => "fence",
+            Instruction::Yield => "yield",
+            Instruction::Send(..) => "send",
+            Instruction::Recv(..) => "recv",
+            Instruction::Native(..) => "call",
+        }
+    }
+}
+
+/// The sources an instruction reads, and its target along with whether it
+/// is read before being written. The second target of `xchg` is its
+/// source, and `loop` has `ecx` as its target. The floating-point and
+/// vector registers, the bytes `st` writes, the words `vst`, `cas` and
+/// `xadd` access and whatever a native function uses are left out, and a
+/// float in memory is given by its first word, which `float_address` also
+/// returns.
+pub(crate) fn operands(instruction: Instruction) -> ([Option<Source>; 2], Option<(Target, bool)>) {
+    match instruction {
+        Instruction::Nop
+        | Instruction::Int
+        | Instruction::Pushf
+        | Instruction::Popf
+        | Instruction::Ret
+        | Instruction::Fence
+        | Instruction::Yield => ([None, None], None),
+        Instruction::Mov(t, s)
+        | Instruction::Popcnt(t, s)
+        | Instruction::Clz(t, s)
+        | Instruction::Ctz(t, s)
+        | Instruction::Ld(_, t, s)
+        | Instruction::Lds(_, t, s) => ([Some(s), None], Some((t, false))),
+        Instruction::Pop(t) | Instruction::Rem(t) => ([None, None], Some((t, false))),
+        Instruction::Inc(t)
+        | Instruction::Dec(t)
+        | Instruction::Not(t)
+        | Instruction::Neg(t)
+        | Instruction::Bswap(t) => ([None, None], Some((t, true))),
+        Instruction::Xchg(t1, t2) => ([Some(t2.into()), None], Some((t1, true))),
+        Instruction::Lea(t, address) => (
+            [
+                address.base.map(Source::Register),
+                address.index.map(|(index, _)| Source::Register(index)),
+            ],
+            Some((t, false)),
+        ),
+        // The target keeps its value if the condition does not hold
+        Instruction::Cmov(_, t, s) => ([Some(s), None], Some((t, true))),
+        Instruction::Loop(s) => (
+            [Some(s), None],
+            Some((Target::Register(Register::Ecx), true)),
+        ),
+        Instruction::Add(t, s)
+        | Instruction::Sub(t, s)
+        | Instruction::Mul(t, s)
+        | Instruction::Div(t, s)
+        | Instruction::Xor(t, s)
+        | Instruction::Or(t, s)
+        | Instruction::And(t, s)
+        | Instruction::Shl(t, s)
+        | Instruction::Shr(t, s)
+        | Instruction::Sar(t, s)
+        | Instruction::Rol(t, s)
+        | Instruction::Ror(t, s) => ([Some(s), None], Some((t, true))),
+        Instruction::Push(s)
+        | Instruction::Jmp(s)
+        | Instruction::Call(s)
+        | Instruction::Je(s)
+        | Instruction::Jne(s)
+        | Instruction::Jg(s)
+        | Instruction::Jge(s)
+        | Instruction::Jl(s)
+        | Instruction::Jle(s)
+        | Instruction::Ja(s)
+        | Instruction::Jae(s)
+        | Instruction::Jb(s)
+        | Instruction::Jbe(s)
+        | Instruction::Js(s)
+        | Instruction::Jns(s)
+        | Instruction::Jo(s)
+        | Instruction::Jno(s)
+        | Instruction::Prn(s) => ([Some(s), None], None),
+        Instruction::Mod(s1, s2)
+        | Instruction::Cmp(s1, s2)
+        | Instruction::Test(s1, s2)
+        | Instruction::St(_, s1, s2) => ([Some(s1), Some(s2)], None),
+        Instruction::Fld(_, s)
+        | Instruction::Fadd(_, s)
+        | Instruction::Fsub(_, s)
+        | Instruction::Fmul(_, s)
+        | Instruction::Fdiv(_, s)
+        | Instruction::Fcmp(_, s)
+        | Instruction::Fprn(s) => match s {
+            FloatSource::Address(addr) => ([Some(Source::Address(addr)), None], None),
+            _ => ([None, None], None),
+        },
+        Instruction::Fst(addr, _) => ([None, None], Some((Target::Address(addr), false))),
+        Instruction::Fneg(_) | Instruction::Fabs(_) | Instruction::Fsqrt(_) => ([None, None], None),
+        Instruction::Itof(_, s) => ([Some(s), None], None),
+        Instruction::Ftoi(t, _) => ([None, None], Some((t, false))),
+        Instruction::Vld(_, s) | Instruction::Vst(s, _) | Instruction::Vins(_, _, s) => {
+            ([Some(s), None], None)
+        }
+        Instruction::Vop(..) => ([None, None], None),
+        Instruction::Vext(t, ..) | Instruction::Vsum(t, _) => ([None, None], Some((t, false))),
+        Instruction::Spawn(t, s1, s2) => ([Some(s1), Some(s2)], Some((t, false))),
+        Instruction::Join(s) => ([Some(s), None], None),
+        Instruction::Cas(t, s1, s2) => ([Some(s1), Some(s2)], Some((t, true))),
+        Instruction::Xadd(t, s) => ([Some(s), None], Some((t, true))),
+        Instruction::Send(s1, s2) => ([Some(s1), Some(s2)], None),
+        Instruction::Recv(t, s) => ([Some(s), None], Some((t, false))),
+        Instruction::Native(..) => ([None, None], None),
+    }
+}
+
+/// The address of the float in memory an instruction reads or writes, whose
+/// second word is at the address after it.
+pub(crate) fn float_address(instruction: Instruction) -> Option<i32> {
+    match instruction {
+        Instruction::Fld(_, FloatSource::Address(addr))
+        | Instruction::Fadd(_, FloatSource::Address(addr))
+        | Instruction::Fsub(_, FloatSource::Address(addr))
+        | Instruction::Fmul(_, FloatSource::Address(addr))
+        | Instruction::Fdiv(_, FloatSource::Address(addr))
+        | Instruction::Fcmp(_, FloatSource::Address(addr))
+        | Instruction::Fprn(FloatSource::Address(addr))
+        | Instruction::Fst(addr, _) => Some(addr),
+        _ => None,
+    }
+}
+
+/// The target of a jump or call.
+pub(crate) fn jump_target(instruction: Instruction) -> Option<Source> {
+    match instruction {
+        Instruction::Jmp(s)
+        | Instruction::Call(s)
+        | Instruction::Je(s)
+        | Instruction::Jne(s)
+        | Instruction::Jg(s)
+        | Instruction::Jge(s)
+        | Instruction::Jl(s)
+        | Instruction::Jle(s)
+        | Instruction::Ja(s)
+        | Instruction::Jae(s)
+        | Instruction::Jb(s)
+        | Instruction::Jbe(s)
+        | Instruction::Js(s)
+        | Instruction::Jns(s)
+        | Instruction::Jo(s)
+        | Instruction::Jno(s)
+        | Instruction::Loop(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Formats the instruction in assembly syntax, with labels shown as the
+/// instruction indices they resolved to.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.mnemonic())?;
+
+        match *self {
+            Instruction::Nop
+            | Instruction::Int
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Ret
+            | Instruction::Fence
+            | Instruction::Yield => Ok(()),
+            Instruction::Pop(t)
+            | Instruction::Inc(t)
+            | Instruction::Dec(t)
+            | Instruction::Rem(t)
+            | Instruction::Not(t)
+            | Instruction::Neg(t)
+            | Instruction::Bswap(t) => write!(f, " {}", t),
+            Instruction::Fneg(r) | Instruction::Fabs(r) | Instruction::Fsqrt(r) => {
+                write!(f, " {}", r)
+            }
+            Instruction::Fprn(s) => write!(f, " {}", s),
+            Instruction::Fld(r, s)
+            | Instruction::Fadd(r, s)
+            | Instruction::Fsub(r, s)
+            | Instruction::Fmul(r, s)
+            | Instruction::Fdiv(r, s)
+            | Instruction::Fcmp(r, s) => write!(f, " {}, {}", r, s),
+            Instruction::Fst(addr, r) => write!(f, " [{}], {}", addr, r),
+            Instruction::Itof(r, s) => write!(f, " {}, {}", r, s),
+            Instruction::Ftoi(t, r) => write!(f, " {}, {}", t, r),
+            Instruction::Vld(r, s) => write!(f, " {}, {}", r, s),
+            Instruction::Vst(s, r) => write!(f, " {}, {}", s, r),
+            Instruction::Vop(_, r1, r2) => write!(f, " {}, {}", r1, r2),
+            Instruction::Vins(r, lane, s) => write!(f, " {}, {}, {}", r, lane, s),
+            Instruction::Vext(t, r, lane) => write!(f, " {}, {}, {}", t, r, lane),
+            Instruction::Vsum(t, r) => write!(f, " {}, {}", t, r),
+            Instruction::Spawn(t, s1, s2) | Instruction::Cas(t, s1, s2) => {
+                write!(f, " {}, {}, {}", t, s1, s2)
+            }
+            Instruction::Push(s)
+            | Instruction::Jmp(s)
+            | Instruction::Call(s)
+            | Instruction::Je(s)
+            | Instruction::Jne(s)
+            | Instruction::Jg(s)
+            | Instruction::Jge(s)
+            | Instruction::Jl(s)
+            | Instruction::Jle(s)
+            | Instruction::Ja(s)
+            | Instruction::Jae(s)
+            | Instruction::Jb(s)
+            | Instruction::Jbe(s)
+            | Instruction::Js(s)
+            | Instruction::Jns(s)
+            | Instruction::Jo(s)
+            | Instruction::Jno(s)
+            | Instruction::Loop(s)
+            | Instruction::Prn(s)
+            | Instruction::Join(s) => write!(f, " {}", s),
+            Instruction::Mov(t, s)
+            | Instruction::Add(t, s)
+            | Instruction::Sub(t, s)
+            | Instruction::Mul(t, s)
+            | Instruction::Div(t, s)
+            | Instruction::Xor(t, s)
+            | Instruction::Or(t, s)
+            | Instruction::And(t, s)
+            | Instruction::Shl(t, s)
+            | Instruction::Shr(t, s)
+            | Instruction::Sar(t, s)
+            | Instruction::Rol(t, s)
+            | Instruction::Ror(t, s)
+            | Instruction::Cmov(_, t, s)
+            | Instruction::Popcnt(t, s)
+            | Instruction::Clz(t, s)
+            | Instruction::Ctz(t, s)
+            | Instruction::Ld(_, t, s)
+            | Instruction::Lds(_, t, s)
+            | Instruction::Xadd(t, s)
+            | Instruction::Recv(t, s) => write!(f, " {}, {}", t, s),
+            Instruction::Xchg(t1, t2) => write!(f, " {}, {}", t1, t2),
+            Instruction::Native(_, name) => write!(f, " {}", name),
+            Instruction::Lea(t, address) => write!(f, " {}, {}", t, address),
+            Instruction::Mod(s1, s2)
+            | Instruction::Cmp(s1, s2)
+            | Instruction::Test(s1, s2)
+            | Instruction::St(_, s1, s2)
+            | Instruction::Send(s1, s2) => write!(f, " {}, {}", s1, s2),
+        }
+    }
 }
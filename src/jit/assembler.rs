@@ -0,0 +1,295 @@
+//! Just enough of an x86-64 assembler for the JIT compiler.
+
+/// The general purpose registers, by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    R12 = 12,
+    R13 = 13,
+}
+
+/// The conditions of `jcc`, by their encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+/// A position in the code that jumps can refer to before it is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Label(usize);
+
+/// Two-operand 32-bit ALU instructions, as the opcode of the
+/// `op r/m32, r32` form.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+    Test = 0x85,
+}
+
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// The positions of rel32 fields and the labels they refer to.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    pub fn position(self: &Assembler) -> usize {
+        self.code.len()
+    }
+
+    pub fn new_label(self: &mut Assembler) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(self: &mut Assembler, label: Label) {
+        debug_assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Resolves the jumps and returns the code.
+    pub fn finish(mut self: Assembler) -> Vec<u8> {
+        for (position, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("jump to an unbound label");
+            let offset = target as i64 - (position as i64 + 4);
+            self.code[position..position + 4].copy_from_slice(&(offset as i32).to_le_bytes());
+        }
+        self.code
+    }
+
+    pub fn bytes(self: &mut Assembler, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(self: &mut Assembler, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn rel32(self: &mut Assembler, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.imm32(0);
+    }
+
+    /// An instruction with a `[base + disp32]` operand, 64-bit if `wide`.
+    fn memory_operand(
+        self: &mut Assembler,
+        wide: bool,
+        opcode: &[u8],
+        reg: u8,
+        base: Reg,
+        disp: i32,
+    ) {
+        let base = base as u8;
+        let rex = ((wide as u8) << 3) | ((reg >> 3) << 2) | (base >> 3);
+        if rex != 0 {
+            self.bytes(&[0x40 | rex]);
+        }
+        self.bytes(opcode);
+        self.bytes(&[0x80 | ((reg & 7) << 3) | (base & 7)]);
+        if base & 7 == 4 {
+            // rsp and r12 need a SIB byte
+            self.bytes(&[0x24]);
+        }
+        self.imm32(disp);
+    }
+
+    /// `mov reg32, [base + disp]`
+    pub fn load(self: &mut Assembler, reg: Reg, base: Reg, disp: i32) {
+        self.memory_operand(false, &[0x8b], reg as u8, base, disp);
+    }
+
+    /// `mov [base + disp], reg32`
+    pub fn store(self: &mut Assembler, base: Reg, disp: i32, reg: Reg) {
+        self.memory_operand(false, &[0x89], reg as u8, base, disp);
+    }
+
+    /// `movsxd reg, reg32; mov [base + disp], reg`, storing a VM word
+    /// sign extended to its 64-bit slot.
+    pub fn store_word(self: &mut Assembler, base: Reg, disp: i32, reg: Reg) {
+        self.sign_extend(reg);
+        self.memory_operand(true, &[0x89], reg as u8, base, disp);
+    }
+
+    /// `movsxd reg, reg32`
+    fn sign_extend(self: &mut Assembler, reg: Reg) {
+        debug_assert!((reg as u8) < 8);
+        self.bytes(&[0x48, 0x63, 0xc0 | ((reg as u8) << 3) | reg as u8]);
+    }
+
+    /// `mov reg32, [r12 + rax * 8]`, the low half of a 64-bit slot.
+    pub fn load_indexed(self: &mut Assembler, reg: Reg) {
+        debug_assert!((reg as u8) < 8);
+        self.bytes(&[0x41, 0x8b, ((reg as u8) << 3) | 4, 0xc4]);
+    }
+
+    /// `movsxd reg, reg32; mov [r12 + rax * 8], reg`
+    pub fn store_indexed(self: &mut Assembler, reg: Reg) {
+        self.sign_extend(reg);
+        self.bytes(&[0x49, 0x89, ((reg as u8) << 3) | 4, 0xc4]);
+    }
+
+    /// `mov reg32, imm32`
+    pub fn mov_imm(self: &mut Assembler, reg: Reg, value: i32) {
+        debug_assert!((reg as u8) < 8);
+        self.bytes(&[0xb8 + reg as u8]);
+        self.imm32(value);
+    }
+
+    /// `op eax, ecx`
+    pub fn alu(self: &mut Assembler, op: AluOp) {
+        self.bytes(&[op as u8, 0xc8]);
+    }
+
+    /// `add eax, value`
+    pub fn add_imm(self: &mut Assembler, value: i8) {
+        self.bytes(&[0x83, 0xc0, value as u8]);
+    }
+
+    /// `cmp reg32, imm32`, unsigned comparisons follow with `Below` and
+    /// `AboveOrEqual`.
+    pub fn cmp_imm(self: &mut Assembler, reg: Reg, value: i32) {
+        debug_assert!((reg as u8) < 8);
+        self.bytes(&[0x81, 0xf8 | reg as u8]);
+        self.imm32(value);
+    }
+
+    /// `test eax, mask`
+    pub fn test_imm(self: &mut Assembler, mask: i32) {
+        self.bytes(&[0xa9]);
+        self.imm32(mask);
+    }
+
+    /// `cdq; idiv ecx`, leaving the quotient in `eax` and the remainder in
+    /// `edx`.
+    pub fn idiv(self: &mut Assembler) {
+        self.bytes(&[0x99, 0xf7, 0xf9]);
+    }
+
+    /// `not eax`
+    pub fn not(self: &mut Assembler) {
+        self.bytes(&[0xf7, 0xd0]);
+    }
+
+    /// `neg eax`, which sets the host flags like `sub` from zero.
+    pub fn neg(self: &mut Assembler) {
+        self.bytes(&[0xf7, 0xd8]);
+    }
+
+    /// `bswap eax`
+    pub fn bswap(self: &mut Assembler) {
+        self.bytes(&[0x0f, 0xc8]);
+    }
+
+    /// Sets `edx` to the VM flags for the flags of the last ALU
+    /// instruction, and clobbers `ecx`. `add`, `sub`, `cmp`, `neg`, `test`
+    /// and the logic instructions set the host flags just like their VM
+    /// counterparts.
+    pub fn flags(self: &mut Assembler) {
+        self.bytes(&[
+            0x0f, 0x94, 0xc2, // sete dl
+            0x0f, 0x9f, 0xc1, // setg cl
+            0x0f, 0xb6, 0xd2, // movzx edx, dl
+            0x0f, 0xb6, 0xc9, // movzx ecx, cl
+            0x8d, 0x14, 0x4a, // lea edx, [rdx + rcx * 2]
+            0x0f, 0x92, 0xc1, // setb cl
+            0x0f, 0xb6, 0xc9, // movzx ecx, cl
+            0x8d, 0x14, 0x8a, // lea edx, [rdx + rcx * 4]
+            0x0f, 0x98, 0xc1, // sets cl
+            0x0f, 0xb6, 0xc9, // movzx ecx, cl
+            0x8d, 0x14, 0xca, // lea edx, [rdx + rcx * 8]
+            0x0f, 0x90, 0xc1, // seto cl
+            0x0f, 0xb6, 0xc9, // movzx ecx, cl
+            0xc1, 0xe1, 0x04, // shl ecx, 4
+            0x09, 0xca, // or edx, ecx
+        ]);
+    }
+
+    /// Replaces the carry flag in the VM flags in `edx` with the one in
+    /// `ecx`.
+    pub fn keep_carry(self: &mut Assembler) {
+        self.bytes(&[
+            0x83, 0xe2, 0xfb, // and edx, ~4
+            0x83, 0xe1, 0x04, // and ecx, 4
+            0x09, 0xca, // or edx, ecx
+        ]);
+    }
+
+    /// `sub r15, 1`
+    pub fn decrement_r15(self: &mut Assembler) {
+        self.bytes(&[0x49, 0x83, 0xef, 0x01]);
+    }
+
+    pub fn jmp(self: &mut Assembler, label: Label) {
+        self.bytes(&[0xe9]);
+        self.rel32(label);
+    }
+
+    pub fn jcc(self: &mut Assembler, condition: Condition, label: Label) {
+        self.bytes(&[0x0f, 0x80 | condition as u8]);
+        self.rel32(label);
+    }
+
+    /// `jmp [r14 + rax * 8]`
+    pub fn jmp_table(self: &mut Assembler) {
+        self.bytes(&[0x41, 0xff, 0x24, 0xc6]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_memory_operands() {
+        let mut asm = Assembler::new();
+        asm.load(Reg::Rax, Reg::Rbx, 8);
+        asm.store(Reg::R12, 0x40, Reg::Rcx);
+        asm.load(Reg::Rdx, Reg::R13, -4);
+        asm.store_word(Reg::Rbx, 16, Reg::Rdx);
+        asm.store_indexed(Reg::Rcx);
+        assert_eq!(
+            asm.finish(),
+            [
+                0x8b, 0x83, 8, 0, 0, 0, // mov eax, [rbx + 8]
+                0x41, 0x89, 0x8c, 0x24, 0x40, 0, 0, 0, // mov [r12 + 0x40], ecx
+                0x41, 0x8b, 0x95, 0xfc, 0xff, 0xff, 0xff, // mov edx, [r13 - 4]
+                0x48, 0x63, 0xd2, // movsxd rdx, edx
+                0x48, 0x89, 0x93, 16, 0, 0, 0, // mov [rbx + 16], rdx
+                0x48, 0x63, 0xc9, // movsxd rcx, ecx
+                0x49, 0x89, 0x0c, 0xc4, // mov [r12 + rax * 8], rcx
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_jumps_in_both_directions() {
+        let mut asm = Assembler::new();
+        let start = asm.new_label();
+        let end = asm.new_label();
+        asm.bind(start);
+        asm.jcc(Condition::Equal, end);
+        asm.jmp(start);
+        asm.bind(end);
+        assert_eq!(
+            asm.finish(),
+            [0x0f, 0x84, 5, 0, 0, 0, 0xe9, 0xf5, 0xff, 0xff, 0xff]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::{io, ptr};
+
+/// Machine code in memory that can be executed but not written.
+pub struct ExecutableCode {
+    address: *mut u8,
+    len: usize,
+}
+
+impl ExecutableCode {
+    pub fn new(code: &[u8]) -> io::Result<ExecutableCode> {
+        let len = code.len().max(1);
+        unsafe {
+            let address = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if address == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            let executable = ExecutableCode {
+                address: address as *mut u8,
+                len,
+            };
+            ptr::copy_nonoverlapping(code.as_ptr(), executable.address, code.len());
+            if libc::mprotect(address, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(executable)
+        }
+    }
+
+    pub fn address(self: &ExecutableCode, offset: usize) -> usize {
+        debug_assert!(offset < self.len);
+        self.address as usize + offset
+    }
+}
+
+impl Drop for ExecutableCode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.address as *mut libc::c_void, self.len);
+        }
+    }
+}
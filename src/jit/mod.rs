@@ -0,0 +1,716 @@
+//! A JIT compiler from programs to x86-64 machine code.
+//!
+//! The whole program is compiled when a `JitProgram` is created. The
+//! registers stay in `Memory` and are read and written by the generated code
+//! as it runs, so the state is up to date whenever the code returns to Rust.
+//! Registers and memory words are 64-bit slots, of which the code reads the
+//! low half and to which it writes 32-bit values sign extended, so only
+//! 32-bit programs are compiled; 64-bit ones run in `DecodedProgram`.
+//!
+//! The generated code never fails. Anything it cannot do, such as `call`,
+//! `ret`, `prn`, `int`, instructions with `eip` operands, accesses outside
+//! the memory, division by zero or jumps outside the program, makes it
+//! return so that the instruction is executed by `Program::step`, which
+//! reports errors as usual. So do `mul` and the shifts and rotates, as the
+//! flags x86 sets for them differ from those of the VM, and `lea` and the
+//! bit counting instructions other than `bswap`, the byte and halfword
+//! loads and stores, the floating-point and vector instructions, and those
+//! that act on other cores or on channels: `spawn`, `join`, `cas`, `xadd`,
+//! `fence`, `yield`, `send` and `recv`, and calls of native functions.
+//!
+//! While the code runs, the registers of the host hold:
+//!
+//! | Register | Contents                                         |
+//! |----------|--------------------------------------------------|
+//! | rbx      | the address of the VM registers                  |
+//! | r12      | the address of the VM memory                     |
+//! | r13      | the address of the `State`                       |
+//! | r14      | the address of the table of instruction addresses |
+//! | r15      | the number of steps left                         |
+
+mod assembler;
+mod code;
+
+use self::{
+    assembler::{AluOp, Assembler, Condition, Label, Reg},
+    code::ExecutableCode,
+};
+use crate::{
+    context::{ExecutionError, Memory, Program, WordSize, MEMORY_SIZE},
+    decoded::{uses_eip, DecodedProgram},
+    instruction::{Condition as FlagCondition, Instruction, Register, Source, Target},
+};
+use std::{convert::TryFrom, io, mem};
+
+const EIP: usize = Register::Eip as usize;
+const ESP_OFFSET: i32 = Register::Esp as i32 * 8;
+
+/// What the generated code shares with `JitProgram::run_steps`.
+#[repr(C)]
+struct State {
+    flags: i32,
+    remainder: i32,
+    /// The instruction index the code returned at.
+    pc: i32,
+    /// Why the code returned, one of the `EXIT_` constants.
+    exit: i32,
+    steps: u64,
+    table: *const usize,
+}
+
+const FLAGS_OFFSET: i32 = 0;
+const REMAINDER_OFFSET: i32 = 4;
+
+/// The step budget ran out before the instruction at `pc`.
+const EXIT_BUDGET: i32 = 0;
+/// The instruction at `pc` must be executed by `Program::step`, and has
+/// been counted.
+const EXIT_INTERPRET: i32 = 1;
+
+type Entry = unsafe extern "C" fn(*mut i64, *mut i64, *mut State, usize);
+
+/// Saves the callee-saved registers, loads the fixed registers from the
+/// arguments and jumps to the instruction address in `rcx`.
+const PROLOGUE: &[u8] = &[
+    0x53, // push rbx
+    0x55, // push rbp
+    0x41, 0x54, // push r12
+    0x41, 0x55, // push r13
+    0x41, 0x56, // push r14
+    0x41, 0x57, // push r15
+    0x48, 0x89, 0xfb, // mov rbx, rdi
+    0x49, 0x89, 0xf4, // mov r12, rsi
+    0x49, 0x89, 0xd5, // mov r13, rdx
+    0x4d, 0x8b, 0x75, 0x18, // mov r14, [r13 + 24]
+    0x4d, 0x8b, 0x7d, 0x10, // mov r15, [r13 + 16]
+    0xff, 0xe1, // jmp rcx
+];
+
+/// Stores `eax` as the instruction index, `edx` as the reason and the steps
+/// left, and returns.
+const EPILOGUE: &[u8] = &[
+    0x41, 0x89, 0x45, 0x08, // mov [r13 + 8], eax
+    0x41, 0x89, 0x55, 0x0c, // mov [r13 + 12], edx
+    0x4d, 0x89, 0x7d, 0x10, // mov [r13 + 16], r15
+    0x41, 0x5f, // pop r15
+    0x41, 0x5e, // pop r14
+    0x41, 0x5d, // pop r13
+    0x41, 0x5c, // pop r12
+    0x5d, // pop rbp
+    0x5b, // pop rbx
+    0xc3, // ret
+];
+
+pub struct JitProgram<'a> {
+    program: &'a Program,
+    /// Runs programs with memory of another size than the code was
+    /// compiled for.
+    decoded: DecodedProgram<'a>,
+    code: ExecutableCode,
+    /// The address of the code of each instruction, and of the end of the
+    /// program.
+    table: Vec<usize>,
+}
+
+impl<'a> JitProgram<'a> {
+    pub fn new(program: &'a Program) -> io::Result<JitProgram<'a>> {
+        let (code, offsets) = compile(program);
+        let code = ExecutableCode::new(&code)?;
+        let table = offsets.into_iter().map(|o| code.address(o)).collect();
+
+        Ok(JitProgram {
+            program,
+            decoded: DecodedProgram::new(program),
+            code,
+            table,
+        })
+    }
+
+    /// Runs the program from the current `eip` until it ends, with the same
+    /// effect as calling `Program::step` until it returns `false`.
+    pub fn run(self: &JitProgram<'a>, memory: &mut Memory) -> Result<(), ExecutionError> {
+        while self.run_steps(memory, u64::MAX)? {}
+        Ok(())
+    }
+
+    /// Executes at most `steps` instructions, and returns whether the
+    /// program is still running.
+    pub fn run_steps(
+        self: &JitProgram<'a>,
+        memory: &mut Memory,
+        steps: u64,
+    ) -> Result<bool, ExecutionError> {
+        if memory.mem_space.len() != MEMORY_SIZE || self.program.word_size != WordSize::Bits32 {
+            return self.decoded.run_steps(memory, steps);
+        }
+
+        let mut state = State {
+            flags: memory.flags,
+            remainder: memory.remainder as i32,
+            pc: 0,
+            exit: 0,
+            steps,
+            table: self.table.as_ptr(),
+        };
+
+        loop {
+            let eip = memory.registers[EIP];
+            let entry = match usize::try_from(eip).ok().and_then(|i| self.table.get(i)) {
+                Some(entry) => *entry,
+                // Let step report it
+                None => return self.program.step(memory),
+            };
+            if state.steps == 0 {
+                return Ok(true);
+            }
+
+            unsafe {
+                let code: Entry = mem::transmute(self.code.address(0));
+                code(
+                    memory.registers.as_mut_ptr(),
+                    memory.mem_space.as_mut_ptr(),
+                    &mut state,
+                    entry,
+                );
+            }
+            memory.registers[EIP] = state.pc.into();
+            memory.flags = state.flags;
+            memory.remainder = state.remainder.into();
+
+            if state.exit == EXIT_BUDGET {
+                return Ok(true);
+            }
+            if !self.program.step(memory)? {
+                return Ok(false);
+            }
+            state.flags = memory.flags;
+            state.remainder = memory.remainder as i32;
+        }
+    }
+}
+
+/// An operand the generated code can access without checks.
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(Register),
+    Value(i32),
+    Address(i32),
+}
+
+impl Operand {
+    fn target(target: Target) -> Option<Operand> {
+        match target {
+            Target::Register(reg) => Some(Operand::Register(reg)),
+            Target::Address(address) => Operand::address(address),
+        }
+    }
+
+    fn source(source: Source) -> Option<Operand> {
+        match source {
+            Source::Register(reg) => Some(Operand::Register(reg)),
+            Source::Value(value) => i32::try_from(value).ok().map(Operand::Value),
+            Source::Address(address) => Operand::address(address),
+        }
+    }
+
+    fn address(address: i32) -> Option<Operand> {
+        match usize::try_from(address) {
+            Ok(a) if a < MEMORY_SIZE => Some(Operand::Address(address)),
+            _ => None,
+        }
+    }
+}
+
+struct Compiler {
+    asm: Assembler,
+    /// The code of each instruction, and of the end of the program.
+    instructions: Vec<Label>,
+}
+
+/// Compiles the program, returning the code and the offset of each
+/// instruction in it.
+fn compile(program: &Program) -> (Vec<u8>, Vec<usize>) {
+    let len = program.instructions.len();
+    let mut asm = Assembler::new();
+    asm.bytes(PROLOGUE);
+    let instructions = (0..=len).map(|_| asm.new_label()).collect();
+    let mut compiler = Compiler { asm, instructions };
+
+    let exit = compiler.asm.new_label();
+    let mut stubs = vec![];
+    let mut offsets = vec![];
+    for pc in 0..=len {
+        let budget = compiler.asm.new_label();
+        let interpret = compiler.asm.new_label();
+        stubs.push((pc, budget, interpret));
+
+        compiler.asm.bind(compiler.instructions[pc]);
+        offsets.push(compiler.asm.position());
+        compiler.asm.decrement_r15();
+        compiler.asm.jcc(Condition::Below, budget);
+
+        let compiled = match program.instructions.get(pc) {
+            Some(instruction) if !uses_eip(instruction) => {
+                compiler.instruction(*instruction, interpret)
+            }
+            _ => None,
+        };
+        if compiled.is_none() {
+            compiler.asm.jmp(interpret);
+        }
+    }
+
+    let mut asm = compiler.asm;
+    for (pc, budget, interpret) in stubs {
+        for (label, reason) in [(budget, EXIT_BUDGET), (interpret, EXIT_INTERPRET)] {
+            asm.bind(label);
+            asm.mov_imm(Reg::Rax, pc as i32);
+            asm.mov_imm(Reg::Rdx, reason);
+            asm.jmp(exit);
+        }
+    }
+    asm.bind(exit);
+    asm.bytes(EPILOGUE);
+
+    (asm.finish(), offsets)
+}
+
+impl Compiler {
+    /// Compiles an instruction that jumps to `interpret` when it cannot go
+    /// on, or returns `None` if it should always be interpreted.
+    fn instruction(self: &mut Compiler, instruction: Instruction, interpret: Label) -> Option<()> {
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::Mov(t, s) => {
+                let (t, s) = (Operand::target(t)?, Operand::source(s)?);
+                self.load(Reg::Rax, s);
+                self.store(t, Reg::Rax);
+            }
+            Instruction::Push(s) => {
+                let s = Operand::source(s)?;
+                self.push(interpret, |c| c.load(Reg::Rcx, s));
+            }
+            Instruction::Pushf => {
+                self.push(interpret, |c| c.asm.load(Reg::Rcx, Reg::R13, FLAGS_OFFSET));
+            }
+            Instruction::Pop(t) => {
+                let t = Operand::target(t)?;
+                self.pop(interpret);
+                self.store(t, Reg::Rcx);
+            }
+            Instruction::Popf => {
+                self.pop(interpret);
+                self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rcx);
+            }
+            Instruction::Inc(t) => self.step(t, 1)?,
+            Instruction::Dec(t) => self.step(t, -1)?,
+            Instruction::Not(t) => self.unary(t, Assembler::not)?,
+            Instruction::Neg(t) => {
+                self.unary(t, |asm| {
+                    asm.neg();
+                    asm.flags();
+                })?;
+                self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rdx);
+            }
+            Instruction::Bswap(t) => self.unary(t, Assembler::bswap)?,
+            Instruction::Xchg(t1, t2) => {
+                let (t1, t2) = (Operand::target(t1)?, Operand::target(t2)?);
+                self.load(Reg::Rax, t1);
+                self.load(Reg::Rcx, t2);
+                self.store(t1, Reg::Rcx);
+                self.store(t2, Reg::Rax);
+            }
+            Instruction::Cmov(condition, t, s) => {
+                let (t, s) = (Operand::target(t)?, Operand::source(s)?);
+                let skip = self.asm.new_label();
+                self.skip_unless(condition, skip);
+                self.load(Reg::Rax, s);
+                self.store(t, Reg::Rax);
+                self.asm.bind(skip);
+            }
+            Instruction::Add(t, s) => self.alu(t, s, AluOp::Add)?,
+            Instruction::Sub(t, s) => self.alu(t, s, AluOp::Sub)?,
+            Instruction::Xor(t, s) => self.alu(t, s, AluOp::Xor)?,
+            Instruction::Or(t, s) => self.alu(t, s, AluOp::Or)?,
+            Instruction::And(t, s) => self.alu(t, s, AluOp::And)?,
+            Instruction::Div(t, s) => self.binary(t, s, |asm| {
+                check_divisor(asm, interpret);
+                asm.idiv();
+            })?,
+            Instruction::Mod(s1, s2) => {
+                let (s1, s2) = (Operand::source(s1)?, Operand::source(s2)?);
+                self.load(Reg::Rax, s1);
+                self.load(Reg::Rcx, s2);
+                check_divisor(&mut self.asm, interpret);
+                self.asm.idiv();
+                self.asm.store(Reg::R13, REMAINDER_OFFSET, Reg::Rdx);
+            }
+            Instruction::Rem(t) => {
+                let t = Operand::target(t)?;
+                self.asm.load(Reg::Rax, Reg::R13, REMAINDER_OFFSET);
+                self.store(t, Reg::Rax);
+            }
+            Instruction::Cmp(s1, s2) => {
+                let (s1, s2) = (Operand::source(s1)?, Operand::source(s2)?);
+                self.load(Reg::Rax, s1);
+                self.load(Reg::Rcx, s2);
+                self.asm.alu(AluOp::Cmp);
+                self.asm.flags();
+                self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rdx);
+            }
+            Instruction::Test(s1, s2) => {
+                let (s1, s2) = (Operand::source(s1)?, Operand::source(s2)?);
+                self.load(Reg::Rax, s1);
+                self.load(Reg::Rcx, s2);
+                self.asm.alu(AluOp::Test);
+                self.asm.flags();
+                self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rdx);
+            }
+            Instruction::Jmp(s) => self.jump(s, interpret)?,
+            Instruction::Je(s) => self.conditional_jump(s, FlagCondition::Equal, interpret)?,
+            Instruction::Jne(s) => self.conditional_jump(s, FlagCondition::NotEqual, interpret)?,
+            Instruction::Jg(s) => self.conditional_jump(s, FlagCondition::Greater, interpret)?,
+            Instruction::Jge(s) => {
+                self.conditional_jump(s, FlagCondition::GreaterOrEqual, interpret)?
+            }
+            Instruction::Jl(s) => self.conditional_jump(s, FlagCondition::Less, interpret)?,
+            Instruction::Jle(s) => {
+                self.conditional_jump(s, FlagCondition::LessOrEqual, interpret)?
+            }
+            Instruction::Ja(s) => self.conditional_jump(s, FlagCondition::Above, interpret)?,
+            Instruction::Jae(s) => {
+                self.conditional_jump(s, FlagCondition::AboveOrEqual, interpret)?
+            }
+            Instruction::Jb(s) => self.conditional_jump(s, FlagCondition::Below, interpret)?,
+            Instruction::Jbe(s) => {
+                self.conditional_jump(s, FlagCondition::BelowOrEqual, interpret)?
+            }
+            Instruction::Js(s) => self.conditional_jump(s, FlagCondition::Sign, interpret)?,
+            Instruction::Jns(s) => self.conditional_jump(s, FlagCondition::NotSign, interpret)?,
+            Instruction::Jo(s) => self.conditional_jump(s, FlagCondition::Overflow, interpret)?,
+            Instruction::Jno(s) => {
+                self.conditional_jump(s, FlagCondition::NotOverflow, interpret)?
+            }
+            Instruction::Loop(s) => {
+                // `ecx` changes before the jump, so the jump must not go
+                // back to `Program::step`
+                let len = self.instructions.len() - 1;
+                match Operand::source(s)? {
+                    Operand::Value(target) if usize::try_from(target).is_ok_and(|t| t <= len) => {}
+                    _ => return None,
+                }
+
+                let skip = self.asm.new_label();
+                self.load(Reg::Rax, Operand::Register(Register::Ecx));
+                self.asm.add_imm(-1);
+                self.store(Operand::Register(Register::Ecx), Reg::Rax);
+                self.asm.jcc(Condition::Equal, skip);
+                self.jump(s, interpret)?;
+                self.asm.bind(skip);
+            }
+            Instruction::Mul(..)
+            | Instruction::Shl(..)
+            | Instruction::Shr(..)
+            | Instruction::Sar(..)
+            | Instruction::Rol(..)
+            | Instruction::Ror(..)
+            | Instruction::Lea(..)
+            | Instruction::Ld(..)
+            | Instruction::Lds(..)
+            | Instruction::St(..)
+            | Instruction::Popcnt(..)
+            | Instruction::Clz(..)
+            | Instruction::Ctz(..)
+            | Instruction::Int
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::Prn(_)
+            | Instruction::Fld(..)
+            | Instruction::Fst(..)
+            | Instruction::Fadd(..)
+            | Instruction::Fsub(..)
+            | Instruction::Fmul(..)
+            | Instruction::Fdiv(..)
+            | Instruction::Fneg(_)
+            | Instruction::Fabs(_)
+            | Instruction::Fsqrt(_)
+            | Instruction::Fcmp(..)
+            | Instruction::Itof(..)
+            | Instruction::Ftoi(..)
+            | Instruction::Fprn(_)
+            | Instruction::Vld(..)
+            | Instruction::Vst(..)
+            | Instruction::Vop(..)
+            | Instruction::Vins(..)
+            | Instruction::Vext(..)
+            | Instruction::Vsum(..)
+            | Instruction::Spawn(..)
+            | Instruction::Join(_)
+            | Instruction::Cas(..)
+            | Instruction::Xadd(..)
+            | Instruction::Fence
+            | Instruction::Yield
+            | Instruction::Send(..)
+            | Instruction::Recv(..)
+            | Instruction::Native(..) => return None,
+        }
+
+        Some(())
+    }
+
+    fn load(self: &mut Compiler, reg: Reg, operand: Operand) {
+        match operand {
+            Operand::Register(r) => self.asm.load(reg, Reg::Rbx, r as i32 * 8),
+            Operand::Value(value) => self.asm.mov_imm(reg, value),
+            Operand::Address(address) => self.asm.load(reg, Reg::R12, address * 8),
+        }
+    }
+
+    fn store(self: &mut Compiler, operand: Operand, reg: Reg) {
+        match operand {
+            Operand::Register(r) => self.asm.store_word(Reg::Rbx, r as i32 * 8, reg),
+            Operand::Value(_) => unreachable!("immediate target"),
+            Operand::Address(address) => self.asm.store_word(Reg::R12, address * 8, reg),
+        }
+    }
+
+    fn unary<F: FnOnce(&mut Assembler)>(self: &mut Compiler, t: Target, op: F) -> Option<()> {
+        let t = Operand::target(t)?;
+        self.load(Reg::Rax, t);
+        op(&mut self.asm);
+        self.store(t, Reg::Rax);
+        Some(())
+    }
+
+    /// Applies `op` to the target in `eax` and the source in `ecx`.
+    fn binary<F: FnOnce(&mut Assembler)>(
+        self: &mut Compiler,
+        t: Target,
+        s: Source,
+        op: F,
+    ) -> Option<()> {
+        let (t, s) = (Operand::target(t)?, Operand::source(s)?);
+        self.load(Reg::Rax, t);
+        self.load(Reg::Rcx, s);
+        op(&mut self.asm);
+        self.store(t, Reg::Rax);
+        Some(())
+    }
+
+    /// Applies an ALU instruction to the target and the source, and sets the
+    /// flags from it.
+    fn alu(self: &mut Compiler, t: Target, s: Source, op: AluOp) -> Option<()> {
+        self.binary(t, s, |asm| {
+            asm.alu(op);
+            asm.flags();
+        })?;
+        self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rdx);
+        Some(())
+    }
+
+    /// Adds `value` to the target like `inc` or `dec`, which leave the carry
+    /// flag as it was.
+    fn step(self: &mut Compiler, t: Target, value: i8) -> Option<()> {
+        self.unary(t, |asm| {
+            asm.add_imm(value);
+            asm.flags();
+            asm.load(Reg::Rcx, Reg::R13, FLAGS_OFFSET);
+            asm.keep_carry();
+        })?;
+        self.asm.store(Reg::R13, FLAGS_OFFSET, Reg::Rdx);
+        Some(())
+    }
+
+    /// Pushes the value `value` loads into `ecx`, after checking the stack.
+    fn push<F: FnOnce(&mut Compiler)>(self: &mut Compiler, interpret: Label, value: F) {
+        self.asm.load(Reg::Rax, Reg::Rbx, ESP_OFFSET);
+        self.asm.add_imm(-1);
+        self.check_address(interpret);
+        value(self);
+        self.asm.store_indexed(Reg::Rcx);
+        self.asm.store_word(Reg::Rbx, ESP_OFFSET, Reg::Rax);
+    }
+
+    /// Pops a value into `ecx`.
+    fn pop(self: &mut Compiler, interpret: Label) {
+        self.asm.load(Reg::Rax, Reg::Rbx, ESP_OFFSET);
+        self.check_address(interpret);
+        self.asm.load_indexed(Reg::Rcx);
+        self.asm.add_imm(1);
+        self.asm.store_word(Reg::Rbx, ESP_OFFSET, Reg::Rax);
+    }
+
+    /// Jumps to `interpret` unless the address in `eax` is in the memory.
+    fn check_address(self: &mut Compiler, interpret: Label) {
+        self.asm.cmp_imm(Reg::Rax, MEMORY_SIZE as i32);
+        self.asm.jcc(Condition::AboveOrEqual, interpret);
+    }
+
+    fn jump(self: &mut Compiler, s: Source, interpret: Label) -> Option<()> {
+        let len = self.instructions.len() - 1;
+        match Operand::source(s)? {
+            Operand::Value(target) => match usize::try_from(target) {
+                Ok(t) if t <= len => self.asm.jmp(self.instructions[t]),
+                _ => self.asm.jmp(interpret),
+            },
+            operand => {
+                self.load(Reg::Rax, operand);
+                self.asm.cmp_imm(Reg::Rax, len as i32 + 1);
+                self.asm.jcc(Condition::AboveOrEqual, interpret);
+                self.asm.jmp_table();
+            }
+        }
+        Some(())
+    }
+
+    fn conditional_jump(
+        self: &mut Compiler,
+        s: Source,
+        condition: FlagCondition,
+        interpret: Label,
+    ) -> Option<()> {
+        // Check the operand before generating any code
+        Operand::source(s)?;
+
+        let skip = self.asm.new_label();
+        self.skip_unless(condition, skip);
+        self.jump(s, interpret)?;
+        self.asm.bind(skip);
+        Some(())
+    }
+
+    /// Jumps to `skip` unless `condition` holds for the VM flags.
+    fn skip_unless(self: &mut Compiler, condition: FlagCondition, skip: Label) {
+        // Whether the condition holds if any of the flags in `mask` are set,
+        // or if none are
+        let (mask, if_set) = match condition {
+            FlagCondition::Equal => (0x1, true),
+            FlagCondition::NotEqual => (0x1, false),
+            FlagCondition::Greater => (0x2, true),
+            FlagCondition::GreaterOrEqual => (0x3, true),
+            FlagCondition::Less => (0x3, false),
+            FlagCondition::LessOrEqual => (0x2, false),
+            FlagCondition::Above => (0x5, false),
+            FlagCondition::AboveOrEqual => (0x4, false),
+            FlagCondition::Below => (0x4, true),
+            FlagCondition::BelowOrEqual => (0x5, true),
+            FlagCondition::Sign => (0x8, true),
+            FlagCondition::NotSign => (0x8, false),
+            FlagCondition::Overflow => (0x10, true),
+            FlagCondition::NotOverflow => (0x10, false),
+        };
+        self.asm.load(Reg::Rax, Reg::R13, FLAGS_OFFSET);
+        self.asm.test_imm(mask);
+        let skip_condition = if if_set {
+            Condition::Equal
+        } else {
+            Condition::NotEqual
+        };
+        self.asm.jcc(skip_condition, skip);
+    }
+}
+
+/// Interprets division by zero, which fails, and by -1, which traps on
+/// `i32::MIN`.
+fn check_divisor(asm: &mut Assembler, interpret: Label) {
+    asm.cmp_imm(Reg::Rcx, 0);
+    asm.jcc(Condition::Equal, interpret);
+    asm.cmp_imm(Reg::Rcx, -1);
+    asm.jcc(Condition::Equal, interpret);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(source: &str) {
+        let program = Program::load(source.to_owned()).unwrap();
+
+        let mut stepped = program.initialize();
+        stepped.output = Box::new(std::io::sink());
+        let step_result = loop {
+            match program.step(&mut stepped) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let mut compiled = program.initialize();
+        compiled.output = Box::new(std::io::sink());
+        let jit_result = JitProgram::new(&program).unwrap().run(&mut compiled);
+
+        assert_eq!(jit_result, step_result, "{}", source);
+        assert_eq!(compiled.registers, stepped.registers, "{}", source);
+        assert_eq!(compiled.flags, stepped.flags, "{}", source);
+        assert_eq!(compiled.remainder, stepped.remainder, "{}", source);
+        assert_eq!(compiled.call_stack, stepped.call_stack, "{}", source);
+        assert!(compiled.mem_space == stepped.mem_space, "{}", source);
+    }
+
+    #[test]
+    fn compiled_instructions_behave_like_step() {
+        for source in &[
+            "mov eax, 5\nmov [10], eax\nmov ebx, [10]\nmov [11], [10]\nmov [12], 7",
+            "mov eax, 7\nadd eax, 3\nsub [5], eax\nmul eax, [5]\ndiv eax, -3\nxor eax, 0xff",
+            "mov eax, 1\nshl eax, 33\nmov ebx, -8\nshr ebx, 1\nor [3], ebx\nand [3], 12",
+            "mov eax, 0x7fffffff\ninc eax\ndec [4]\nnot [4]\nnot eax",
+            "mod 17, 5\nrem eax\nmod [1], -3\nrem [2]\nmov ebx, -2147483648\ndiv ebx, -1",
+            "push 1\npush [0]\npush esp\npushf\ncmp 2, 1\npopf\npop eax\npop [9]\npop esp",
+            "cmp 1, 2\njl 3\nprn 1\ncmp eax, ebx\nje 6\nprn 2\njge 8\nprn 3\nprn 4",
+            "mov eax, 0x7fffffff\nadd eax, 1\npushf\nsub eax, 1\npushf\ninc eax\npushf\nmul eax, 2\npushf",
+            "mov ebx, -1\nadd ebx, 1\npushf\ndec ebx\npushf\nshl ebx, 31\npushf\nshr ebx, 32\npushf\nxor ebx, ebx\npushf",
+            "cmp -1, 1\nja 3\nprn 1\ncmp 1, -1\njb 6\njbe 6\nprn 2\nmov eax, -5\nor eax, eax\njs 10\nprn 3",
+            "mov eax, -2147483648\nsub eax, 1\njo 3\nprn 4\njno 5\njns 6\nprn 5\ncmp 2, 2\njae 9\nprn 6",
+            "mov ecx, 5\nloop: dec ecx\ncmp ecx, 0\njg loop\nmov eax, 8\njmp eax\nnop\nnop\nnop",
+            "mov eax, 5\nneg eax\npushf\nneg [3]\npushf\nmov ebx, -2147483648\nneg ebx\npushf\nbswap eax\nbswap [3]",
+            "mov eax, -8\nsar eax, 1\npushf\nrol eax, 33\npushf\nror [2], 1\nmov ebx, 3\nror ebx, 2\npushf\nshr eax, 4",
+            "mov eax, 1\nmov ebx, 2\nxchg eax, ebx\nxchg ebx, [4]\nxchg [4], [5]\nlea ecx, [eax+ebx*8-3]\nlea edx, [7]",
+            "mov eax, 6\ntest eax, 1\npushf\ntest [2], eax\ncmp 1, 2\ncmovl eax, 9\ncmovge ebx, 9\ncmova [3], eax\ncmovb [4], [3]",
+            "popcnt eax, -1\nclz ebx, 1\nctz ecx, 0\nclz [3], 0\nctz edx, [3]\npopcnt [5], 1234",
+            "mov ecx, 3\nadd eax, 2\nloop 1\nprn eax\nmov ecx, 1\nloop 0\nprn ecx",
+            "call 3\nprn eax\njmp 6\nmov eax, 5\nret\nnop\nnop",
+            "mov eax, eip\nadd eip, 2\nmov ebx, eip",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn errors_behave_like_step() {
+        for source in &[
+            "mov eax, 1\nmov [100000000], eax",
+            "mov [100000000], [200000000]",
+            "mov eax, 0\ndiv ebx, eax",
+            "mod 1, 0",
+            "jmp 100",
+            "mov eax, -1\njl eax",
+            "call -1",
+            "mov ecx, 2\nloop 100",
+            "xchg eax, [100000000]",
+            "cmove [100000000], 1",
+            "mov esp, 0\npush 1",
+            "mov esp, 16777216\npop eax",
+            "ret",
+        ] {
+            assert_same(source);
+        }
+    }
+
+    #[test]
+    fn run_steps_stops_after_the_given_number_of_steps() {
+        let program = Program::load("inc eax\nprn eax\ninc eax".to_owned()).unwrap();
+        let jit = JitProgram::new(&program).unwrap();
+        let mut memory = program.initialize();
+        memory.output = Box::new(std::io::sink());
+
+        assert_eq!(jit.run_steps(&mut memory, 2), Ok(true));
+        assert_eq!(memory.registers[Register::Eax as usize], 1);
+        assert_eq!(memory.registers[EIP], 2);
+
+        assert_eq!(jit.run_steps(&mut memory, 1), Ok(true));
+        assert_eq!(memory.registers[Register::Eax as usize], 2);
+        assert_eq!(jit.run_steps(&mut memory, 1), Ok(false));
+        assert_eq!(memory.registers[EIP], 3);
+    }
+}
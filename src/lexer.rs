@@ -18,7 +18,7 @@ impl<'a> LexerContext<'a> {
     
                 let line_tokens: Vec<_> = line
                     .split(&[' ', '\t', ','][..])
-                    .filter(|token| token.len() > 0)
+                    .filter(|token| !token.is_empty())
                     .map(|token| {
                         let token = match defines.get(token) {
                             Some(value) => value,
@@ -1,8 +1,23 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod backtrace;
+pub mod c_backend;
+pub mod cfg;
+pub mod channels;
 pub mod context;
+pub mod core_dump;
+pub mod debugger;
+pub mod decoded;
 pub mod instruction;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
 pub mod lexer;
+pub mod multicore;
+pub mod native;
+pub mod optimizer;
 pub mod parser;
 pub mod preprocessor;
+pub mod snapshot;
+pub mod stack;
+pub mod verifier;
@@ -0,0 +1,679 @@
+//! Running a program on several cores that share its memory.
+//!
+//! A program starts on core 0, and `spawn` starts more cores, numbered in
+//! the order they were spawned. Each core has registers, flags, a call stack
+//! and an `eip` of its own, and shares the memory words and the output of
+//! the program with the others. A core finishes when it runs off the end of
+//! the program, and a machine when all of its cores have.
+//!
+//! A `Machine` runs its cores in one of three ways:
+//!
+//! - `run_round_robin` runs them in turn on the calling thread, in the
+//!   order of their ids, each for a fixed number of instructions. This is
+//!   deterministic: with the same quantum a program interleaves the same
+//!   way every time, so a race shows up, or does not, reproducibly.
+//! - `run_cooperative` runs them in turn in the same order, each until it
+//!   executes a `yield`, which makes the cores green threads: they switch
+//!   only where the program says, such as a generator handing a value to
+//!   the core that consumes it.
+//! - `run_threaded` runs every core on a thread of its own, but it is
+//!   serialized: a thread holds a lock over the whole machine while it runs
+//!   an instruction, so no two instructions ever run in parallel. Only the
+//!   order in which the threads get the lock, and so how the cores
+//!   interleave, is left to the operating system, and nothing makes it
+//!   fair.
+//!
+//! Either way, every instruction is atomic: it sees memory as the
+//! instructions before it, on any core, left it, and no other core sees it
+//! half done, so memory is sequentially consistent. A sequence of
+//! instructions is not atomic, which is where races come from. `cas` and
+//! `xadd` read and write a word in one instruction, and are what locks and
+//! counters are built on. `fence` orders the accesses before it against
+//! those after it, which every instruction already does, so it does
+//! nothing. It is there so programs say where they rely on the ordering.
+//!
+//! A core that joins a core that has not finished stays at the `join`, and
+//! gives up the rest of its turn, as does a core that yields. On threads,
+//! both let the operating system run another thread. When every core that
+//! has not finished waits in a `join`, none of them ever will, and the
+//! machine stops with `MachineError::Deadlock`.
+
+use crate::{
+    context::{ExecutionError, Memory, Program},
+    instruction::{Instruction, Register},
+    preprocessor::SourceLocation,
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, Write},
+    mem,
+    sync::Mutex,
+    thread::{self, Scope},
+};
+
+/// The number of instructions a core runs per turn in round-robin mode,
+/// unless told otherwise.
+pub const DEFAULT_QUANTUM: u64 = 1;
+
+/// The cores of a machine, as the running core sees them through
+/// `Memory::cores`.
+pub struct Cores {
+    /// Whether each core, by id, has finished.
+    finished: Vec<bool>,
+    /// The cores spawned since the machine last started them, with their
+    /// ids.
+    spawned: Vec<(usize, Memory)>,
+}
+
+/// A core of a machine waiting in a `join`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Joining {
+    pub core: usize,
+    pub instruction_index: i64,
+    pub instruction: Instruction,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MachineError {
+    /// A runtime error on the core with the given id.
+    Execution(usize, ExecutionError),
+    /// The cores that have not finished, all waiting in a `join` of a core
+    /// that will not finish either.
+    Deadlock(Vec<Joining>),
+}
+
+/// A program running on several cores.
+pub struct Machine<'a> {
+    program: &'a Program,
+    /// The cores by id. While a core is not running, the memory words and
+    /// output it shares with the others are in `shared` instead.
+    cores: Vec<Memory>,
+    shared: Shared,
+}
+
+/// What the cores of a machine share, which is moved into a core while it
+/// runs.
+struct Shared {
+    mem_space: Vec<i64>,
+    output: Box<dyn Write + Send>,
+    cores: Option<Box<Cores>>,
+}
+
+/// The state of a machine running on threads.
+struct Threads {
+    shared: Shared,
+    /// The cores whose threads have finished, by id.
+    cores: Vec<Option<Memory>>,
+    /// The `eip` of the `join` each core, by id, has waited in since a core
+    /// last made progress.
+    joining: Vec<Option<i64>>,
+    error: Option<MachineError>,
+}
+
+/// What a step did to a core.
+enum Step {
+    Ran,
+    /// The core is waiting in a `join`.
+    Blocked,
+    Yielded,
+    Finished,
+}
+
+impl Cores {
+    pub(crate) fn next_id(self: &Cores) -> usize {
+        self.finished.len()
+    }
+
+    pub(crate) fn spawn(self: &mut Cores, core: Memory) {
+        self.spawned.push((self.next_id(), core));
+        self.finished.push(false);
+    }
+
+    /// Whether the core has finished, or `None` if there is no such core.
+    pub(crate) fn is_finished(self: &Cores, id: i64) -> Option<bool> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|id| self.finished.get(id))
+            .copied()
+    }
+}
+
+/// The state of a core started by `spawn`, which has the registers, flags
+/// and remainder of the core that spawned it, with `eip` at `entry` and an
+/// empty stack at `stack`.
+pub(crate) fn spawned_core(memory: &Memory, entry: i64, stack: i64) -> Memory {
+    let mut core = Memory::new(0, 0);
+    core.flags = memory.flags;
+    core.remainder = memory.remainder;
+    core.registers = memory.registers;
+    core.float_registers = memory.float_registers;
+    core.vector_registers = memory.vector_registers;
+    core.strict_returns = memory.strict_returns;
+
+    core.registers[Register::Eip as usize] = entry;
+    core.registers[Register::Esp as usize] = stack;
+    core.registers[Register::Ebp as usize] = stack;
+
+    core
+}
+
+impl Program {
+    /// Whether the program starts other cores, and so needs a `Machine` to
+    /// run.
+    pub fn spawns_cores(self: &Program) -> bool {
+        self.instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::Spawn(..)))
+    }
+}
+
+impl<'a> Machine<'a> {
+    /// A machine running the program with `memory` as the state of core 0.
+    pub fn new(program: &'a Program, mut memory: Memory) -> Machine<'a> {
+        let shared = Shared {
+            mem_space: mem::take(&mut memory.mem_space),
+            output: mem::replace(&mut memory.output, Box::new(io::sink())),
+            cores: Some(Box::new(Cores {
+                finished: vec![false],
+                spawned: vec![],
+            })),
+        };
+
+        Machine {
+            program,
+            cores: vec![memory],
+            shared,
+        }
+    }
+
+    /// The number of cores started so far, including core 0.
+    pub fn core_count(self: &Machine<'a>) -> usize {
+        self.cores.len()
+    }
+
+    pub fn is_finished(self: &Machine<'a>) -> bool {
+        self.cores().finished.iter().all(|finished| *finished)
+    }
+
+    /// The state of a core along with the memory words and output of the
+    /// machine, which can be saved or stepped on its own like that of any
+    /// program.
+    pub fn into_memory(mut self: Machine<'a>, core: usize) -> Memory {
+        let mut memory = self.cores.swap_remove(core);
+        switch(&mut memory, &mut self.shared);
+        memory.cores = None;
+        memory
+    }
+
+    /// Runs the cores in turn, `quantum` instructions at a time, until they
+    /// have all finished, one of them fails or they deadlock.
+    pub fn run_round_robin(self: &mut Machine<'a>, quantum: u64) -> Result<(), MachineError> {
+        while !self.is_finished() {
+            // Cores spawned during a round take their first turn in it
+            let mut progressed = false;
+            let mut id = 0;
+            while id < self.cores.len() {
+                if !self.cores().finished[id] {
+                    progressed |= self.run_turn(id, quantum)?;
+                }
+                id += 1;
+            }
+
+            if !progressed {
+                return Err(MachineError::Deadlock(self.joining()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the cores in turn, each until it yields, waits in a `join` or
+    /// finishes, until they have all finished, one of them fails or they
+    /// deadlock. A core that never yields keeps the others from running.
+    pub fn run_cooperative(self: &mut Machine<'a>) -> Result<(), MachineError> {
+        self.run_round_robin(u64::MAX)
+    }
+
+    /// Runs a turn of a core, and returns whether it did anything but wait
+    /// in a `join`.
+    fn run_turn(self: &mut Machine<'a>, id: usize, quantum: u64) -> Result<bool, MachineError> {
+        for step_index in 0..quantum.max(1) {
+            let step = step(self.program, id, &mut self.cores[id], &mut self.shared);
+            self.start_spawned();
+            match step.map_err(|error| MachineError::Execution(id, error))? {
+                Step::Ran => {}
+                Step::Blocked => return Ok(step_index > 0),
+                Step::Yielded | Step::Finished => break,
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn joining(self: &Machine<'a>) -> Vec<Joining> {
+        self.cores
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !self.cores().finished[*id])
+            .map(|(core, memory)| {
+                joining(self.program, core, memory.registers[Register::Eip as usize])
+            })
+            .collect()
+    }
+
+    fn start_spawned(self: &mut Machine<'a>) {
+        for (id, core) in self.shared.take_spawned() {
+            debug_assert_eq!(id, self.cores.len());
+            self.cores.push(core);
+        }
+    }
+
+    /// Runs every core on a thread of its own until they have all finished,
+    /// one of them fails or they deadlock, in which case the others stop
+    /// too. The threads run one instruction at a time, never in parallel.
+    pub fn run_threaded(self: &mut Machine<'a>) -> Result<(), MachineError> {
+        let finished = self.cores().finished.clone();
+        let threads = Mutex::new(Threads {
+            shared: mem::replace(&mut self.shared, Shared::empty()),
+            cores: vec![],
+            joining: vec![],
+            error: None,
+        });
+        let program = self.program;
+
+        thread::scope(|scope| {
+            for (id, core) in mem::take(&mut self.cores).into_iter().enumerate() {
+                if finished[id] {
+                    threads.lock().unwrap().finish(id, core);
+                } else {
+                    let threads = &threads;
+                    scope.spawn(move || run_thread(scope, program, threads, id, core));
+                }
+            }
+        });
+
+        let threads = threads.into_inner().unwrap();
+        self.shared = threads.shared;
+        self.cores = threads.cores.into_iter().map(Option::unwrap).collect();
+        match threads.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn cores(self: &Machine<'a>) -> &Cores {
+        self.shared.cores.as_ref().unwrap()
+    }
+}
+
+fn run_thread<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    program: &'scope Program,
+    threads: &'scope Mutex<Threads>,
+    id: usize,
+    mut core: Memory,
+) {
+    loop {
+        let mut state = threads.lock().unwrap();
+        if state.error.is_some() {
+            break;
+        }
+
+        let step = step(program, id, &mut core, &mut state.shared);
+        for (id, core) in state.shared.take_spawned() {
+            scope.spawn(move || run_thread(scope, program, threads, id, core));
+        }
+        if !matches!(step, Ok(Step::Blocked)) {
+            state.joining.clear();
+        }
+        match step {
+            Ok(Step::Ran) => {}
+            Ok(Step::Blocked) if state.wait(id, core.registers[Register::Eip as usize]) => {
+                state.error = Some(MachineError::Deadlock(state.joining(program)));
+                break;
+            }
+            Ok(Step::Blocked) | Ok(Step::Yielded) => {
+                drop(state);
+                thread::yield_now();
+            }
+            Ok(Step::Finished) => break,
+            Err(error) => {
+                state.error = Some(MachineError::Execution(id, error));
+                break;
+            }
+        }
+    }
+
+    threads.lock().unwrap().finish(id, core);
+}
+
+/// Runs an instruction on a core.
+fn step(
+    program: &Program,
+    id: usize,
+    core: &mut Memory,
+    shared: &mut Shared,
+) -> Result<Step, ExecutionError> {
+    let eip = core.registers[Register::Eip as usize];
+
+    switch(core, shared);
+    let result = program.step(core);
+    switch(core, shared);
+
+    if !result? {
+        shared.cores.as_mut().unwrap().finished[id] = true;
+        return Ok(Step::Finished);
+    }
+    match program.instructions.get(eip as usize) {
+        Some(Instruction::Join(_)) if core.registers[Register::Eip as usize] == eip => {
+            Ok(Step::Blocked)
+        }
+        Some(Instruction::Yield) => Ok(Step::Yielded),
+        _ => Ok(Step::Ran),
+    }
+}
+
+/// A core waiting in the `join` at `eip`.
+fn joining(program: &Program, core: usize, eip: i64) -> Joining {
+    let index = eip as usize;
+    Joining {
+        core,
+        instruction_index: eip,
+        instruction: program.instructions[index],
+        location: program.locations.get(index).cloned(),
+    }
+}
+
+/// Moves what the cores share into `core`, or back into `shared`.
+fn switch(core: &mut Memory, shared: &mut Shared) {
+    mem::swap(&mut core.mem_space, &mut shared.mem_space);
+    mem::swap(&mut core.output, &mut shared.output);
+    mem::swap(&mut core.cores, &mut shared.cores);
+}
+
+impl Shared {
+    fn empty() -> Shared {
+        Shared {
+            mem_space: vec![],
+            output: Box::new(io::sink()),
+            cores: None,
+        }
+    }
+
+    fn take_spawned(self: &mut Shared) -> Vec<(usize, Memory)> {
+        mem::take(&mut self.cores.as_mut().unwrap().spawned)
+    }
+}
+
+impl Threads {
+    fn finish(self: &mut Threads, id: usize, core: Memory) {
+        if self.cores.len() <= id {
+            self.cores.resize_with(id + 1, || None);
+        }
+        self.cores[id] = Some(core);
+    }
+
+    /// Records that a core waits in the `join` at `eip`, and returns
+    /// whether every core that has not finished now does, since none has
+    /// made progress.
+    fn wait(self: &mut Threads, id: usize, eip: i64) -> bool {
+        if self.joining.len() <= id {
+            self.joining.resize(id + 1, None);
+        }
+        self.joining[id] = Some(eip);
+
+        let finished = &self.shared.cores.as_ref().unwrap().finished;
+        finished
+            .iter()
+            .enumerate()
+            .all(|(id, finished)| *finished || self.joining.get(id).copied().flatten().is_some())
+    }
+
+    fn joining(self: &Threads, program: &Program) -> Vec<Joining> {
+        self.joining
+            .iter()
+            .enumerate()
+            .filter_map(|(core, eip)| eip.map(|eip| joining(program, core, eip)))
+            .collect()
+    }
+}
+
+impl fmt::Display for Joining {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "core {} at instruction {}: {}",
+            self.core, self.instruction_index, self.instruction
+        )?;
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineError::Execution(core, error) => write!(f, "on core {}: {}", core, error),
+            MachineError::Deadlock(joining) => {
+                write!(f, "deadlock, every core is waiting in a join:")?;
+                for joining in joining {
+                    write!(f, "\n  {}", joining)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ExecutionErrorKind;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Two cores that each add 1 to the word at 0 a hundred times, reading
+    // and writing it with separate instructions
+    const RACE: &str = "spawn ebx, core, 1000\ncall work\njoin ebx\njmp end\n\
+                        core: call work\njmp end\n\
+                        work: mov ecx, 100\n\
+                        count: mov eax, [0]\ninc eax\nmov [0], eax\nloop count\nret\nend:";
+
+    // The same with `xadd` for the word at 0, and a lock taken with `cas`
+    // around the increment of the word at 1
+    const ATOMIC: &str = "spawn ebx, core, 1000\ncall work\njoin ebx\njmp end\n\
+                          core: call work\njmp end\n\
+                          work: mov ecx, 100\n\
+                          count: mov eax, 1\nxadd eax, 0\n\
+                          lock: mov eax, 0\ncas eax, 2, 1\njne lock\n\
+                          mov eax, [1]\ninc eax\nmov [1], eax\nmov [2], 0\n\
+                          loop count\nret\nend:";
+
+    // A generator of squares on core 1 writing each to the word at 0, which
+    // core 0 prints, until core 0 sets the word at 1
+    const GENERATOR: &str = "spawn ebx, squares, 1000\nmov ecx, 4\n\
+                             next: yield\nprn [0]\nloop next\n\
+                             mov [1], 1\njoin ebx\njmp end\n\
+                             squares: mov eax, 1\n\
+                             square: mov edx, eax\nmul edx, eax\nmov [0], edx\ninc eax\n\
+                             yield\ncmp [1], 0\nje square\nend:";
+
+    fn machine(program: &Program) -> Machine<'_> {
+        let mut memory = program.initialize();
+        memory.output = Box::new(io::sink());
+        Machine::new(program, memory)
+    }
+
+    #[test]
+    fn round_robin_interleaves_the_same_way_every_time() {
+        let program = Program::load(RACE.to_owned()).unwrap();
+        let count = |quantum| {
+            let mut machine = machine(&program);
+            machine.run_round_robin(quantum).unwrap();
+            machine.into_memory(0).mem_space[0]
+        };
+
+        // In lockstep, both cores read a value before either writes it
+        assert_eq!(count(1), 100);
+        assert_eq!(count(1), 100);
+        // Other quanta lose other increments, but the same ones every time
+        assert_eq!(count(7), 115);
+        assert_eq!(count(7), 115);
+        // One turn is enough for all of the work of a core
+        assert_eq!(count(1000), 200);
+    }
+
+    #[test]
+    fn atomic_instructions_do_not_race() {
+        let program = Program::load(ATOMIC.to_owned()).unwrap();
+
+        for quantum in [1, 3, 1000] {
+            let mut machine = machine(&program);
+            machine.run_round_robin(quantum).unwrap();
+            assert_eq!(machine.into_memory(0).mem_space[..3], [200, 200, 0]);
+        }
+
+        let mut machine = machine(&program);
+        machine.run_threaded().unwrap();
+        assert!(machine.is_finished());
+        assert_eq!(machine.core_count(), 2);
+        assert_eq!(machine.into_memory(0).mem_space[..3], [200, 200, 0]);
+    }
+
+    #[test]
+    fn cooperative_cores_switch_where_they_yield() {
+        let program = Program::load(GENERATOR.to_owned()).unwrap();
+        let printed = |run: &dyn Fn(&mut Machine) -> Result<(), MachineError>| {
+            let output = SharedOutput::default();
+            let mut memory = program.initialize();
+            memory.output = Box::new(output.clone());
+            let mut machine = Machine::new(&program, memory);
+            run(&mut machine).unwrap();
+            let printed = output.0.lock().unwrap().clone();
+            String::from_utf8(printed).unwrap()
+        };
+
+        assert_eq!(printed(&|m| m.run_cooperative()), "1\n4\n9\n16\n");
+        // Yielding ends a turn early in round-robin mode too
+        assert_eq!(printed(&|m| m.run_round_robin(1000)), "1\n4\n9\n16\n");
+        // Turns that end before the yields no longer hand over every value
+        assert_eq!(printed(&|m| m.run_round_robin(1)), "0\n1\n1\n4\n");
+
+        // On a single core, yielding does nothing
+        let program = Program::load("mov eax, 2\nyield\nprn eax".to_owned()).unwrap();
+        assert!(!program.spawns_cores());
+        program.run().unwrap();
+    }
+
+    #[test]
+    fn spawned_cores_start_with_the_registers_of_their_parent() {
+        let program = Program::load(
+            "mov ecx, 7\nspawn eax, core, 500\nmov ecx, 8\njoin eax\njmp end\n\
+             core: mov [0], ecx\nmov [1], eax\nmov [2], esp\nend:"
+                .to_owned(),
+        )
+        .unwrap();
+        let mut machine = machine(&program);
+        machine.run_round_robin(DEFAULT_QUANTUM).unwrap();
+
+        let memory = machine.into_memory(1);
+        assert_eq!(memory.mem_space[..3], [7, 1, 500]);
+        assert_eq!(memory.registers[Register::Eip as usize], 8);
+        assert_eq!(memory.registers[Register::Ebp as usize], 500);
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_core() {
+        let program = Program::load(
+            "spawn eax, core, 500\njoin eax\njmp end\ncore: mov eax, [300000000]\nend:".to_owned(),
+        )
+        .unwrap();
+
+        let mut round_robin = machine(&program);
+        let error = round_robin.run_round_robin(DEFAULT_QUANTUM).unwrap_err();
+        let (core, execution_error) = match &error {
+            MachineError::Execution(core, error) => (*core, error),
+            error => panic!("expected an execution error, got {:?}", error),
+        };
+        assert_eq!(core, 1);
+        assert_eq!(execution_error.instruction_index, 3);
+        assert_eq!(
+            execution_error.kind,
+            ExecutionErrorKind::DataAddressOutOfRange(300000000)
+        );
+
+        let mut threaded = machine(&program);
+        assert_eq!(threaded.run_threaded(), Err(error));
+    }
+
+    #[test]
+    fn cores_joining_each_other_deadlock() {
+        // Core 0 joins core 1, which joins core 0 after a few instructions
+        let program = Program::load(
+            "spawn ebx, core, 500\njoin ebx\njmp end\n\
+             core: nop\nnop\njoin 0\nend:"
+                .to_owned(),
+        )
+        .unwrap();
+        let joining = |result| match result {
+            Err(MachineError::Deadlock(joining)) => joining
+                .iter()
+                .map(|j: &Joining| (j.core, j.instruction_index))
+                .collect::<Vec<_>>(),
+            result => panic!("expected a deadlock, got {:?}", result),
+        };
+
+        for quantum in [1, 2, 1000] {
+            let result = machine(&program).run_round_robin(quantum);
+            assert_eq!(joining(result), [(0, 1), (1, 5)]);
+        }
+        assert_eq!(
+            joining(machine(&program).run_cooperative()),
+            [(0, 1), (1, 5)]
+        );
+        assert_eq!(joining(machine(&program).run_threaded()), [(0, 1), (1, 5)]);
+
+        // A core joining itself on its own
+        let program = Program::load("spawn eax, end, 500\njoin 0\nend:".to_owned()).unwrap();
+        let error = machine(&program).run_threaded().unwrap_err();
+        assert_eq!(joining(Err(error.clone())), [(0, 1)]);
+        assert_eq!(
+            error.to_string(),
+            "deadlock, every core is waiting in a join:\n  core 0 at instruction 1: join 0 (line 2)"
+        );
+    }
+
+    #[test]
+    fn spawn_and_join_need_a_machine() {
+        let program = Program::load("spawn eax, end, 500\njoin 2\nend:".to_owned()).unwrap();
+
+        match machine(&program).run_round_robin(1).unwrap_err() {
+            MachineError::Execution(core, error) => {
+                assert_eq!((core, error.instruction_index), (0, 1));
+                assert_eq!(error.kind, ExecutionErrorKind::InvalidCore(2));
+            }
+            error => panic!("expected an execution error, got {:?}", error),
+        }
+
+        let error = program.run().unwrap_err();
+        assert_eq!(error.instruction_index, 0);
+        assert_eq!(error.kind, ExecutionErrorKind::SingleCore);
+    }
+}
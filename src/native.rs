@@ -0,0 +1,256 @@
+//! Rust functions that programs call by name.
+//!
+//! A host embedding the VM registers its functions in `Natives` and loads
+//! programs with `Program::load_with_natives`. A `call` of a name that is
+//! not a label of the program, but that of a native function, calls the
+//! function instead of jumping, as `Instruction::Native`. A label of the
+//! program takes precedence over a function of the same name.
+//!
+//! The calling convention follows that of programs calling each other:
+//!
+//! - The caller pushes the arguments, last to first, so the first argument
+//!   is at `[esp]`, the second at `[esp + 1]`, and so on. No return address
+//!   is pushed, and no frame is added to the call stack. `Memory::argument`
+//!   reads them.
+//! - The function returns its result, if any, in `eax`, and the caller pops
+//!   the arguments.
+//! - The function can read and write all of `Memory`, but after it returns,
+//!   the program goes on at the instruction after the `call`, whatever it
+//!   left in `eip`. In 32-bit programs, the registers are truncated to 32
+//!   bits.
+//! - An error returned by the function is reported at the `call`, like any
+//!   runtime error. `ExecutionErrorKind::Native` carries errors of the
+//!   host's own.
+//!
+//! Observers are told that the function may have written any register, the
+//! flags and the remainder, but not which memory words it wrote, so the
+//! debugger cannot step back over a call to a native function that writes
+//! memory.
+//!
+//! Only `Program::step` runs native functions: the other engines leave
+//! them to it, and programs that call them cannot be translated to C.
+
+use crate::{
+    context::{ExecutionErrorKind, Memory, Program},
+    instruction::{Instruction, Register},
+};
+use std::{collections::HashSet, convert::TryFrom, sync::Mutex};
+
+/// A function that programs can call.
+///
+/// It returns an `ExecutionErrorKind` rather than a whole `ExecutionError`,
+/// like the instructions do: the rest of the error is the state of the VM at
+/// the `call`, such as its index, source location and backtrace, which the
+/// VM fills in when it reports the error, and which the function could only
+/// get wrong.
+pub type NativeFunction = fn(&mut Memory) -> Result<(), ExecutionErrorKind>;
+
+/// The native functions programs are loaded with.
+#[derive(Clone, Default)]
+pub struct Natives {
+    functions: Vec<(&'static str, NativeFunction)>,
+}
+
+lazy_static! {
+    /// The names of the native functions registered so far, which calls of
+    /// them refer to.
+    static ref NAMES: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+impl Natives {
+    pub fn new() -> Natives {
+        Natives::default()
+    }
+
+    /// Registers `function` under `name`, replacing the function registered
+    /// under it before, if any.
+    pub fn register(self: &mut Natives, name: &str, function: NativeFunction) {
+        match self.index_of(name) {
+            Some(index) => self.functions[index as usize].1 = function,
+            None => self.functions.push((intern(name), function)),
+        }
+    }
+
+    /// The name of the function `Instruction::Native` calls with `index`.
+    pub fn name(self: &Natives, index: u32) -> &'static str {
+        self.functions[index as usize].0
+    }
+
+    /// The names of the functions, in the order of their indices.
+    pub fn names(self: &Natives) -> impl Iterator<Item = &'static str> + '_ {
+        self.functions.iter().map(|(name, _)| *name)
+    }
+
+    pub(crate) fn index_of(self: &Natives, name: &str) -> Option<u32> {
+        self.functions
+            .iter()
+            .position(|(n, _)| *n == name)
+            .map(|index| index as u32)
+    }
+
+    pub(crate) fn function(self: &Natives, index: u32) -> NativeFunction {
+        self.functions[index as usize].1
+    }
+}
+
+/// The name as a string that lives as long as the program, which only
+/// leaks memory the first time a name is registered.
+fn intern(name: &str) -> &'static str {
+    let mut names = NAMES.lock().unwrap();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+impl Program {
+    /// Whether the program calls native functions.
+    pub fn calls_natives(self: &Program) -> bool {
+        self.instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::Native(..)))
+    }
+}
+
+impl Memory {
+    /// The argument of a native function with the given index, counting from
+    /// 0 for the last one pushed.
+    pub fn argument(self: &Memory, index: usize) -> Result<i64, ExecutionErrorKind> {
+        let addr = self.registers[Register::Esp as usize].wrapping_add(index as i64);
+        usize::try_from(addr)
+            .ok()
+            .and_then(|a| self.mem_space.get(a))
+            .copied()
+            .ok_or(ExecutionErrorKind::DataAddressOutOfRange(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{c_backend::TranslationError, context::ExecutionError};
+    use std::io;
+
+    fn sum(memory: &mut Memory) -> Result<(), ExecutionErrorKind> {
+        let count = memory.argument(0)?;
+        let mut sum = 0;
+        for i in 1..=count {
+            sum += memory.argument(i as usize)?;
+        }
+        memory.registers[Register::Eax as usize] = sum;
+        Ok(())
+    }
+
+    fn fail(_: &mut Memory) -> Result<(), ExecutionErrorKind> {
+        Err(ExecutionErrorKind::Native(Box::new(
+            "no such file".to_owned(),
+        )))
+    }
+
+    fn natives() -> Natives {
+        let mut natives = Natives::new();
+        natives.register("sum", sum);
+        natives.register("fail", fail);
+        natives
+    }
+
+    fn run(source: &str) -> (Result<(), ExecutionError>, Memory) {
+        let program = Program::load_with_natives(source.to_owned(), None, &natives()).unwrap();
+        let mut memory = program.initialize();
+        memory.output = Box::new(io::sink());
+        let result = loop {
+            match program.step(&mut memory) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(error) => break Err(error),
+            }
+        };
+        (result, memory)
+    }
+
+    #[test]
+    fn natives_take_their_arguments_from_the_stack() {
+        let (result, memory) =
+            run("push 5\npush 4\npush 2\ncall sum\nadd esp, 3\nmov ebx, eax\nmov [0], esp");
+        result.unwrap();
+        assert_eq!(memory.registers[Register::Ebx as usize], 9);
+        // Nothing else was pushed, and the call stack is untouched
+        assert_eq!(
+            memory.mem_space[0],
+            memory.registers[Register::Esp as usize]
+        );
+        assert!(memory.call_stack.is_empty());
+    }
+
+    #[test]
+    fn results_are_truncated_in_32_bit_programs() {
+        let (result, memory) = run("push 0x7fffffff\npush 0x7fffffff\npush 2\ncall sum");
+        result.unwrap();
+        assert_eq!(memory.registers[Register::Eax as usize], -2);
+
+        let (result, memory) = run("%bits 64\npush 0x7fffffff\npush 0x7fffffff\npush 2\ncall sum");
+        result.unwrap();
+        assert_eq!(memory.registers[Register::Eax as usize], 0xfffffffe);
+    }
+
+    #[test]
+    fn labels_take_precedence_over_natives() {
+        let (result, memory) = run("call sum\njmp end\nsum: mov eax, 7\nret\nend:");
+        result.unwrap();
+        assert_eq!(memory.registers[Register::Eax as usize], 7);
+    }
+
+    #[test]
+    fn errors_are_reported_at_the_call() {
+        let (result, _) = run("nop\ncall fail");
+        let error = result.unwrap_err();
+        assert_eq!(error.instruction_index, 1);
+        assert_eq!(
+            error.kind,
+            ExecutionErrorKind::Native(Box::new("no such file".to_owned()))
+        );
+
+        // Reading an argument outside of memory
+        let (result, _) = run("mov esp, -1\ncall sum");
+        assert_eq!(
+            result.unwrap_err().kind,
+            ExecutionErrorKind::DataAddressOutOfRange(-1)
+        );
+    }
+
+    #[test]
+    fn programs_calling_natives_are_not_translated_to_c() {
+        let program = Program::load_with_natives("call sum".to_owned(), None, &natives()).unwrap();
+        assert!(program.calls_natives());
+        assert_eq!(program.to_c(), Err(TranslationError::Natives));
+    }
+
+    #[test]
+    fn calls_are_written_with_the_name_of_the_function() {
+        let program = Program::load_with_natives("call sum".to_owned(), None, &natives()).unwrap();
+        assert_eq!(program.instructions[0], Instruction::Native(0, "sum"));
+        assert_eq!(program.instructions[0].to_string(), "call sum");
+
+        // and so parse back to the same instruction
+        let source = program.instructions[0].to_string();
+        let reloaded = Program::load_with_natives(source, None, &natives()).unwrap();
+        assert_eq!(reloaded.instructions, program.instructions);
+
+        let (result, _) = run("nop\ncall fail");
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("at instruction 1: call fail"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_names_are_undefined_labels() {
+        assert!(Program::load_with_natives("call sum".to_owned(), None, &natives()).is_ok());
+        assert!(Program::load_with_natives("call product".to_owned(), None, &natives()).is_err());
+        // Only `call` can call a native function
+        assert!(Program::load_with_natives("jmp sum".to_owned(), None, &natives()).is_err());
+        assert!(Program::load("call sum".to_owned()).is_err());
+    }
+}
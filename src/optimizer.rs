@@ -0,0 +1,966 @@
+//! Rewrites programs into equivalent ones that execute fewer instructions.
+//!
+//! The passes replace the instructions they remove with `nop`, so that
+//! instruction indices stay valid while they run, and the `nop`s are removed
+//! at the end with every jump target remapped. That last step changes code
+//! addresses, so it is skipped for programs that could observe them: those
+//! that read `eip`, jump to computed addresses, print backtraces with `int`,
+//! use a label as a value other than as a jump target, or start cores.
+//! Return addresses pushed by `call` are assumed to only be used by `ret`.
+//!
+//! Runtime errors are still reported, but with the instruction indices of
+//! the optimized program.
+
+use crate::{
+    context::{
+        add, compare, decrement, divide, increment, logic, multiply, negate, remainder,
+        rotate_left, rotate_right, shift_arithmetic, shift_left, shift_right, subtract, Program,
+        WordSize, FLAG_CARRY, FLAG_OVERFLOW, FLAG_SIGN, FLAG_ZERO, MEMORY_SIZE,
+    },
+    instruction::{
+        float_address, jump_target, operands, Instruction, Register, Source, Target, NUM_REGISTERS,
+    },
+};
+use std::convert::TryFrom;
+
+impl Program {
+    /// An equivalent program with redundant instructions removed: peephole
+    /// rules, jump threading, constant folding and dead code removal.
+    pub fn optimize(self: &Program) -> Program {
+        // The passes evaluate instructions on 32-bit words, so 64-bit
+        // programs are left as they are
+        let optimizable = self.word_size == WordSize::Bits32;
+        let mut instructions = self.instructions.clone();
+        let mut changed = optimizable;
+        while changed {
+            let control_flow = self.control_flow(&instructions);
+            changed = peephole(&mut instructions, &control_flow);
+            changed |= thread_jumps(&mut instructions);
+            let control_flow = self.control_flow(&instructions);
+            changed |= fold_constants(&mut instructions, &control_flow);
+            let control_flow = self.control_flow(&instructions);
+            changed |= remove_dead_code(&mut instructions, &control_flow);
+        }
+
+        if optimizable && self.is_relocatable() {
+            self.compact(instructions)
+        } else {
+            Program {
+                instructions,
+                start_instruction_index: self.start_instruction_index,
+                locations: self.locations.clone(),
+                labels: self.labels.clone(),
+                addresses_taken: self.addresses_taken.clone(),
+                word_size: self.word_size,
+                natives: self.natives.clone(),
+            }
+        }
+    }
+
+    /// Whether removing instructions, and so changing code addresses, keeps
+    /// the behavior of the program.
+    fn is_relocatable(self: &Program) -> bool {
+        self.addresses_taken.is_empty()
+            && !self.instructions.iter().any(|instruction| {
+                *instruction == Instruction::Int
+                    || matches!(instruction, Instruction::Spawn(..))
+                    || uses_eip(*instruction)
+                    || matches!(jump_target(*instruction), Some(source) if !matches!(source, Source::Value(_)))
+            })
+    }
+
+    fn control_flow(self: &Program, instructions: &[Instruction]) -> ControlFlow {
+        let len = instructions.len();
+        let mut entries = vec![false; len + 1];
+        let mut roots = vec![self.start_instruction_index];
+        let mut indirect = false;
+
+        for (pc, instruction) in instructions.iter().enumerate() {
+            match jump_target(*instruction) {
+                Some(Source::Value(target)) => {
+                    if let Some(target) = index(target, len) {
+                        entries[target] = true;
+                    }
+                }
+                Some(_) => indirect = true,
+                None => {}
+            }
+            match instruction {
+                Instruction::Call(_) => entries[pc + 1] = true,
+                // Another core starts running there
+                Instruction::Spawn(_, Source::Value(entry), _) => {
+                    roots.extend(i32::try_from(*entry).ok());
+                }
+                Instruction::Spawn(..) => indirect = true,
+                _ => {}
+            }
+            indirect |= uses_eip(*instruction);
+        }
+
+        // `ret` can return to any address pushed on the stack
+        if instructions.contains(&Instruction::Ret) {
+            roots.extend(&self.addresses_taken);
+        }
+
+        if indirect {
+            return ControlFlow {
+                entries: vec![true; len + 1],
+                reachable: vec![true; len],
+            };
+        }
+
+        let mut reachable = vec![false; len];
+        let mut pending: Vec<_> = roots
+            .into_iter()
+            .filter_map(|i| index(i.into(), len))
+            .collect();
+        for &root in &pending {
+            entries[root] = true;
+        }
+        while let Some(pc) = pending.pop() {
+            if pc == len || reachable[pc] {
+                continue;
+            }
+            reachable[pc] = true;
+
+            let instruction = instructions[pc];
+            if let Some(Source::Value(target)) = jump_target(instruction) {
+                pending.extend(index(target, len));
+            }
+            match instruction {
+                Instruction::Jmp(_) | Instruction::Ret => {}
+                _ => pending.push(pc + 1),
+            }
+        }
+
+        ControlFlow { entries, reachable }
+    }
+
+    /// Removes the `nop`s, remapping the instruction indices that refer to
+    /// them to the next remaining instruction.
+    fn compact(self: &Program, instructions: Vec<Instruction>) -> Program {
+        let len = instructions.len();
+        let mut new_indices = Vec::with_capacity(len + 1);
+        let mut kept = 0;
+        for instruction in &instructions {
+            new_indices.push(kept);
+            if *instruction != Instruction::Nop {
+                kept += 1;
+            }
+        }
+        new_indices.push(kept);
+
+        let remap = |target: i32| match index(target.into(), len) {
+            Some(target) => new_indices[target],
+            None => target,
+        };
+
+        let mut program = Program {
+            instructions: vec![],
+            start_instruction_index: remap(self.start_instruction_index),
+            locations: vec![],
+            labels: self
+                .labels
+                .iter()
+                .map(|(label, target)| (label.clone(), remap(*target)))
+                .collect(),
+            addresses_taken: vec![],
+            word_size: self.word_size,
+            natives: self.natives.clone(),
+        };
+        for (instruction, location) in instructions.into_iter().zip(&self.locations) {
+            if instruction == Instruction::Nop {
+                continue;
+            }
+            program.instructions.push(match jump_target(instruction) {
+                Some(Source::Value(target)) => {
+                    with_jump_target(instruction, Source::Value(remap(target as i32).into()))
+                }
+                _ => instruction,
+            });
+            program.locations.push(location.clone());
+        }
+
+        program
+    }
+}
+
+struct ControlFlow {
+    /// The instructions that can be reached other than by falling through
+    /// from the previous one, and the end of the program.
+    entries: Vec<bool>,
+    reachable: Vec<bool>,
+}
+
+/// Simplifies instructions on their own, or along with the instructions
+/// after them for the flags.
+fn peephole(instructions: &mut [Instruction], control_flow: &ControlFlow) -> bool {
+    let mut changed = false;
+    for pc in 0..instructions.len() {
+        let flags_dead = || flags_dead(instructions, control_flow, pc);
+        let simplified = match instructions[pc] {
+            Instruction::Mov(Target::Register(t), Source::Register(s)) if t == s => {
+                Instruction::Nop
+            }
+            Instruction::Div(t, Source::Value(1)) if !can_fail_target(t) => Instruction::Nop,
+            Instruction::Add(t, Source::Value(0))
+            | Instruction::Sub(t, Source::Value(0))
+            | Instruction::Or(t, Source::Value(0))
+            | Instruction::Xor(t, Source::Value(0))
+            | Instruction::Mul(t, Source::Value(1))
+            | Instruction::And(t, Source::Value(-1))
+                if !can_fail_target(t) && flags_dead() =>
+            {
+                Instruction::Nop
+            }
+            Instruction::Shl(t, Source::Value(shift))
+            | Instruction::Shr(t, Source::Value(shift))
+            | Instruction::Sar(t, Source::Value(shift))
+            | Instruction::Rol(t, Source::Value(shift))
+            | Instruction::Ror(t, Source::Value(shift))
+                if shift % 32 == 0 && !can_fail_target(t) && flags_dead() =>
+            {
+                Instruction::Nop
+            }
+            Instruction::Mul(Target::Register(t), Source::Value(0))
+            | Instruction::And(Target::Register(t), Source::Value(0))
+                if flags_dead() =>
+            {
+                Instruction::Mov(Target::Register(t), Source::Value(0))
+            }
+            Instruction::Sub(Target::Register(t), Source::Register(s))
+            | Instruction::Xor(Target::Register(t), Source::Register(s))
+                if t == s && t != Register::Eip && flags_dead() =>
+            {
+                Instruction::Mov(Target::Register(t), Source::Value(0))
+            }
+            instruction => match jump_target(instruction) {
+                Some(Source::Value(target))
+                    if instruction_at(instructions, target) == next(instructions, pc)
+                        && !matches!(instruction, Instruction::Call(_) | Instruction::Loop(_)) =>
+                {
+                    Instruction::Nop
+                }
+                _ => instruction,
+            },
+        };
+
+        if simplified != instructions[pc] {
+            instructions[pc] = simplified;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Makes jumps to unconditional jumps go to their final target.
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    for pc in 0..instructions.len() {
+        let instruction = instructions[pc];
+        let target = match (instruction, jump_target(instruction)) {
+            (Instruction::Call(_), _) => continue,
+            (_, Some(Source::Value(target))) => target,
+            _ => continue,
+        };
+
+        let final_target = final_target(instructions, target);
+        if final_target != target {
+            instructions[pc] = with_jump_target(instruction, Source::Value(final_target));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Where a jump to `target` ends up after following `nop`s and `jmp`s, or
+/// `target` itself if they loop forever.
+fn final_target(instructions: &[Instruction], target: i64) -> i64 {
+    let mut current = target;
+    for _ in 0..=instructions.len() {
+        current = match instruction_at(instructions, current).and_then(|i| instructions.get(i)) {
+            Some(Instruction::Jmp(Source::Value(next))) => *next,
+            _ => return current,
+        };
+    }
+    target
+}
+
+/// The index of the first instruction that is not a `nop` at or after
+/// `target`, if `target` is in the program.
+fn instruction_at(instructions: &[Instruction], target: i64) -> Option<usize> {
+    let mut target = index(target, instructions.len())?;
+    while instructions.get(target) == Some(&Instruction::Nop) {
+        target += 1;
+    }
+    Some(target)
+}
+
+/// The instruction executed after `pc` when it falls through.
+fn next(instructions: &[Instruction], pc: usize) -> Option<usize> {
+    instruction_at(instructions, pc as i64 + 1)
+}
+
+/// What is known about the state at some point of a basic block.
+#[derive(Clone, Copy)]
+struct Known {
+    registers: [Option<i32>; NUM_REGISTERS],
+    flags: Option<i32>,
+    remainder: Option<i32>,
+}
+
+const UNKNOWN: Known = Known {
+    registers: [None; NUM_REGISTERS],
+    flags: None,
+    remainder: None,
+};
+
+/// Propagates constants through basic blocks, computing the instructions
+/// whose operands are known and deciding the conditional jumps whose flags
+/// are known.
+fn fold_constants(instructions: &mut [Instruction], control_flow: &ControlFlow) -> bool {
+    let mut changed = false;
+    let mut known = UNKNOWN;
+    for pc in 0..instructions.len() {
+        if control_flow.entries[pc] {
+            known = UNKNOWN;
+        }
+
+        let flags_dead = flags_dead(instructions, control_flow, pc);
+        let folded = fold(instructions[pc], &known, flags_dead);
+        if folded != instructions[pc] {
+            instructions[pc] = folded;
+            changed = true;
+        }
+        known.update(folded);
+    }
+    changed
+}
+
+/// Folds an instruction, which can be replaced by one that does not set the
+/// flags if `flags_dead`.
+fn fold(instruction: Instruction, known: &Known, flags_dead: bool) -> Instruction {
+    let instruction = map_sources(instruction, |source| match source {
+        Source::Register(reg) => match known.register(reg) {
+            Some(value) => Source::Value(value.into()),
+            None => source,
+        },
+        _ => source,
+    });
+
+    if let (Some(flags), Some(target)) = (known.flags, jump_target(instruction)) {
+        match jump_taken(instruction, flags) {
+            Some(true) => return Instruction::Jmp(target),
+            Some(false) => return Instruction::Nop,
+            None => {}
+        }
+    }
+
+    match instruction {
+        Instruction::Mov(Target::Register(reg), Source::Value(value))
+            if known.register(reg).map(i64::from) == Some(value) =>
+        {
+            Instruction::Nop
+        }
+        Instruction::Rem(target) => match known.remainder {
+            Some(value) => Instruction::Mov(target, Source::Value(value.into())),
+            None => instruction,
+        },
+        Instruction::Cmov(condition, target, source) => match known.flags {
+            Some(flags) if condition.holds(flags) => Instruction::Mov(target, source),
+            Some(_) if !can_fail(instruction) => Instruction::Nop,
+            _ => instruction,
+        },
+        Instruction::Popcnt(target, Source::Value(value)) => {
+            Instruction::Mov(target, Source::Value((value as i32).count_ones().into()))
+        }
+        Instruction::Clz(target, Source::Value(value)) => {
+            Instruction::Mov(target, Source::Value((value as i32).leading_zeros().into()))
+        }
+        Instruction::Ctz(target, Source::Value(value)) => Instruction::Mov(
+            target,
+            Source::Value((value as i32).trailing_zeros().into()),
+        ),
+        Instruction::Lea(target, address) => {
+            let registers = [address.base, address.index.map(|(index, _)| index)];
+            if registers
+                .iter()
+                .flatten()
+                .all(|reg| known.register(*reg).is_some())
+            {
+                let value: i32 = address.address(|reg| known.register(reg).unwrap_or(0));
+                Instruction::Mov(target, Source::Value(value.into()))
+            } else {
+                instruction
+            }
+        }
+        _ => match (operands(instruction).1, evaluate(instruction, known)) {
+            (Some((target, _)), Some((value, _))) if flags_dead || !writes_flags(instruction) => {
+                Instruction::Mov(target, Source::Value(value.into()))
+            }
+            _ => instruction,
+        },
+    }
+}
+
+/// The value a register-modifying instruction writes, if it is known, and
+/// the flags after it, if they are known too.
+fn evaluate(instruction: Instruction, known: &Known) -> Option<(i32, Option<i32>)> {
+    let (sources, target) = operands(instruction);
+    let value = match target {
+        Some((Target::Register(reg), true)) => known.register(reg)?,
+        _ => return None,
+    };
+    let operand = match sources[0] {
+        Some(Source::Value(operand)) => operand as i32,
+        Some(_) => return None,
+        None => 0,
+    };
+
+    let (value, flags) = match instruction {
+        Instruction::Inc(_) => match known.flags {
+            Some(flags) => increment(value, flags),
+            None => return Some((value.wrapping_add(1), None)),
+        },
+        Instruction::Dec(_) => match known.flags {
+            Some(flags) => decrement(value, flags),
+            None => return Some((value.wrapping_sub(1), None)),
+        },
+        Instruction::Not(_) => return Some((!value, known.flags)),
+        Instruction::Bswap(_) => return Some((value.swap_bytes(), known.flags)),
+        Instruction::Neg(_) => negate(value),
+        Instruction::Div(..) => return Some((divide(value, operand).ok()?, known.flags)),
+        Instruction::Add(..) => add(value, operand),
+        Instruction::Sub(..) => subtract(value, operand),
+        Instruction::Mul(..) => multiply(value, operand),
+        Instruction::Xor(..) => logic(value ^ operand),
+        Instruction::Or(..) => logic(value | operand),
+        Instruction::And(..) => logic(value & operand),
+        Instruction::Shl(..) => shift_left(value, operand),
+        Instruction::Shr(..) => shift_right(value, operand),
+        Instruction::Sar(..) => shift_arithmetic(value, operand),
+        Instruction::Rol(..) => rotate_left(value, operand),
+        Instruction::Ror(..) => rotate_right(value, operand),
+        _ => return None,
+    };
+    Some((value, Some(flags)))
+}
+
+impl Known {
+    fn register(self: &Known, reg: Register) -> Option<i32> {
+        match reg {
+            Register::Eip => None,
+            _ => self.registers[reg as usize],
+        }
+    }
+
+    /// Updates what is known after executing `instruction`.
+    fn update(self: &mut Known, instruction: Instruction) {
+        let evaluated = evaluate(instruction, self);
+        if writes_flags(instruction) {
+            self.flags = None;
+        }
+        if let Some((Target::Register(reg), _)) = operands(instruction).1 {
+            self.registers[reg as usize] = None;
+            if let Some((value, flags)) = evaluated {
+                self.registers[reg as usize] = Some(value);
+                self.flags = flags;
+            }
+        }
+        if reads_register(instruction, Register::Esp) {
+            self.registers[Register::Esp as usize] = None;
+        }
+
+        match instruction {
+            Instruction::Mov(Target::Register(reg), Source::Value(value)) => {
+                self.registers[reg as usize] = Some(value as i32);
+            }
+            Instruction::Rem(Target::Register(reg)) => {
+                self.registers[reg as usize] = self.remainder;
+            }
+            Instruction::Mod(Source::Value(dividend), Source::Value(divisor)) => {
+                self.remainder = remainder(dividend as i32, divisor as i32).ok();
+            }
+            Instruction::Mod(..) => self.remainder = None,
+            Instruction::Cmp(Source::Value(value1), Source::Value(value2)) => {
+                self.flags = Some(compare(value1 as i32, value2 as i32));
+            }
+            Instruction::Test(Source::Value(value1), Source::Value(value2)) => {
+                self.flags = Some(logic(value1 as i32 & value2 as i32).1);
+            }
+            Instruction::Xchg(_, Target::Register(reg)) => self.registers[reg as usize] = None,
+            Instruction::Jmp(_)
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::Native(..) => *self = UNKNOWN,
+            _ => {}
+        }
+    }
+}
+
+/// Removes unreachable instructions, and register writes that are
+/// overwritten in the same basic block before being read.
+fn remove_dead_code(instructions: &mut [Instruction], control_flow: &ControlFlow) -> bool {
+    let mut changed = false;
+    for pc in 0..instructions.len() {
+        if instructions[pc] == Instruction::Nop {
+            continue;
+        }
+        if !control_flow.reachable[pc] || is_dead_store(instructions, control_flow, pc) {
+            instructions[pc] = Instruction::Nop;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn is_dead_store(instructions: &[Instruction], control_flow: &ControlFlow, pc: usize) -> bool {
+    let reg = match register_write(instructions[pc]) {
+        Some(reg) => reg,
+        None => return false,
+    };
+    if writes_flags(instructions[pc]) && !flags_dead(instructions, control_flow, pc) {
+        return false;
+    }
+
+    for (&instruction, entry) in instructions.iter().zip(&control_flow.entries).skip(pc + 1) {
+        if *entry
+            || reads_register(instruction, reg)
+            || can_fail(instruction)
+            || jump_target(instruction).is_some()
+            || matches!(instruction, Instruction::Ret | Instruction::Int)
+        {
+            return false;
+        }
+        match instruction {
+            Instruction::Mov(Target::Register(t), _) | Instruction::Rem(Target::Register(t))
+                if t == reg =>
+            {
+                return true
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Whether the flags written by the instruction at `pc` are written again
+/// in the same basic block before anything can read them.
+fn flags_dead(instructions: &[Instruction], control_flow: &ControlFlow, pc: usize) -> bool {
+    for (&instruction, entry) in instructions.iter().zip(&control_flow.entries).skip(pc + 1) {
+        if *entry
+            || reads_flags(instruction)
+            || can_fail(instruction)
+            || jump_target(instruction).is_some()
+            || matches!(instruction, Instruction::Ret | Instruction::Int)
+        {
+            return false;
+        }
+        if writes_flags(instruction) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether the instruction sets the flags. `inc` and `dec` also read them,
+/// as they keep the carry flag.
+fn writes_flags(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Inc(_)
+            | Instruction::Dec(_)
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Xor(..)
+            | Instruction::Or(..)
+            | Instruction::And(..)
+            | Instruction::Shl(..)
+            | Instruction::Shr(..)
+            | Instruction::Sar(..)
+            | Instruction::Rol(..)
+            | Instruction::Ror(..)
+            | Instruction::Neg(_)
+            | Instruction::Test(..)
+            | Instruction::Cmp(..)
+            | Instruction::Fcmp(..)
+            | Instruction::Popf
+            | Instruction::Cas(..)
+            | Instruction::Xadd(..)
+    )
+}
+
+fn reads_flags(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Inc(_)
+            | Instruction::Dec(_)
+            | Instruction::Cmov(..)
+            | Instruction::Pushf
+            | Instruction::Spawn(..)
+            | Instruction::Native(..)
+    ) || jump_taken(instruction, 0).is_some()
+}
+
+/// The register written by an instruction that has no other effect and
+/// cannot fail.
+fn register_write(instruction: Instruction) -> Option<Register> {
+    match instruction {
+        Instruction::Mov(..)
+        | Instruction::Rem(_)
+        | Instruction::Inc(_)
+        | Instruction::Dec(_)
+        | Instruction::Add(..)
+        | Instruction::Sub(..)
+        | Instruction::Mul(..)
+        | Instruction::Div(..)
+        | Instruction::Not(_)
+        | Instruction::Xor(..)
+        | Instruction::Or(..)
+        | Instruction::And(..)
+        | Instruction::Shl(..)
+        | Instruction::Shr(..)
+        | Instruction::Sar(..)
+        | Instruction::Rol(..)
+        | Instruction::Ror(..)
+        | Instruction::Neg(_)
+        | Instruction::Lea(..)
+        | Instruction::Cmov(..)
+        | Instruction::Popcnt(..)
+        | Instruction::Clz(..)
+        | Instruction::Ctz(..)
+        | Instruction::Bswap(_) => {}
+        _ => return None,
+    }
+    match operands(instruction).1 {
+        Some((Target::Register(reg), _)) if reg != Register::Eip && !can_fail(instruction) => {
+            Some(reg)
+        }
+        _ => None,
+    }
+}
+
+fn map_sources(instruction: Instruction, f: impl Fn(Source) -> Source) -> Instruction {
+    match instruction {
+        Instruction::Mov(t, s) => Instruction::Mov(t, f(s)),
+        Instruction::Add(t, s) => Instruction::Add(t, f(s)),
+        Instruction::Sub(t, s) => Instruction::Sub(t, f(s)),
+        Instruction::Mul(t, s) => Instruction::Mul(t, f(s)),
+        Instruction::Div(t, s) => Instruction::Div(t, f(s)),
+        Instruction::Xor(t, s) => Instruction::Xor(t, f(s)),
+        Instruction::Or(t, s) => Instruction::Or(t, f(s)),
+        Instruction::And(t, s) => Instruction::And(t, f(s)),
+        Instruction::Shl(t, s) => Instruction::Shl(t, f(s)),
+        Instruction::Shr(t, s) => Instruction::Shr(t, f(s)),
+        Instruction::Sar(t, s) => Instruction::Sar(t, f(s)),
+        Instruction::Rol(t, s) => Instruction::Rol(t, f(s)),
+        Instruction::Ror(t, s) => Instruction::Ror(t, f(s)),
+        Instruction::Cmov(c, t, s) => Instruction::Cmov(c, t, f(s)),
+        Instruction::Popcnt(t, s) => Instruction::Popcnt(t, f(s)),
+        Instruction::Clz(t, s) => Instruction::Clz(t, f(s)),
+        Instruction::Ctz(t, s) => Instruction::Ctz(t, f(s)),
+        Instruction::Ld(w, t, s) => Instruction::Ld(w, t, f(s)),
+        Instruction::Lds(w, t, s) => Instruction::Lds(w, t, f(s)),
+        Instruction::St(w, s1, s2) => Instruction::St(w, f(s1), f(s2)),
+        Instruction::Push(s) => Instruction::Push(f(s)),
+        Instruction::Prn(s) => Instruction::Prn(f(s)),
+        Instruction::Itof(r, s) => Instruction::Itof(r, f(s)),
+        Instruction::Vld(r, s) => Instruction::Vld(r, f(s)),
+        Instruction::Vst(s, r) => Instruction::Vst(f(s), r),
+        Instruction::Vins(r, lane, s) => Instruction::Vins(r, lane, f(s)),
+        Instruction::Mod(s1, s2) => Instruction::Mod(f(s1), f(s2)),
+        Instruction::Cmp(s1, s2) => Instruction::Cmp(f(s1), f(s2)),
+        Instruction::Test(s1, s2) => Instruction::Test(f(s1), f(s2)),
+        Instruction::Spawn(t, s1, s2) => Instruction::Spawn(t, f(s1), f(s2)),
+        Instruction::Join(s) => Instruction::Join(f(s)),
+        Instruction::Cas(t, s1, s2) => Instruction::Cas(t, f(s1), f(s2)),
+        Instruction::Xadd(t, s) => Instruction::Xadd(t, f(s)),
+        Instruction::Send(s1, s2) => Instruction::Send(f(s1), f(s2)),
+        Instruction::Recv(t, s) => Instruction::Recv(t, f(s)),
+        _ => match jump_target(instruction) {
+            Some(s) => with_jump_target(instruction, f(s)),
+            None => instruction,
+        },
+    }
+}
+
+fn reads_register(instruction: Instruction, reg: Register) -> bool {
+    let (sources, target) = operands(instruction);
+    sources.contains(&Some(Source::Register(reg)))
+        || target == Some((Target::Register(reg), true))
+        || match instruction {
+            Instruction::Push(_)
+            | Instruction::Pop(_)
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Call(_)
+            | Instruction::Ret => reg == Register::Esp,
+            Instruction::Int => reg == Register::Eax,
+            // The new core starts with a copy of them, and a native function
+            // can read any of them
+            Instruction::Spawn(..) | Instruction::Native(..) => true,
+            _ => false,
+        }
+}
+
+fn uses_eip(instruction: Instruction) -> bool {
+    let (sources, target) = operands(instruction);
+    sources.contains(&Some(Source::Register(Register::Eip)))
+        || matches!(target, Some((Target::Register(Register::Eip), _)))
+}
+
+fn can_fail(instruction: Instruction) -> bool {
+    let (sources, target) = operands(instruction);
+    let invalid_address = |source: &Option<Source>| match source {
+        Some(Source::Address(addr)) => !is_valid_address(*addr),
+        _ => false,
+    };
+
+    sources.iter().any(invalid_address)
+        || float_address(instruction).is_some_and(|addr| !is_valid_address(addr.wrapping_add(1)))
+        || matches!(target, Some((t, _)) if can_fail_target(t))
+        || match instruction {
+            Instruction::Push(_)
+            | Instruction::Pop(_)
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::Ld(..)
+            | Instruction::Lds(..)
+            | Instruction::St(..)
+            | Instruction::Vld(..)
+            | Instruction::Vst(..)
+            | Instruction::Spawn(..)
+            | Instruction::Join(_)
+            | Instruction::Cas(..)
+            | Instruction::Xadd(..)
+            | Instruction::Send(..)
+            | Instruction::Recv(..)
+            | Instruction::Native(..) => true,
+            Instruction::Div(_, divisor) | Instruction::Mod(_, divisor) => {
+                !matches!(divisor, Source::Value(d) if d != 0)
+            }
+            _ => false,
+        }
+}
+
+fn can_fail_target(target: Target) -> bool {
+    match target {
+        Target::Register(_) => false,
+        Target::Address(addr) => !is_valid_address(addr),
+    }
+}
+
+fn is_valid_address(addr: i32) -> bool {
+    usize::try_from(addr).is_ok_and(|addr| addr < MEMORY_SIZE)
+}
+
+/// `target` as an instruction index, if it is within the program or its end.
+fn index(target: i64, len: usize) -> Option<usize> {
+    usize::try_from(target).ok().filter(|target| *target <= len)
+}
+
+/// Whether a conditional jump is taken, given the flags, or `None` if the
+/// instruction is not one.
+fn jump_taken(instruction: Instruction, flags: i32) -> Option<bool> {
+    match instruction {
+        Instruction::Je(_) => Some(flags & 1 != 0),
+        Instruction::Jne(_) => Some(flags & 1 == 0),
+        Instruction::Jg(_) => Some(flags & 2 != 0),
+        Instruction::Jge(_) => Some(flags & 3 != 0),
+        Instruction::Jl(_) => Some(flags & 3 == 0),
+        Instruction::Jle(_) => Some(flags & 2 == 0),
+        Instruction::Ja(_) => Some(flags & (FLAG_CARRY | FLAG_ZERO) == 0),
+        Instruction::Jae(_) => Some(flags & FLAG_CARRY == 0),
+        Instruction::Jb(_) => Some(flags & FLAG_CARRY != 0),
+        Instruction::Jbe(_) => Some(flags & (FLAG_CARRY | FLAG_ZERO) != 0),
+        Instruction::Js(_) => Some(flags & FLAG_SIGN != 0),
+        Instruction::Jns(_) => Some(flags & FLAG_SIGN == 0),
+        Instruction::Jo(_) => Some(flags & FLAG_OVERFLOW != 0),
+        Instruction::Jno(_) => Some(flags & FLAG_OVERFLOW == 0),
+        _ => None,
+    }
+}
+
+/// The same jump, to `target` instead.
+fn with_jump_target(instruction: Instruction, target: Source) -> Instruction {
+    match instruction {
+        Instruction::Jmp(_) => Instruction::Jmp(target),
+        Instruction::Call(_) => Instruction::Call(target),
+        Instruction::Je(_) => Instruction::Je(target),
+        Instruction::Jne(_) => Instruction::Jne(target),
+        Instruction::Jg(_) => Instruction::Jg(target),
+        Instruction::Jge(_) => Instruction::Jge(target),
+        Instruction::Jl(_) => Instruction::Jl(target),
+        Instruction::Jle(_) => Instruction::Jle(target),
+        Instruction::Ja(_) => Instruction::Ja(target),
+        Instruction::Jae(_) => Instruction::Jae(target),
+        Instruction::Jb(_) => Instruction::Jb(target),
+        Instruction::Jbe(_) => Instruction::Jbe(target),
+        Instruction::Js(_) => Instruction::Js(target),
+        Instruction::Jns(_) => Instruction::Jns(target),
+        Instruction::Jo(_) => Instruction::Jo(target),
+        Instruction::Jno(_) => Instruction::Jno(target),
+        Instruction::Loop(_) => Instruction::Loop(target),
+        _ => instruction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize(source: &str) -> Program {
+        Program::load(source.to_owned()).unwrap().optimize()
+    }
+
+    fn instructions(source: &str) -> Vec<String> {
+        optimize(source)
+            .instructions
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn removes_instructions_without_effect() {
+        assert_eq!(
+            instructions(
+                "mov eax, eax\nadd ebx, 0\nmul ecx, 1\nand edx, -1\nshl eax, 32\n\
+                 jmp next\nnext: nop\nprn eax\ncmp eax, 1"
+            ),
+            ["prn eax", "cmp eax, 1"]
+        );
+    }
+
+    #[test]
+    fn keeps_flags_that_are_read() {
+        assert_eq!(
+            instructions(
+                "add eax, 0\njz zero\nxor ebx, ebx\npushf\nmul ecx, 0\ncmp ecx, 0\n\
+                 zero: shl edx, 32\njs zero\nprn edx"
+            ),
+            [
+                "add eax, 0",
+                "je 6",
+                "xor ebx, ebx",
+                "pushf",
+                "mov ecx, 0",
+                "cmp 0, 0",
+                "shl edx, 32",
+                "js 6",
+                "prn edx"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_instructions_that_can_fail() {
+        assert_eq!(
+            instructions(
+                "add [200000000], 0\ndiv eax, 1\nmov eax, 1\nmov ebx, [300000000]\nmov eax, 2"
+            ),
+            [
+                "add [200000000], 0",
+                "mov eax, 1",
+                "mov ebx, [300000000]",
+                "mov eax, 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn threads_jumps_and_remaps_targets() {
+        let program = optimize(
+            "start: jmp a\nprn 1\na: jmp b\nprn 2\nb: cmp eax, 0\nje a\nloop: prn 3\njmp loop",
+        );
+        assert_eq!(
+            program
+                .instructions
+                .iter()
+                .map(|instruction| instruction.to_string())
+                .collect::<Vec<_>>(),
+            ["cmp eax, 0", "je 0", "prn 3", "jmp 2"]
+        );
+        assert_eq!(program.start_instruction_index, 0);
+        assert_eq!(program.label_at(2), Some("loop"));
+        assert_eq!(program.locations[2].line, 7);
+    }
+
+    #[test]
+    fn folds_constants_and_conditional_jumps() {
+        assert_eq!(
+            instructions(
+                "mov eax, 5\nadd eax, 3\nmul eax, 2\ncmp eax, 16\njne skip\nmod eax, 3\n\
+                 rem ebx\nprn ebx\nskip: prn eax"
+            ),
+            [
+                "mov eax, 16",
+                "cmp 16, 16",
+                "mod 16, 3",
+                "mov ebx, 1",
+                "prn 1",
+                "prn 16"
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_dead_stores_within_basic_blocks() {
+        assert_eq!(
+            instructions(
+                "mov eax, ebx\nmov ecx, 1\nmov eax, 2\nloop: mov ecx, 2\nprn eax\njmp loop"
+            ),
+            ["mov ecx, 1", "mov eax, 2", "mov ecx, 2", "prn eax", "jmp 2"]
+        );
+    }
+
+    #[test]
+    fn keeps_code_addresses_that_the_program_can_observe() {
+        for source in &[
+            "nop\nlabel: prn label",
+            "nop\nprn eip",
+            "nop\nmov eax, 2\njmp eax",
+            "nop\nint",
+        ] {
+            let len = source.lines().count();
+            assert_eq!(optimize(source).instructions.len(), len, "{}", source);
+        }
+    }
+
+    #[test]
+    fn keeps_return_sites_and_addresses_taken_reachable() {
+        assert_eq!(
+            instructions("f: ret\nstart: call f\nprn 1\njmp end\nprn 2\nend:"),
+            ["ret", "call 0", "prn 1"]
+        );
+        assert_eq!(
+            optimize("push back\nret\nprn 1\nback: prn 2").instructions,
+            [
+                Instruction::Push(Source::Value(3)),
+                Instruction::Ret,
+                Instruction::Nop,
+                Instruction::Prn(Source::Value(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_code_of_spawned_cores_and_the_registers_they_start_with() {
+        assert_eq!(
+            instructions(
+                "mov ecx, 5\nspawn eax, 5, 1000\nmov ecx, 6\njoin eax\njmp end\nprn ecx\nend:"
+            ),
+            [
+                "mov ecx, 5",
+                "spawn eax, 5, 1000",
+                "mov ecx, 6",
+                "join eax",
+                "jmp 6",
+                "prn ecx"
+            ]
+        );
+    }
+}
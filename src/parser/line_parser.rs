@@ -1,12 +1,30 @@
 use super::{is_valid_label, unresolved_instruction::UnresolvedInstruction, ParseErrorKind};
+use crate::context::WordSize;
+
+/// The directive selecting the word size, as in `%bits 64`.
+const TOK_BITS: &str = "%bits";
 
 #[derive(Debug, PartialEq)]
 pub(super) enum ParsedLineInstruction<'a> {
     Some(UnresolvedInstruction<'a>),
+    WordSize(WordSize),
     None,
     Err(ParseErrorKind),
 }
 
+fn parse_word_size(tokens: &[&str]) -> Result<WordSize, ParseErrorKind> {
+    let word_size = match tokens.get(1) {
+        Some(&"32") => WordSize::Bits32,
+        Some(&"64") => WordSize::Bits64,
+        Some(token) => return Err(ParseErrorKind::InvalidOperand((*token).to_owned())),
+        None => return Err(ParseErrorKind::MissingOperand(1)),
+    };
+    match tokens.get(2) {
+        Some(token) => Err(ParseErrorKind::ExtraToken((*token).to_owned())),
+        None => Ok(word_size),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub(super) struct ParsedLine<'a> {
     pub labels: Vec<&'a str>,
@@ -14,6 +32,16 @@ pub(super) struct ParsedLine<'a> {
 }
 
 pub(super) fn parse_line<'a>(tokens: &[&'a str]) -> ParsedLine<'a> {
+    if tokens.first() == Some(&TOK_BITS) {
+        return ParsedLine {
+            labels: vec![],
+            instruction: match parse_word_size(tokens) {
+                Ok(word_size) => ParsedLineInstruction::WordSize(word_size),
+                Err(e) => ParsedLineInstruction::Err(e),
+            },
+        };
+    }
+
     let mut labels = Vec::<&str>::default();
 
     for i in 0..tokens.len() {
@@ -146,6 +174,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_parse_word_size() {
+        run(
+            &["%bits", "32"],
+            &[],
+            WordSize(crate::context::WordSize::Bits32),
+        );
+        run(
+            &["%bits", "64"],
+            &[],
+            WordSize(crate::context::WordSize::Bits64),
+        );
+        run(&["%bits"], &[], Err(ParseErrorKind::MissingOperand(1)));
+        run(
+            &["%bits", "16"],
+            &[],
+            Err(ParseErrorKind::InvalidOperand("16".to_owned())),
+        );
+        run(
+            &["%bits", "64", "32"],
+            &[],
+            Err(ParseErrorKind::ExtraToken("32".to_owned())),
+        );
+    }
+
     #[test]
     fn labels_are_returned_for_lines_with_errors() {
         run(
@@ -1,7 +1,10 @@
 mod line_parser;
+#[allow(clippy::module_inception)]
 mod parser;
 mod register;
 mod resolver;
 mod unresolved_instruction;
 
 pub(crate) use parser::*;
+pub(crate) use register::parse_register;
+pub(crate) use unresolved_instruction::parse_value;
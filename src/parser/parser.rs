@@ -2,17 +2,17 @@ use super::{
     line_parser::{parse_line, ParsedLine, ParsedLineInstruction},
     resolver::resolve,
 };
-use crate::{context::Program, instruction::Instruction};
+use crate::{
+    context::{Program, WordSize},
+    instruction::Instruction,
+    native::Natives,
+    preprocessor::SourceLocation,
+};
 use std::collections::{hash_map::Entry, HashMap};
 
 pub(super) fn is_valid_label(s: &str) -> bool {
     fn is_valid_first_char(c: u8) -> bool {
-        match c {
-            b'$' | b'@' | b'_' => true,
-            b'A'..=b'Z' => true,
-            b'a'..=b'z' => true,
-            _ => false,
-        }
+        matches!(c, b'$' | b'@' | b'_' | b'A'..=b'Z' | b'a'..=b'z')
     }
 
     fn is_valid_char(c: u8) -> bool {
@@ -38,6 +38,8 @@ pub enum ParseErrorKind {
     MissingOperand(usize),
     InvalidOperand(String),
     ExtraToken(String),
+    /// A `%bits` directive disagreeing with an earlier one.
+    ConflictingWordSize(WordSize),
 }
 
 #[derive(Debug, PartialEq)]
@@ -71,19 +73,53 @@ fn gather_label_values<'a>(lines: &[ParsedLine<'a>]) -> Result<HashMap<&'a str,
     Ok(labels)
 }
 
-pub(crate) fn parse<'a>(lines: &[Vec<&'a str>]) -> Result<Program, ParseError> {
+/// The word size given by the `%bits` directives, which may appear anywhere
+/// but must all agree.
+fn gather_word_size(lines: &[ParsedLine]) -> Result<WordSize, ParseError> {
+    let mut word_size = None;
+    for (line_index, line) in lines.iter().enumerate() {
+        if let ParsedLineInstruction::WordSize(size) = line.instruction {
+            if word_size.is_some_and(|word_size| word_size != size) {
+                return Err(ParseError {
+                    line_index,
+                    error: ParseErrorKind::ConflictingWordSize(size),
+                });
+            }
+            word_size = Some(size);
+        }
+    }
+
+    Ok(word_size.unwrap_or_default())
+}
+
+pub(crate) fn parse(lines: &[Vec<&str>], natives: &Natives) -> Result<Program, ParseError> {
     let parsed_lines: Vec<_> = lines.iter().map(|l| parse_line(l)).collect();
 
     let labels = gather_label_values(&parsed_lines)?;
+    let word_size = gather_word_size(&parsed_lines)?;
 
     let mut instructions: Vec<Instruction> = vec![];
+    let mut locations: Vec<SourceLocation> = vec![];
+    let mut addresses_taken: Vec<i32> = vec![];
 
     for (line_index, line) in parsed_lines.iter().enumerate() {
         match &line.instruction {
             ParsedLineInstruction::Some(instruction) => {
-                let resolved = resolve(instruction, &labels);
+                let resolved = resolve(
+                    instruction,
+                    &labels,
+                    natives,
+                    word_size,
+                    &mut addresses_taken,
+                );
                 match resolved {
-                    Ok(i) => instructions.push(i),
+                    Ok(i) => {
+                        instructions.push(i);
+                        locations.push(SourceLocation {
+                            file: None,
+                            line: line_index + 1,
+                        });
+                    }
                     Err(e) => {
                         return Err(ParseError {
                             line_index,
@@ -92,7 +128,7 @@ pub(crate) fn parse<'a>(lines: &[Vec<&'a str>]) -> Result<Program, ParseError> {
                     }
                 };
             }
-            ParsedLineInstruction::None => {}
+            ParsedLineInstruction::WordSize(_) | ParsedLineInstruction::None => {}
             ParsedLineInstruction::Err(e) => {
                 return Err(ParseError {
                     line_index,
@@ -102,11 +138,24 @@ pub(crate) fn parse<'a>(lines: &[Vec<&'a str>]) -> Result<Program, ParseError> {
         }
     }
 
-    let start_instruction_index = labels.get("start").map(|v| *v as i32).unwrap_or(0);
+    let start_instruction_index = labels.get("start").copied().unwrap_or(0);
+
+    let mut labels: Vec<(String, i32)> = labels
+        .into_iter()
+        .map(|(label, index)| (label.to_owned(), index))
+        .collect();
+    labels.sort_by(|(l1, i1), (l2, i2)| (i1, l1).cmp(&(i2, l2)));
+    addresses_taken.sort_unstable();
+    addresses_taken.dedup();
 
     let program = Program {
         instructions,
         start_instruction_index,
+        locations,
+        labels,
+        addresses_taken,
+        word_size,
+        natives: natives.clone(),
     };
 
     Ok(program)
@@ -122,7 +171,7 @@ mod tests {
     fn can_parse_with_resolved_labels() {
         let defines = HashMap::<String, String>::default();
         let lexer = LexerContext::lex("label1: add eax, ebx\njmp label4\nstart: inc ebx \n\ndec eax\nlabel2: sub eax, label1\nlabel3:\nlabel4:\ninc eax\njmp start\njmp label3", &defines);
-        let result = parse(&lexer.tokens()).unwrap();
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
 
         assert_eq!(
             result.instructions,
@@ -146,20 +195,108 @@ mod tests {
     fn start_instruction_index_is_set_to_the_start_label() {
         let defines = HashMap::<String, String>::default();
         let lexer = LexerContext::lex("label1: add eax, ebx\njmp label4\nstart: inc ebx \n\ndec eax\nlabel2: sub eax, label1\nlabel3:\nlabel4:\ninc eax\njmp start\njmp label3", &defines);
-        let result = parse(&lexer.tokens()).unwrap();
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
 
         assert_eq!(result.start_instruction_index, 2);
     }
 
+    #[test]
+    fn locations_are_the_lines_of_the_instructions() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex("label1:\n\ninc eax\n# comment\ndec eax", &defines);
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
+
+        let lines: Vec<_> = result.locations.iter().map(|l| l.line).collect();
+        assert_eq!(lines, &[3, 5]);
+    }
+
+    #[test]
+    fn labels_are_sorted_by_instruction_index() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex("b: inc eax\na: inc eax\nd:\nc:\ninc eax", &defines);
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
+
+        assert_eq!(
+            result.labels,
+            &[
+                ("b".to_owned(), 0),
+                ("a".to_owned(), 1),
+                ("c".to_owned(), 2),
+                ("d".to_owned(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_used_as_values_are_addresses_taken() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex(
+            "a: push c\nb: jmp b\ncall a\nc: mov eax, c\nprn a\nje b",
+            &defines,
+        );
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
+
+        assert_eq!(result.addresses_taken, &[0, 3]);
+    }
+
     #[test]
     fn start_instruction_index_is_zero_if_no_start_label_exists() {
         let defines = HashMap::<String, String>::default();
         let lexer = LexerContext::lex("label1: add eax, ebx\njmp label4\ninc ebx \n\ndec eax\nlabel2: sub eax, label1\nlabel3:\nlabel4:\ninc eax", &defines);
-        let result = parse(&lexer.tokens()).unwrap();
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
 
         assert_eq!(result.start_instruction_index, 0);
     }
 
+    #[test]
+    fn word_size_is_32_bits_unless_selected() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex("mov eax, 1", &defines);
+        assert_eq!(
+            parse(lexer.tokens(), &Natives::default())
+                .unwrap()
+                .word_size,
+            WordSize::Bits32
+        );
+
+        let lexer = LexerContext::lex("mov eax, 0x100000000\n%bits 64", &defines);
+        let result = parse(lexer.tokens(), &Natives::default()).unwrap();
+        assert_eq!(result.word_size, WordSize::Bits64);
+        assert_eq!(
+            result.instructions,
+            &[Instruction::Mov(
+                Target::Register(Register::Eax),
+                Source::Value(0x100000000)
+            )]
+        );
+    }
+
+    #[test]
+    fn values_must_fit_the_word_size() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex("mov eax, 1\nmov eax, 0x100000000", &defines);
+        let error = parse(lexer.tokens(), &Natives::default()).err().unwrap();
+
+        assert_eq!(error.line_index, 1);
+        assert_eq!(
+            error.error,
+            ParseErrorKind::InvalidOperand("4294967296".to_owned())
+        );
+    }
+
+    #[test]
+    fn word_sizes_must_agree() {
+        let defines = HashMap::<String, String>::default();
+        let lexer = LexerContext::lex("%bits 64\nnop\n%bits 32", &defines);
+        let error = parse(lexer.tokens(), &Natives::default()).err().unwrap();
+
+        assert_eq!(error.line_index, 2);
+        assert_eq!(
+            error.error,
+            ParseErrorKind::ConflictingWordSize(WordSize::Bits32)
+        );
+    }
+
     #[test]
     fn returns_duplicate_definition_error_if_a_label_is_defined_twice() {
         let defines = HashMap::<String, String>::default();
@@ -167,7 +304,7 @@ mod tests {
             "label1: add eax, ebx\n\nlabel1: inc ebx\nlabel2: dec eax",
             &defines,
         );
-        let result = parse(&lexer.tokens());
+        let result = parse(lexer.tokens(), &Natives::default());
 
         match result {
             Err(e) => {
@@ -182,7 +319,7 @@ mod tests {
     fn returns_undefined_error_if_a_label_is_not_defined() {
         let defines = HashMap::<String, String>::default();
         let lexer = LexerContext::lex("inc eax\n\njmp label1", &defines);
-        let result = parse(&lexer.tokens());
+        let result = parse(lexer.tokens(), &Natives::default());
 
         match result {
             Err(e) => {
@@ -197,7 +334,7 @@ mod tests {
     fn parse_errors_are_correctly_returned() {
         let defines = HashMap::<String, String>::default();
         let lexer = LexerContext::lex("inc eax\n\nbad", &defines);
-        let result = parse(&lexer.tokens());
+        let result = parse(lexer.tokens(), &Natives::default());
 
         match result {
             Err(e) => {
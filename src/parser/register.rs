@@ -1,30 +1,27 @@
-use crate::instruction::Register;
+use crate::instruction::{FloatRegister, Register, VectorRegister};
 use std::collections::HashMap;
 
 lazy_static! {
-    static ref REGISTER_MAP: HashMap<&'static str, Register> = vec!(
-        ("eax", Register::Eax),
-        ("ebx", Register::Ebx),
-        ("ecx", Register::Ecx),
-        ("edx", Register::Edx),
-        ("esi", Register::Esi),
-        ("edi", Register::Edi),
-        ("esp", Register::Esp),
-        ("ebp", Register::Ebp),
-        ("eip", Register::Eip),
-        ("r08", Register::R08),
-        ("r09", Register::R09),
-        ("r10", Register::R10),
-        ("r11", Register::R11),
-        ("r12", Register::R12),
-        ("r13", Register::R13),
-        ("r14", Register::R14),
-        ("r15", Register::R15),
-    )
-    .into_iter()
-    .collect();
+    static ref REGISTER_MAP: HashMap<&'static str, Register> =
+        Register::ALL.iter().map(|reg| (reg.name(), *reg)).collect();
+    static ref FLOAT_REGISTER_MAP: HashMap<&'static str, FloatRegister> = FloatRegister::ALL
+        .iter()
+        .map(|reg| (reg.name(), *reg))
+        .collect();
+    static ref VECTOR_REGISTER_MAP: HashMap<&'static str, VectorRegister> = VectorRegister::ALL
+        .iter()
+        .map(|reg| (reg.name(), *reg))
+        .collect();
 }
 
-pub(super) fn parse_register(name: &str) -> Option<Register> {
-    REGISTER_MAP.get(name).map(|v| *v)
+pub(crate) fn parse_register(name: &str) -> Option<Register> {
+    REGISTER_MAP.get(name).copied()
+}
+
+pub(crate) fn parse_float_register(name: &str) -> Option<FloatRegister> {
+    FLOAT_REGISTER_MAP.get(name).copied()
+}
+
+pub(crate) fn parse_vector_register(name: &str) -> Option<VectorRegister> {
+    VECTOR_REGISTER_MAP.get(name).copied()
 }
@@ -2,29 +2,53 @@ use super::{
     unresolved_instruction::{UnresolvedInstruction, UnresolvedSource},
     ParseErrorKind,
 };
-use crate::instruction::{Instruction, Source};
-use std::collections::HashMap;
+use crate::{
+    context::WordSize,
+    instruction::{Instruction, Source},
+    native::Natives,
+};
+use std::{collections::HashMap, convert::TryFrom};
 
 pub(super) fn resolve<'a>(
     instruction: &UnresolvedInstruction<'a>,
     labels: &HashMap<&str, i32>,
+    natives: &Natives,
+    word_size: WordSize,
+    addresses_taken: &mut Vec<i32>,
 ) -> Result<Instruction, ParseErrorKind> {
-    macro_rules! resolve {
+    macro_rules! resolve_jump {
         ($value:expr) => {
             match $value {
                 UnresolvedSource::Register(reg) => Source::Register(*reg),
-                UnresolvedSource::Value(value) => Source::Value(*value),
+                UnresolvedSource::Value(value) => {
+                    if word_size == WordSize::Bits32 && i32::try_from(*value).is_err() {
+                        return Err(ParseErrorKind::InvalidOperand(value.to_string()));
+                    }
+                    Source::Value(*value)
+                }
                 UnresolvedSource::Address(address) => Source::Address(*address),
                 UnresolvedSource::Label(label) => {
                     let value = labels
                         .get(label)
                         .ok_or(ParseErrorKind::UndefinedLabel((*label).to_owned()))?;
-                    Source::Value(*value)
+                    Source::Value((*value).into())
                 }
             }
         };
     }
 
+    // Labels are only code addresses as jump targets, elsewhere they are
+    // values the program can observe
+    macro_rules! resolve {
+        ($value:expr) => {{
+            let source = resolve_jump!($value);
+            if let (UnresolvedSource::Label(_), Source::Value(value)) = ($value, source) {
+                addresses_taken.push(value as i32);
+            }
+            source
+        }};
+    }
+
     let result = match instruction {
         UnresolvedInstruction::Nop => Instruction::Nop,
         UnresolvedInstruction::Int => Instruction::Int,
@@ -49,19 +73,105 @@ pub(super) fn resolve<'a>(
         UnresolvedInstruction::And(target, source) => Instruction::And(*target, resolve!(source)),
         UnresolvedInstruction::Shl(target, source) => Instruction::Shl(*target, resolve!(source)),
         UnresolvedInstruction::Shr(target, source) => Instruction::Shr(*target, resolve!(source)),
+        UnresolvedInstruction::Sar(target, source) => Instruction::Sar(*target, resolve!(source)),
+        UnresolvedInstruction::Rol(target, source) => Instruction::Rol(*target, resolve!(source)),
+        UnresolvedInstruction::Ror(target, source) => Instruction::Ror(*target, resolve!(source)),
+        UnresolvedInstruction::Neg(target) => Instruction::Neg(*target),
+        UnresolvedInstruction::Xchg(target1, target2) => Instruction::Xchg(*target1, *target2),
+        UnresolvedInstruction::Lea(target, address) => Instruction::Lea(*target, *address),
+        UnresolvedInstruction::Test(source1, source2) => {
+            Instruction::Test(resolve!(source1), resolve!(source2))
+        }
+        UnresolvedInstruction::Cmov(condition, target, source) => {
+            Instruction::Cmov(*condition, *target, resolve!(source))
+        }
+        UnresolvedInstruction::Popcnt(target, source) => {
+            Instruction::Popcnt(*target, resolve!(source))
+        }
+        UnresolvedInstruction::Clz(target, source) => Instruction::Clz(*target, resolve!(source)),
+        UnresolvedInstruction::Ctz(target, source) => Instruction::Ctz(*target, resolve!(source)),
+        UnresolvedInstruction::Bswap(target) => Instruction::Bswap(*target),
+        UnresolvedInstruction::Ld(width, target, source) => {
+            Instruction::Ld(*width, *target, resolve!(source))
+        }
+        UnresolvedInstruction::Lds(width, target, source) => {
+            Instruction::Lds(*width, *target, resolve!(source))
+        }
+        UnresolvedInstruction::St(width, address, source) => {
+            Instruction::St(*width, resolve!(address), resolve!(source))
+        }
         UnresolvedInstruction::Cmp(source1, source2) => {
             Instruction::Cmp(resolve!(source1), resolve!(source2))
         }
-        UnresolvedInstruction::Jmp(source) => Instruction::Jmp(resolve!(source)),
-        UnresolvedInstruction::Call(source) => Instruction::Call(resolve!(source)),
+        UnresolvedInstruction::Jmp(source) => Instruction::Jmp(resolve_jump!(source)),
+        // A label of the program takes precedence over a native function
+        UnresolvedInstruction::Call(UnresolvedSource::Label(name))
+            if !labels.contains_key(name) =>
+        {
+            match natives.index_of(name) {
+                Some(index) => Instruction::Native(index, natives.name(index)),
+                None => return Err(ParseErrorKind::UndefinedLabel((*name).to_owned())),
+            }
+        }
+        UnresolvedInstruction::Call(source) => Instruction::Call(resolve_jump!(source)),
         UnresolvedInstruction::Ret => Instruction::Ret,
-        UnresolvedInstruction::Je(source) => Instruction::Je(resolve!(source)),
-        UnresolvedInstruction::Jne(source) => Instruction::Jne(resolve!(source)),
-        UnresolvedInstruction::Jg(source) => Instruction::Jg(resolve!(source)),
-        UnresolvedInstruction::Jge(source) => Instruction::Jge(resolve!(source)),
-        UnresolvedInstruction::Jl(source) => Instruction::Jl(resolve!(source)),
-        UnresolvedInstruction::Jle(source) => Instruction::Jle(resolve!(source)),
+        UnresolvedInstruction::Je(source) => Instruction::Je(resolve_jump!(source)),
+        UnresolvedInstruction::Jne(source) => Instruction::Jne(resolve_jump!(source)),
+        UnresolvedInstruction::Jg(source) => Instruction::Jg(resolve_jump!(source)),
+        UnresolvedInstruction::Jge(source) => Instruction::Jge(resolve_jump!(source)),
+        UnresolvedInstruction::Jl(source) => Instruction::Jl(resolve_jump!(source)),
+        UnresolvedInstruction::Jle(source) => Instruction::Jle(resolve_jump!(source)),
+        UnresolvedInstruction::Ja(source) => Instruction::Ja(resolve_jump!(source)),
+        UnresolvedInstruction::Jae(source) => Instruction::Jae(resolve_jump!(source)),
+        UnresolvedInstruction::Jb(source) => Instruction::Jb(resolve_jump!(source)),
+        UnresolvedInstruction::Jbe(source) => Instruction::Jbe(resolve_jump!(source)),
+        UnresolvedInstruction::Js(source) => Instruction::Js(resolve_jump!(source)),
+        UnresolvedInstruction::Jns(source) => Instruction::Jns(resolve_jump!(source)),
+        UnresolvedInstruction::Jo(source) => Instruction::Jo(resolve_jump!(source)),
+        UnresolvedInstruction::Jno(source) => Instruction::Jno(resolve_jump!(source)),
+        UnresolvedInstruction::Loop(source) => Instruction::Loop(resolve_jump!(source)),
         UnresolvedInstruction::Prn(source) => Instruction::Prn(resolve!(source)),
+        UnresolvedInstruction::Fld(reg, source) => Instruction::Fld(*reg, *source),
+        UnresolvedInstruction::Fst(address, reg) => Instruction::Fst(*address, *reg),
+        UnresolvedInstruction::Fadd(reg, source) => Instruction::Fadd(*reg, *source),
+        UnresolvedInstruction::Fsub(reg, source) => Instruction::Fsub(*reg, *source),
+        UnresolvedInstruction::Fmul(reg, source) => Instruction::Fmul(*reg, *source),
+        UnresolvedInstruction::Fdiv(reg, source) => Instruction::Fdiv(*reg, *source),
+        UnresolvedInstruction::Fneg(reg) => Instruction::Fneg(*reg),
+        UnresolvedInstruction::Fabs(reg) => Instruction::Fabs(*reg),
+        UnresolvedInstruction::Fsqrt(reg) => Instruction::Fsqrt(*reg),
+        UnresolvedInstruction::Fcmp(reg, source) => Instruction::Fcmp(*reg, *source),
+        UnresolvedInstruction::Itof(reg, source) => Instruction::Itof(*reg, resolve!(source)),
+        UnresolvedInstruction::Ftoi(target, reg) => Instruction::Ftoi(*target, *reg),
+        UnresolvedInstruction::Fprn(source) => Instruction::Fprn(*source),
+        UnresolvedInstruction::Vld(reg, source) => Instruction::Vld(*reg, resolve!(source)),
+        UnresolvedInstruction::Vst(source, reg) => Instruction::Vst(resolve!(source), *reg),
+        UnresolvedInstruction::Vop(op, reg1, reg2) => Instruction::Vop(*op, *reg1, *reg2),
+        UnresolvedInstruction::Vins(reg, lane, source) => {
+            Instruction::Vins(*reg, *lane, resolve!(source))
+        }
+        UnresolvedInstruction::Vext(target, reg, lane) => Instruction::Vext(*target, *reg, *lane),
+        UnresolvedInstruction::Vsum(target, reg) => Instruction::Vsum(*target, *reg),
+        // The entry of a core is a label the program passes around as a
+        // value, like the address of a function it calls through a register
+        UnresolvedInstruction::Spawn(target, entry, stack) => {
+            Instruction::Spawn(*target, resolve!(entry), resolve!(stack))
+        }
+        UnresolvedInstruction::Join(source) => Instruction::Join(resolve!(source)),
+        UnresolvedInstruction::Cas(target, address, source) => {
+            Instruction::Cas(*target, resolve!(address), resolve!(source))
+        }
+        UnresolvedInstruction::Xadd(target, address) => {
+            Instruction::Xadd(*target, resolve!(address))
+        }
+        UnresolvedInstruction::Fence => Instruction::Fence,
+        UnresolvedInstruction::Yield => Instruction::Yield,
+        UnresolvedInstruction::Send(channel, source) => {
+            Instruction::Send(resolve!(channel), resolve!(source))
+        }
+        UnresolvedInstruction::Recv(target, channel) => {
+            Instruction::Recv(*target, resolve!(channel))
+        }
     };
 
     Ok(result)
@@ -1,11 +1,18 @@
-use super::{is_valid_label, register::parse_register, ParseErrorKind};
-use crate::instruction::{Register, Target};
-use std::num::ParseIntError;
+use super::{
+    is_valid_label,
+    register::{parse_float_register, parse_register, parse_vector_register},
+    ParseErrorKind,
+};
+use crate::instruction::{
+    Condition, EffectiveAddress, FloatRegister, FloatSource, Register, Target, VectorOp,
+    VectorRegister, Width, NUM_LANES,
+};
+use std::{convert::TryFrom, num::ParseIntError};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(super) enum UnresolvedSource<'a> {
     Register(Register),
-    Value(i32),
+    Value(i64),
     Address(i32),
     Label(&'a str),
 }
@@ -33,6 +40,21 @@ pub(super) enum UnresolvedInstruction<'a> {
     And(Target, UnresolvedSource<'a>),
     Shl(Target, UnresolvedSource<'a>),
     Shr(Target, UnresolvedSource<'a>),
+    Sar(Target, UnresolvedSource<'a>),
+    Rol(Target, UnresolvedSource<'a>),
+    Ror(Target, UnresolvedSource<'a>),
+    Neg(Target),
+    Xchg(Target, Target),
+    Lea(Target, EffectiveAddress),
+    Test(UnresolvedSource<'a>, UnresolvedSource<'a>),
+    Cmov(Condition, Target, UnresolvedSource<'a>),
+    Popcnt(Target, UnresolvedSource<'a>),
+    Clz(Target, UnresolvedSource<'a>),
+    Ctz(Target, UnresolvedSource<'a>),
+    Bswap(Target),
+    Ld(Width, Target, UnresolvedSource<'a>),
+    Lds(Width, Target, UnresolvedSource<'a>),
+    St(Width, UnresolvedSource<'a>, UnresolvedSource<'a>),
     Cmp(UnresolvedSource<'a>, UnresolvedSource<'a>),
     Jmp(UnresolvedSource<'a>),
     Call(UnresolvedSource<'a>),
@@ -43,24 +65,60 @@ pub(super) enum UnresolvedInstruction<'a> {
     Jge(UnresolvedSource<'a>),
     Jl(UnresolvedSource<'a>),
     Jle(UnresolvedSource<'a>),
+    Ja(UnresolvedSource<'a>),
+    Jae(UnresolvedSource<'a>),
+    Jb(UnresolvedSource<'a>),
+    Jbe(UnresolvedSource<'a>),
+    Js(UnresolvedSource<'a>),
+    Jns(UnresolvedSource<'a>),
+    Jo(UnresolvedSource<'a>),
+    Jno(UnresolvedSource<'a>),
+    Loop(UnresolvedSource<'a>),
     Prn(UnresolvedSource<'a>),
+    Fld(FloatRegister, FloatSource),
+    Fst(i32, FloatRegister),
+    Fadd(FloatRegister, FloatSource),
+    Fsub(FloatRegister, FloatSource),
+    Fmul(FloatRegister, FloatSource),
+    Fdiv(FloatRegister, FloatSource),
+    Fneg(FloatRegister),
+    Fabs(FloatRegister),
+    Fsqrt(FloatRegister),
+    Fcmp(FloatRegister, FloatSource),
+    Itof(FloatRegister, UnresolvedSource<'a>),
+    Ftoi(Target, FloatRegister),
+    Fprn(FloatSource),
+    Vld(VectorRegister, UnresolvedSource<'a>),
+    Vst(UnresolvedSource<'a>, VectorRegister),
+    Vop(VectorOp, VectorRegister, VectorRegister),
+    Vins(VectorRegister, u8, UnresolvedSource<'a>),
+    Vext(Target, VectorRegister, u8),
+    Vsum(Target, VectorRegister),
+    Spawn(Target, UnresolvedSource<'a>, UnresolvedSource<'a>),
+    Join(UnresolvedSource<'a>),
+    Cas(Target, UnresolvedSource<'a>, UnresolvedSource<'a>),
+    Xadd(Target, UnresolvedSource<'a>),
+    Fence,
+    Yield,
+    Send(UnresolvedSource<'a>, UnresolvedSource<'a>),
+    Recv(Target, UnresolvedSource<'a>),
 }
 
-fn parse_value(value: &str) -> Result<i32, ParseIntError> {
+pub(crate) fn parse_value(value: &str) -> Result<i64, ParseIntError> {
     if value.ends_with("|h") {
-        i32::from_str_radix(&value[0..value.len() - 2], 16)
+        i64::from_str_radix(&value[0..value.len() - 2], 16)
     } else if value.ends_with("h") {
-        i32::from_str_radix(&value[0..value.len() - 1], 16)
+        i64::from_str_radix(&value[0..value.len() - 1], 16)
     } else if value.ends_with("|b") {
-        i32::from_str_radix(&value[0..value.len() - 2], 2)
+        i64::from_str_radix(&value[0..value.len() - 2], 2)
     } else if value.ends_with("b") {
-        i32::from_str_radix(&value[0..value.len() - 1], 2)
-    } else if value.starts_with("0x") {
-        i32::from_str_radix(&value[2..], 16)
-    } else if value.starts_with("-0x") {
-        i32::from_str_radix(&value[3..], 16).map(|i| -i)
+        i64::from_str_radix(&value[0..value.len() - 1], 2)
+    } else if let Some(hex) = value.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(hex) = value.strip_prefix("-0x") {
+        i64::from_str_radix(hex, 16).map(|i| -i)
     } else {
-        i32::from_str_radix(value, 10)
+        value.parse::<i64>()
     }
 }
 
@@ -73,6 +131,141 @@ fn parse_target(tokens: &[&str], index: usize) -> Result<Target, ParseErrorKind>
     }
 }
 
+/// Parses the operand of `lea`, such as `[ebx+ecx*4-8]`: a base register, an
+/// index register with a scale of 1, 2, 4 or 8, and a displacement, all
+/// optional.
+fn parse_effective_address(
+    tokens: &[&str],
+    index: usize,
+) -> Result<EffectiveAddress, ParseErrorKind> {
+    let token = tokens
+        .get(index)
+        .ok_or(ParseErrorKind::MissingOperand(index))?;
+    let invalid = || ParseErrorKind::InvalidOperand((*token).to_owned());
+    let inner = token
+        .strip_prefix('[')
+        .and_then(|t| t.strip_suffix(']'))
+        .ok_or_else(invalid)?;
+
+    let mut terms = vec![];
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        if (c == '+' || c == '-') && i > 0 {
+            terms.push(&inner[start..i]);
+            start = i;
+        }
+    }
+    terms.push(&inner[start..]);
+
+    let mut address = EffectiveAddress {
+        base: None,
+        index: None,
+        displacement: 0,
+    };
+    for term in terms {
+        let (negative, term) = match term.strip_prefix('-') {
+            Some(term) => (true, term),
+            None => (false, term.strip_prefix('+').unwrap_or(term)),
+        };
+
+        if let Some((reg, scale)) = term.split_once('*') {
+            let reg = parse_register(reg).ok_or_else(invalid)?;
+            let scale = match parse_value(scale) {
+                Ok(scale @ (1 | 2 | 4 | 8)) => scale as i32,
+                _ => return Err(invalid()),
+            };
+            if negative || address.index.is_some() {
+                return Err(invalid());
+            }
+            address.index = Some((reg, scale));
+        } else if let Some(reg) = parse_register(term) {
+            if negative {
+                return Err(invalid());
+            } else if address.base.is_none() {
+                address.base = Some(reg);
+            } else if address.index.is_none() {
+                address.index = Some((reg, 1));
+            } else {
+                return Err(invalid());
+            }
+        } else {
+            let value = parse_value(term)
+                .ok()
+                .and_then(|value| i32::try_from(value).ok())
+                .ok_or_else(invalid)?;
+            let value = if negative {
+                value.wrapping_neg()
+            } else {
+                value
+            };
+            address.displacement = address.displacement.wrapping_add(value);
+        }
+    }
+
+    Ok(address)
+}
+
+fn parse_float_register_operand(
+    tokens: &[&str],
+    index: usize,
+) -> Result<FloatRegister, ParseErrorKind> {
+    let token = tokens
+        .get(index)
+        .ok_or(ParseErrorKind::MissingOperand(index))?;
+    parse_float_register(token).ok_or_else(|| ParseErrorKind::InvalidOperand((*token).to_owned()))
+}
+
+/// Parses a floating-point register, an address in memory, or a literal
+/// such as `1.5`, `-2`, `6.02e23` or `inf`.
+fn parse_float_source(tokens: &[&str], index: usize) -> Result<FloatSource, ParseErrorKind> {
+    let token = tokens
+        .get(index)
+        .ok_or(ParseErrorKind::MissingOperand(index))?;
+
+    if let Some(reg) = parse_float_register(token) {
+        return Ok(FloatSource::Register(reg));
+    }
+    match parse_source(tokens, index) {
+        Ok(UnresolvedSource::Address(addr)) => return Ok(FloatSource::Address(addr)),
+        Err(ParseErrorKind::InvalidOperand(_)) | Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+    match token.parse::<f64>() {
+        Ok(value) => Ok(FloatSource::Value(value)),
+        Err(_) => Err(ParseErrorKind::InvalidOperand((*token).to_owned())),
+    }
+}
+
+/// Parses the memory operand `fst` stores to.
+fn parse_float_target(tokens: &[&str], index: usize) -> Result<i32, ParseErrorKind> {
+    match parse_target(tokens, index)? {
+        Target::Address(addr) => Ok(addr),
+        Target::Register(_) => Err(ParseErrorKind::InvalidOperand(tokens[index].to_owned())),
+    }
+}
+
+fn parse_vector_register_operand(
+    tokens: &[&str],
+    index: usize,
+) -> Result<VectorRegister, ParseErrorKind> {
+    let token = tokens
+        .get(index)
+        .ok_or(ParseErrorKind::MissingOperand(index))?;
+    parse_vector_register(token).ok_or_else(|| ParseErrorKind::InvalidOperand((*token).to_owned()))
+}
+
+/// Parses the lane number of `vins` and `vext`, a constant below
+/// `NUM_LANES`.
+fn parse_lane(tokens: &[&str], index: usize) -> Result<u8, ParseErrorKind> {
+    let token = tokens
+        .get(index)
+        .ok_or(ParseErrorKind::MissingOperand(index))?;
+    match parse_value(token) {
+        Ok(lane @ 0..) if (lane as usize) < NUM_LANES => Ok(lane as u8),
+        _ => Err(ParseErrorKind::InvalidOperand((*token).to_owned())),
+    }
+}
+
 fn parse_source<'a>(
     tokens: &[&'a str],
     index: usize,
@@ -86,7 +279,7 @@ fn parse_source<'a>(
     } else if token.starts_with('[') && token.ends_with(']') {
         match parse_value(token[1..token.len() - 1].trim()) {
             Ok(value) => {
-                if value >= 0 {
+                if let Ok(value @ 0..) = i32::try_from(value) {
                     Ok(UnresolvedSource::Address(value))
                 } else {
                     Err(ParseErrorKind::InvalidOperand((*token).to_owned()))
@@ -129,6 +322,48 @@ impl UnresolvedInstruction<'_> {
             }};
         }
 
+        macro_rules! address {
+            () => {{
+                arg_number += 1;
+                parse_effective_address(tokens, arg_number)?
+            }};
+        }
+
+        macro_rules! float_register {
+            () => {{
+                arg_number += 1;
+                parse_float_register_operand(tokens, arg_number)?
+            }};
+        }
+
+        macro_rules! float_source {
+            () => {{
+                arg_number += 1;
+                parse_float_source(tokens, arg_number)?
+            }};
+        }
+
+        macro_rules! float_target {
+            () => {{
+                arg_number += 1;
+                parse_float_target(tokens, arg_number)?
+            }};
+        }
+
+        macro_rules! vector_register {
+            () => {{
+                arg_number += 1;
+                parse_vector_register_operand(tokens, arg_number)?
+            }};
+        }
+
+        macro_rules! lane {
+            () => {{
+                arg_number += 1;
+                parse_lane(tokens, arg_number)?
+            }};
+        }
+
         macro_rules! instr {
             ($mnemonic:literal, $instruction:ident) => {
                 if (mnemonic == $mnemonic) {
@@ -139,6 +374,16 @@ impl UnresolvedInstruction<'_> {
                     }
                 }
             };
+            ($mnemonic:literal, $instruction:ident($condition:expr), $($arg:tt),+) => {
+                if (mnemonic == $mnemonic) {
+                    let result = UnresolvedInstruction::$instruction($condition, $($arg!()),+);
+                    return if arg_number == tokens.len() - 1 {
+                        Ok(result)
+                    } else {
+                        Err(ParseErrorKind::ExtraToken(tokens[arg_number + 1].to_owned()))
+                    }
+                }
+            };
             ($mnemonic:literal, $instruction:ident, $($arg:tt),+) => {
                 if (mnemonic == $mnemonic) {
                     let result = UnresolvedInstruction::$instruction($($arg!()),+);
@@ -172,6 +417,41 @@ impl UnresolvedInstruction<'_> {
         instr!("and", And, target, source);
         instr!("shl", Shl, target, source);
         instr!("shr", Shr, target, source);
+        instr!("sar", Sar, target, source);
+        instr!("rol", Rol, target, source);
+        instr!("ror", Ror, target, source);
+        instr!("neg", Neg, target);
+        instr!("xchg", Xchg, target, target);
+        instr!("lea", Lea, target, address);
+        instr!("test", Test, source, source);
+        instr!("cmove", Cmov(Condition::Equal), target, source);
+        instr!("cmovne", Cmov(Condition::NotEqual), target, source);
+        instr!("cmovg", Cmov(Condition::Greater), target, source);
+        instr!("cmovge", Cmov(Condition::GreaterOrEqual), target, source);
+        instr!("cmovl", Cmov(Condition::Less), target, source);
+        instr!("cmovle", Cmov(Condition::LessOrEqual), target, source);
+        instr!("cmova", Cmov(Condition::Above), target, source);
+        instr!("cmovae", Cmov(Condition::AboveOrEqual), target, source);
+        instr!("cmovb", Cmov(Condition::Below), target, source);
+        instr!("cmovbe", Cmov(Condition::BelowOrEqual), target, source);
+        instr!("cmovs", Cmov(Condition::Sign), target, source);
+        instr!("cmovns", Cmov(Condition::NotSign), target, source);
+        instr!("cmovo", Cmov(Condition::Overflow), target, source);
+        instr!("cmovno", Cmov(Condition::NotOverflow), target, source);
+        instr!("cmovz", Cmov(Condition::Equal), target, source);
+        instr!("cmovnz", Cmov(Condition::NotEqual), target, source);
+        instr!("cmovc", Cmov(Condition::Below), target, source);
+        instr!("cmovnc", Cmov(Condition::AboveOrEqual), target, source);
+        instr!("popcnt", Popcnt, target, source);
+        instr!("clz", Clz, target, source);
+        instr!("ctz", Ctz, target, source);
+        instr!("bswap", Bswap, target);
+        instr!("ldb", Ld(Width::Byte), target, source);
+        instr!("ldh", Ld(Width::Halfword), target, source);
+        instr!("ldsb", Lds(Width::Byte), target, source);
+        instr!("ldsh", Lds(Width::Halfword), target, source);
+        instr!("stb", St(Width::Byte), source, source);
+        instr!("sth", St(Width::Halfword), source, source);
         instr!("cmp", Cmp, source, source);
         instr!("jmp", Jmp, source);
         instr!("call", Call, source);
@@ -182,9 +462,66 @@ impl UnresolvedInstruction<'_> {
         instr!("jge", Jge, source);
         instr!("jl", Jl, source);
         instr!("jle", Jle, source);
+        instr!("ja", Ja, source);
+        instr!("jae", Jae, source);
+        instr!("jb", Jb, source);
+        instr!("jbe", Jbe, source);
+        instr!("js", Js, source);
+        instr!("jns", Jns, source);
+        instr!("jo", Jo, source);
+        instr!("jno", Jno, source);
+        // Other names for the same conditions, as on x86
+        instr!("jz", Je, source);
+        instr!("jnz", Jne, source);
+        instr!("jc", Jb, source);
+        instr!("jnc", Jae, source);
+        instr!("loop", Loop, source);
         instr!("prn", Prn, source);
+        instr!("fld", Fld, float_register, float_source);
+        instr!("fst", Fst, float_target, float_register);
+        instr!("fadd", Fadd, float_register, float_source);
+        instr!("fsub", Fsub, float_register, float_source);
+        instr!("fmul", Fmul, float_register, float_source);
+        instr!("fdiv", Fdiv, float_register, float_source);
+        instr!("fneg", Fneg, float_register);
+        instr!("fabs", Fabs, float_register);
+        instr!("fsqrt", Fsqrt, float_register);
+        instr!("fcmp", Fcmp, float_register, float_source);
+        instr!("itof", Itof, float_register, source);
+        instr!("ftoi", Ftoi, target, float_register);
+        instr!("fprn", Fprn, float_source);
+        instr!("vld", Vld, vector_register, source);
+        instr!("vst", Vst, source, vector_register);
+        instr!("vadd", Vop(VectorOp::Add), vector_register, vector_register);
+        instr!("vsub", Vop(VectorOp::Sub), vector_register, vector_register);
+        instr!("vmul", Vop(VectorOp::Mul), vector_register, vector_register);
+        instr!("vmin", Vop(VectorOp::Min), vector_register, vector_register);
+        instr!("vmax", Vop(VectorOp::Max), vector_register, vector_register);
+        instr!(
+            "vcmpeq",
+            Vop(VectorOp::CmpEq),
+            vector_register,
+            vector_register
+        );
+        instr!(
+            "vcmpgt",
+            Vop(VectorOp::CmpGt),
+            vector_register,
+            vector_register
+        );
+        instr!("vins", Vins, vector_register, lane, source);
+        instr!("vext", Vext, target, vector_register, lane);
+        instr!("vsum", Vsum, target, vector_register);
+        instr!("spawn", Spawn, target, source, source);
+        instr!("join", Join, source);
+        instr!("cas", Cas, target, source, source);
+        instr!("xadd", Xadd, target, source);
+        instr!("fence", Fence);
+        instr!("yield", Yield);
+        instr!("send", Send, source, source);
+        instr!("recv", Recv, target, source);
 
-        return Err(ParseErrorKind::InvalidInstruction(tokens[0].to_owned()));
+        Err(ParseErrorKind::InvalidInstruction(tokens[0].to_owned()))
     }
 }
 
@@ -232,6 +569,36 @@ mod tests {
         run("and eax ebx", And(eax, ebx));
         run("shl eax ebx", Shl(eax, ebx));
         run("shr eax ebx", Shr(eax, ebx));
+        run("sar eax ebx", Sar(eax, ebx));
+        run("rol eax ebx", Rol(eax, ebx));
+        run("ror eax ebx", Ror(eax, ebx));
+        run("neg eax", Neg(eax));
+        run("xchg eax [5]", Xchg(eax, Target::Address(5)));
+        run(
+            "lea eax [ebx+ecx*4-8]",
+            Lea(
+                eax,
+                EffectiveAddress {
+                    base: Some(Register::Ebx),
+                    index: Some((Register::Ecx, 4)),
+                    displacement: -8,
+                },
+            ),
+        );
+        run("test ebx ecx", Test(ebx, ecx));
+        run("cmovge eax ebx", Cmov(Condition::GreaterOrEqual, eax, ebx));
+        run("cmovbe eax ebx", Cmov(Condition::BelowOrEqual, eax, ebx));
+        run("cmovz eax ebx", Cmov(Condition::Equal, eax, ebx));
+        run("popcnt eax ebx", Popcnt(eax, ebx));
+        run("clz eax ebx", Clz(eax, ebx));
+        run("ctz eax ebx", Ctz(eax, ebx));
+        run("bswap eax", Bswap(eax));
+        run("ldb eax ebx", Ld(Width::Byte, eax, ebx));
+        run(
+            "ldsh eax [5]",
+            Lds(Width::Halfword, eax, UnresolvedSource::Address(5)),
+        );
+        run("sth ebx ecx", St(Width::Halfword, ebx, ecx));
         run("cmp ebx ecx", Cmp(ebx, ecx));
         run("jmp ebx", Jmp(ebx));
         run("call ebx", Call(ebx));
@@ -242,7 +609,157 @@ mod tests {
         run("jge ebx", Jge(ebx));
         run("jl ebx", Jl(ebx));
         run("jle ebx", Jle(ebx));
+        run("ja ebx", Ja(ebx));
+        run("jae ebx", Jae(ebx));
+        run("jb ebx", Jb(ebx));
+        run("jbe ebx", Jbe(ebx));
+        run("js ebx", Js(ebx));
+        run("jns ebx", Jns(ebx));
+        run("jo ebx", Jo(ebx));
+        run("jno ebx", Jno(ebx));
+        run("jz ebx", Je(ebx));
+        run("jnz ebx", Jne(ebx));
+        run("jc ebx", Jb(ebx));
+        run("jnc ebx", Jae(ebx));
+        run("loop ebx", Loop(ebx));
         run("prn ebx", Prn(ebx));
+        run(
+            "fld f0 f1",
+            Fld(FloatRegister::F0, FloatSource::Register(FloatRegister::F1)),
+        );
+        run("fst [4] f2", Fst(4, FloatRegister::F2));
+        run(
+            "fadd f3 1.5",
+            Fadd(FloatRegister::F3, FloatSource::Value(1.5)),
+        );
+        run(
+            "fsub f3 [8]",
+            Fsub(FloatRegister::F3, FloatSource::Address(8)),
+        );
+        run(
+            "fmul f4 -2",
+            Fmul(FloatRegister::F4, FloatSource::Value(-2.0)),
+        );
+        run(
+            "fdiv f5 1e-3",
+            Fdiv(FloatRegister::F5, FloatSource::Value(1e-3)),
+        );
+        run("fneg f6", Fneg(FloatRegister::F6));
+        run("fabs f6", Fabs(FloatRegister::F6));
+        run("fsqrt f7", Fsqrt(FloatRegister::F7));
+        run(
+            "fcmp f0 inf",
+            Fcmp(FloatRegister::F0, FloatSource::Value(f64::INFINITY)),
+        );
+        run("itof f1 ebx", Itof(FloatRegister::F1, ebx));
+        run("ftoi eax f1", Ftoi(eax, FloatRegister::F1));
+        run("fprn f1", Fprn(FloatSource::Register(FloatRegister::F1)));
+        run("vld v0 ebx", Vld(VectorRegister::V0, ebx));
+        run(
+            "vst [8] v1",
+            Vst(UnresolvedSource::Address(8), VectorRegister::V1),
+        );
+        run(
+            "vadd v2 v3",
+            Vop(VectorOp::Add, VectorRegister::V2, VectorRegister::V3),
+        );
+        run(
+            "vcmpgt v4 v5",
+            Vop(VectorOp::CmpGt, VectorRegister::V4, VectorRegister::V5),
+        );
+        run("vins v6 3 ebx", Vins(VectorRegister::V6, 3, ebx));
+        run("vext eax v7 0", Vext(eax, VectorRegister::V7, 0));
+        run("vsum eax v7", Vsum(eax, VectorRegister::V7));
+        run("spawn eax ebx ecx", Spawn(eax, ebx, ecx));
+        run("join ebx", Join(ebx));
+        run(
+            "cas eax [5] ecx",
+            Cas(eax, UnresolvedSource::Address(5), ecx),
+        );
+        run("xadd eax ebx", Xadd(eax, ebx));
+        run("fence", Fence);
+        run("yield", Yield);
+        run("send 1 ebx", Send(UnresolvedSource::Value(1), ebx));
+        run("recv eax ecx", Recv(eax, ecx));
+    }
+
+    #[test]
+    fn vector_operands_are_checked() {
+        run_error(
+            "vld eax 1",
+            ParseErrorKind::InvalidOperand("eax".to_owned()),
+        );
+        run_error(
+            "vadd v0 v8",
+            ParseErrorKind::InvalidOperand("v8".to_owned()),
+        );
+        run_error(
+            "vins v0 4 1",
+            ParseErrorKind::InvalidOperand("4".to_owned()),
+        );
+        run_error(
+            "vext eax v0 -1",
+            ParseErrorKind::InvalidOperand("-1".to_owned()),
+        );
+        run_error(
+            "vext eax v0 ebx",
+            ParseErrorKind::InvalidOperand("ebx".to_owned()),
+        );
+    }
+
+    #[test]
+    fn float_operands_are_checked() {
+        run_error(
+            "fld eax 1.5",
+            ParseErrorKind::InvalidOperand("eax".to_owned()),
+        );
+        run_error(
+            "fld f0 1.5.2",
+            ParseErrorKind::InvalidOperand("1.5.2".to_owned()),
+        );
+        run_error("fld f8 1", ParseErrorKind::InvalidOperand("f8".to_owned()));
+        run_error("fst f0 f1", ParseErrorKind::InvalidOperand("f0".to_owned()));
+        run_error(
+            "fst eax f1",
+            ParseErrorKind::InvalidOperand("eax".to_owned()),
+        );
+        run_error(
+            "ftoi f0 f1",
+            ParseErrorKind::InvalidOperand("f0".to_owned()),
+        );
+    }
+
+    #[test]
+    fn can_parse_effective_addresses() {
+        let lea = |base, index, displacement| {
+            Lea(
+                Target::Register(Register::Eax),
+                EffectiveAddress {
+                    base,
+                    index,
+                    displacement,
+                },
+            )
+        };
+
+        run("lea eax [100]", lea(None, None, 100));
+        run("lea eax [esp]", lea(Some(Register::Esp), None, 0));
+        run(
+            "lea eax [ebx+ecx]",
+            lea(Some(Register::Ebx), Some((Register::Ecx, 1)), 0),
+        );
+        run(
+            "lea eax [10h+esi*8+ebp-2]",
+            lea(Some(Register::Ebp), Some((Register::Esi, 8)), 14),
+        );
+        run("lea eax [-4]", lea(None, None, -4));
+
+        for operand in &["[eax*3]", "[eax+ebx+ecx]", "[-eax]", "eax", "[eax+]", "[x]"] {
+            run_error(
+                &format!("lea eax {}", operand),
+                ParseErrorKind::InvalidOperand((*operand).to_owned()),
+            );
+        }
     }
 
     #[test]
@@ -1,4 +1,8 @@
-use std::{collections::{HashMap, hash_map::Entry}, io::Error};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::Error,
+    sync::Arc,
+};
 
 pub const TOK_INCLUDE: &str = "%include";
 pub const TOK_DEFINE: &str = "%define";
@@ -18,16 +22,47 @@ pub enum PreprocessingError {
     DefineWithoutValue(String),
 }
 
+/// Where a line of preprocessed source came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// The file the line was read from, or `None` for the top-level source
+    /// when it was not loaded from a named file.
+    pub file: Option<Arc<str>>,
+    /// The 1-based line number within the file.
+    pub line: usize,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// The locations of each line in `src`, which was read from `file`.
+fn line_locations(file: Option<Arc<str>>, src: &str) -> Vec<SourceLocation> {
+    (1..=src.lines().count())
+        .map(|line| SourceLocation {
+            file: file.clone(),
+            line,
+        })
+        .collect()
+}
+
 /// Scan through the input string looking for a line starting with some
 /// directive, using a callback to figure out what to replace the directive line
-/// with.
+/// with. The callback also returns the name of the file the replacement was
+/// read from, if any, and `locations` is updated to match the new lines.
 fn process_directive_line<F>(
     mut src: String,
     directive: &str,
+    locations: &mut Vec<SourceLocation>,
     replace_line: F,
 ) -> Result<(String, bool), PreprocessingError>
 where
-    F: FnOnce(&str) -> Result<String, PreprocessingError>,
+    F: FnOnce(&str) -> Result<(String, Option<&str>), PreprocessingError>,
 {
     let directive_delimiter = match src.find(directive) {
         Some(ix) => ix,
@@ -41,7 +76,15 @@ where
 
     let directive_line = src[directive_delimiter + directive.len()..end_ix].trim();
 
-    let replacement = replace_line(directive_line)?;
+    let (replacement, file) = replace_line(directive_line)?;
+
+    // The directive line is replaced by the lines of the replacement
+    let line_index = src[..directive_delimiter].matches('\n').count();
+    let replacement_locations = match file {
+        Some(file) => line_locations(Some(Arc::from(file)), &replacement),
+        None => vec![locations[line_index].clone(); replacement.matches('\n').count()],
+    };
+    locations.splice(line_index..line_index + 1, replacement_locations);
 
     src.drain(directive_delimiter..end_ix + 1);
     src.insert_str(directive_delimiter, &replacement);
@@ -49,22 +92,35 @@ where
     Ok((src, true))
 }
 
-fn process_includes(src: String) -> Result<(String, bool), PreprocessingError> {
-    process_directive_line(src, TOK_INCLUDE, |line| {
-        std::fs::read_to_string(line).map_err(|e| PreprocessingError::FailedInclude {
-            name: line.to_string(),
-            inner: e,
-        })
+fn process_includes(
+    src: String,
+    locations: &mut Vec<SourceLocation>,
+) -> Result<(String, bool), PreprocessingError> {
+    process_directive_line(src, TOK_INCLUDE, locations, |line| {
+        let mut contents =
+            std::fs::read_to_string(line).map_err(|e| PreprocessingError::FailedInclude {
+                name: line.to_string(),
+                inner: e,
+            })?;
+
+        // Keep the last included line from running into the line after the
+        // directive
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+
+        Ok((contents, Some(line)))
     })
 }
 
 fn process_defines(
     src: String,
     defines: &mut HashMap<String, String>,
+    locations: &mut Vec<SourceLocation>,
 ) -> Result<(String, bool), PreprocessingError> {
-    process_directive_line(src, TOK_DEFINE, |line| {
+    process_directive_line(src, TOK_DEFINE, locations, |line| {
         parse_define(line, defines)?;
-        Ok(String::from("\n"))
+        Ok((String::from("\n"), None))
     })
 }
 
@@ -97,13 +153,28 @@ fn parse_define(line: &str, defines: &mut HashMap<String, String>) -> Result<(),
     Ok(())
 }
 
-pub fn preprocess(mut src: String, defines: &mut HashMap<String, String>) -> Result<String, PreprocessingError> {
+pub fn preprocess(
+    src: String,
+    defines: &mut HashMap<String, String>,
+) -> Result<String, PreprocessingError> {
+    preprocess_with_locations(src, None, defines).map(|(src, _)| src)
+}
+
+/// Like `preprocess`, but also returns the original location of every line in
+/// the output, with `file` being the name of the file `src` was read from.
+pub fn preprocess_with_locations(
+    mut src: String,
+    file: Option<&str>,
+    defines: &mut HashMap<String, String>,
+) -> Result<(String, Vec<SourceLocation>), PreprocessingError> {
+    let mut locations = line_locations(file.map(Arc::from), &src);
+
     loop {
-        let (modified, any_includes) = process_includes(src)?;
-        let (modified, any_defines) = process_defines(modified, defines)?;
+        let (modified, any_includes) = process_includes(src, &mut locations)?;
+        let (modified, any_defines) = process_defines(modified, defines, &mut locations)?;
 
         if !any_includes && !any_defines {
-            return Ok(modified);
+            return Ok((modified, locations));
         }
 
         src = modified;
@@ -161,15 +232,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn locations_follow_includes_and_defines() {
+        let mut nested = tempfile::NamedTempFile::new().unwrap();
+        nested.write_all(b"first nested\nlast nested").unwrap();
+        let nested_filename = nested.path().to_str().unwrap();
+
+        let src = format!("%define a 1\n%include {}\nlast line\n", nested_filename);
+        let mut defines = HashMap::<String, String>::default();
+
+        let (preprocessed, locations) =
+            preprocess_with_locations(src, Some("top.vm"), &mut defines).unwrap();
+
+        assert_eq!(preprocessed, "\nfirst nested\nlast nested\nlast line\n");
+
+        let locations: Vec<_> = locations.iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            locations,
+            &[
+                "top.vm:1".to_owned(),
+                format!("{}:1", nested_filename),
+                format!("{}:2", nested_filename),
+                "top.vm:3".to_owned(),
+            ]
+        );
+    }
+
     #[test]
     fn empty_string() {
         let src = String::from("");
         let mut defines = HashMap::<String, String>::default();
 
-        let (got, replacements) = process_defines(src, &mut defines).unwrap();
+        let (got, replacements) = process_defines(src, &mut defines, &mut vec![]).unwrap();
 
         assert!(got.is_empty());
-        assert_eq!(replacements, false);
+        assert!(!replacements);
         assert!(defines.is_empty());
     }
 
@@ -178,10 +275,11 @@ mod tests {
         let src = String::from("this string contains a % symbol");
         let mut defines = HashMap::<String, String>::default();
 
-        let (got, replacements) = process_defines(src.clone(), &mut defines).unwrap();
+        let (got, replacements) =
+            process_defines(src.clone(), &mut defines, &mut line_locations(None, &src)).unwrap();
 
         assert_eq!(got, src);
-        assert_eq!(replacements, false);
+        assert!(!replacements);
         assert!(defines.is_empty());
     }
 
@@ -190,7 +288,8 @@ mod tests {
         let src = String::from("%define\n");
         let mut defines = HashMap::<String, String>::default();
 
-        let err = process_defines(src.clone(), &mut defines).unwrap_err();
+        let err = process_defines(src.clone(), &mut defines, &mut line_locations(None, &src))
+            .unwrap_err();
 
         match err {
             PreprocessingError::EmptyDefine => {}
@@ -203,7 +302,8 @@ mod tests {
         let src = String::from("%define key\n");
         let mut defines = HashMap::<String, String>::default();
 
-        let err = process_defines(src.clone(), &mut defines).unwrap_err();
+        let err = process_defines(src.clone(), &mut defines, &mut line_locations(None, &src))
+            .unwrap_err();
 
         match err {
             PreprocessingError::DefineWithoutValue(key) => assert_eq!(key, "key"),
@@ -216,10 +316,11 @@ mod tests {
         let src = String::from("%define key value\n");
         let mut defines = HashMap::<String, String>::default();
 
-        let (got, had_defines) = process_defines(src.clone(), &mut defines).unwrap();
+        let (got, had_defines) =
+            process_defines(src.clone(), &mut defines, &mut line_locations(None, &src)).unwrap();
 
         assert_eq!(got, "\n");
-        assert_eq!(had_defines, true);
+        assert!(had_defines);
         assert_eq!(defines.len(), 1);
         assert_eq!(defines.get("key").unwrap(), "value");
     }
@@ -0,0 +1,410 @@
+//! Saving and restoring the complete state of a running program.
+//!
+//! A snapshot is a little-endian binary file laid out as follows:
+//!
+//! | Field                 | Type                            |
+//! |-----------------------|---------------------------------|
+//! | magic                 | `b"TVMSNAP\0"`                  |
+//! | version               | u32 (currently 1)               |
+//! | program hash          | u64                             |
+//! | register count        | u32                             |
+//! | registers             | word × register count           |
+//! | flags                 | i32                             |
+//! | remainder             | word                            |
+//! | frame count           | u32                             |
+//! | frames                | i32 × 3 × frame count           |
+//! | float register count  | u32                             |
+//! | float registers       | f64 × float register count      |
+//! | vector register count | u32                             |
+//! | vector registers      | i32 × 4 × vector register count |
+//! | memory size           | u32 (in words)                  |
+//! | run count             | u32                             |
+//! | runs                  | see below                       |
+//!
+//! Words are i64s, whatever the word size of the program. Each frame of the
+//! call stack, outermost first, is its callee, call site and stack pointer.
+//! Each vector register is its lanes, lowest first.
+//!
+//! Memory is stored sparsely, as runs of non-zero words. Each run is its
+//! start address and length as u32s, followed by the words themselves.
+//!
+//! The program hash identifies the program the snapshot was taken from, so
+//! that a snapshot is not accidentally resumed with a different program.
+//!
+//! A snapshot is the state of a single core without channels: the other
+//! cores of a `Machine` and the channels of a `Network` are not part of it,
+//! and neither is the output stream. Nor is `strict_returns`, which has to
+//! be set again on the restored `Memory` before resuming.
+
+use crate::{
+    context::{Frame, Memory, Program, MEMORY_SIZE},
+    instruction::{NUM_FLOAT_REGISTERS, NUM_LANES, NUM_REGISTERS, NUM_VECTOR_REGISTERS},
+};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"TVMSNAP\0";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The data is not a snapshot, or is corrupt.
+    InvalidFormat,
+    UnsupportedVersion(u32),
+    /// The snapshot was taken from a different program.
+    ProgramMismatch,
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> SnapshotError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::InvalidFormat,
+            _ => SnapshotError::Io(error),
+        }
+    }
+}
+
+impl Program {
+    /// Writes the state in `memory` to `writer`.
+    pub fn save_snapshot<W: Write>(
+        self: &Program,
+        memory: &Memory,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.hash().to_le_bytes())?;
+        write_state(memory, writer)
+    }
+
+    /// Reads a snapshot saved by `save_snapshot`, to be used instead of the
+    /// state returned by `initialize`.
+    pub fn restore_snapshot<R: Read>(
+        self: &Program,
+        reader: &mut R,
+    ) -> Result<Memory, SnapshotError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::InvalidFormat);
+        }
+
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut hash = [0; 8];
+        reader.read_exact(&mut hash)?;
+        if u64::from_le_bytes(hash) != self.hash() {
+            return Err(SnapshotError::ProgramMismatch);
+        }
+
+        read_state(reader)
+    }
+
+    /// A 64-bit FNV-1a hash of the word size, the start index and the
+    /// instructions as assembly, one per line.
+    fn hash(self: &Program) -> u64 {
+        let mut bytes = self.word_size.bits().to_le_bytes().to_vec();
+        bytes.extend(self.start_instruction_index.to_le_bytes());
+        for instruction in &self.instructions {
+            bytes.extend(instruction.to_string().bytes());
+            bytes.push(b'\n');
+        }
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+}
+
+/// Writes everything in a snapshot after the program hash.
+pub(crate) fn write_state<W: Write>(memory: &Memory, writer: &mut W) -> io::Result<()> {
+    writer.write_all(&(NUM_REGISTERS as u32).to_le_bytes())?;
+    for register in memory.registers.iter() {
+        writer.write_all(&register.to_le_bytes())?;
+    }
+    writer.write_all(&memory.flags.to_le_bytes())?;
+    writer.write_all(&memory.remainder.to_le_bytes())?;
+
+    writer.write_all(&(memory.call_stack.len() as u32).to_le_bytes())?;
+    for frame in &memory.call_stack {
+        writer.write_all(&frame.callee.to_le_bytes())?;
+        writer.write_all(&frame.call_site.to_le_bytes())?;
+        writer.write_all(&frame.stack_pointer.to_le_bytes())?;
+    }
+
+    writer.write_all(&(NUM_FLOAT_REGISTERS as u32).to_le_bytes())?;
+    for register in memory.float_registers.iter() {
+        writer.write_all(&register.to_le_bytes())?;
+    }
+
+    writer.write_all(&(NUM_VECTOR_REGISTERS as u32).to_le_bytes())?;
+    for register in memory.vector_registers.iter() {
+        for lane in register {
+            writer.write_all(&lane.to_le_bytes())?;
+        }
+    }
+
+    let runs = non_zero_runs(&memory.mem_space);
+    writer.write_all(&(memory.mem_space.len() as u32).to_le_bytes())?;
+    writer.write_all(&(runs.len() as u32).to_le_bytes())?;
+    for (start, end) in runs {
+        writer.write_all(&(start as u32).to_le_bytes())?;
+        writer.write_all(&((end - start) as u32).to_le_bytes())?;
+        for word in &memory.mem_space[start..end] {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads everything in a snapshot after the program hash.
+pub(crate) fn read_state<R: Read>(reader: &mut R) -> Result<Memory, SnapshotError> {
+    if read_u32(reader)? as usize != NUM_REGISTERS {
+        return Err(SnapshotError::InvalidFormat);
+    }
+    let mut registers = [0; NUM_REGISTERS];
+    for register in registers.iter_mut() {
+        *register = read_i64(reader)?;
+    }
+    let flags = read_i32(reader)?;
+    let remainder = read_i64(reader)?;
+
+    let mut call_stack = vec![];
+    for _ in 0..read_u32(reader)? {
+        call_stack.push(Frame {
+            callee: read_i32(reader)?,
+            call_site: read_i32(reader)?,
+            stack_pointer: read_i32(reader)?,
+        });
+    }
+
+    if read_u32(reader)? as usize != NUM_FLOAT_REGISTERS {
+        return Err(SnapshotError::InvalidFormat);
+    }
+    let mut float_registers = [0.0; NUM_FLOAT_REGISTERS];
+    for register in float_registers.iter_mut() {
+        let mut bytes = [0; 8];
+        reader.read_exact(&mut bytes)?;
+        *register = f64::from_le_bytes(bytes);
+    }
+
+    if read_u32(reader)? as usize != NUM_VECTOR_REGISTERS {
+        return Err(SnapshotError::InvalidFormat);
+    }
+    let mut vector_registers = [[0; NUM_LANES]; NUM_VECTOR_REGISTERS];
+    for lane in vector_registers.iter_mut().flatten() {
+        *lane = read_i32(reader)?;
+    }
+
+    // Programs never have more memory, so a larger size means the file is
+    // corrupt
+    let size = read_u32(reader)? as usize;
+    if size > MEMORY_SIZE {
+        return Err(SnapshotError::InvalidFormat);
+    }
+    let mut memory = Memory::new(size, 0);
+    memory.registers = registers;
+    memory.float_registers = float_registers;
+    memory.vector_registers = vector_registers;
+    memory.flags = flags;
+    memory.remainder = remainder;
+    memory.call_stack = call_stack;
+
+    let run_count = read_u32(reader)?;
+    for _ in 0..run_count {
+        let start = read_u32(reader)? as usize;
+        let length = read_u32(reader)? as usize;
+        let words = start
+            .checked_add(length)
+            .and_then(|end| memory.mem_space.get_mut(start..end))
+            .ok_or(SnapshotError::InvalidFormat)?;
+
+        for word in words {
+            *word = read_i64(reader)?;
+        }
+    }
+
+    Ok(memory)
+}
+
+/// Finds the `(start, end)` ranges of non-zero words.
+fn non_zero_runs(words: &[i64]) -> Vec<(usize, usize)> {
+    let mut runs = vec![];
+    let mut start = None;
+
+    for (index, word) in words.iter().enumerate() {
+        match (start, *word == 0) {
+            (None, false) => start = Some(index),
+            (Some(s), true) => {
+                runs.push((s, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(s) = start {
+        runs.push((s, words.len()));
+    }
+
+    runs
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_i64<R: Read>(reader: &mut R) -> io::Result<i64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Register;
+
+    fn load(source: &str) -> Program {
+        Program::load(source.to_owned()).unwrap()
+    }
+
+    fn save(program: &Program, memory: &Memory) -> Vec<u8> {
+        let mut data = vec![];
+        program.save_snapshot(memory, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip_restores_state() {
+        let program = load(
+            "call f\nf: mov [3], 7\nmov [4], 8\nmov [100], 9\npush 5\nmod 7, 4\ncmp 1, 1\n\
+             mov eax, 42\nfld f2, -0.5\nvins v5, 3, -6\n",
+        );
+        let mut memory = program.initialize();
+        while program.step(&mut memory).unwrap() {}
+
+        let restored = program
+            .restore_snapshot(&mut &save(&program, &memory)[..])
+            .unwrap();
+
+        assert_eq!(restored.registers, memory.registers);
+        assert_eq!(restored.float_registers, memory.float_registers);
+        assert_eq!(restored.vector_registers, memory.vector_registers);
+        assert_eq!(restored.flags, memory.flags);
+        assert_eq!(restored.remainder, memory.remainder);
+        assert_eq!(restored.call_stack, memory.call_stack);
+        assert!(restored.mem_space == memory.mem_space);
+    }
+
+    #[test]
+    fn memory_is_sparse() {
+        let program = load("mov [3], 7\nmov [4], 8\nmov [100], 9\n");
+        let mut memory = program.initialize();
+        while program.step(&mut memory).unwrap() {}
+
+        assert_eq!(non_zero_runs(&memory.mem_space), vec![(3, 5), (100, 101)]);
+        let registers = 4 + 8 * NUM_REGISTERS + 4 + 8;
+        let float_registers = 4 + 8 * NUM_FLOAT_REGISTERS;
+        let vector_registers = 4 + 4 * NUM_LANES * NUM_VECTOR_REGISTERS;
+        assert_eq!(
+            save(&program, &memory).len(),
+            8 + 4 + 8 + registers + 4 + float_registers + vector_registers + 4 + 4 + 2 * 8 + 3 * 8
+        );
+    }
+
+    #[test]
+    fn resumed_program_continues() {
+        let program = load("mov eax, 1\nadd eax, 2\nadd eax, 3\n");
+        let mut memory = program.initialize();
+        program.step(&mut memory).unwrap();
+
+        let mut restored = program
+            .restore_snapshot(&mut &save(&program, &memory)[..])
+            .unwrap();
+        while program.step(&mut restored).unwrap() {}
+
+        assert_eq!(restored.registers[Register::Eax as usize], 6);
+    }
+
+    #[test]
+    fn round_trip_keeps_64_bit_words() {
+        let program = load("%bits 64\nmov eax, 0x123456789\nmov [2], -0x100000000\n");
+        let mut memory = program.initialize();
+        while program.step(&mut memory).unwrap() {}
+
+        let restored = program
+            .restore_snapshot(&mut &save(&program, &memory)[..])
+            .unwrap();
+        assert_eq!(restored.registers[Register::Eax as usize], 0x1_2345_6789);
+        assert_eq!(restored.mem_space[2], -0x1_0000_0000);
+    }
+
+    #[test]
+    fn rejects_other_program() {
+        let program = load("mov eax, 1\n");
+        let data = save(&program, &program.initialize());
+
+        assert!(matches!(
+            load("mov eax, 2\n").restore_snapshot(&mut &data[..]),
+            Err(SnapshotError::ProgramMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_word_size() {
+        let program = load("mov eax, 1\n");
+        let data = save(&program, &program.initialize());
+
+        assert!(matches!(
+            load("%bits 64\nmov eax, 1\n").restore_snapshot(&mut &data[..]),
+            Err(SnapshotError::ProgramMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_memory_larger_than_programs_have() {
+        let program = load("mov eax, 1\n");
+        let mut data = save(&program, &program.initialize());
+        // Memory is all zero, so the memory size is followed by no runs
+        let size = data.len() - 8;
+        data[size..size + 4].copy_from_slice(&(MEMORY_SIZE as u32 + 1).to_le_bytes());
+
+        assert!(matches!(
+            program.restore_snapshot(&mut &data[..]),
+            Err(SnapshotError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let program = load("mov eax, 1\n");
+        let mut data = save(&program, &program.initialize());
+
+        assert!(matches!(
+            program.restore_snapshot(&mut &data[..data.len() - 1]),
+            Err(SnapshotError::InvalidFormat)
+        ));
+        assert!(matches!(
+            program.restore_snapshot(&mut &b"not a snapshot"[..]),
+            Err(SnapshotError::InvalidFormat)
+        ));
+
+        data[8] = 2;
+        assert!(matches!(
+            program.restore_snapshot(&mut &data[..]),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+    }
+}
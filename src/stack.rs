@@ -0,0 +1,455 @@
+//! Static stack-depth analysis: follows the stack effects of `push`, `pop`,
+//! `pushf`, `popf`, `call` and `ret` along every path through each function,
+//! to find how deep the stack can get and where it is used inconsistently.
+//!
+//! Constant changes to `esp`, such as `sub esp, 4`, and restoring it from a
+//! frame pointer set with `mov ebp, esp` are followed too. Any other write to
+//! `esp`, and jumps or calls to computed addresses, make the depth of the
+//! function unknown.
+
+use crate::{
+    cfg::Function,
+    context::Program,
+    instruction::{jump_target, operands, Instruction, Register, Source, Target},
+    verifier::{Diagnostic, DiagnosticKind, Severity},
+};
+use std::convert::TryFrom;
+
+/// How much of the stack a function uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackUsage {
+    /// The instruction index of the entry.
+    pub entry: usize,
+    pub name: Option<String>,
+    /// The most words the function and those it calls have on the stack at
+    /// once, not counting the return address pushed by the call to it, or
+    /// `None` if that cannot be known statically, like for recursive
+    /// functions.
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackAnalysis {
+    /// The start of the program first, then the targets of calls in
+    /// instruction order.
+    pub functions: Vec<StackUsage>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The stack at an instruction, relative to the entry of its function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    depth: i64,
+    /// The depth `ebp` was set to with `mov ebp, esp`.
+    frame: Option<i64>,
+}
+
+/// What the analysis of a single function found, without its calls.
+struct Local {
+    /// The deepest the function itself gets, and where.
+    max_depth: (i64, usize),
+    /// The depth before each call with a constant target, and the target.
+    calls: Vec<(usize, i64, usize)>,
+    /// Whether the depth could not be followed everywhere.
+    unknown: bool,
+}
+
+impl Program {
+    /// Analyzes the stack usage of every function, for a stack of
+    /// `stack_size` words.
+    pub fn analyze_stack(self: &Program, stack_size: usize) -> StackAnalysis {
+        let graph = self.control_flow_graph();
+        let mut diagnostics = vec![];
+
+        let locals: Vec<Local> = graph
+            .functions
+            .iter()
+            .map(|function| {
+                let is_main = function.entry as i32 == self.start_instruction_index;
+                self.analyze_function(function.entry, is_main, &mut diagnostics)
+            })
+            .collect();
+
+        let mut max_depths = vec![Summary::Pending; locals.len()];
+        for function in 0..locals.len() {
+            summarize(function, &graph.functions, &locals, &mut max_depths);
+        }
+
+        let functions: Vec<StackUsage> = graph
+            .functions
+            .iter()
+            .zip(&max_depths)
+            .map(|(function, max_depth)| StackUsage {
+                entry: function.entry,
+                name: function.name.clone(),
+                max_depth: match max_depth {
+                    Summary::Done(Some((depth, _))) => usize::try_from(*depth).ok(),
+                    _ => None,
+                },
+            })
+            .collect();
+
+        // Only the start runs with an empty stack
+        if let Some(Summary::Done(Some((depth, pc)))) = graph
+            .functions
+            .first()
+            .filter(|f| f.entry as i32 == self.start_instruction_index)
+            .map(|_| &max_depths[0])
+        {
+            if *depth > stack_size as i64 {
+                diagnostics.push(self.diagnostic(
+                    Severity::Error,
+                    DiagnosticKind::StackOverflow(*depth),
+                    *pc,
+                ));
+            }
+        }
+        // Code shared by several functions is analyzed for each of them
+        diagnostics.sort_by_key(|d| d.instruction_index);
+        diagnostics.dedup();
+
+        StackAnalysis {
+            functions,
+            diagnostics,
+        }
+    }
+
+    fn analyze_function(
+        self: &Program,
+        entry: usize,
+        is_main: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Local {
+        let len = self.instructions.len();
+        let mut states: Vec<Option<State>> = vec![None; len];
+        let mut reported = vec![false; len];
+        let mut local = Local {
+            max_depth: (0, entry),
+            calls: vec![],
+            unknown: false,
+        };
+
+        let mut pending = vec![(
+            entry,
+            State {
+                depth: 0,
+                frame: None,
+            },
+        )];
+        while let Some((pc, state)) = pending.pop() {
+            if pc >= len {
+                continue;
+            }
+            match states[pc] {
+                Some(old) if old.depth != state.depth => {
+                    if !reported[pc] {
+                        reported[pc] = true;
+                        diagnostics.push(self.diagnostic(
+                            Severity::Warning,
+                            DiagnosticKind::InconsistentStackDepth(old.depth, state.depth),
+                            pc,
+                        ));
+                    }
+                    local.unknown = true;
+                    continue;
+                }
+                Some(old) if old.frame == state.frame || old.frame.is_none() => continue,
+                // Paths that disagree on the frame pointer leave it unknown
+                Some(_) => {
+                    states[pc] = Some(State {
+                        frame: None,
+                        ..state
+                    })
+                }
+                None => states[pc] = Some(state),
+            }
+            let state = states[pc].unwrap();
+
+            let instruction = self.instructions[pc];
+            let after = match effect(instruction, state) {
+                Some(after) => after,
+                None => {
+                    local.unknown = true;
+                    continue;
+                }
+            };
+            if after.depth > local.max_depth.0 {
+                local.max_depth = (after.depth, pc);
+            }
+            if after.depth < 0 && after.depth < state.depth && !reported[pc] {
+                reported[pc] = true;
+                diagnostics.push(self.diagnostic(
+                    Severity::Warning,
+                    DiagnosticKind::StackUnderflow,
+                    pc,
+                ));
+            }
+
+            let mut next = |target: i64| {
+                if let Ok(target) = usize::try_from(target) {
+                    pending.push((target, after));
+                }
+            };
+            match instruction {
+                Instruction::Jmp(Source::Value(target)) => next(target),
+                Instruction::Call(Source::Value(target)) => {
+                    if let Ok(target) = usize::try_from(target) {
+                        local.calls.push((pc, state.depth, target));
+                    }
+                    next(pc as i64 + 1);
+                }
+                Instruction::Ret => {
+                    if state.depth != 0 && !is_main && !reported[pc] {
+                        reported[pc] = true;
+                        diagnostics.push(self.diagnostic(
+                            Severity::Warning,
+                            DiagnosticKind::UnbalancedStack(state.depth),
+                            pc,
+                        ));
+                    }
+                }
+                Instruction::Jmp(_) | Instruction::Call(_) => local.unknown = true,
+                // Conditional jumps, and instructions that fall through
+                _ => {
+                    match jump_target(instruction) {
+                        Some(Source::Value(target)) => next(target),
+                        Some(_) => local.unknown = true,
+                        None => {}
+                    }
+                    next(pc as i64 + 1);
+                }
+            }
+        }
+
+        local
+    }
+
+    fn diagnostic(
+        self: &Program,
+        severity: Severity,
+        kind: DiagnosticKind,
+        pc: usize,
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            kind,
+            instruction_index: pc,
+            instruction: self.instructions[pc],
+            location: self.locations.get(pc).cloned(),
+        }
+    }
+}
+
+/// The stack after an instruction, or `None` if it cannot be known. A `call`
+/// is assumed to leave the stack as it was, and `ret` to leave it alone.
+fn effect(instruction: Instruction, state: State) -> Option<State> {
+    let esp = Target::Register(Register::Esp);
+    let ebp = Target::Register(Register::Ebp);
+    let depth = |depth| {
+        Some(State {
+            depth,
+            frame: state.frame,
+        })
+    };
+
+    match instruction {
+        Instruction::Push(_) | Instruction::Pushf => depth(state.depth + 1),
+        Instruction::Pop(target) if target == esp => None,
+        Instruction::Pop(target) if target == ebp => Some(State {
+            depth: state.depth - 1,
+            frame: None,
+        }),
+        Instruction::Pop(_) | Instruction::Popf => depth(state.depth - 1),
+        Instruction::Mov(target, Source::Register(Register::Esp)) if target == ebp => Some(State {
+            depth: state.depth,
+            frame: Some(state.depth),
+        }),
+        Instruction::Mov(target, Source::Register(Register::Ebp)) if target == esp => {
+            state.frame.map(|frame| State {
+                depth: frame,
+                frame: state.frame,
+            })
+        }
+        // Adjustments too large to be meaningful leave the depth unknown
+        Instruction::Add(target, Source::Value(n)) if target == esp => {
+            depth(state.depth.checked_sub(n)?)
+        }
+        Instruction::Sub(target, Source::Value(n)) if target == esp => {
+            depth(state.depth.checked_add(n)?)
+        }
+        Instruction::Inc(target) if target == esp => depth(state.depth - 1),
+        Instruction::Dec(target) if target == esp => depth(state.depth + 1),
+        Instruction::Lea(target, address)
+            if target == esp && address.base == Some(Register::Esp) && address.index.is_none() =>
+        {
+            depth(state.depth - i64::from(address.displacement))
+        }
+        // The second target of `xchg` is not the one `operands` gives
+        Instruction::Xchg(_, target) if target == esp => None,
+        Instruction::Xchg(_, target) if target == ebp => Some(State {
+            depth: state.depth,
+            frame: None,
+        }),
+        _ => match operands(instruction).1 {
+            Some((target, _)) if target == esp => None,
+            Some((target, _)) if target == ebp => Some(State {
+                depth: state.depth,
+                frame: None,
+            }),
+            _ => Some(state),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Summary {
+    Pending,
+    InProgress,
+    /// The maximum depth and the instruction it is reached at, or `None`
+    /// if it is unknown.
+    Done(Option<(i64, usize)>),
+}
+
+/// The maximum depth of a function including its calls, found depth first
+/// through the calls so that recursion is noticed.
+fn summarize(
+    function: usize,
+    functions: &[Function],
+    locals: &[Local],
+    summaries: &mut [Summary],
+) -> Option<(i64, usize)> {
+    match summaries[function] {
+        Summary::Done(summary) => return summary,
+        Summary::InProgress => return None,
+        Summary::Pending => {}
+    }
+    summaries[function] = Summary::InProgress;
+
+    let local = &locals[function];
+    let mut max_depth = if local.unknown {
+        None
+    } else {
+        Some(local.max_depth)
+    };
+    for &(pc, depth, target) in &local.calls {
+        let callee = functions.iter().position(|f| f.entry == target);
+        let callee = callee.and_then(|callee| summarize(callee, functions, locals, summaries));
+        max_depth = match (max_depth, callee) {
+            (Some(max), Some((callee, _))) => {
+                let depth = depth + 1 + callee;
+                Some(if depth > max.0 { (depth, pc) } else { max })
+            }
+            _ => None,
+        };
+    }
+
+    summaries[function] = Summary::Done(max_depth);
+    max_depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::STACK_SIZE;
+
+    /// The name and maximum depth of each function.
+    type Depths = Vec<(Option<String>, Option<usize>)>;
+
+    fn analyze(source: &str) -> (Depths, Vec<(DiagnosticKind, usize)>) {
+        let analysis = Program::load(source.to_owned())
+            .unwrap()
+            .analyze_stack(STACK_SIZE);
+        (
+            analysis
+                .functions
+                .into_iter()
+                .map(|f| (f.name, f.max_depth))
+                .collect(),
+            analysis
+                .diagnostics
+                .into_iter()
+                .map(|d| (d.kind, d.instruction_index))
+                .collect(),
+        )
+    }
+
+    fn name(name: &str) -> Option<String> {
+        Some(name.to_owned())
+    }
+
+    #[test]
+    fn depths_include_calls() {
+        let (functions, diagnostics) = analyze(
+            "f: push eax\npushf\ncall g\npopf\npop eax\nret\n\
+             g: push ebp\nmov ebp, esp\nsub esp, 3\nmov esp, ebp\npop ebp\nret\n\
+             start: push 1\ncall g\ncall f\npop eax",
+        );
+
+        assert_eq!(
+            functions,
+            &[
+                (name("start"), Some(9)),
+                (name("f"), Some(7)),
+                (name("g"), Some(4))
+            ]
+        );
+        assert_eq!(diagnostics, &[]);
+    }
+
+    #[test]
+    fn recursion_and_computed_jumps_are_unknown() {
+        let (functions, diagnostics) = analyze(
+            "f: cmp eax, 0\nje done\ndec eax\ncall f\ndone: ret\n\
+             g: jmp eax\nstart: call f\ncall g",
+        );
+
+        assert_eq!(
+            functions,
+            &[(name("start"), None), (name("f"), None), (name("g"), None)]
+        );
+        assert_eq!(diagnostics, &[]);
+    }
+
+    #[test]
+    fn unbalanced_functions() {
+        let (functions, diagnostics) = analyze(
+            "f: push 1\ncmp eax, 0\nje skip\npop eax\nskip: ret\n\
+             g: pop eax\npop eax\nret\nstart: call f\ncall g",
+        );
+
+        assert_eq!(
+            functions,
+            &[
+                (name("start"), None),
+                (name("f"), None),
+                (name("g"), Some(0))
+            ]
+        );
+        assert_eq!(
+            diagnostics,
+            &[
+                (DiagnosticKind::InconsistentStackDepth(0, 1), 4),
+                (DiagnosticKind::StackUnderflow, 5),
+                (DiagnosticKind::StackUnderflow, 6),
+                (DiagnosticKind::UnbalancedStack(-2), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let analysis =
+            Program::load("sub esp, 10\ncall f\njmp end\nf: push 1\npop eax\nret\nend:".to_owned())
+                .unwrap()
+                .analyze_stack(11);
+
+        assert_eq!(analysis.functions[0].max_depth, Some(12));
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(analysis.diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            analysis.diagnostics[0].kind,
+            DiagnosticKind::StackOverflow(12)
+        );
+        assert_eq!(analysis.diagnostics[0].instruction_index, 1);
+    }
+}
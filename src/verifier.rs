@@ -0,0 +1,345 @@
+//! Finds runtime errors that can be detected without running a program.
+//!
+//! Only operands that are constants are checked, along with the stack
+//! usage found by `Program::analyze_stack`, so a program without diagnostics
+//! can still fail. Problems in instructions that can be executed
+//! are errors, and problems in instructions that can never be reached from
+//! the start of the program are warnings.
+
+use crate::{
+    cfg::{ControlFlowGraph, Destination},
+    context::{Program, MEMORY_SIZE, STACK_SIZE},
+    instruction::{float_address, jump_target, operands, Instruction, Source, Target},
+    preprocessor::SourceLocation,
+};
+use std::{convert::TryFrom, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    /// A jump or call to a constant instruction index outside the program.
+    InstructionOutOfRange(i64),
+    /// A constant address outside memory.
+    DataAddressOutOfRange(i32),
+    /// A `div` or `mod` by a literal zero.
+    DivisionByZero,
+    /// A `ret` that can be reached from the start of the program without
+    /// going through a `call`. It is only a warning, as the return address
+    /// could have been pushed by hand.
+    ReturnWithoutCall,
+    /// A `pop` or `popf`, or a change to `esp`, that takes more off the
+    /// stack than the function put on it.
+    StackUnderflow,
+    /// A `ret` with the given number of words left on the stack by its
+    /// function, so that it does not return to its caller.
+    UnbalancedStack(i64),
+    /// An instruction reached with different stack depths along different
+    /// paths.
+    InconsistentStackDepth(i64, i64),
+    /// A path from the start of the program that needs the given number of
+    /// words of stack, more than there are.
+    StackOverflow(i64),
+}
+
+/// A problem found by `Program::verify`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    pub instruction_index: usize,
+    pub instruction: Instruction,
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiagnosticKind::InstructionOutOfRange(index) => {
+                write!(f, "instruction index {} is out of range", index)
+            }
+            DiagnosticKind::DataAddressOutOfRange(address) => {
+                write!(f, "data address {} is out of range", address)
+            }
+            DiagnosticKind::DivisionByZero => write!(f, "division by zero"),
+            DiagnosticKind::ReturnWithoutCall => write!(f, "return without a call"),
+            DiagnosticKind::StackUnderflow => write!(f, "stack underflow"),
+            DiagnosticKind::UnbalancedStack(depth) => {
+                write!(f, "return with a stack depth of {} words", depth)
+            }
+            DiagnosticKind::InconsistentStackDepth(depth1, depth2) => write!(
+                f,
+                "stack depth is {} or {} words depending on the path",
+                depth1, depth2
+            ),
+            DiagnosticKind::StackOverflow(depth) => {
+                write!(f, "stack overflow at a depth of {} words", depth)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(
+            f,
+            "{}: {}\n  at instruction {}: {}",
+            self.severity, self.kind, self.instruction_index, self.instruction
+        )
+    }
+}
+
+impl Diagnostic {
+    pub fn is_error(self: &Diagnostic) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Program {
+    /// The problems found in the program, in instruction order.
+    pub fn verify(self: &Program) -> Vec<Diagnostic> {
+        let graph = self.control_flow_graph();
+        let reachable = reachable(self, &graph);
+        let len = self.instructions.len();
+
+        let mut diagnostics = vec![];
+        for (pc, &instruction) in self.instructions.iter().enumerate() {
+            let mut kinds = vec![];
+
+            let (sources, target) = operands(instruction);
+            let addresses = sources
+                .iter()
+                .filter_map(|source| match source {
+                    Some(Source::Address(addr)) => Some(*addr),
+                    _ => None,
+                })
+                .chain(match target {
+                    Some((Target::Address(addr), _)) => Some(addr),
+                    _ => None,
+                })
+                // The second word of a float, when only the first is in range
+                .chain(
+                    float_address(instruction)
+                        .map(|addr| addr.wrapping_add(1))
+                        .filter(|&addr| usize::try_from(addr) == Ok(MEMORY_SIZE)),
+                );
+            for addr in addresses {
+                if !usize::try_from(addr).is_ok_and(|addr| addr < MEMORY_SIZE) {
+                    kinds.push(DiagnosticKind::DataAddressOutOfRange(addr));
+                }
+            }
+
+            if let Some(Source::Value(target)) = jump_target(instruction) {
+                if !usize::try_from(target).is_ok_and(|target| target <= len) {
+                    kinds.push(DiagnosticKind::InstructionOutOfRange(target));
+                }
+            }
+
+            match instruction {
+                Instruction::Div(_, Source::Value(0)) | Instruction::Mod(_, Source::Value(0)) => {
+                    kinds.push(DiagnosticKind::DivisionByZero)
+                }
+                _ => {}
+            }
+
+            let severity = if reachable[pc] {
+                Severity::Error
+            } else {
+                Severity::Warning
+            };
+            diagnostics.extend(kinds.into_iter().map(|kind| Diagnostic {
+                severity,
+                kind,
+                instruction_index: pc,
+                instruction,
+                location: self.locations.get(pc).cloned(),
+            }));
+        }
+
+        // Blocks of the function at the start are run without a call
+        let main = graph.functions.first();
+        if let Some(main) = main.filter(|f| f.entry as i32 == self.start_instruction_index) {
+            for &block in &main.blocks {
+                let pc = graph.blocks[block].end - 1;
+                if self.instructions[pc] == Instruction::Ret {
+                    diagnostics.push(Diagnostic {
+                        severity: Severity::Warning,
+                        kind: DiagnosticKind::ReturnWithoutCall,
+                        instruction_index: pc,
+                        instruction: Instruction::Ret,
+                        location: self.locations.get(pc).cloned(),
+                    });
+                }
+            }
+        }
+        diagnostics.extend(self.analyze_stack(STACK_SIZE).diagnostics);
+        diagnostics.sort_by_key(|d| d.instruction_index);
+
+        diagnostics
+    }
+}
+
+/// Whether each instruction can be reached from the start of the program.
+/// Everything is considered reachable once a jump or return to a computed
+/// address is.
+fn reachable(program: &Program, graph: &ControlFlowGraph) -> Vec<bool> {
+    let mut reached = vec![false; graph.blocks.len()];
+    let mut pending: Vec<usize> = usize::try_from(program.start_instruction_index)
+        .ok()
+        .and_then(|start| graph.block_of(start))
+        .into_iter()
+        .collect();
+
+    while let Some(block) = pending.pop() {
+        if reached[block] {
+            continue;
+        }
+        reached[block] = true;
+
+        for edge in &graph.blocks[block].edges {
+            match edge.destination {
+                Destination::Block(next) => pending.push(next),
+                Destination::Unknown => {
+                    return vec![true; program.instructions.len()];
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut instructions = vec![false; program.instructions.len()];
+    for (block, reached) in graph.blocks.iter().zip(reached) {
+        for instruction in &mut instructions[block.start..block.end] {
+            *instruction = reached;
+        }
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(source: &str) -> Vec<(Severity, DiagnosticKind, usize)> {
+        Program::load(source.to_owned())
+            .unwrap()
+            .verify()
+            .into_iter()
+            .map(|d| (d.severity, d.kind, d.instruction_index))
+            .collect()
+    }
+
+    #[test]
+    fn valid_programs_have_no_diagnostics() {
+        assert_eq!(
+            verify(
+                "f: mov eax, [0]\ndiv eax, 2\nret\nstart: call f\njmp end\nmov [16777215], 1\nend:"
+            ),
+            &[]
+        );
+    }
+
+    #[test]
+    fn both_words_of_floats_are_checked() {
+        assert_eq!(
+            verify("fld f0, [16777214]\nfst [16777215], f0\nfadd f1, [16777216]"),
+            &[
+                (
+                    Severity::Error,
+                    DiagnosticKind::DataAddressOutOfRange(16777216),
+                    1
+                ),
+                (
+                    Severity::Error,
+                    DiagnosticKind::DataAddressOutOfRange(16777216),
+                    2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_operands_are_checked() {
+        assert_eq!(
+            verify("jg 5\njl -1\nmov [16777216], eax\nprn [300000000]\ndiv eax, 0\nmod 1, 0"),
+            &[
+                (
+                    Severity::Error,
+                    DiagnosticKind::InstructionOutOfRange(-1),
+                    1
+                ),
+                (
+                    Severity::Error,
+                    DiagnosticKind::DataAddressOutOfRange(16777216),
+                    2
+                ),
+                (
+                    Severity::Error,
+                    DiagnosticKind::DataAddressOutOfRange(300000000),
+                    3
+                ),
+                (Severity::Error, DiagnosticKind::DivisionByZero, 4),
+                (Severity::Error, DiagnosticKind::DivisionByZero, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_problems_are_warnings() {
+        assert_eq!(
+            verify("jmp end\ndiv eax, 0\njmp 100\nend:"),
+            &[
+                (Severity::Warning, DiagnosticKind::DivisionByZero, 1),
+                (
+                    Severity::Warning,
+                    DiagnosticKind::InstructionOutOfRange(100),
+                    2
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn computed_jumps_make_everything_reachable() {
+        assert_eq!(
+            verify("mov eax, 3\njmp eax\ndiv eax, 0\nprn eax"),
+            &[(Severity::Error, DiagnosticKind::DivisionByZero, 2)]
+        );
+    }
+
+    #[test]
+    fn returns_without_calls_are_warnings() {
+        assert_eq!(
+            verify("f: ret\nstart: push back\nret\nback: call f"),
+            &[(Severity::Warning, DiagnosticKind::ReturnWithoutCall, 2)]
+        );
+    }
+
+    #[test]
+    fn diagnostics_show_the_location() {
+        let program = Program::load("inc eax\n\ndiv eax, 0".to_owned()).unwrap();
+        let diagnostics = program.verify();
+
+        assert!(diagnostics[0].is_error());
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "line 3: error: division by zero\n  at instruction 1: div eax, 0"
+        );
+    }
+}
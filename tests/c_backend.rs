@@ -0,0 +1,160 @@
+//! Translates programs to C, builds them with the system C compiler and
+//! checks that they behave like the interpreter.
+
+mod common;
+
+use common::{load, programs, VENDOR_PROGRAMS};
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex},
+};
+use tinyvm::{context::Program, decoded::DecodedProgram};
+
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Programs that run for longer than this are too slow to interpret in a
+/// debug build and are skipped.
+const STEP_LIMIT: u64 = 50_000_000;
+
+/// The output and exit status of running the program with `tvmi`, or `None`
+/// if it does not finish within `STEP_LIMIT` steps.
+fn interpret(program: &Program) -> Option<(Vec<u8>, i32)> {
+    let mut memory = program.initialize();
+    let output = SharedOutput::default();
+    memory.output = Box::new(output.clone());
+
+    let result = DecodedProgram::new(program).run_steps(&mut memory, STEP_LIMIT);
+    let mut output = output.0.lock().unwrap().clone();
+    match result {
+        Ok(true) => None,
+        Ok(false) => Some((output, 0)),
+        Err(e) => {
+            writeln!(output, "Error executing program: {}", e).unwrap();
+            Some((output, 1))
+        }
+    }
+}
+
+fn compile_and_run(c: &str, directory: &Path) -> (Vec<u8>, i32) {
+    let source = directory.join("program.c");
+    let executable = directory.join("program");
+    std::fs::write(&source, c).unwrap();
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let output = Command::new(&compiler)
+        .args(["-std=c99", "-O2", "-Wall", "-Wextra", "-o"])
+        .arg(&executable)
+        .arg(&source)
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run {}: {}", compiler, e));
+    assert!(
+        output.status.success() && output.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = Command::new(&executable).output().unwrap();
+    (output.stdout, output.status.code().unwrap())
+}
+
+fn assert_same_behavior(name: &str, program: &Program) {
+    let c = match program.to_c() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}: skipped, {}", name, e);
+            return;
+        }
+    };
+
+    let directory = tempfile::tempdir().unwrap();
+    let (expected_output, expected_status) = match interpret(program) {
+        Some(expected) => expected,
+        None => {
+            eprintln!("{}: skipped, it runs for too long", name);
+            return;
+        }
+    };
+    let (output, status) = compile_and_run(&c, directory.path());
+
+    assert!(
+        output == expected_output,
+        "{}: the output differs:\n{}\nexpected:\n{}",
+        name,
+        String::from_utf8_lossy(&output[..output.len().min(4096)]),
+        String::from_utf8_lossy(&expected_output[..expected_output.len().min(4096)])
+    );
+    assert_eq!(status, expected_status, "{}", name);
+}
+
+#[test]
+fn vendor_programs() {
+    let paths = programs(Path::new(VENDOR_PROGRAMS));
+    assert!(paths.len() >= 15, "vendor programs not found");
+
+    for path in paths {
+        assert_same_behavior(&path.display().to_string(), &load(&path));
+    }
+}
+
+#[test]
+fn test_programs() {
+    for path in programs(Path::new("tests")) {
+        assert_same_behavior(&path.display().to_string(), &load(&path));
+    }
+}
+
+#[test]
+fn edge_cases() {
+    for source in &[
+        "mov eax, 0x7fffffff\nadd eax, 1\nmul eax, eax\nmov ebx, -2147483648\ndiv ebx, -1\nprn ebx",
+        "mov eax, -7\nshr eax, 1\nprn eax\nshl eax, 31\nprn eax\nmod -7, 2\nrem eax\nprn eax",
+        "mov eax, 5\nmod eax, 0",
+        "cmp 1, 1\nje 10",
+        "mov eax, -1\njmp eax",
+        "call 2\njmp 4\nret",
+        "mov eax, eip\nprn eax\nadd eip, 1\nprn 1\nprn 2\nmov eip, 100",
+        "push eip\npop eip\nprn 3\npush esp\npop eax\nprn eax\npushf\npopf",
+        "mov eax, 1\ncall f\nprn eax\njmp end\nf: int\nret\nend:",
+        "start: call f\nprn 2\njmp end\nf: mov [7], 1\nprn [7]\nmov eax, [100000000]\nend:",
+        "mov ecx, 524288\nloop: push ecx\ndec ecx\njne loop\npop eax",
+        "mov eax, -1\nsth 67108862, eax\nldsh ebx, 67108862\nprn ebx\nldh ebx, 67108863",
+        "stb -1, 1",
+        "mov [3], 0x1234\nldsb eax, 12\nprn eax\nldb [100000000], 12",
+        "fld f0, 2\nfsqrt f0\nfprn f0\nfld f1, 0.1\nfadd f1, 0.2\nfprn f1\nfdiv f1, 0\nfprn f1\n\
+         fneg f1\nfprn f1\nfsub f1, f1\nfprn f1\nftoi eax, f1\nprn eax\nfld f2, -0.0\nfprn f2\n\
+         fabs f2\nfprn f2\nfld f3, 1e300\nfmul f3, 1e10\nfprn f3\nfld f4, 5e-324\nfprn f4\n\
+         fsqrt f4\nfprn f4\nfld f5, -1\nfsqrt f5\nfprn f5\nfld f6, 1.5e300\nfprn f6\nfld f7, -2.5e-8\n\
+         fprn f7\nfst [3], f7\nprn [3]\nprn [4]\nitof f0, -7\nfdiv f0, 2\nftoi eax, f0\nprn eax\n\
+         fld f0, 3e9\nftoi eax, f0\nprn eax\nfcmp f0, [3]\npushf\npop eax\nprn eax",
+        "mov ecx, 1\nl: itof f0, ecx\nfdiv f0, 7\nfsqrt f0\nfprn f0\nfmul f0, f0\nfprn f0\n\
+         fld f1, 1\nfdiv f1, f0\nfprn f1\ninc ecx\ncmp ecx, 3000\njl l",
+        "fld f0, 1\nfst [16777215], f0",
+        "vins v0, 3, -1\nvst 16777212, v0\nvld v1, 16777212\nvsum eax, v1\nprn eax\nvld v2, 16777213",
+        "mov esi, -1\nvst esi, v0",
+        "mov [5], 3\nmov eax, 3\ncas eax, 5, 9\nprn eax\npushf\npop eax\nprn eax\ncas eax, 5, 1\n\
+         prn eax\nprn [5]\nmov eax, 0x7fffffff\nxadd eax, 5\nprn eax\nprn [5]\npushf\npop eax\nprn eax\n\
+         fence\nyield\nxadd eax, 16777216",
+        "mov eax, 1\njoin eax",
+        "prn 1\nsend 0, 1",
+        "recv [100000000], 0",
+        "",
+    ] {
+        let program =
+            Program::load_with_file_name(source.to_string(), Some("edge \"case\".vm")).unwrap();
+        assert_same_behavior(source, &program);
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the integration tests that run the vendor programs.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tinyvm::context::Program;
+
+pub const VENDOR_PROGRAMS: &str = "vendor/tinyvm/programs/tinyvm";
+
+/// The `.vm` files in `directory` and its subdirectories, sorted.
+pub fn programs(directory: &Path) -> Vec<PathBuf> {
+    fn find(directory: &Path, programs: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                find(&path, programs);
+            } else if path.extension().is_some_and(|e| e == "vm") {
+                programs.push(path);
+            }
+        }
+    }
+
+    let mut programs = vec![];
+    find(directory, &mut programs);
+    programs.sort();
+    programs
+}
+
+/// Loads a program, resolving includes relative to its directory as `tvmi`
+/// would when run from there.
+pub fn load(path: &Path) -> Program {
+    let directory = path.parent().unwrap();
+    let source: String = fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| match line.strip_prefix("%include ") {
+            Some(file) => format!("%include {}\n", directory.join(file.trim()).display()),
+            None => format!("{}\n", line),
+        })
+        .collect();
+
+    Program::load_with_file_name(source, path.to_str())
+        .unwrap_or_else(|e| panic!("Failed to load {}: {:?}", path.display(), e))
+}
@@ -0,0 +1,191 @@
+//! Runs every vendor program with `Program::step` and with the other
+//! engines, and checks that they stay in the same state.
+
+mod common;
+
+use common::{load, programs, VENDOR_PROGRAMS};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tinyvm::{
+    context::{ExecutionError, Memory, Program},
+    decoded::DecodedProgram,
+};
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use tinyvm::jit::JitProgram;
+
+/// Enough for most vendor programs to finish in a debug build; the others are
+/// compared up to here.
+const STEP_LIMIT: u64 = 4_000_000;
+const CHECKPOINT: u64 = 1_000_000;
+
+/// Hashes the output instead of keeping it, since some programs print a lot.
+#[derive(Clone, Default)]
+struct OutputHash(Arc<Mutex<(DefaultHasher, usize)>>);
+
+impl OutputHash {
+    fn get(self: &OutputHash) -> (u64, usize) {
+        let output = self.0.lock().unwrap();
+        (output.0.finish(), output.1)
+    }
+}
+
+impl Write for OutputHash {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.0.lock().unwrap();
+        output.0.write(buf);
+        output.1 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn initialize(program: &Program) -> (Memory, OutputHash) {
+    let mut memory = program.initialize();
+    let output = OutputHash::default();
+    memory.output = Box::new(output.clone());
+    (memory, output)
+}
+
+/// Runs `program` with `step` and with an engine's `run_steps` for at most
+/// `STEP_LIMIT` steps, and compares their state every `CHECKPOINT` steps.
+fn assert_same_execution<F>(name: &str, program: &Program, strict_returns: bool, run_steps: F)
+where
+    F: Fn(&mut Memory, u64) -> Result<bool, ExecutionError>,
+{
+    let (mut stepped, stepped_output) = initialize(program);
+    stepped.strict_returns = strict_returns;
+    let (mut engine, engine_output) = initialize(program);
+    engine.strict_returns = strict_returns;
+
+    let mut steps = 0;
+    while steps < STEP_LIMIT {
+        let mut step_result: Result<bool, ExecutionError> = Ok(true);
+        for _ in 0..CHECKPOINT {
+            step_result = program.step(&mut stepped);
+            if step_result != Ok(true) {
+                break;
+            }
+        }
+        let engine_result = run_steps(&mut engine, CHECKPOINT);
+        steps += CHECKPOINT;
+
+        let name = format!("{} after {} steps", name, steps);
+        assert_eq!(engine_result, step_result, "{}", name);
+        assert_eq!(engine_output.get(), stepped_output.get(), "{}", name);
+        assert_eq!(engine.registers, stepped.registers, "{}", name);
+        assert_eq!(engine.flags, stepped.flags, "{}", name);
+        assert_eq!(engine.remainder, stepped.remainder, "{}", name);
+        assert_eq!(
+            engine.float_registers.map(f64::to_bits),
+            stepped.float_registers.map(f64::to_bits),
+            "{}",
+            name
+        );
+        assert_eq!(
+            engine.vector_registers, stepped.vector_registers,
+            "{}",
+            name
+        );
+        assert_eq!(engine.call_stack, stepped.call_stack, "{}", name);
+        assert!(engine.mem_space == stepped.mem_space, "{}", name);
+
+        if step_result != Ok(true) {
+            break;
+        }
+    }
+}
+
+fn assert_same_executions(name: &str, program: &Program) {
+    for strict_returns in [false, true] {
+        let decoded = DecodedProgram::new(program);
+        assert_same_execution(name, program, strict_returns, |memory, steps| {
+            decoded.run_steps(memory, steps)
+        });
+
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        {
+            let jit = JitProgram::new(program).unwrap();
+            assert_same_execution(name, program, strict_returns, |memory, steps| {
+                jit.run_steps(memory, steps)
+            });
+        }
+    }
+}
+
+#[test]
+fn vendor_programs() {
+    let paths = programs(Path::new(VENDOR_PROGRAMS));
+    assert!(paths.len() >= 15, "vendor programs not found");
+
+    for path in paths {
+        let program = load(&path);
+        let name = path.display().to_string();
+        assert_same_executions(&name, &program);
+    }
+}
+
+#[test]
+fn edge_cases() {
+    for source in &[
+        "mov eax, 0x7fffffff\nadd eax, 1\nmul eax, eax\nmov ebx, -2147483648\ndiv ebx, -1\nprn ebx",
+        "mov eax, 5\nmod eax, 0",
+        "div eax, [3]",
+        "cmp 1, 1\nje 10",
+        "mov eax, -1\njmp eax",
+        "call 2\nret\nmov esp, 0\nret",
+        "mov eax, eip\nprn eax\nadd eip, 1\nprn 1\nprn 2\nmov eip, 100",
+        "push eip\npop eip\nprn 3",
+        "mov eax, 1\ncall 3\nprn eax\nint\nret",
+        "mov ebp, 3\ncall ebp\njmp 5\nmov [4], 1\nret",
+        "mov ecx, 524288\nloop: push ecx\ndec ecx\njne loop\npop eax",
+        // Carry, overflow and sign, and the unsigned jumps
+        "mov eax, -2147483648\nsub eax, 1\npushf\npop ebx\nprn ebx\nneg eax\nmov [0], -2147483648\n\
+         neg [0]\njs s\nprn 1\ns: cmp 0, -1\nja a\nprn 2\na: jb b\nprn 3\nb: jo c\nprn 4\n\
+         c: test eax, eax\njns d\nprn 5\nd: inc eax\ndec eax\npushf\npop ecx\njae e\njbe e\ne:",
+        "mov eax, 0x7fffffff\nadd eax, 1\njno n\nmov ebx, -1\nadd ebx, 1\npushf\npop ecx\nn:",
+        // Shifts and rotates by 0, by the word size and beyond it
+        "mov eax, -1\nsar eax, 31\nrol eax, 33\nror eax, 0\nmov ebx, -2147483647\nrol ebx, 1\n\
+         pushf\npop ecx\nshl ebx, 32\nshr ebx, 40\nmov [0], -100\nsar [0], 5\nror [0], 31",
+        "mov eax, 1\nmov ecx, 63\nshl eax, ecx\nsar eax, ecx\nrol eax, ecx",
+        // The other integer instructions of x86
+        "mov ebx, 100\nmov ecx, 3\nlea eax, [ebx+ecx*4-8]\nxchg eax, [2]\nxchg eax, ebx\n\
+         cmp 1, 2\ncmovl edx, 7\ncmovg esi, 9\ncmova edi, 1\ncmovb edi, 2\npopcnt eax, -1\n\
+         clz ebx, 0\nctz ecx, 0\nbswap edx\ntest edx, 0\npushf\npop esi",
+        "mov ecx, -1\nlea eax, [ecx*2]\nxchg eax, [0x7fffffff]",
+        // Infinities and NaN
+        "fld f0, 2.5\nfdiv f0, 0\nfld f1, 0\nfdiv f1, f1\nfcmp f1, f1\npushf\npop eax\n\
+         fld f2, -7.9\nftoi [0], f2\nfsqrt f2\nfst [1], f2\nfprn f2\nitof f3, -2147483648\n\
+         fneg f3\nfabs f3\nfcmp f3, [0]\nftoi edx, f1",
+        "fld f0, 1e300\nfmul f0, f0\nftoi eax, f0",
+        // 64-bit words
+        "%bits 64\nmov eax, 0x7fffffffffffffff\nadd eax, 1\npushf\npop ecx\nmov ebx, -1\n\
+         shr ebx, 1\nrol ebx, 65\nmul ebx, ebx\nmov [0], eax\nldsb edx, 7\ndiv eax, -1",
+        // Bytes and halfwords, within and across words and out of range
+        "mov [0], -1\nldb eax, 3\nldsb ebx, 3\nldsh ecx, 1\nsth 7, 0x12345\nldh edx, 7\n\
+         ldsh esi, 7\nstb 2, 256\nstb -1, 1",
+        "ldh eax, 0x7fffffff",
+        // Lanes that wrap around, and vectors out of range
+        "vins v0, 0, 0x7fffffff\nvins v0, 3, -1\nvmul v0, v0\nvcmpgt v1, v0\nvsum eax, v0\n\
+         vst 0, v0\nvld v2, 0\nvext ebx, v2, 3\nvmin v2, v1\nvmax v3, v2\nvcmpeq v3, v1\n\
+         vsub v3, v0\nvadd v3, v3\nvld v4, -1",
+        // Atomic instructions, and those that need other cores or channels
+        "mov [0], 5\ncas eax, 0, 9\nmov eax, 5\ncas eax, 0, 9\npushf\npop ebx\nxadd eax, 0\n\
+         fence\nyield\nprn [0]\nspawn ecx, 0, 100",
+        "xadd eax, -1",
+        "join 0",
+        "send 0, 1",
+        "recv eax, 0",
+    ] {
+        let program = Program::load(source.to_string()).unwrap();
+        assert_same_executions(source, &program);
+    }
+}
@@ -1,17 +1,41 @@
 use std::{
+    fmt::Debug,
     io::{stderr, stdout, Write},
     process::Command,
+    str::FromStr,
 };
 
+/// Runs `tvmi` with the same features as the tests.
+fn tvmi() -> Command {
+    let mut command = Command::new("cargo");
+    command.args(["run", "--example", "tvmi"]);
+    if cfg!(feature = "jit") {
+        command.args(["--features", "jit"]);
+    }
+    command.arg("--");
+    command
+}
+
 fn run(program: &str, expected_output: &[i32]) {
-    let output = Command::new("cargo")
-        .args(&["run", "--example", "tvmi", "--", program])
+    run_with_args(&[], program, expected_output);
+}
+
+/// Runs `program` and compares the lines it prints, parsed as `T`, with
+/// `expected_output`.
+fn run_with_args<T>(args: &[&str], program: &str, expected_output: &[T])
+where
+    T: FromStr + PartialEq + Debug,
+    T::Err: Debug,
+{
+    let output = tvmi()
+        .args(args)
+        .arg(program)
         .output()
-        .expect(&format!("Failed to execute {}", program));
+        .unwrap_or_else(|_| panic!("Failed to execute {}", program));
 
     if !output.status.success() {
-        stdout().write(&output.stdout).unwrap();
-        stderr().write(&output.stderr).unwrap();
+        stdout().write_all(&output.stdout).unwrap();
+        stderr().write_all(&output.stderr).unwrap();
         panic!(
             "Execution of {} resulted in status {}",
             program,
@@ -25,10 +49,10 @@ fn run(program: &str, expected_output: &[i32]) {
 
     let result: &str = &result;
 
-    let actual_output: Vec<i32> = result
+    let actual_output: Vec<T> = result
         .split("\n")
-        .filter(|s| s.len() > 0)
-        .map(|s| s.parse::<i32>().unwrap())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<T>().unwrap())
         .collect();
 
     assert_eq!(actual_output, expected_output);
@@ -53,17 +77,126 @@ fn fact() {
     );
 }
 
+/// The output of tests/instructions.vm, which the options of `tvmi` do not
+/// change. It prints floats as well as integers.
+const INSTRUCTIONS_OUTPUT: &[f64] = &[
+    1.0,
+    2.0,
+    1.0,
+    12.0,
+    2.0,
+    20.0,
+    22.0,
+    4.0,
+    2.0,
+    7.0,
+    1.0,
+    12.0,
+    3.0,
+    2.0,
+    -5.0,
+    59.0,
+    4.0,
+    63.0,
+    20.0,
+    6.0,
+    2.0,
+    10.0,
+    11.0,
+    100.0,
+    102.0,
+    200.0,
+    202.0,
+    300.0,
+    301.0,
+    303.0,
+    401.0,
+    403.0,
+    500.0,
+    502.0,
+    503.0,
+    602.0,
+    603.0,
+    5.0,
+    30.0,
+    1.0,
+    12.0,
+    16.0,
+    2.0,
+    700.0,
+    702.0,
+    800.0,
+    802.0,
+    900.0,
+    902.0,
+    1000.0,
+    1002.0,
+    1101.0,
+    1200.0,
+    1202.0,
+    1301.0,
+    1400.0,
+    1402.0,
+    -5.0,
+    12.0,
+    1.0,
+    2147483644.0,
+    -4.0,
+    8.0,
+    3.0,
+    6.0,
+    -1073741824.0,
+    12.0,
+    2.0,
+    1.0,
+    3.0,
+    2.0,
+    104.0,
+    6.0,
+    113.0,
+    1.0,
+    2.0,
+    2.0,
+    2.0,
+    9.0,
+    8.0,
+    24.0,
+    3.0,
+    32.0,
+    16777216.0,
+    30.0,
+    0.0,
+    3.0,
+    3.0,
+    12.0,
+    1.5,
+    0.0,
+    1.0,
+    1.0,
+    4.0,
+    21.0,
+    -7.0,
+];
+
 #[test]
 fn instructions() {
-    run_local(
-        "instructions.vm",
-        &[
-            1, 2, 1, 0, 2, 20, 22, 4, 2, 7, 1, 12, 3, 2, -5, 59, 4, 63, 20, 6, 2, 10, 11, 100, 102,
-            200, 202, 300, 301, 303, 401, 403, 500, 502, 503, 602, 603,
-        ],
+    run_with_args(&[], "tests/instructions.vm", INSTRUCTIONS_OUTPUT);
+}
+
+#[test]
+fn instructions_optimized() {
+    run_with_args(
+        &["--optimize"],
+        "tests/instructions.vm",
+        INSTRUCTIONS_OUTPUT,
     );
 }
 
+#[test]
+fn instructions_verified() {
+    run_with_args(&["--verify"], "tests/instructions.vm", INSTRUCTIONS_OUTPUT);
+}
+
 #[test]
 fn operands() {
     run_local(
@@ -74,3 +207,121 @@ fn operands() {
         ],
     );
 }
+
+#[test]
+fn bits64() {
+    run_local(
+        "bits64.vm",
+        &[65536, 1, 100000, 1, 40, 23, 137, 1, 5, 1, 18, -8577],
+    );
+}
+
+#[test]
+fn bytes() {
+    run_local(
+        "bytes.vm",
+        &[
+            120, 18, 13398, 254, -2, -1, 65535, -855638016, 171, 43981, 72, 105, 26952,
+        ],
+    );
+}
+
+#[test]
+fn vectors() {
+    run_local(
+        "vectors.vm",
+        &[-10, -100, 22, -27, -56, 46, -2, -4, -2147483648],
+    );
+}
+
+#[test]
+fn cores() {
+    let expected = [300, 300, 5, 7, 7, 9];
+    run_local("cores.vm", &expected);
+    run_with_args(&["--quantum", "7"], "tests/cores.vm", &expected);
+    run_with_args(&["--threads"], "tests/cores.vm", &expected);
+}
+
+#[test]
+fn generator() {
+    let expected = [1, 1, 2, 3, 5, 8];
+    run_with_args(&["--cooperative"], "tests/generator.vm", &expected);
+    run_with_args(&["--quantum", "1000"], "tests/generator.vm", &expected);
+}
+
+#[test]
+fn pipeline() {
+    let stages = ["tests/pipeline/producer.vm", "tests/pipeline/filter.vm"];
+    let expected = [1, 9, 25, 49, 81, 165];
+    run_with_args(&stages, "tests/pipeline/consumer.vm", &expected);
+    let args = [&["--capacity", "4", "--quantum", "3"], &stages[..]].concat();
+    run_with_args(&args, "tests/pipeline/consumer.vm", &expected);
+}
+
+#[test]
+fn deadlock() {
+    let output = tvmi()
+        .args(["--channels", "2"])
+        .args(["tests/pipeline/consumer.vm", "tests/pipeline/consumer.vm"])
+        .output()
+        .expect("Failed to execute tests/pipeline/consumer.vm");
+
+    assert!(!output.status.success());
+
+    let result = String::from_utf8(output.stdout).unwrap();
+    assert!(result.contains("deadlock"));
+    for program in 0..2 {
+        assert!(result.contains(&format!(
+            "program {} at instruction 1: recv eax, 1 (tests/pipeline/consumer.vm:5)",
+            program
+        )));
+    }
+}
+
+#[test]
+fn runtime_error() {
+    let output = tvmi()
+        .arg("tests/runtime_error.vm")
+        .output()
+        .expect("Failed to execute tests/runtime_error.vm");
+
+    assert!(!output.status.success());
+
+    let result = String::from_utf8(output.stdout).unwrap();
+    assert!(result.contains("data address 16777216 is out of range"));
+    assert!(result.contains("at instruction 2: mov [16777216], eax (tests/runtime_error.vm:5)"));
+    assert!(result.contains("eax=1 ebx=2"));
+}
+
+#[test]
+fn core_dump() {
+    let directory = tempfile::tempdir().unwrap();
+    let core = directory.path().join("core_dump.core");
+    let core = core.to_str().unwrap();
+
+    let output = tvmi()
+        .args(["--core-dump", core])
+        .arg("tests/core_dump.vm")
+        .output()
+        .expect("Failed to execute tests/core_dump.vm");
+    assert!(!output.status.success());
+
+    let inspect = |command: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--example", "tvm-core", "--", core])
+            .args(command)
+            .output()
+            .expect("Failed to execute tvm-core");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        inspect(&["backtrace"]),
+        "#0 instruction 6 in inner (tests/core_dump.vm:13)\n\
+         #1 instruction 4 in outer (tests/core_dump.vm:9)\n\
+         #2 instruction 1 in start (tests/core_dump.vm:4)\n"
+    );
+    assert!(inspect(&["info"]).contains("data address 16777216 is out of range"));
+    assert!(inspect(&["stack", "3"]).contains(": 00000005 00000022 00000002"));
+}
@@ -0,0 +1,221 @@
+//! Runs every test program before and after `Program::optimize`, and checks
+//! that they print the same output and end in the same state.
+
+mod common;
+
+use common::{load, programs, VENDOR_PROGRAMS};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tinyvm::{
+    context::{ExecutionError, Memory, Program},
+    instruction::{Instruction, Register},
+};
+
+/// Enough for most programs to finish in a debug build; the others only have
+/// their output compared.
+const STEP_LIMIT: u64 = 4_000_000;
+
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Execution {
+    memory: Memory,
+    output: Vec<u8>,
+    /// `None` if the program did not finish.
+    result: Option<Result<(), ExecutionError>>,
+    /// The addresses `call` pushed return addresses to, which refer to
+    /// different instructions in the optimized program.
+    return_addresses: HashSet<i64>,
+}
+
+fn execute(program: &Program) -> Execution {
+    let mut memory = program.initialize();
+    let output = SharedOutput::default();
+    memory.output = Box::new(output.clone());
+
+    let mut return_addresses = HashSet::new();
+    let mut result = None;
+    for _ in 0..STEP_LIMIT {
+        let eip = memory.registers[Register::Eip as usize];
+        if let Some(Instruction::Call(_)) = usize::try_from(eip)
+            .ok()
+            .and_then(|i| program.instructions.get(i))
+        {
+            return_addresses.insert(memory.registers[Register::Esp as usize] - 1);
+        }
+
+        match program.step(&mut memory) {
+            Ok(true) => {}
+            Ok(false) => {
+                result = Some(Ok(()));
+                break;
+            }
+            Err(e) => {
+                result = Some(Err(e));
+                break;
+            }
+        }
+    }
+
+    let output = output.0.lock().unwrap().clone();
+    Execution {
+        memory,
+        output,
+        result,
+        return_addresses,
+    }
+}
+
+/// The registers other than `eip`, which refers to different instructions
+/// in the optimized program.
+fn registers(registers: &[i64]) -> Vec<i64> {
+    let mut registers = registers.to_vec();
+    registers[Register::Eip as usize] = 0;
+    registers
+}
+
+fn assert_same_behavior(name: &str, program: &Program) {
+    let optimized = program.optimize();
+    assert!(
+        optimized.instructions.len() <= program.instructions.len(),
+        "{}",
+        name
+    );
+
+    let original = execute(program);
+    let result = execute(&optimized);
+
+    let original_result = match original.result {
+        Some(result) => result,
+        None => {
+            // the optimized program may have got further in the same number
+            // of steps
+            let len = original.output.len().min(result.output.len());
+            assert!(
+                original.output[..len] == result.output[..len],
+                "{}: the output differs",
+                name
+            );
+            return;
+        }
+    };
+
+    assert!(
+        original.output == result.output,
+        "{}: the output differs:\n{}\nexpected:\n{}",
+        name,
+        String::from_utf8_lossy(&result.output),
+        String::from_utf8_lossy(&original.output)
+    );
+    match (original_result, result.result) {
+        (Ok(()), Some(Ok(()))) => {}
+        (Err(expected), Some(Err(e))) => {
+            assert_eq!(e.kind, expected.kind, "{}", name);
+            assert_eq!(
+                registers(&*e.registers),
+                registers(&*expected.registers),
+                "{}",
+                name
+            );
+            assert_eq!(e.flags, expected.flags, "{}", name);
+            assert_eq!(e.remainder, expected.remainder, "{}", name);
+        }
+        (expected, result) => panic!("{}: {:?} instead of {:?}", name, result, expected),
+    }
+
+    assert_eq!(
+        registers(&result.memory.registers),
+        registers(&original.memory.registers),
+        "{}",
+        name
+    );
+    assert_eq!(result.memory.flags, original.memory.flags, "{}", name);
+    assert_eq!(
+        result.memory.remainder, original.memory.remainder,
+        "{}",
+        name
+    );
+    for (addr, (value, expected)) in result
+        .memory
+        .mem_space
+        .iter()
+        .zip(original.memory.mem_space.iter())
+        .enumerate()
+    {
+        let addr = addr as i64;
+        if value != expected
+            && !(original.return_addresses.contains(&addr)
+                && result.return_addresses.contains(&addr))
+        {
+            panic!("{}: [{}] is {} instead of {}", name, addr, value, expected);
+        }
+    }
+}
+
+#[test]
+fn vendor_programs() {
+    let paths = programs(Path::new(VENDOR_PROGRAMS));
+    assert!(paths.len() >= 15, "vendor programs not found");
+
+    for path in paths {
+        assert_same_behavior(&path.display().to_string(), &load(&path));
+    }
+}
+
+#[test]
+fn test_programs() {
+    for path in programs(Path::new("tests")) {
+        assert_same_behavior(&path.display().to_string(), &load(&path));
+    }
+}
+
+#[test]
+fn redundant_programs() {
+    for source in &[
+        "mov eax, eax\nmov ebx, 5\nadd ebx, 3\nmul ebx, 2\nadd ecx, 0\njmp a\n\
+         a: jmp b\nprn 99\nb: cmp ebx, 16\nje c\nprn 1\nc: mov eax, 0\n\
+         loop: inc eax\ncmp eax, 10\njl loop\nprn ebx\njmp end\nprn 7\nend:",
+        "mov eax, 7\nmod eax, 3\nrem ebx\nprn ebx\nshl eax, 32\nshr eax, 1\nprn eax",
+        "mov eax, 1\nmov eax, 2\nmov [5], eax\nprn [5]\nmov ebx, 3\nmov ebx, [7]\nprn ebx",
+        "mov eax, 1\nmov ebx, 0\ndiv eax, ebx\nmov eax, 2",
+        "mov eax, 1\npush eax\nmov eax, 2\npop ebx\nprn ebx",
+        "mov eax, 1\nmov ebx, [200000000]\nmov eax, 2",
+        "mov eax, -2147483648\ndiv eax, -1\nprn eax\nmov ebx, 5\nxor ebx, ebx\nsub ecx, ecx",
+        "cmp 1, 2\njg skip\njl taken\nprn 1\ntaken: prn 2\nskip: pushf\npop eax\nprn eax",
+        "start: jmp one\nzero: jmp zero\none: jmp two\ntwo: prn 2\njmp 100",
+        "jmp b\na: jmp a\nb: jmp -1",
+        "f: prn 1\nret\nstart: call f\nmov eax, 3\ncall f\nprn eax",
+    ] {
+        let program = Program::load(source.to_string()).unwrap();
+        assert_same_behavior(source, &program);
+    }
+}
+
+#[test]
+fn programs_that_observe_code_addresses() {
+    for source in &[
+        "nop\nnop\nlabel: mov eax, label\nprn eax\nprn eip",
+        "nop\npush back\nret\nprn 1\nback: prn 2",
+        "nop\nmov eax, target\njmp eax\nprn 1\ntarget: prn 2",
+        "nop\nmov eax, eip\nadd eax, 4\njmp eax\nnop\nprn 1\nprn 2",
+        "nop\nmov eax, 1\nint\nnop",
+    ] {
+        let program = Program::load(source.to_string()).unwrap();
+        assert_same_behavior(source, &program);
+    }
+}
@@ -0,0 +1,48 @@
+//! Checks `Program::verify` against the programs that are known to run
+//! without errors, and those that are known to fail.
+
+mod common;
+
+use common::{load, programs, VENDOR_PROGRAMS};
+use std::path::Path;
+use tinyvm::verifier::{DiagnosticKind, Severity};
+
+#[test]
+fn vendor_programs_have_no_errors() {
+    let paths = programs(Path::new(VENDOR_PROGRAMS));
+    assert!(paths.len() >= 15, "vendor programs not found");
+
+    for path in paths {
+        let diagnostics = load(&path).verify();
+        assert!(
+            !diagnostics.iter().any(|d| d.is_error()),
+            "{}: {:?}",
+            path.display(),
+            diagnostics
+        );
+    }
+}
+
+#[test]
+fn runtime_errors_are_found() {
+    for (path, line) in &[("tests/runtime_error.vm", 5), ("tests/core_dump.vm", 13)] {
+        let diagnostics: Vec<_> = load(Path::new(path))
+            .verify()
+            .into_iter()
+            .filter(|d| d.is_error())
+            .collect();
+
+        assert_eq!(diagnostics.len(), 1, "{}", path);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Severity::Error, "{}", path);
+        assert_eq!(
+            diagnostic.kind,
+            DiagnosticKind::DataAddressOutOfRange(16777216),
+            "{}",
+            path
+        );
+        let location = diagnostic.location.as_ref().unwrap();
+        assert_eq!(location.file.as_deref(), Some(*path));
+        assert_eq!(location.line, *line, "{}", path);
+    }
+}